@@ -0,0 +1,102 @@
+//! Output size and encode time with absolute quantities (the default) against
+//! quantity deltas, on BTCUSDT trades from a `--raw-log` capture.
+//!
+//! cargo bench --bench quantity_deltas -- capture.jsonl
+//!
+//! Without a capture it runs on a synthetic sample: sizes drawn from a handful
+//! of round lots, repeating as they do when one order sweeps the book.
+
+use std::fs::File;
+use std::hint::black_box;
+use std::io::BufReader;
+use std::time::Instant;
+
+use perp_signal_hft::format::{BinaryFormat, Trade};
+use perp_signal_hft::rawlog::read_raw_log;
+
+const SYMBOL: &str = "BTCUSDT";
+const SYNTHETIC_TRADES: u64 = 1_000_000;
+
+fn captured(path: &str) -> Vec<Trade> {
+    let file = File::open(path).expect("open capture");
+    read_raw_log(BufReader::new(file))
+        .expect("read capture")
+        .into_iter()
+        .filter(|raw| raw.asset == SYMBOL)
+        .map(|raw| Trade {
+            symbol: raw.asset,
+            timestamp: raw.timestamp,
+            price: raw.price.parse().expect("price"),
+            quantity: raw.quantity.parse().expect("quantity"),
+            is_buyer_maker: raw.is_buyer_maker,
+        })
+        .collect()
+}
+
+fn synthetic() -> Vec<Trade> {
+    const LOTS: [f64; 6] = [0.001, 0.002, 0.003, 0.005, 0.01, 0.1];
+    let mut rng = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move || {
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        rng
+    };
+    let mut quantity = LOTS[0];
+    let mut price = 45000.0;
+    (0..SYNTHETIC_TRADES)
+        .map(|i| {
+            let roll = next();
+            // A new size for about one trade in four, else the sweep goes on
+            if roll % 4 == 0 {
+                quantity = LOTS[(roll >> 8) as usize % LOTS.len()];
+            }
+            price += ((roll >> 16) % 5) as f64 * 0.1 - 0.2;
+            Trade {
+                symbol: SYMBOL.to_string(),
+                timestamp: 1_700_000_000_000 + i,
+                price,
+                quantity,
+                is_buyer_maker: roll & 0x100 != 0,
+            }
+        })
+        .collect()
+}
+
+fn bench(label: &str, trades: &[Trade], quantity_deltas: bool) {
+    let first = &trades[0];
+    let (mut encoder, _) = BinaryFormat::builder()
+        .reference_timestamp(first.timestamp)
+        .quantity_deltas(quantity_deltas)
+        .assets(vec![(SYMBOL.to_string(), first.price, first.quantity, 1e5)])
+        .build()
+        .unwrap();
+
+    let mut buffer = Vec::with_capacity(64);
+    let mut bytes = 0;
+    let start = Instant::now();
+    for trade in trades {
+        buffer.clear();
+        encoder.encode_into(black_box(trade), &mut buffer).unwrap();
+        bytes += buffer.len();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<10} {:>6.3} bytes/trade {:>8.1} ns/trade",
+        label,
+        bytes as f64 / trades.len() as f64,
+        elapsed.as_nanos() as f64 / trades.len() as f64
+    );
+}
+
+fn main() {
+    // `cargo bench` passes `--bench` along to the harness
+    let trades = match std::env::args().skip(1).find(|arg| !arg.starts_with("--")) {
+        Some(path) => captured(&path),
+        None => synthetic(),
+    };
+    assert!(!trades.is_empty(), "no {} trades in the capture", SYMBOL);
+    println!("{} {} trades", trades.len(), SYMBOL);
+    bench("absolute", &trades, false);
+    bench("deltas", &trades, true);
+}
@@ -0,0 +1,88 @@
+//! SHM queue push/pop round trip: a producer thread pushing a fixed workload
+//! and a spinning consumer thread popping it, (a) saturated, pushing as fast as
+//! the queue takes frames, and (b) paced at a fixed interval. Reports consumer
+//! throughput and push-to-pop latency percentiles.
+//!
+//! Each frame carries its push time up front, as trades carry `received_at`,
+//! so latency is taken when the consumer pops it.
+//!
+//! cargo bench --bench shm_round_trip
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use perp_signal_hft::ipc::shm_queue::{ShmQueue, WaitStrategy};
+
+const CAPACITY: u32 = 1024 * 1024;
+/// Stamp plus a typical encoded trade (4-8 bytes)
+const FRAME_LEN: usize = 16;
+const SATURATED_MESSAGES: u64 = 2_000_000;
+const PACED_MESSAGES: u64 = 200_000;
+const PACED_INTERVAL: Duration = Duration::from_micros(5);
+
+fn bench(label: &str, messages: u64, interval: Option<Duration>) {
+    let name = format!("perp_signal_hft_bench_{}_{}", label, std::process::id());
+    let producer_queue = ShmQueue::create(&name, CAPACITY).unwrap();
+    let consumer_queue = ShmQueue::create(&name, CAPACITY).unwrap();
+    let origin = Instant::now();
+
+    let consumer = thread::spawn(move || {
+        let mut latencies = Vec::with_capacity(messages as usize);
+        let mut first = None;
+        for _ in 0..messages {
+            let frame = consumer_queue.pop_blocking(WaitStrategy::Spin).unwrap();
+            let now = origin.elapsed().as_nanos() as u64;
+            first.get_or_insert(now);
+            let sent = u64::from_le_bytes(frame[..8].try_into().unwrap());
+            latencies.push(now.saturating_sub(sent));
+        }
+        let elapsed = origin.elapsed().as_nanos() as u64 - first.unwrap_or_default();
+        (latencies, elapsed)
+    });
+
+    let producer = thread::spawn(move || {
+        let mut frame = [0u8; FRAME_LEN];
+        for i in 0..messages {
+            if let Some(interval) = interval {
+                let due = interval * i as u32;
+                while origin.elapsed() < due {
+                    std::hint::spin_loop();
+                }
+            }
+            frame[8..].fill(i as u8);
+            frame[..8].copy_from_slice(&(origin.elapsed().as_nanos() as u64).to_le_bytes());
+            producer_queue
+                .push_blocking(&frame, WaitStrategy::Spin, Duration::from_secs(10))
+                .unwrap();
+        }
+    });
+
+    producer.join().unwrap();
+    let (mut latencies, elapsed) = consumer.join().unwrap();
+    std::fs::remove_file(format!("/dev/shm/{}", name)).unwrap();
+
+    latencies.sort_unstable();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!(
+        "{:<10} {:>12.0} msg/s   p50 {:>6} ns   p99 {:>7} ns   p99.9 {:>8} ns   max {:>9} ns",
+        label,
+        messages as f64 / (elapsed as f64 / 1e9),
+        percentile(0.5),
+        percentile(0.99),
+        percentile(0.999),
+        latencies[latencies.len() - 1],
+    );
+}
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    if cores < 2 {
+        println!(
+            "warning: {} core, producer and consumer share it; latencies are scheduler noise",
+            cores
+        );
+    }
+    // Saturated latency is mostly time spent queued behind a full ring
+    bench("saturated", SATURATED_MESSAGES, None);
+    bench("paced", PACED_MESSAGES, Some(PACED_INTERVAL));
+}
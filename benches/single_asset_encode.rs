@@ -0,0 +1,69 @@
+//! Encode throughput for a single-asset encoder (symbol compare fast path)
+//! against a multi-asset one (hash lookup), on the same trades.
+//!
+//! cargo bench --bench single_asset_encode
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use perp_signal_hft::format::{BinaryFormat, Trade};
+
+const TRADES: u64 = 1_000_000;
+
+fn bench(label: &str, assets: Vec<String>) {
+    let n = assets.len();
+    let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
+    let mut header = Vec::new();
+    encoder
+        .write_header(
+            &mut header,
+            1_700_000_000_000,
+            &vec![45000.0; n],
+            &vec![1.0; n],
+        )
+        .unwrap();
+
+    let mut trade = Trade {
+        symbol: "BTCUSDT".to_string(),
+        timestamp: 1_700_000_000_000,
+        price: 45000.0,
+        quantity: 0.001,
+        is_buyer_maker: false,
+    };
+
+    let mut buffer = Vec::with_capacity(64);
+    let start = Instant::now();
+    for i in 0..TRADES {
+        trade.timestamp += 1;
+        trade.price = 45000.0 + (i % 17) as f64 * 0.1;
+        trade.quantity = 0.001 * (i % 13 + 1) as f64;
+        trade.is_buyer_maker = i.is_multiple_of(2);
+        buffer.clear();
+        encoder
+            .write_message(black_box(&trade), &mut buffer)
+            .unwrap();
+        black_box(&buffer);
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<12} {:>8.1} ns/trade",
+        label,
+        elapsed.as_nanos() as f64 / TRADES as f64
+    );
+}
+
+fn main() {
+    bench("1 asset", vec!["BTCUSDT".to_string()]);
+    bench(
+        "10 assets",
+        (0..10)
+            .map(|i| {
+                if i == 0 {
+                    "BTCUSDT".to_string()
+                } else {
+                    format!("ASSET{}USDT", i)
+                }
+            })
+            .collect(),
+    );
+}
@@ -1,4 +1,4 @@
-use perp_signal_hft::ipc::shm_queue::ShmQueue;
+use perp_signal_hft::ipc::shm_queue::{ShmQueue, WaitStrategy};
 use std::{thread, time::Duration};
 
 fn main() -> std::io::Result<()> {
@@ -9,6 +9,21 @@ fn main() -> std::io::Result<()> {
     let producer_queue = ShmQueue::create("trade_queue", capacity)?;
     let consumer_queue = ShmQueue::create("trade_queue", capacity)?;
 
+    // Spawn a consumer thread, parked while the queue is empty
+    let consumer = thread::spawn(move || {
+        let wait = WaitStrategy::Park {
+            spins: 1000,
+            timeout: Duration::from_secs(1),
+        };
+        for _ in 0..5 {
+            let data = consumer_queue.pop_blocking(wait).expect("pop failed");
+            let text = String::from_utf8(data).expect("invalid utf8");
+            println!("Consumed: {}", text);
+        }
+    });
+    // Same process, so the producer can wake it on every push
+    producer_queue.register_consumer(consumer.thread().clone())?;
+
     // Spawn a producer thread
     let producer = thread::spawn(move || {
         for i in 0..5 {
@@ -19,21 +34,6 @@ fn main() -> std::io::Result<()> {
         }
     });
 
-    // Spawn a consumer thread
-    let consumer = thread::spawn(move || {
-        let mut received = 0;
-        while received < 5 {
-            if let Some(data) = consumer_queue.pop().expect("pop failed") {
-                let text = String::from_utf8(data).expect("invalid utf8");
-                println!("Consumed: {}", text);
-                received += 1;
-            } else {
-                // no message yet, wait a bit
-                thread::sleep(Duration::from_millis(50));
-            }
-        }
-    });
-
     producer.join().unwrap();
     consumer.join().unwrap();
 
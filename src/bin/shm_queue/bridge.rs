@@ -0,0 +1,102 @@
+// bridge.rs
+use clap::Parser;
+use perp_signal_hft::ipc::{
+    bridge::{Bridge, DEFAULT_MAX_PENDING},
+    framing::Framing,
+    governor::{MemoryBudget, MemoryGovernor},
+    shm_queue::{ShmQueue, WaitOpts},
+    tcp,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+use tokio::sync::broadcast;
+
+/// SHM fan-out relay
+#[derive(Parser)]
+#[clap(
+    name = "shm_bridge",
+    about = "Relay one SHM queue into other SHM queues and/or TCP clients"
+)]
+struct Opts {
+    /// SHM queue to read from
+    #[clap(long, default_value = "trade_queue")]
+    from: String,
+
+    /// SHM queue to copy into, repeatable
+    #[clap(long)]
+    to: Vec<String>,
+
+    /// Ring-buffer capacity in bytes, for every queue
+    #[clap(long, default_value_t = 1024 * 1024)]
+    capacity: u32,
+
+    /// Frames held per full output queue before dropping
+    #[clap(long, default_value_t = DEFAULT_MAX_PENDING)]
+    max_pending: usize,
+
+    /// Also serve the stream to TCP clients on this port
+    #[clap(long)]
+    tcp_port: Option<u16>,
+
+    /// Length prefix of each TCP frame
+    #[clap(long, value_enum, default_value_t = Framing::U32)]
+    framing: Framing,
+
+    #[clap(flatten)]
+    budget: MemoryBudget,
+
+    #[command(flatten)]
+    wait: WaitOpts,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt::init();
+    let opts = Opts::parse();
+    if opts.to.is_empty() && opts.tcp_port.is_none() {
+        return Err("nothing to relay to, give --to and/or --tcp-port".into());
+    }
+    let wait = opts.wait.strategy();
+
+    let upstream = ShmQueue::create(&opts.from, opts.capacity)?;
+    let downstreams = opts
+        .to
+        .iter()
+        .map(|name| ShmQueue::create(name, opts.capacity))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut bridge = Bridge::new(upstream, downstreams).with_max_pending(opts.max_pending);
+
+    let server = match opts.tcp_port {
+        Some(port) => {
+            let (tx, _) = broadcast::channel::<Vec<u8>>(100);
+            let header = Arc::new(RwLock::new(Vec::new()));
+            bridge = bridge.with_tcp(header.clone(), tx.clone());
+            Some((port, header, tx))
+        }
+        None => None,
+    };
+
+    let mut bridge =
+        tokio::task::spawn_blocking(move || bridge.forward_handshake(wait).map(|_| bridge))
+            .await??;
+    println!("Bridge: relaying {} to {:?}", opts.from, opts.to);
+
+    if let Some((port, header, tx)) = server {
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        let governor = Arc::new(MemoryGovernor::new(opts.budget));
+        let serve_opts = tcp::ServeOptions {
+            framing: opts.framing,
+            ..Default::default()
+        };
+        tokio::spawn(async move {
+            if let Err(e) = tcp::serve(addr, header, tx, governor, serve_opts).await {
+                tracing::error!("TCP server failed: {}", e);
+            }
+        });
+    }
+
+    tokio::task::spawn_blocking(move || bridge.run(wait)).await??;
+    Ok(())
+}
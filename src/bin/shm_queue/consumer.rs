@@ -1,35 +1,30 @@
-use perp_signal_hft::ipc::shm_queue::ShmQueue;
-use std::hint;
+use clap::Parser;
+use perp_signal_hft::ipc::shm_queue::{ShmQueue, WaitOpts};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Latency probe for the raw SHM queue, paired with `shm-q-p`
+#[derive(Parser)]
+#[clap(name = "shm_latency_consumer", about = "Measure SHM queue latency")]
+struct Opts {
+    #[command(flatten)]
+    wait: WaitOpts,
+}
+
 fn main() -> std::io::Result<()> {
+    let wait = Opts::parse().wait.strategy();
     // Shared memory queue must match producer
     let capacity = 1024 * 1024;
     let queue_name = "trade_queue";
     let queue = ShmQueue::create(queue_name, capacity)?;
 
-    // Spin-wait for START handshake without sleeping
-    loop {
-        if let Some(data) = queue.pop()? {
-            if data == b"START" {
-                println!("Consumer: received START handshake");
-                break;
-            }
-        }
-        hint::spin_loop();
-    }
+    while queue.pop_blocking(wait)? != b"START" {}
+    println!("Consumer: received START handshake");
 
     let mut out = vec![];
 
-    // Process timestamped messages with busy spin
+    // Process timestamped messages
     for count in 0..100 {
-        // Wait for next message
-        let data = loop {
-            if let Some(d) = queue.pop()? {
-                break d;
-            }
-            hint::spin_loop();
-        };
+        let data = queue.pop_blocking(wait)?;
 
         // Compute latency
         let sent = u128::from_le_bytes(data.try_into().unwrap());
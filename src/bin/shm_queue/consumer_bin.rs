@@ -1,12 +1,14 @@
 // consumer.rs
 use clap::Parser;
 use perp_signal_hft::{
-    format::{BinaryFormat, Trade},
-    ipc::shm_queue::ShmQueue,
+    format::Record,
+    ipc::{
+        consumer::{ErrorPolicy, ShmSource, TradeConsumer},
+        shm_queue::{ShmQueue, WaitOpts},
+    },
 };
 use std::{
-    hint,
-    io::Cursor,
+    path::PathBuf,
     time::{SystemTime, UNIX_EPOCH},
 };
 
@@ -21,57 +23,85 @@ struct Opts {
     /// Ring-buffer capacity in bytes
     #[clap(long, default_value_t = 1024 * 1024)]
     capacity: u32,
+
+    /// What to do after a frame fails to decode
+    #[clap(long, value_enum, default_value_t = ErrorPolicy::Resync)]
+    on_error: ErrorPolicy,
+
+    /// Replay this recording (eg: a `--raw-log` .bin) before the live queue
+    #[clap(long)]
+    replay: Option<PathBuf>,
+
+    #[command(flatten)]
+    wait: WaitOpts,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opts = Opts::parse();
-    let queue_name = &opts.queue_name;
-    let capacity = opts.capacity;
-
-    // Init decoder and SHM queue
-    let mut decoder = BinaryFormat::new();
-    let queue = ShmQueue::create(queue_name, capacity)?;
-
-    loop {
-        if let Some(data) = queue.pop()? {
-            if data == b"START" {
-                println!("Consumer: received START handshake");
-                break;
-            }
-        }
-        hint::spin_loop();
+    let queue = ShmQueue::create(&opts.queue_name, opts.capacity)?;
+    let mut consumer =
+        TradeConsumer::new(ShmSource::new(queue, opts.wait.strategy()), opts.on_error);
+    if let Some(path) = &opts.replay {
+        consumer = consumer.with_replay(path)?;
     }
 
-    let header_buf = loop {
-        if let Some(buf) = queue.pop()? {
-            break buf;
-        }
-        hint::spin_loop();
-    };
-    decoder.read_header(&mut Cursor::new(&header_buf))?;
-    println!("Consumer: read HEADER");
-
     let mut count = 0;
-    loop {
-        let data = loop {
-            if let Some(buf) = queue.pop()? {
-                break buf;
+    while let Some(record) = consumer.next_record() {
+        let trade = match record {
+            Err(e) => {
+                println!(
+                    "Consumer: decode error #{} ({:?}): {}",
+                    consumer.errors(),
+                    opts.on_error,
+                    e
+                );
+                continue;
+            }
+            Ok(Record::Heartbeat(ts)) => {
+                println!("Consumer: heartbeat, producer alive at {}", ts);
+                continue;
+            }
+            Ok(Record::Funding { symbol, rate, .. }) => {
+                println!("Consumer: {} funding rate {}", symbol, rate);
+                continue;
+            }
+            Ok(Record::Gap { symbol, dropped }) => {
+                println!(
+                    "Consumer: {} dropped ~{} trades, volume is short",
+                    symbol, dropped
+                );
+                continue;
+            }
+            Ok(Record::Reset(ts)) => {
+                println!("Consumer: producer reset every asset's reference at {}", ts);
+                continue;
+            }
+            Ok(Record::Unknown { .. }) => continue,
+            Ok(Record::Trade(trade)) => trade,
+            Ok(Record::Keyframe(trade)) => {
+                println!(
+                    "Consumer: keyframe for {}, producer has dropped {} messages",
+                    trade.symbol,
+                    consumer.source().queue().dropped()
+                );
+                trade
             }
-            hint::spin_loop();
         };
 
-        let mut cursor = Cursor::new(&data);
-        let trade: Trade = decoder.read_message(&mut cursor)?;
-
-        let now_ns = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
-        let latency = now_ns.saturating_sub(trade.timestamp);
+        let now_us = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64;
+        let sent_us = consumer
+            .decoder()
+            .timestamp_unit()
+            .to_micros(trade.timestamp);
+        let latency = now_us.saturating_sub(sent_us);
         count += 1;
 
         println!(
-            "Consumed {idx}: {trade:?}, latency {ms} millis",
+            "Consumed {idx}: {trade:?}, latency {us} micros",
             idx = count,
             trade = trade,
-            ms = format_args!("{:}", latency),
+            us = format_args!("{:}", latency),
         );
     }
+    Ok(())
 }
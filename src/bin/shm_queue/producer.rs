@@ -1,5 +1,5 @@
+use perp_signal_hft::clock;
 use perp_signal_hft::ipc::shm_queue::ShmQueue;
-use std::time::{SystemTime, UNIX_EPOCH};
 use std::{thread, time::Duration};
 
 fn main() -> std::io::Result<()> {
@@ -12,10 +12,7 @@ fn main() -> std::io::Result<()> {
     println!("Producer: sent START handshake");
 
     for i in 0..100 {
-        let micros = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
+        let micros = clock::unix_now().as_nanos();
 
         queue.push(&micros.to_le_bytes())?;
         println!("Produced {}: {} µs", i, micros);
@@ -1,9 +1,10 @@
 use perp_signal_hft::binance::TradeMessage;
-use perp_signal_hft::{format::BinaryFormat, ipc::shm_queue::ShmQueue};
-use std::{
-    thread,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+use perp_signal_hft::clock;
+use perp_signal_hft::{
+    format::{BinaryFormat, STREAM_MAGIC, TimestampUnit},
+    ipc::shm_queue::ShmQueue,
 };
+use std::{thread, time::Duration};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let capacity = 1024 * 1024; // 1 MiB
@@ -14,14 +15,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         "ETHUSDT".to_string(),
         "SOLUSDT".to_string(),
     ];
-    let mut encoder = BinaryFormat::new().with_assets(assets.clone())?;
+    // Stamped with the local clock, so microseconds are meaningful here
+    let unit = TimestampUnit::Micros;
+    let mut encoder = BinaryFormat::new()
+        .with_assets(assets.clone())?
+        .with_timestamp_unit(unit);
 
     let queue = ShmQueue::create(queue_name, capacity)?;
 
+    queue.push(STREAM_MAGIC)?;
     queue.push(b"START")?;
     println!("Producer: sent START handshake");
 
-    let reference_timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    let reference_timestamp = unit.from_duration(clock::unix_now());
     let reference_prices = vec![45000.0f64, 2500.5f64, 120.75f64];
     let reference_quantities = vec![0.0f64, 0.0f64, 0.0f64];
     let mut header_buf = Vec::new();
@@ -37,7 +43,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     for i in 0..100 {
         let idx = (i % assets.len()) as usize;
         let symbol = assets[idx].clone();
-        let ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64;
+        let ts = unit.from_duration(clock::unix_now());
         let price = reference_prices[idx] + (i as f64);
         let quantity = 0.01 * (i as f64 + 1.0);
         let is_buyer_maker = i % 2 == 0;
@@ -49,8 +55,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             quantity: quantity.to_string(),
             is_buyer_maker,
             received_at: ts as u128,
+            shard: None,
+            raw: None,
         };
-        let trade = b.to_trade().unwrap();
+        let trade = b.to_trade()?;
         let encoded = encoder.encode(&trade)?;
         queue.push(&encoded)?;
         println!("Produced {}: {:?}", i, trade);
@@ -1,8 +1,36 @@
-use perp_signal_hft::format::{BinaryFormat, BinaryFormatError, Trade};
+use clap::Parser;
+use perp_signal_hft::clock;
+use perp_signal_hft::format::{BinaryFormat, BinaryFormatError, STREAM_MAGIC, Trade};
 use std::io::Write;
 use std::net::{TcpListener, TcpStream};
-use std::thread::sleep;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, sleep};
+use std::time::Duration;
+
+/// Synthetic trade server for local client development.
+///
+/// Defaults match the original fixture: one client, 10 trades 50ms apart, then exit.
+#[derive(Parser, Clone)]
+#[clap(name = "tcp_server", about = "Serve synthetic trades over TCP")]
+struct Opts {
+    /// Trades sent to each client (ignored with --forever)
+    #[clap(long, default_value_t = 10)]
+    count: u64,
+
+    /// Delay between trades in milliseconds
+    #[clap(long, default_value_t = 50)]
+    rate_ms: u64,
+
+    /// Clients served at the same time. Without --forever the server exits once
+    /// this many clients have been served; with it, extra connections are refused.
+    #[clap(long, default_value_t = 1)]
+    max_clients: usize,
+
+    /// Keep accepting clients and stream trades until each one disconnects
+    #[clap(long)]
+    forever: bool,
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum AppError {
@@ -10,18 +38,16 @@ pub enum AppError {
     Io(#[from] std::io::Error),
     #[error("Format error: {0}")]
     Format(#[from] BinaryFormatError),
-    #[error("Time error: {0}")]
-    Time(#[from] std::time::SystemTimeError),
 }
 
-fn handle_client(mut stream: TcpStream) -> Result<(), AppError> {
+fn handle_client(mut stream: TcpStream, opts: &Opts) -> Result<(), AppError> {
     stream.set_nodelay(true)?;
 
-    // Sending a start hand shake
-    let start = b"START";
-    let len = (start.len() as u32).to_le_bytes();
-    stream.write_all(&len)?;
-    stream.write_all(start)?;
+    // Sending the stream magic and a start hand shake
+    for frame in [STREAM_MAGIC, b"START"] {
+        stream.write_all(&(frame.len() as u32).to_le_bytes())?;
+        stream.write_all(frame)?;
+    }
 
     let assets = vec![
         "BTCUSDT".to_string(),
@@ -29,7 +55,7 @@ fn handle_client(mut stream: TcpStream) -> Result<(), AppError> {
         "SOLUSDT".to_string(),
     ];
     let mut encoder = BinaryFormat::new().with_assets(assets.clone())?;
-    let reference_timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    let reference_timestamp = clock::unix_now().as_millis() as u64;
     let reference_prices = vec![45000.0, 2500.5, 120.75];
     let reference_quantities = vec![0.0, 0.0, 0.0];
     let mut header_buf = Vec::new();
@@ -43,13 +69,15 @@ fn handle_client(mut stream: TcpStream) -> Result<(), AppError> {
     stream.write_all(&hdr_len)?;
     stream.write_all(&header_buf)?;
 
-    for i in 0..10 {
-        let idx = (i % assets.len()) as usize;
+    let mut i = 0u64;
+    while opts.forever || i < opts.count {
+        let idx = (i % assets.len() as u64) as usize;
         let symbol = &assets[idx];
-        let ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
-        let price = reference_prices[idx] + (i as f64);
-        let quantity = 0.01 * (i as f64 + 1.0);
-        let is_buyer_maker = i % 2 == 0;
+        let ts = clock::unix_now().as_millis() as u64;
+        // Wander around the reference instead of drifting away when running forever
+        let price = reference_prices[idx] + (i % 100) as f64;
+        let quantity = 0.01 * ((i % 100) as f64 + 1.0);
+        let is_buyer_maker = i.is_multiple_of(2);
         let trade = Trade {
             symbol: symbol.clone(),
             timestamp: ts,
@@ -63,17 +91,59 @@ fn handle_client(mut stream: TcpStream) -> Result<(), AppError> {
         stream.write_all(&encoded)?;
 
         println!("Server: sent {:?}", trade);
-        sleep(Duration::from_millis(50));
+        sleep(Duration::from_millis(opts.rate_ms));
+        i += 1;
     }
 
     Ok(())
 }
 
+fn spawn_client(
+    stream: TcpStream,
+    opts: Arc<Opts>,
+    active: Arc<AtomicUsize>,
+) -> thread::JoinHandle<()> {
+    let peer = stream.peer_addr().ok();
+    println!("Server: client {:?} connected", peer);
+    thread::spawn(move || {
+        if let Err(e) = handle_client(stream, &opts) {
+            println!("Server: client {:?} error: {}", peer, e);
+        }
+        active.fetch_sub(1, Ordering::SeqCst);
+        println!("Server: client {:?} done", peer);
+    })
+}
+
 fn main() -> Result<(), AppError> {
+    let opts = Arc::new(Opts::parse());
     let listener = TcpListener::bind("0.0.0.0:9000")?;
     println!("Server listening on port 9000");
-    for stream in listener.incoming().take(1) {
-        handle_client(stream?)?;
+
+    let active = Arc::new(AtomicUsize::new(0));
+    if !opts.forever {
+        let mut handles = Vec::with_capacity(opts.max_clients);
+        for stream in listener.incoming().take(opts.max_clients) {
+            active.fetch_add(1, Ordering::SeqCst);
+            handles.push(spawn_client(stream?, opts.clone(), active.clone()));
+        }
+        for handle in handles {
+            let _ = handle.join();
+        }
+        return Ok(());
+    }
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        if active.load(Ordering::SeqCst) >= opts.max_clients {
+            println!(
+                "Server: refusing {:?}, {} clients already connected",
+                stream.peer_addr().ok(),
+                opts.max_clients
+            );
+            continue;
+        }
+        active.fetch_add(1, Ordering::SeqCst);
+        spawn_client(stream, opts.clone(), active.clone());
     }
     Ok(())
 }
@@ -0,0 +1,49 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use clap::Parser;
+use perp_signal_hft::rawlog::{RawLog, read_raw_log, verify};
+
+/// Decode a stream recorded with `--raw-log` and diff it against the raw trades.
+///
+/// Exits non-zero if a decoded trade has no raw counterpart or any error exceeds
+/// the stream's resolution.
+#[derive(Parser)]
+#[clap(
+    name = "verify_raw_log",
+    about = "Check an encoded stream against its raw log"
+)]
+struct Opts {
+    /// Prefix passed to --raw-log (reads <prefix>.jsonl and <prefix>.bin)
+    prefix: PathBuf,
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::parse();
+    let (trades_path, stream_path) = RawLog::paths(&opts.prefix);
+    let raw = read_raw_log(BufReader::new(File::open(trades_path)?))?;
+    let stream = std::fs::read(stream_path)?;
+    let report = verify(&stream, &raw)?;
+
+    println!("raw trades:         {}", raw.len());
+    println!("matched:            {}", report.matched);
+    println!("dropped:            {}", report.dropped.len());
+    println!("extra:              {}", report.extra.len());
+    println!(
+        "max price error:    {:e} (resolution {:e})",
+        report.max_price_error, report.price_resolution
+    );
+    println!(
+        "max quantity error: {:e} (resolution {:e})",
+        report.max_quantity_error, report.quantity_resolution
+    );
+    for trade in &report.extra {
+        println!("extra: {:?}", trade);
+    }
+
+    if !report.within_resolution() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -1,11 +1,12 @@
-use futures_util::{SinkExt, StreamExt};
+use futures_util::StreamExt;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
-use tokio::{sync::mpsc, time};
+use tokio::time;
 use tokio_tungstenite::connect_async;
 use url::Url;
 
+#[allow(non_snake_case)]
 #[derive(Debug, Deserialize)]
 #[serde(tag = "e")]
 pub enum StreamMessage {
@@ -37,7 +38,7 @@ struct StreamStats {
     total_delta: Duration,
     count: u64,
     delta_count: u64,
-    total_qty: f64
+    total_qty: f64,
 }
 
 impl StreamStats {
@@ -93,7 +94,7 @@ async fn main() {
         .expect("Failed to connect");
     println!("Connected to Binance WebSocket");
 
-    let (mut write, mut read) = ws_stream.split();
+    let (_write, mut read) = ws_stream.split();
 
     let mut stats: HashMap<String, StreamStats> = HashMap::new();
     let mut interval = time::interval(Duration::from_secs(60));
@@ -103,25 +104,24 @@ async fn main() {
         tokio::select! {
             msg = read.next() => {
                 if let Some(Ok(msg)) = msg {
-                    if let Ok(text) = msg.to_text() {
-                        if let Ok(value) = serde_json::from_str::<serde_json::Value>(text) {
-                            if let Some(data) = value.get("data") {
-                                match serde_json::from_value::<StreamMessage>(data.clone()) {
-                                    Ok(StreamMessage::Trade { q, .. }) => {
-                                        if let Ok(qty) = q.parse::<f64>() {
-                                            stats.entry("trade".into()).or_default().record(qty);
-                                        }
-                                    }
-                                    Ok(StreamMessage::AggTrade { q, .. }) => {
-                                        if let Ok(qty) = q.parse::<f64>() {
-                                            stats.entry("aggTrade".into()).or_default().record(qty);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        eprintln!("Failed to parse stream message: {e:?}");
-                                    }
+                    if let Ok(text) = msg.to_text()
+                        && let Ok(value) = serde_json::from_str::<serde_json::Value>(text)
+                        && let Some(data) = value.get("data")
+                    {
+                        match serde_json::from_value::<StreamMessage>(data.clone()) {
+                            Ok(StreamMessage::Trade { q, .. }) => {
+                                if let Ok(qty) = q.parse::<f64>() {
+                                    stats.entry("trade".into()).or_default().record(qty);
                                 }
                             }
+                            Ok(StreamMessage::AggTrade { q, .. }) => {
+                                if let Ok(qty) = q.parse::<f64>() {
+                                    stats.entry("aggTrade".into()).or_default().record(qty);
+                                }
+                            }
+                            Err(e) => {
+                                eprintln!("Failed to parse stream message: {e:?}");
+                            }
                         }
                     }
                 } else {
@@ -137,4 +137,3 @@ async fn main() {
         }
     }
 }
-
@@ -1,15 +1,22 @@
 // std
 use std::future::Future;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 // external
 use futures::stream::{self, StreamExt};
 use futures_util::SinkExt;
 use serde::de::Error as DeError;
 use serde::{Deserialize, Deserializer};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::{self, Message, error::UrlError};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, client_async_tls, connect_async};
 
 // internal
+use crate::clock::{Clock, SystemClock};
+use crate::config::diff_assets;
 use crate::format::Trade;
 
 #[derive(Debug, thiserror::Error)]
@@ -20,6 +27,31 @@ pub enum TradeMessageError {
     JsonParseError(#[from] serde_json::Error),
     #[error("failed to send pong")]
     FailedToSendPong,
+    #[error("invalid number {0:?}")]
+    InvalidNumber(String),
+}
+
+/// Parse a Binance numeric string into a finite, non-negative `f64`.
+///
+/// Accepted: plain decimals with or without a fraction (`45000`, `0.00010000`,
+/// `.5`) and scientific notation with either case of `e`, with or without a
+/// decimal point (`1.0E-4`, `1e-4`, `1E+2`), surrounded by optional whitespace.
+/// Rejected: `NaN`/`inf`, negatives, locale separators (`1,5`, `1_000`) and
+/// anything else `f64::from_str` would be lenient about.
+pub fn parse_decimal(s: &str) -> Result<f64, TradeMessageError> {
+    let invalid = || TradeMessageError::InvalidNumber(s.to_string());
+    let t = s.trim();
+    if t.is_empty()
+        || !t
+            .bytes()
+            .all(|b| b.is_ascii_digit() || matches!(b, b'.' | b'e' | b'E' | b'+' | b'-'))
+    {
+        return Err(invalid());
+    }
+    match t.parse::<f64>() {
+        Ok(v) if v.is_finite() && v >= 0.0 => Ok(v),
+        _ => Err(invalid()),
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -28,10 +60,44 @@ pub enum BinanceWebsocketError {
     FailedToSendPong(String),
     #[error("web socket connection error: {0}")]
     WebsocketConnectionError(String),
+    #[error("failed to send subscription request: {0}")]
+    FailedToSubscribe(String),
+    #[error("gave up after {attempts} failed connection attempts, last: {last}")]
+    ReconnectsExhausted { attempts: u32, last: String },
 }
 #[derive(serde::Deserialize)]
 pub struct WebSocketMessage {
-    pub data: WebSocketTrade,
+    /// Combined-stream name, eg: `btcusdt@aggTrade`
+    #[serde(default)]
+    pub stream: String,
+    pub data: StreamEvent,
+}
+
+/// Payload of a combined-stream message, told apart by its `e` field. Both
+/// events carry the fields `WebSocketTrade` needs, so mixed `@trade` and
+/// `@aggTrade` subscriptions on one connection all end up as `TradeMessage`s.
+#[derive(serde::Deserialize)]
+#[serde(tag = "e")]
+pub enum StreamEvent {
+    #[serde(rename = "trade")]
+    Trade(WebSocketTrade),
+    #[serde(rename = "aggTrade")]
+    AggTrade(WebSocketTrade),
+}
+
+impl StreamEvent {
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            StreamEvent::Trade(_) => "trade",
+            StreamEvent::AggTrade(_) => "aggTrade",
+        }
+    }
+
+    pub fn into_trade(self) -> WebSocketTrade {
+        match self {
+            StreamEvent::Trade(trade) | StreamEvent::AggTrade(trade) => trade,
+        }
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -57,12 +123,18 @@ pub struct TradeMessage {
     pub is_buyer_maker: bool,
     // TODO: To measure the latency within the internal systems.
     pub received_at: u128,
+    /// Connection that delivered the trade, see `BinanceWebsocketConfig::shard`.
+    /// Only used for metrics, never encoded.
+    pub shard: Option<u16>,
+    /// The websocket payload as received, kept only for
+    /// `BinanceWebsocketConfig::passthrough`
+    pub raw: Option<String>,
 }
 
 impl TradeMessage {
-    pub fn to_trade(self) -> Result<Trade, std::num::ParseFloatError> {
-        let price: f64 = self.price.parse()?;
-        let quantity: f64 = self.quantity.parse()?;
+    pub fn to_trade(self) -> Result<Trade, TradeMessageError> {
+        let price = parse_decimal(&self.price)?;
+        let quantity = parse_decimal(&self.quantity)?;
         Ok(Trade {
             timestamp: self.timestamp,
             symbol: self.asset,
@@ -72,52 +144,155 @@ impl TradeMessage {
         })
     }
 
+    /// Parse a websocket data message. Binance sends text frames, but a binary
+    /// frame carrying the same UTF-8 JSON is accepted too.
     pub fn create_from_ws(msg: Message) -> Result<Self, TradeMessageError> {
-        let text = match msg {
-            Message::Text(t) => t,
-            _ => return Err(TradeMessageError::InvalidMessageFromWebsocket),
-        };
+        match msg {
+            Message::Text(text) => Self::from_ws_text(&text),
+            Message::Binary(data) => Self::from_ws_text(
+                std::str::from_utf8(&data)
+                    .map_err(|_| TradeMessageError::InvalidMessageFromWebsocket)?,
+            ),
+            _ => Err(TradeMessageError::InvalidMessageFromWebsocket),
+        }
+    }
+
+    /// Parse a combined-stream JSON message carrying either a trade or an aggTrade.
+    pub fn from_ws_text(text: &str) -> Result<Self, TradeMessageError> {
         // Deserialize the full WebSocket message
-        let ws_message: WebSocketMessage =
-            serde_json::from_str(&text).map_err(TradeMessageError::JsonParseError)?;
+        let ws_message: WebSocketMessage = serde_json::from_str(text)?;
+        tracing::trace!(
+            "{} event from stream {}",
+            ws_message.data.event_type(),
+            ws_message.stream
+        );
         // Convert the nested WebSocketTrade into TradeMessage
-        Ok(Self::from_ws_payload(ws_message.data))
+        Ok(Self::from_ws_payload(
+            ws_message.data.into_trade(),
+            &SystemClock,
+        ))
     }
 
-    pub fn from_ws_payload(payload: WebSocketTrade) -> Self {
+    /// Build a message from a parsed trade, stamping `received_at` from `clock`.
+    pub fn from_ws_payload(payload: WebSocketTrade, clock: &dyn Clock) -> Self {
         TradeMessage {
             timestamp: payload.timestamp,
             asset: payload.asset,
             price: payload.price,
             quantity: payload.quantity,
             is_buyer_maker: payload.is_buyer_maker,
-            received_at: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_micros(),
+            received_at: clock.now().as_micros(),
+            shard: None,
+            raw: None,
         }
     }
 }
 
+/// Binance futures market family. USDⓈ-M contracts (eg: BTCUSDT) and COIN-M
+/// inverse contracts (eg: BTCUSD_PERP) live on different REST and stream hosts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Market {
+    /// USDⓈ-margined perps on fapi/fstream
+    #[default]
+    Usdm,
+    /// Coin-margined (inverse) perps on dapi/dstream
+    Coinm,
+}
+
+impl Market {
+    pub fn rest_base(self) -> &'static str {
+        match self {
+            Market::Usdm => "https://fapi.binance.com",
+            Market::Coinm => "https://dapi.binance.com",
+        }
+    }
+
+    /// Path prefix of the versioned REST API, eg: `/fapi/v1`
+    pub fn api_prefix(self) -> &'static str {
+        match self {
+            Market::Usdm => "/fapi/v1",
+            Market::Coinm => "/dapi/v1",
+        }
+    }
+
+    pub fn stream_base(self) -> &'static str {
+        match self {
+            Market::Usdm => "wss://fstream.binance.com/stream",
+            Market::Coinm => "wss://dstream.binance.com/stream",
+        }
+    }
+
+    /// Combined-stream url subscribing to the trade stream of every asset.
+    pub fn stream_url<S, I>(self, assets: I) -> String
+    where
+        S: AsRef<str>,
+        I: IntoIterator<Item = S>,
+    {
+        let streams = assets
+            .into_iter()
+            .map(trade_stream)
+            .collect::<Vec<String>>()
+            .join("/");
+        format!("{}?streams={}", self.stream_base(), streams)
+    }
+}
+
+/// Name of an asset's trade stream, eg: `btcusdt@trade`.
+fn trade_stream(asset: impl AsRef<str>) -> String {
+    asset.as_ref().to_lowercase() + "@trade"
+}
+
+/// A live `SUBSCRIBE` / `UNSUBSCRIBE` request for the trade streams of `assets`.
+fn subscription_request(method: &str, assets: &[String], id: u64) -> String {
+    let params: Vec<String> = assets.iter().map(trade_stream).collect();
+    serde_json::json!({ "method": method, "params": params, "id": id }).to_string()
+}
+
+/// Default wait after the first failed websocket connect.
+pub const DEFAULT_WS_BACKOFF: Duration = Duration::from_secs(2);
+/// Default ceiling for the doubling reconnect backoff.
+pub const DEFAULT_WS_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Consecutive failed connection attempts before giving up, as the connect
+/// loop has always done by default.
+pub const DEFAULT_WS_MAX_RECONNECTS: u32 = 5;
+
 /// Retry an async operation up to `max_retries` times, with exponential backoff.
 ///
 /// - `op` is a zero-arg closure returning a Future that yields `Result<T, E>`.
 /// - on `Ok(t)` we return `Ok(t)`.
-/// - on `Err(e)` we wait `2.pow(attempt)` seconds and try again, up to `max_retries`,
-///   after which we return the last `Err(e)`.
-pub async fn retry_with_backoff<Op, Fut, T, E>(mut op: Op, max_retries: u32) -> Result<T, E>
+/// - on `Err(e)` we wait `DEFAULT_WS_BACKOFF`, doubling per attempt up to
+///   `DEFAULT_WS_MAX_BACKOFF`, and try again, up to `max_retries`, after which
+///   we return the last `Err(e)`.
+pub async fn retry_with_backoff<Op, Fut, T, E>(op: Op, max_retries: u32) -> Result<T, E>
+where
+    Op: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: std::fmt::Debug,
+{
+    retry_with_backoff_between(op, max_retries, DEFAULT_WS_BACKOFF, DEFAULT_WS_MAX_BACKOFF).await
+}
+
+/// `retry_with_backoff` waiting `initial_backoff` after the first failure,
+/// doubling per further failure up to `max_backoff`, like the websocket
+/// connect loop (`--reconnect-backoff-ms`, `--reconnect-max-backoff-ms`).
+pub async fn retry_with_backoff_between<Op, Fut, T, E>(
+    mut op: Op,
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+) -> Result<T, E>
 where
     Op: FnMut() -> Fut,
     Fut: Future<Output = Result<T, E>>,
     E: std::fmt::Debug,
 {
     let mut attempt = 0;
+    let mut backoff = initial_backoff.min(max_backoff);
     loop {
         match op().await {
             Ok(val) => return Ok(val),
             Err(err) if attempt < max_retries => {
                 attempt += 1;
-                let backoff = tokio::time::Duration::from_secs(2u64.pow(attempt));
                 tracing::warn!(
                     "operation failed (attempt #{}) – retrying in {:?}: {:?}",
                     attempt,
@@ -125,74 +300,285 @@ where
                     err
                 );
                 tokio::time::sleep(backoff).await;
-                // try again
-            }
-            Err(err) => {
-                // out of retries
-                return Err(err);
+                backoff = (backoff * 2).min(max_backoff);
             }
+            // out of retries
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Connection settings for `BinanceWebsocket::start_with`.
+#[derive(Debug, Clone)]
+pub struct BinanceWebsocketConfig {
+    pub market: Market,
+    /// Consecutive failed connection attempts before giving up, 0 for never
+    pub max_reconnects: u32,
+    /// Wait after the first failed attempt, doubling per further failure
+    pub initial_backoff: Duration,
+    /// Ceiling for the doubled wait
+    pub max_backoff: Duration,
+    /// Connect here instead of the market's stream url, eg: a test server
+    pub url: Option<String>,
+    /// Tag every trade from this connection with this id, when the asset list
+    /// is split over several connections
+    pub shard: Option<u16>,
+    /// Connect from this local address, eg: the NIC dedicated to market data
+    pub local_address: Option<IpAddr>,
+    /// Keep each trade's JSON payload in `TradeMessage::raw`
+    pub passthrough: bool,
+    /// Counts connections made after the first, eg: `Metrics::ws_reconnects`
+    pub reconnects: Arc<AtomicU64>,
+}
+
+impl BinanceWebsocketConfig {
+    /// Wait after a failed attempt that waited `backoff` before it.
+    fn next_backoff(&self, backoff: Duration) -> Duration {
+        (backoff * 2).min(self.max_backoff)
+    }
+}
+
+impl Default for BinanceWebsocketConfig {
+    fn default() -> Self {
+        Self {
+            market: Market::default(),
+            max_reconnects: DEFAULT_WS_MAX_RECONNECTS,
+            initial_backoff: DEFAULT_WS_BACKOFF,
+            max_backoff: DEFAULT_WS_MAX_BACKOFF,
+            url: None,
+            shard: None,
+            local_address: None,
+            passthrough: false,
+            reconnects: Arc::default(),
         }
     }
 }
 
 //TODO:
 // - Adding lifecycle state tracking could improve resilliency and visibility.
-// - Add some intelligence in handling websocket disconnections
 // - Should move the urls and params to a configuration file.
+/// Connections never ask for compression (permessage-deflate): tungstenite, 0.21
+/// here and every release through 0.30, can't negotiate it and rejects frames
+/// with RSV1 set. Not worth doing its frame handling ourselves for bandwidth.
 pub struct BinanceWebsocket {}
 impl BinanceWebsocket {
     pub async fn start<S, I>(
         s: tokio::sync::mpsc::UnboundedSender<TradeMessage>,
         assets: I,
-    ) -> Result<(), BinanceWebsocketError> 
+    ) -> Result<(), BinanceWebsocketError>
     where
         S: AsRef<str> + Send,
         I: IntoIterator<Item = S>,
     {
-        let url = {
-            let streams = assets
-                .into_iter()
-                .map(|s| s.as_ref().to_lowercase() + "@trade")
-                .collect::<Vec<String>>()
-                .join("/");
-            format!("wss://fstream.binance.com/stream?streams={}", streams)
-        };
+        Self::start_with(s, assets, &BinanceWebsocketConfig::default()).await
+    }
 
-        tracing::debug!("Attempting to connect to {}", url);
-        // wrap the async connect in a zero-arg closure
-        let connect_op = || connect_async(&url);
+    /// Stream trades into `s`, reconnecting whenever the connection drops.
+    ///
+    /// Failed connection attempts back off exponentially from
+    /// `config.initial_backoff` up to `config.max_backoff`. After `config.max_reconnects` consecutive
+    /// failures this gives up with `ReconnectsExhausted`, so a supervisor can
+    /// restart the process instead of it backing off forever. Returns `Ok` once
+    /// the receiving end of `s` is gone.
+    pub async fn start_with<S, I>(
+        s: tokio::sync::mpsc::UnboundedSender<TradeMessage>,
+        assets: I,
+        config: &BinanceWebsocketConfig,
+    ) -> Result<(), BinanceWebsocketError>
+    where
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S>,
+    {
+        let assets = assets.into_iter().map(|s| s.as_ref().to_string()).collect();
+        // Kept until this returns, so the list never changes
+        let (_assets_tx, assets) = watch::channel(assets);
+        Self::start_watched(s, assets, config).await
+    }
 
-        let (mut ws_stream, _) = retry_with_backoff(connect_op, 5)
-            .await
-            .map_err(|e| BinanceWebsocketError::WebsocketConnectionError(e.to_string()))?;
+    /// `start_with`, following the asset list in `assets`. A change is applied
+    /// to the live connection: `UNSUBSCRIBE` for the assets it drops and
+    /// `SUBSCRIBE` for those it adds, and trades of dropped assets still arriving
+    /// are discarded. Reconnects subscribe to the current list. While the list
+    /// is empty no connection is made.
+    pub async fn start_watched(
+        s: tokio::sync::mpsc::UnboundedSender<TradeMessage>,
+        mut assets: watch::Receiver<Vec<String>>,
+        config: &BinanceWebsocketConfig,
+    ) -> Result<(), BinanceWebsocketError> {
+        let mut failures = 0;
+        let mut backoff = config.initial_backoff.min(config.max_backoff);
+        let mut connected_before = false;
+        loop {
+            let subscribed = assets.borrow_and_update().clone();
+            if subscribed.is_empty() {
+                tokio::select! {
+                    changed = assets.changed() => if changed.is_err() {
+                        return Ok(());
+                    },
+                    _ = s.closed() => return Ok(()),
+                }
+                continue;
+            }
+            let url = match &config.url {
+                Some(url) => url.clone(),
+                None => config.market.stream_url(&subscribed),
+            };
+            tracing::debug!("Attempting to connect to {}", url);
+            let mut ws_stream = match Self::connect(&url, config.local_address).await {
+                Ok(ws_stream) => ws_stream,
+                Err(e) => {
+                    failures += 1;
+                    if config.max_reconnects != 0 && failures >= config.max_reconnects {
+                        return Err(BinanceWebsocketError::ReconnectsExhausted {
+                            attempts: failures,
+                            last: e.to_string(),
+                        });
+                    }
+                    tracing::warn!(
+                        "connecting failed (attempt #{}) – retrying in {:?}: {}",
+                        failures,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = config.next_backoff(backoff);
+                    continue;
+                }
+            };
+            failures = 0;
+            backoff = config.initial_backoff.min(config.max_backoff);
+            if std::mem::replace(&mut connected_before, true) {
+                config.reconnects.fetch_add(1, Ordering::Relaxed);
+            }
 
-        tracing::info!("Connection to Binance WebSocket established successfully.");
-        while let Some(message) = ws_stream.next().await {
+            tracing::info!("Connection to Binance WebSocket established successfully.");
+            match Self::forward(&mut ws_stream, &s, &mut assets, subscribed, config).await {
+                Ok(()) => tracing::warn!("WebSocket stream ended, reconnecting"),
+                Err(e) => tracing::error!("{}, reconnecting", e),
+            }
+            if s.is_closed() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// `connect_async`, with the TCP connection made from `local_address` when given.
+    async fn connect(
+        url: &str,
+        local_address: Option<IpAddr>,
+    ) -> Result<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>, tungstenite::Error> {
+        let Some(local) = local_address else {
+            return Ok(connect_async(url).await?.0);
+        };
+        let parsed = url::Url::parse(url).map_err(|e| UrlError::UnableToConnect(e.to_string()))?;
+        let host = parsed.host_str().ok_or(UrlError::NoHostName)?;
+        let port = parsed
+            .port_or_known_default()
+            .ok_or(UrlError::UnsupportedUrlScheme)?;
+        let remote = tokio::net::lookup_host((host, port))
+            .await?
+            .find(|addr| addr.is_ipv4() == local.is_ipv4())
+            .ok_or_else(|| {
+                UrlError::UnableToConnect(format!(
+                    "{} has no address reachable from {}",
+                    host, local
+                ))
+            })?;
+
+        let socket = match local {
+            IpAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+            IpAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+        };
+        socket.bind(SocketAddr::new(local, 0))?;
+        let stream = socket.connect(remote).await?;
+        Ok(client_async_tls(url, stream).await?.0)
+    }
+
+    /// Forward trades from one connection until it ends or fails, keeping its
+    /// subscriptions in line with `assets`. The connection starts out
+    /// subscribed to `subscribed`.
+    async fn forward(
+        ws_stream: &mut WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
+        s: &tokio::sync::mpsc::UnboundedSender<TradeMessage>,
+        assets: &mut watch::Receiver<Vec<String>>,
+        mut subscribed: Vec<String>,
+        config: &BinanceWebsocketConfig,
+    ) -> Result<(), BinanceWebsocketError> {
+        let mut watching = true;
+        let mut request_id = 0;
+        loop {
+            let message = tokio::select! {
+                message = ws_stream.next() => match message {
+                    Some(message) => message,
+                    None => return Ok(()),
+                },
+                changed = assets.changed(), if watching => {
+                    if changed.is_err() {
+                        watching = false;
+                        continue;
+                    }
+                    let wanted = assets.borrow_and_update().clone();
+                    let (added, removed) = diff_assets(&subscribed, &wanted);
+                    for (method, list) in [("UNSUBSCRIBE", removed), ("SUBSCRIBE", added)] {
+                        if list.is_empty() {
+                            continue;
+                        }
+                        tracing::info!("{} {:?}", method, list);
+                        request_id += 1;
+                        let request = subscription_request(method, &list, request_id);
+                        if let Err(e) = ws_stream.send(Message::Text(request)).await {
+                            return Err(BinanceWebsocketError::FailedToSubscribe(e.to_string()));
+                        }
+                    }
+                    subscribed = wanted;
+                    continue;
+                }
+            };
             match message {
-                Ok(Message::Text(text)) => match serde_json::from_str::<WebSocketMessage>(&text) {
-                    Ok(ws_message) => {
-                        let trade_message = TradeMessage::from_ws_payload(ws_message.data);
-                        let _ = s.send(trade_message);
+                Ok(msg @ (Message::Text(_) | Message::Binary(_))) => {
+                    let raw = match config.passthrough {
+                        true => msg.to_text().ok().map(str::to_string),
+                        false => None,
+                    };
+                    // `{"result":null,"id":1}` acknowledges a subscription request
+                    let reply =
+                        matches!(&msg, Message::Text(text) if text.starts_with(r#"{"result""#));
+                    match TradeMessage::create_from_ws(msg) {
+                        Ok(trade_message)
+                            if !subscribed
+                                .iter()
+                                .any(|asset| asset.eq_ignore_ascii_case(&trade_message.asset)) =>
+                        {
+                            tracing::debug!("dropping {} trade, unsubscribed", trade_message.asset);
+                        }
+                        Ok(mut trade_message) => {
+                            trade_message.shard = config.shard;
+                            trade_message.raw = raw;
+                            let _ = s.send(trade_message);
+                        }
+                        Err(_) if reply => tracing::debug!("subscription request acknowledged"),
+                        Err(e) => tracing::warn!("Failed to parse trade message: {}", e),
                     }
-                    Err(e) => tracing::warn!("Failed to parse trade message: {}", e),
-                },
+                }
                 Ok(Message::Ping(ping)) => {
                     // Respond to pings to keep connection alive
                     if let Err(e) = ws_stream.send(Message::Pong(ping)).await {
-                        tracing::error!("Failed to send PONG: {}", e);
                         return Err(BinanceWebsocketError::FailedToSendPong(e.to_string()));
                     }
                 }
+                Ok(Message::Pong(_)) => tracing::debug!("PONG received"),
+                Ok(Message::Close(frame)) => {
+                    tracing::info!("WebSocket closed by server: {:?}", frame)
+                }
+                // Only produced when writing raw frames, never by reads
+                Ok(Message::Frame(_)) => {}
                 Err(e) => {
-                    tracing::error!("WebSocket error: {}", e);
                     return Err(BinanceWebsocketError::WebsocketConnectionError(
                         e.to_string(),
                     ));
                 }
-                _ => {}
             }
         }
-        Ok(())
     }
 }
 
@@ -206,16 +592,24 @@ pub enum BinanceError {
 
     #[error("Serde JSON error: {0}")]
     Serde(#[from] serde_json::Error),
+
+    #[error("no premium index returned for {0}")]
+    NoPremiumIndex(String),
+
+    #[error("invalid funding rate {0:?}")]
+    InvalidFundingRate(String),
+
+    #[error("invalid rate limit {0}, must be finite and positive")]
+    InvalidRateLimit(f64),
 }
 
-/// Custom deserializer for converting a string into a `f64`
+/// Custom deserializer for converting a string into a `f64`, see `parse_decimal`
 fn de_string_to_f64<'de, D>(deserializer: D) -> Result<f64, D::Error>
 where
     D: Deserializer<'de>,
 {
     let s = String::deserialize(deserializer)?;
-    s.parse()
-        .map_err(|e| D::Error::custom(format!("parse float error: {}", e)))
+    parse_decimal(&s).map_err(|e| D::Error::custom(format!("parse float error: {}", e)))
 }
 
 #[derive(Debug, Deserialize)]
@@ -227,24 +621,224 @@ struct RawTrade {
     qty: f64,
 }
 
+/// Ticker price; coin-margined endpoints return a list, one per contract.
+#[derive(Debug, Deserialize)]
+struct RawTicker {
+    #[serde(deserialize_with = "de_string_to_f64")]
+    price: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawPremiumIndex {
+    #[serde(rename = "markPrice", deserialize_with = "de_string_to_f64")]
+    mark_price: f64,
+
+    /// Empty for coin-margined delivery contracts, which have no funding
+    #[serde(rename = "lastFundingRate", default)]
+    last_funding_rate: String,
+}
+
+impl RawPremiumIndex {
+    /// `lastFundingRate`, which unlike prices can be negative.
+    fn funding_rate(&self) -> Result<f64, BinanceError> {
+        let s = self.last_funding_rate.trim();
+        let invalid = || BinanceError::InvalidFundingRate(self.last_funding_rate.clone());
+        let (sign, digits) = match s.strip_prefix('-') {
+            Some(digits) => (-1.0, digits),
+            None => (1.0, s),
+        };
+        parse_decimal(digits)
+            .map(|rate| sign * rate)
+            .map_err(|_| invalid())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn into_first(self) -> Option<T> {
+        match self {
+            OneOrMany::One(t) => Some(t),
+            OneOrMany::Many(v) => v.into_iter().next(),
+        }
+    }
+}
+
+/// Samples further than this many standard deviations from their mean are
+/// dropped before a robust reference price is computed.
+pub const OUTLIER_STDDEVS: f64 = 3.0;
+
+/// How the header's reference price is derived.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReferenceStrategy {
+    /// Plain average of the recent trades
+    #[default]
+    Mean,
+    /// Median of recent trades, ticker and mark price, outliers removed
+    Median,
+    /// Mean of recent trades, ticker and mark price, outliers removed
+    TrimmedMean,
+}
+
+impl ReferenceStrategy {
+    /// Reference price of `samples`, or `None` when there are none.
+    pub fn reference_price(self, samples: &[f64]) -> Option<f64> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mean = |xs: &[f64]| xs.iter().sum::<f64>() / xs.len() as f64;
+        if self == ReferenceStrategy::Mean {
+            return Some(mean(samples));
+        }
+
+        let mut kept = reject_outliers(samples, OUTLIER_STDDEVS);
+        match self {
+            ReferenceStrategy::Median => {
+                kept.sort_by(f64::total_cmp);
+                let mid = kept.len() / 2;
+                Some(if kept.len().is_multiple_of(2) {
+                    (kept[mid - 1] + kept[mid]) / 2.0
+                } else {
+                    kept[mid]
+                })
+            }
+            _ => Some(mean(&kept)),
+        }
+    }
+}
+
+/// Drop samples more than `k` standard deviations from the mean. All samples are
+/// kept when they don't vary at all.
+fn reject_outliers(samples: &[f64], k: f64) -> Vec<f64> {
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<f64>() / n;
+    let stddev = (samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n).sqrt();
+    if stddev == 0.0 {
+        return samples.to_vec();
+    }
+    samples
+        .iter()
+        .copied()
+        .filter(|x| (x - mean).abs() <= k * stddev)
+        .collect()
+}
+
+/// Where an asset's reference price came from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReferenceSource {
+    /// Recent trades, plus ticker and mark price for a robust `ReferenceStrategy`
+    Trades,
+    /// No recent trades, so ticker and/or mark price only
+    Ticker,
+    /// Nothing could be fetched, the reference is 0
+    #[default]
+    Unavailable,
+}
+
+impl std::fmt::Display for ReferenceSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ReferenceSource::Trades => "trades",
+            ReferenceSource::Ticker => "ticker",
+            ReferenceSource::Unavailable => "unavailable",
+        })
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct AvgPriceQty {
     pub price: f64,
     pub qty: f64,
+    pub source: ReferenceSource,
+    /// Prices the reference was taken from
+    pub samples: usize,
+}
+
+/// One line per asset of where its reference price came from, for auditing
+/// startup: symbol, reference price, source and sample count.
+pub fn reference_summary<S: AsRef<str>>(symbols: &[S], stats: &[AvgPriceQty]) -> String {
+    let mut table = format!(
+        "{:<16} {:>16} {:<12} {:>7}",
+        "symbol", "reference", "source", "samples"
+    );
+    for (symbol, stats) in symbols.iter().zip(stats) {
+        table.push_str(&format!(
+            "\n{:<16} {:>16} {:<12} {:>7}",
+            symbol.as_ref(),
+            stats.price,
+            stats.source,
+            stats.samples
+        ));
+    }
+    table
+}
+
+/// Environment variable the API key is read from. Deliberately not a CLI flag,
+/// so the key doesn't show up in `ps`.
+pub const API_KEY_ENV: &str = "BINANCE_API_KEY";
+
+const API_KEY_HEADER: &str = "X-MBX-APIKEY";
+
+/// Binance request weights of the endpoints we call, at their default `limit`.
+const TRADES_WEIGHT: u32 = 5;
+const TICKER_PRICE_WEIGHT: u32 = 1;
+const PREMIUM_INDEX_WEIGHT: u32 = 1;
+
+/// Evenly spaces out units of some budget (requests, request weight) at `rate`
+/// per `per`. No bursts: a request costing `n` units pushes the next slot out by
+/// `n` intervals. Shared by clones of the client, so concurrent batch requests
+/// draw from one budget.
+#[derive(Debug)]
+struct RateLimiter {
+    interval: Duration,
+    next: tokio::sync::Mutex<tokio::time::Instant>,
+}
+
+impl RateLimiter {
+    /// Fails for a rate that isn't finite and positive, or so small that one
+    /// interval overflows a `Duration`.
+    fn new(rate: f64, per: Duration) -> Result<Self, BinanceError> {
+        if !(rate.is_finite() && rate > 0.0) {
+            return Err(BinanceError::InvalidRateLimit(rate));
+        }
+        let interval = Duration::try_from_secs_f64(per.as_secs_f64() / rate)
+            .map_err(|_| BinanceError::InvalidRateLimit(rate))?;
+        Ok(Self {
+            interval,
+            next: tokio::sync::Mutex::new(tokio::time::Instant::now()),
+        })
+    }
+
+    async fn acquire(&self, cost: u32) {
+        let slot = {
+            let mut next = self.next.lock().await;
+            let slot = (*next).max(tokio::time::Instant::now());
+            *next = slot + self.interval * cost;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
 }
 
 #[derive(Clone)]
 pub struct BinanceClient {
     http: reqwest::Client,
     base: url::Url,
+    market: Market,
+    api_key: Option<String>,
+    reference: ReferenceStrategy,
+    max_rps: Option<Arc<RateLimiter>>,
+    max_weight: Option<Arc<RateLimiter>>,
 }
 
 impl Default for BinanceClient {
     fn default() -> Self {
-        Self {
-            http: reqwest::Client::new(),
-            base: url::Url::parse("https://fapi.binance.com").unwrap(),
-        }
+        Self::for_market(Market::default())
     }
 }
 impl BinanceClient {
@@ -252,50 +846,675 @@ impl BinanceClient {
         Self::default()
     }
 
+    /// Client for the REST host of `market`.
+    pub fn for_market(market: Market) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base: url::Url::parse(market.rest_base()).unwrap(),
+            market,
+            api_key: None,
+            reference: ReferenceStrategy::default(),
+            max_rps: None,
+            max_weight: None,
+        }
+    }
+
+    /// Make every REST request from `addr`, eg: the NIC dedicated to market data.
+    pub fn with_local_address(mut self, addr: IpAddr) -> Result<Self, BinanceError> {
+        self.http = reqwest::Client::builder().local_address(addr).build()?;
+        Ok(self)
+    }
+
+    /// Send REST requests here instead of the market's host, eg: a test server.
+    pub fn with_base_url(mut self, base: url::Url) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Send at most `rps` requests per second, spaced evenly. Fails with
+    /// `InvalidRateLimit` unless `rps` is finite and positive.
+    pub fn with_max_rps(mut self, rps: f64) -> Result<Self, BinanceError> {
+        self.max_rps = Some(Arc::new(RateLimiter::new(rps, Duration::from_secs(1))?));
+        Ok(self)
+    }
+
+    /// Spend at most `weight` of Binance's per-minute request weight, spaced
+    /// evenly. `/trades` costs 5, ticker and mark price 1 each. Fails with
+    /// `InvalidRateLimit` for a weight of 0.
+    pub fn with_max_weight_per_minute(mut self, weight: u32) -> Result<Self, BinanceError> {
+        self.max_weight = Some(Arc::new(RateLimiter::new(
+            weight as f64,
+            Duration::from_secs(60),
+        )?));
+        Ok(self)
+    }
+
+    /// Use `strategy` for the reference price in `avg_stats`. Anything other than
+    /// `Mean` also samples the ticker and mark price.
+    pub fn with_reference_strategy(mut self, strategy: ReferenceStrategy) -> Self {
+        self.reference = strategy;
+        self
+    }
+
+    /// Send `key` as `X-MBX-APIKEY` on every request. Public endpoints don't need
+    /// it, but keyed requests get more rate-limit headroom.
+    pub fn with_api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    /// `with_api_key` using `API_KEY_ENV`, if it is set and non-empty.
+    pub fn with_api_key_from_env(self) -> Self {
+        match std::env::var(API_KEY_ENV) {
+            Ok(key) if !key.is_empty() => self.with_api_key(key),
+            _ => self,
+        }
+    }
+
+    /// `GET url` once the rate limits allow a request of `weight`.
+    async fn get(&self, url: url::Url, weight: u32) -> reqwest::RequestBuilder {
+        if let Some(limiter) = &self.max_rps {
+            limiter.acquire(1).await;
+        }
+        if let Some(limiter) = &self.max_weight {
+            limiter.acquire(weight).await;
+        }
+        let request = self.http.get(url);
+        match &self.api_key {
+            Some(key) => request.header(API_KEY_HEADER, key),
+            None => request,
+        }
+    }
+
+    /// Url of the recent-trades endpoint for `symbol`.
+    pub fn trades_url(&self, symbol: &str) -> Result<url::Url, BinanceError> {
+        self.endpoint_url("trades", symbol)
+    }
+
+    fn endpoint_url(&self, endpoint: &str, symbol: &str) -> Result<url::Url, BinanceError> {
+        Ok(self.base.join(&format!(
+            "{}/{}?symbol={}",
+            self.market.api_prefix(),
+            endpoint,
+            symbol
+        ))?)
+    }
+
+    /// Latest ticker price and mark price for `symbol`, whichever could be fetched.
+    async fn price_samples(&self, symbol: &str) -> Vec<f64> {
+        let mut samples = Vec::with_capacity(2);
+
+        let ticker = async {
+            let url = self.endpoint_url("ticker/price", symbol)?;
+            let ticker: OneOrMany<RawTicker> = self
+                .get(url, TICKER_PRICE_WEIGHT)
+                .await
+                .send()
+                .await?
+                .json()
+                .await?;
+            Ok::<_, BinanceError>(ticker.into_first().map(|t| t.price))
+        };
+        match ticker.await {
+            Ok(price) => samples.extend(price),
+            Err(e) => tracing::warn!("ticker price for {} unavailable: {}", symbol, e),
+        }
+
+        let mark = async {
+            let url = self.endpoint_url("premiumIndex", symbol)?;
+            let index: OneOrMany<RawPremiumIndex> = self
+                .get(url, PREMIUM_INDEX_WEIGHT)
+                .await
+                .send()
+                .await?
+                .json()
+                .await?;
+            Ok::<_, BinanceError>(index.into_first().map(|i| i.mark_price))
+        };
+        match mark.await {
+            Ok(price) => samples.extend(price),
+            Err(e) => tracing::warn!("mark price for {} unavailable: {}", symbol, e),
+        }
+
+        samples
+    }
+
+    /// Funding rate most recently applied to `symbol`, eg: `0.0001` for 0.01%,
+    /// negative when shorts pay longs.
+    pub async fn funding_rate(&self, symbol: &str) -> Result<f64, BinanceError> {
+        let url = self.endpoint_url("premiumIndex", symbol)?;
+        let index: OneOrMany<RawPremiumIndex> = self
+            .get(url, PREMIUM_INDEX_WEIGHT)
+            .await
+            .send()
+            .await?
+            .json()
+            .await?;
+        index
+            .into_first()
+            .ok_or_else(|| BinanceError::NoPremiumIndex(symbol.to_string()))?
+            .funding_rate()
+    }
+
     /// Fetch recent trades for `symbol` and compute their average price & qty.
-    pub async fn avg_stats<S>(&self, symbol: S) -> Result<AvgPriceQty, BinanceError> 
+    /// Without recent trades, the ticker and mark price stand in for the price
+    /// and the quantity is 0.
+    pub async fn avg_stats<S>(&self, symbol: S) -> Result<AvgPriceQty, BinanceError>
     where
-        S: AsRef<str>
+        S: AsRef<str>,
     {
-        let sym = symbol.as_ref();
-        let url = self
-            .base
-            .join(&format!("/fapi/v1/trades?symbol={}", sym))?;
+        let url = self.trades_url(symbol.as_ref())?;
 
         // GET … → Vec<RawTrade>
-        let trades: Vec<RawTrade> = self.http.get(url).send().await?.json().await?;
+        let trades: Vec<RawTrade> = self
+            .get(url, TRADES_WEIGHT)
+            .await
+            .send()
+            .await?
+            .json()
+            .await?;
         let n = trades.len() as f64;
         if n == 0.0 {
-            return Ok(AvgPriceQty::default());
+            tracing::warn!(
+                "no recent trades for {}, falling back to ticker",
+                symbol.as_ref()
+            );
+            let samples = self.price_samples(symbol.as_ref()).await;
+            return Ok(match self.reference.reference_price(&samples) {
+                Some(price) => AvgPriceQty {
+                    price,
+                    qty: 0.0,
+                    source: ReferenceSource::Ticker,
+                    samples: samples.len(),
+                },
+                None => AvgPriceQty::default(),
+            });
         }
 
-        let (sum_p, sum_q) = trades
-            .into_iter()
-            .fold((0.0, 0.0), |(sp, sq), t| (sp + t.price, sq + t.qty));
+        let sum_q: f64 = trades.iter().map(|t| t.qty).sum();
+        let mut samples: Vec<f64> = trades.iter().map(|t| t.price).collect();
+        if self.reference != ReferenceStrategy::Mean {
+            samples.extend(self.price_samples(symbol.as_ref()).await);
+        }
 
         Ok(AvgPriceQty {
-            price: sum_p / n,
+            price: self.reference.reference_price(&samples).unwrap_or_default(),
             qty: sum_q / n,
+            source: ReferenceSource::Trades,
+            samples: samples.len(),
         })
     }
 
-    /// Compute averages for all symbols, up to `max_concurrency` at a time.
+    /// Compute averages for all symbols, up to `max_concurrency` at a time and
+    /// within any configured rate limits. Results come back in the order of
+    /// `symbols`, whichever request finishes first; a failed one is the default.
     pub async fn avg_stats_batch<S>(
         &self,
-        symbols: impl IntoIterator<Item=S>,
+        symbols: impl IntoIterator<Item = S>,
         max_concurrency: usize,
-    ) -> Vec<AvgPriceQty> 
+    ) -> Vec<AvgPriceQty>
     where
         S: AsRef<str> + Send + 'static,
     {
         let client = self.clone();
-        stream::iter(symbols.into_iter())
+        stream::iter(symbols)
             .map(move |sym| {
                 let cli = client.clone();
                 async move { cli.avg_stats(sym).await.unwrap_or_default() }
             })
-            .buffer_unordered(max_concurrency)
+            .buffered(max_concurrency)
             .collect()
             .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coin_margined_urls() {
+        let client = BinanceClient::for_market(Market::Coinm);
+        assert_eq!(
+            client.trades_url("BTCUSD_PERP").unwrap().as_str(),
+            "https://dapi.binance.com/dapi/v1/trades?symbol=BTCUSD_PERP"
+        );
+        assert_eq!(
+            Market::Coinm.stream_url(["BTCUSD_PERP", "ETHUSD_PERP"]),
+            "wss://dstream.binance.com/stream?streams=btcusd_perp@trade/ethusd_perp@trade"
+        );
+
+        let client = BinanceClient::new();
+        assert_eq!(
+            client.trades_url("BTCUSDT").unwrap().as_str(),
+            "https://fapi.binance.com/fapi/v1/trades?symbol=BTCUSDT"
+        );
+        assert_eq!(
+            Market::Usdm.stream_url(["BTCUSDT"]),
+            "wss://fstream.binance.com/stream?streams=btcusdt@trade"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_with_backoff_between() {
+        let calls = std::cell::Cell::new(0);
+        let started = tokio::time::Instant::now();
+        let op = || {
+            calls.set(calls.get() + 1);
+            std::future::ready(if calls.get() < 4 {
+                Err(calls.get())
+            } else {
+                Ok("up")
+            })
+        };
+        let max = Duration::from_millis(20);
+        let result = retry_with_backoff_between(op, 5, Duration::from_millis(10), max).await;
+        assert_eq!(result, Ok("up"));
+        assert_eq!(calls.get(), 4);
+        // 10ms, then 20ms twice: doubled, then held at the ceiling
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        // Out of retries, the last error comes back
+        calls.set(0);
+        let op = || {
+            calls.set(calls.get() + 1);
+            std::future::ready(Err::<(), _>(calls.get()))
+        };
+        let result = retry_with_backoff_between(op, 2, Duration::ZERO, Duration::ZERO).await;
+        assert_eq!(result, Err(3));
+    }
+
+    #[tokio::test]
+    async fn test_api_key_header_sent() {
+        let heads = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = heads.clone();
+        let base = mock_rest(move |head| {
+            seen.lock().unwrap().push(head);
+            async {
+                Some(r#"[{"price":"100.0","qty":"2.0"},{"price":"102.0","qty":"4.0"}]"#.into())
+            }
+        })
+        .await;
+
+        let client = BinanceClient::new()
+            .with_api_key("test-key")
+            .with_base_url(base);
+        let stats = client.avg_stats("BTCUSDT").await.unwrap();
+        assert_eq!(stats.price, 101.0);
+        assert_eq!(stats.qty, 3.0);
+
+        let head = heads.lock().unwrap()[0].to_lowercase();
+        assert!(head.starts_with("get /fapi/v1/trades?symbol=btcusdt "));
+        assert!(head.contains("x-mbx-apikey: test-key\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_reference_summary_shows_ticker_fallback() {
+        let base = mock_rest(|head| async move {
+            let body = match head.split_whitespace().nth(1).unwrap() {
+                "/fapi/v1/trades?symbol=BTCUSDT" => {
+                    r#"[{"price":"100.0","qty":"2.0"},{"price":"102.0","qty":"4.0"}]"#
+                }
+                "/fapi/v1/trades?symbol=QUIETUSDT" => "[]",
+                "/fapi/v1/ticker/price?symbol=QUIETUSDT" => r#"{"price":"1.25"}"#,
+                _ => return None,
+            };
+            Some(body.into())
+        })
+        .await;
+
+        let client = BinanceClient::new().with_base_url(base);
+        let symbols = ["BTCUSDT", "QUIETUSDT", "GONEUSDT"];
+        let mut stats = Vec::new();
+        for symbol in symbols {
+            stats.push(client.avg_stats(symbol).await.unwrap_or_default());
+        }
+        // The mark price 404s, so the ticker alone stands in
+        assert_eq!((stats[1].price, stats[1].qty), (1.25, 0.0));
+        assert_eq!(stats[1].source, ReferenceSource::Ticker);
+        assert_eq!(stats[2].source, ReferenceSource::Unavailable);
+
+        let summary = reference_summary(&symbols, &stats);
+        let rows: Vec<Vec<&str>> = summary
+            .lines()
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert_eq!(rows[0], ["symbol", "reference", "source", "samples"]);
+        assert_eq!(rows[1], ["BTCUSDT", "101", "trades", "2"]);
+        assert_eq!(rows[2], ["QUIETUSDT", "1.25", "ticker", "1"]);
+        assert_eq!(rows[3], ["GONEUSDT", "0", "unavailable", "0"]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_keeps_symbol_order() {
+        // Earlier symbols answer later, so completion order is the reverse
+        let base = mock_rest(|head| async move {
+            let i: u64 = head
+                .split_once("symbol=SYM")
+                .and_then(|(_, rest)| rest.split_once("USDT"))
+                .unwrap()
+                .0
+                .parse()
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(50 * (4 - i))).await;
+            Some(format!(
+                r#"[{{"price":"{}.0","qty":"1.0"}}]"#,
+                100 * (i + 1)
+            ))
+        })
+        .await;
+
+        let client = BinanceClient::new().with_base_url(base);
+        let symbols: Vec<String> = (0..4).map(|i| format!("SYM{}USDT", i)).collect();
+        let stats = client.avg_stats_batch(symbols, 4).await;
+        let prices: Vec<f64> = stats.iter().map(|s| s.price).collect();
+        assert_eq!(prices, [100.0, 200.0, 300.0, 400.0]);
+    }
+
+    #[test]
+    fn test_reference_price_rejects_outlier() {
+        // Recent trades around 100 with one fat-fingered print, plus ticker and mark
+        let mut samples: Vec<f64> = (0..20).map(|i| 99.5 + (i % 5) as f64 * 0.25).collect();
+        samples.push(1000.0);
+        samples.extend([100.0, 100.1]);
+
+        let mean = ReferenceStrategy::Mean.reference_price(&samples).unwrap();
+        assert!(mean > 130.0);
+
+        let median = ReferenceStrategy::Median.reference_price(&samples).unwrap();
+        assert_eq!(median, 100.0);
+
+        let trimmed = ReferenceStrategy::TrimmedMean
+            .reference_price(&samples)
+            .unwrap();
+        assert!((trimmed - 100.0).abs() < 0.1);
+        assert!(!reject_outliers(&samples, OUTLIER_STDDEVS).contains(&1000.0));
+
+        assert_eq!(ReferenceStrategy::Median.reference_price(&[]), None);
+        assert_eq!(
+            ReferenceStrategy::TrimmedMean.reference_price(&[5.0, 5.0]),
+            Some(5.0)
+        );
+    }
+
+    /// REST server handing the head (request line and headers) of each request
+    /// to `handler`, and answering with the JSON body it returns, or a 404 for
+    /// `None`. Requests are served concurrently.
+    async fn mock_rest<F, Fut>(handler: F) -> url::Url
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Option<String>> + Send + 'static,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                        let n = socket.read(&mut chunk).await.unwrap();
+                        if n == 0 {
+                            return;
+                        }
+                        head.extend_from_slice(&chunk[..n]);
+                    }
+                    let body = handler(String::from_utf8(head).unwrap()).await;
+                    let status = if body.is_some() {
+                        "200 OK"
+                    } else {
+                        "404 Not Found"
+                    };
+                    let body = body.unwrap_or_default();
+                    let response = format!(
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        url::Url::parse(&format!("http://{}", addr)).unwrap()
+    }
+
+    /// Fetch stats for 10 symbols at once and check their `/trades` requests
+    /// were spaced out to 20 per second.
+    async fn assert_batch_paced_to_20_rps(client: BinanceClient) {
+        let arrivals = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = arrivals.clone();
+        let base = mock_rest(move |_| {
+            seen.lock().unwrap().push(tokio::time::Instant::now());
+            async { Some(r#"[{"price":"100.0","qty":"1.0"}]"#.into()) }
+        })
+        .await;
+        let client = client.with_base_url(base);
+        let symbols: Vec<String> = (0..10).map(|i| format!("SYM{}USDT", i)).collect();
+        let stats = client.avg_stats_batch(symbols, 10).await;
+        assert_eq!(stats.len(), 10);
+
+        let mut arrivals = arrivals.lock().unwrap().clone();
+        arrivals.sort();
+        assert_eq!(arrivals.len(), 10);
+        // Evenly spaced: 10 requests at 20 rps span at least 9 intervals of 50ms,
+        // less some scheduling slack
+        let span = arrivals[9] - arrivals[0];
+        assert!(span >= Duration::from_millis(400), "span {:?}", span);
+        // ...and no 1s window could have held more than 20
+        for (i, start) in arrivals.iter().enumerate() {
+            let within = arrivals[i..]
+                .iter()
+                .filter(|t| **t - *start < Duration::from_millis(250))
+                .count();
+            assert!(within <= 6, "{} requests within 250ms", within);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_respects_max_rps() {
+        assert_batch_paced_to_20_rps(BinanceClient::new().with_max_rps(20.0).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_batch_respects_max_weight() {
+        // `/trades` costs 5, so 6000 a minute is 20 of them a second
+        let client = BinanceClient::new()
+            .with_max_weight_per_minute(20 * TRADES_WEIGHT * 60)
+            .unwrap();
+        assert_batch_paced_to_20_rps(client).await;
+    }
+
+    #[test]
+    fn test_rate_limits_must_be_positive() {
+        for rps in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e-300] {
+            assert!(matches!(
+                BinanceClient::new().with_max_rps(rps),
+                Err(BinanceError::InvalidRateLimit(_))
+            ));
+        }
+        assert!(matches!(
+            BinanceClient::new().with_max_weight_per_minute(0),
+            Err(BinanceError::InvalidRateLimit(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_decimal_formats() {
+        assert_eq!(parse_decimal("1.0E-4").unwrap(), 0.0001);
+        assert_eq!(parse_decimal("1e-4").unwrap(), 0.0001);
+        assert_eq!(parse_decimal("0.00010000").unwrap(), 0.0001);
+        assert_eq!(parse_decimal("45000").unwrap(), 45000.0);
+        assert_eq!(parse_decimal(" 2.5E+1 ").unwrap(), 25.0);
+
+        for bad in ["", "NaN", "inf", "-1", "1,5", "1_000", "0x10", "1e400"] {
+            assert!(parse_decimal(bad).is_err(), "{:?} accepted", bad);
+        }
+    }
+
+    #[test]
+    fn test_funding_rate_from_premium_index() {
+        // Captured from /fapi/v1/premiumIndex?symbol=BTCUSDT
+        let one = r#"{"symbol":"BTCUSDT","markPrice":"67012.45000000","indexPrice":"67041.23612245","estimatedSettlePrice":"67030.11520631","lastFundingRate":"-0.00004012","interestRate":"0.00010000","nextFundingTime":1700006400000,"time":1700000000000}"#;
+        let index: OneOrMany<RawPremiumIndex> = serde_json::from_str(one).unwrap();
+        let index = index.into_first().unwrap();
+        assert_eq!(index.mark_price, 67012.45);
+        assert_eq!(index.funding_rate().unwrap(), -0.00004012);
+
+        // Coin-margined returns a list, delivery contracts without a rate
+        let many = r#"[{"symbol":"BTCUSD_PERP","pair":"BTCUSD","markPrice":"67020.1","indexPrice":"67041.2","estimatedSettlePrice":"67030.1","lastFundingRate":"0.00010000","interestRate":"0.00010000","nextFundingTime":1700006400000,"time":1700000000000},{"symbol":"BTCUSD_241227","pair":"BTCUSD","markPrice":"68100.5","indexPrice":"67041.2","estimatedSettlePrice":"67030.1","lastFundingRate":"","interestRate":"","nextFundingTime":0,"time":1700000000000}]"#;
+        let index: Vec<RawPremiumIndex> = serde_json::from_str(many).unwrap();
+        assert_eq!(index[0].funding_rate().unwrap(), 0.0001);
+        assert!(matches!(
+            index[1].funding_rate(),
+            Err(BinanceError::InvalidFundingRate(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_trade_and_agg_trade() {
+        let trade = r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1700000000100,"T":1700000000099,"s":"BTCUSDT","t":5001,"p":"45000.10","q":"0.250","X":"MARKET","m":true}}"#;
+        let msg = TradeMessage::from_ws_text(trade).unwrap();
+        assert_eq!(msg.asset, "BTCUSDT");
+        assert_eq!(msg.timestamp, 1700000000099);
+        assert_eq!(msg.price, "45000.10");
+        assert_eq!(msg.quantity, "0.250");
+        assert!(msg.is_buyer_maker);
+
+        let agg_trade = r#"{"stream":"ethusdt@aggTrade","data":{"e":"aggTrade","E":1700000000200,"a":77,"s":"ETHUSDT","p":"2500.50","q":"3.000","f":100,"l":105,"T":1700000000198,"m":false}}"#;
+        let msg = TradeMessage::from_ws_text(agg_trade).unwrap();
+        assert_eq!(msg.asset, "ETHUSDT");
+        assert_eq!(msg.timestamp, 1700000000198);
+        assert_eq!(msg.price, "2500.50");
+        assert!(!msg.is_buyer_maker);
+
+        let ticker = r#"{"stream":"btcusdt@bookTicker","data":{"e":"bookTicker","s":"BTCUSDT"}}"#;
+        assert!(TradeMessage::from_ws_text(ticker).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_reconnects() {
+        // Accepts TCP but hangs up before the websocket handshake, every time
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                drop(socket);
+            }
+        });
+
+        let config = BinanceWebsocketConfig {
+            max_reconnects: 3,
+            initial_backoff: Duration::from_millis(1),
+            url: Some(format!("ws://{}/stream", addr)),
+            ..Default::default()
+        };
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            BinanceWebsocket::start_with(tx, ["BTCUSDT"], &config),
+        )
+        .await
+        .expect("should give up, not retry forever");
+        assert!(matches!(
+            result,
+            Err(BinanceWebsocketError::ReconnectsExhausted { attempts: 3, .. })
+        ));
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_connections_made_from_local_address() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Answers REST with one trade and hangs up on anything else, noting
+        // where each connection came from
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peers = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = peers.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, peer)) = listener.accept().await {
+                seen.lock().unwrap().push(peer.ip());
+                let mut head = vec![0u8; 1024];
+                let n = socket.read(&mut head).await.unwrap_or(0);
+                if head[..n].starts_with(b"GET /fapi/v1/trades") {
+                    let body = r#"[{"price":"100.0","qty":"2.0"}]"#;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                }
+            }
+        });
+        // Anywhere in 127/8 is loopback on Linux, but not the default source
+        let local: IpAddr = "127.0.0.2".parse().unwrap();
+
+        let client = BinanceClient::new()
+            .with_local_address(local)
+            .unwrap()
+            .with_base_url(url::Url::parse(&format!("http://{}", addr)).unwrap());
+        assert_eq!(client.avg_stats("BTCUSDT").await.unwrap().price, 100.0);
+
+        let config = BinanceWebsocketConfig {
+            max_reconnects: 1,
+            url: Some(format!("ws://{}/stream", addr)),
+            local_address: Some(local),
+            ..Default::default()
+        };
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        assert!(
+            BinanceWebsocket::start_with(tx, ["BTCUSDT"], &config)
+                .await
+                .is_err()
+        );
+
+        assert_eq!(*peers.lock().unwrap(), [local, local]);
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_cap() {
+        let config = BinanceWebsocketConfig {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(3),
+            ..Default::default()
+        };
+        let waits: Vec<_> = std::iter::successors(Some(config.initial_backoff), |&b| {
+            Some(config.next_backoff(b))
+        })
+        .take(5)
+        .map(|b| b.as_millis())
+        .collect();
+        assert_eq!(waits, vec![500, 1000, 2000, 3000, 3000]);
+
+        let default = BinanceWebsocketConfig::default();
+        assert_eq!(
+            default.next_backoff(Duration::from_secs(32)),
+            DEFAULT_WS_MAX_BACKOFF
+        );
+    }
+
+    #[test]
+    fn test_binary_frame_with_json() {
+        let trade = r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1700000000100,"T":1700000000099,"s":"BTCUSDT","t":5001,"p":"45000.10","q":"0.250","X":"MARKET","m":true}}"#;
+        let msg = TradeMessage::create_from_ws(Message::Binary(trade.as_bytes().to_vec())).unwrap();
+        assert_eq!(msg.asset, "BTCUSDT");
+        assert_eq!(msg.price, "45000.10");
+
+        assert!(matches!(
+            TradeMessage::create_from_ws(Message::Binary(vec![0xFF, 0xFE])),
+            Err(TradeMessageError::InvalidMessageFromWebsocket)
+        ));
+        assert!(TradeMessage::create_from_ws(Message::Pong(vec![])).is_err());
+    }
+}
@@ -1,4 +1,16 @@
-use clap::{Parser, Subcommand};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use clap::{CommandFactory, Parser, Subcommand};
+
+use crate::binance::{
+    DEFAULT_WS_BACKOFF, DEFAULT_WS_MAX_BACKOFF, DEFAULT_WS_MAX_RECONNECTS, Market,
+    ReferenceStrategy,
+};
+use crate::ipc::framing::Framing;
+use crate::ipc::governor::MemoryBudget;
+use crate::pipeline::{DEFAULT_LATENCY_SLO_WINDOW, PausePolicy};
+use crate::recent::DEFAULT_RECENT_DEPTH;
 
 #[derive(Debug, Parser)]
 #[command(
@@ -7,22 +19,218 @@ use clap::{Parser, Subcommand};
     about = "Low-latency perp trade forward service"
 )]
 pub struct Cli {
-    /// List of usdt perp symbols to subscribe to (eg: BTCUSDT). Upto 10.
-    #[clap(short, long, value_delimiter = ',', required = true)]
+    /// Print the wire-format spec as JSON and exit
+    #[clap(long, exclusive = true)]
+    pub print_format_spec: bool,
+
+    /// List of perp symbols to subscribe to (eg: BTCUSDT, or BTCUSD_PERP for coinm). Upto 10.
+    #[clap(
+        short,
+        long,
+        value_delimiter = ',',
+        required_unless_present_any = ["print_format_spec", "config"]
+    )]
     pub assets: Vec<String>,
 
-    /// Communication protocol
+    /// JSON file listing the assets instead, eg: {"assets": ["BTCUSDT"]}. Re-read
+    /// on SIGHUP: the websocket subscriptions follow it and a new header goes out.
+    #[clap(long, conflicts_with = "assets")]
+    pub config: Option<PathBuf>,
+
+    /// Binance futures market the assets trade on
+    #[clap(long, value_enum, default_value_t = Market::Usdm)]
+    pub market: Market,
+
+    /// How the header reference price is computed from Binance REST data
+    #[clap(long, value_enum, default_value_t = ReferenceStrategy::Mean)]
+    pub reference_strategy: ReferenceStrategy,
+
+    /// Exit non-zero after this many consecutive failed websocket connection
+    /// attempts, so an orchestrator can restart the process. 0 retries forever.
+    #[clap(long, default_value_t = DEFAULT_WS_MAX_RECONNECTS)]
+    pub max_reconnects: u32,
+
+    /// Split the assets over this many websocket connections, round robin, and
+    /// count trades per connection in /metrics
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    pub ws_shards: u16,
+
+    /// Milliseconds to wait after the first failed websocket connect, doubling
+    /// per further failure
+    #[clap(long, default_value_t = DEFAULT_WS_BACKOFF.as_millis() as u64)]
+    pub reconnect_backoff_ms: u64,
+
+    /// Ceiling for the doubling reconnect wait, in milliseconds
+    #[clap(long, default_value_t = DEFAULT_WS_MAX_BACKOFF.as_millis() as u64)]
+    pub reconnect_max_backoff_ms: u64,
+
+    /// Cap on Binance REST requests per second while building the header
+    #[clap(long, value_parser = parse_rate)]
+    pub rest_max_rps: Option<f64>,
+
+    /// Cap on the Binance REST request weight spent per minute while building
+    /// the header; `/trades` costs 5, ticker and mark price 1 each
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rest_max_weight: Option<u32>,
+
+    /// Forward Binance's trade JSON unchanged instead of the binary encoding,
+    /// eg: to compare against it or feed tools that already parse it
+    #[clap(long)]
+    pub passthrough: bool,
+
+    /// Local IP that websocket and REST connections to Binance are made from,
+    /// eg: the address of a NIC dedicated to market data
+    #[clap(long)]
+    pub local_address: Option<IpAddr>,
+
+    /// Address for the debug HTTP endpoint (eg: 127.0.0.1:8080). Disabled when unset.
+    #[clap(long)]
+    pub http_addr: Option<SocketAddr>,
+
+    /// Address serving decoded trades over HTTP (eg: 127.0.0.1:8090): `GET /trades`
+    /// (JSON lines) or `GET /events` (SSE). For demos and dashboards, not the
+    /// latency path. Disabled when unset.
+    #[clap(long)]
+    pub trade_stream_addr: Option<SocketAddr>,
+
+    /// Number of recent trades kept per asset for `/recent`
+    #[clap(long, default_value_t = DEFAULT_RECENT_DEPTH)]
+    pub recent_depth: usize,
+
+    /// What happens to trades while paused via `POST /control/pause`
+    #[clap(long, value_enum, default_value_t = PausePolicy::Drop)]
+    pub pause_policy: PausePolicy,
+
+    /// Exit non-zero once no trade arrived for this many seconds, after the sink
+    /// took everything before. Off by default: an illiquid asset can be quiet
+    /// for minutes, so pick a value well past its longest normal lull
+    #[clap(long)]
+    pub idle_timeout_secs: Option<u64>,
+
+    /// Stop after forwarding this many trades, eg: for a bounded capture. Not
+    /// counted under --passthrough
+    #[clap(long)]
+    pub max_trades: Option<u64>,
+
+    /// Drop trades that waited longer than this many milliseconds before encoding
+    #[clap(long)]
+    pub latency_budget_ms: Option<u64>,
+
+    /// Warn (and count in /metrics) when a window's p99 receive-to-sink latency
+    /// exceeds this many microseconds
+    #[clap(long)]
+    pub latency_slo_us: Option<u64>,
+
+    /// Length of each --latency-slo-us window, in seconds
+    #[clap(long, default_value_t = DEFAULT_LATENCY_SLO_WINDOW.as_secs())]
+    pub latency_slo_window_secs: u64,
+
+    /// Time every trade's encode and log the p50/p99 every this many seconds,
+    /// also exported as the `encode_ns` histogram. Adds two clock reads per trade
+    #[clap(long)]
+    pub encode_timing_secs: Option<u64>,
+
+    /// Exit on a trade for an asset missing from the header, which means the
+    /// subscription and the header diverged, instead of logging and skipping it
+    #[clap(long)]
+    pub strict_symbols: bool,
+
+    /// Send a trade as a keyframe, resetting the delta baseline, when its price
+    /// moved more than this many percent from the asset's last price
+    #[clap(long)]
+    pub gap_keyframe_pct: Option<f64>,
+
+    /// Send a gap record counting an asset's dropped trades ahead of the keyframe
+    /// that resyncs it, instead of a bare keyframe
+    #[clap(long)]
+    pub gap_records: bool,
+
+    /// Forward at most this many trades per second per asset, dropping the excess
+    #[clap(long)]
+    pub max_rate_per_asset: Option<f64>,
+
+    /// Debug: log incoming trades to <prefix>.jsonl and sent frames to <prefix>.bin,
+    /// for checking with `verify-raw-log`
+    #[clap(long)]
+    pub raw_log: Option<PathBuf>,
+
+    /// Communication protocol. Only optional with --print-format-spec, see `Cli::take_comm`.
     #[command(subcommand)]
-    pub comm: Comm,
+    pub comm: Option<Comm>,
+}
+
+impl Cli {
+    /// Take the chosen subcommand, exiting with a usage error when there is none.
+    pub fn take_comm(&mut self) -> Comm {
+        self.comm.take().unwrap_or_else(|| {
+            Self::command()
+                .error(
+                    clap::error::ErrorKind::MissingSubcommand,
+                    "a subcommand (tcp or shm) is required",
+                )
+                .exit()
+        })
+    }
+
+    /// Exit with a usage error if `--assets` names a symbol twice. Compared
+    /// case-insensitively, since both spellings subscribe to the same stream.
+    pub fn check_assets(&self) {
+        if let Some(symbol) = duplicate_asset(&self.assets) {
+            Self::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("--assets lists {} more than once", symbol),
+                )
+                .exit()
+        }
+    }
+}
+
+/// First symbol appearing a second time in `assets`, ignoring case.
+pub(crate) fn duplicate_asset(assets: &[String]) -> Option<&str> {
+    assets.iter().enumerate().find_map(|(idx, symbol)| {
+        assets[..idx]
+            .iter()
+            .any(|seen| seen.eq_ignore_ascii_case(symbol))
+            .then_some(symbol.as_str())
+    })
 }
 
 #[derive(Debug, Subcommand)]
 pub enum Comm {
     /// Use tcp socket
     Tcp {
-        /// Port to bind on (0.0.0.0:<port>)
+        /// Port to bind on (<bind>:<port>)
         #[clap(short, long)]
         port: u16,
+
+        /// Local address to bind on (eg: 127.0.0.1, ::, [::1])
+        #[clap(short, long, default_value = "0.0.0.0", value_parser = parse_bind_ip)]
+        bind: IpAddr,
+
+        #[clap(flatten)]
+        budget: MemoryBudget,
+
+        /// Send new clients a keyframe of each asset's latest trade right after
+        /// the header, instead of leaving them on the reference prices
+        #[clap(long)]
+        snapshot_on_connect: bool,
+
+        /// Only send clients as many frames as they granted credits for, see
+        /// `tcp::CREDIT_GRANT_LEN`
+        #[clap(long)]
+        credit_flow_control: bool,
+
+        /// Length prefix of each frame: a fixed 4-byte `u32`, or a varint that
+        /// takes one byte for a typical trade. Clients pick it up from the handshake
+        #[clap(long, value_enum, default_value_t = Framing::U32)]
+        framing: Framing,
+
+        /// Serve each client the header version named in its version hello,
+        /// transcoding a v2 stream to v1 where needed; clients sending none get v1.
+        /// See `tcp::VERSION_HELLO_PREFIX`
+        #[clap(long)]
+        negotiate_version: bool,
     },
     /// Use shared memory ring buffer via /dev/shm
     Shm {
@@ -33,5 +241,109 @@ pub enum Comm {
         /// Capacity of ring buffer in bytes
         #[clap(short, long, default_value = "1048576")]
         capacity: u32,
+
+        /// Push a heartbeat after this many seconds without a trade
+        #[clap(long)]
+        heartbeat_secs: Option<u64>,
+
+        /// On a full queue, wait up to this long for the consumer to make room
+        /// instead of dropping the frame. Trades queue up in memory meanwhile
+        #[clap(long)]
+        block_when_full_ms: Option<u64>,
     },
 }
+
+/// Parse a bind address, accepting IPv6 with or without brackets (`[::]` or `::`).
+fn parse_bind_ip(s: &str) -> Result<IpAddr, String> {
+    let trimmed = s
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(s);
+    trimmed
+        .parse()
+        .map_err(|e| format!("invalid bind address '{}': {}", s, e))
+}
+
+/// Parse a rate limit, which must be finite and positive.
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        Ok(_) => Err(format!("rate '{}' must be finite and positive", s)),
+        Err(e) => Err(format!("invalid rate '{}': {}", s, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bind_ip() {
+        assert_eq!(parse_bind_ip("127.0.0.1"), Ok(IpAddr::from([127, 0, 0, 1])));
+        assert_eq!(parse_bind_ip("[::]"), Ok("::".parse().unwrap()));
+        assert_eq!(parse_bind_ip("::1"), Ok("::1".parse().unwrap()));
+        assert!(parse_bind_ip("localhost:9000").is_err());
+
+        let cli =
+            Cli::try_parse_from(["perp_signal_hft", "-a", "BTCUSDT", "tcp", "-p", "9000"]).unwrap();
+        // Gives up after as many failed connects as it always has
+        assert_eq!(cli.max_reconnects, 5);
+        match cli.comm.unwrap() {
+            Comm::Tcp {
+                port,
+                bind,
+                budget,
+                snapshot_on_connect,
+                credit_flow_control,
+                framing,
+                negotiate_version,
+            } => {
+                assert_eq!(port, 9000);
+                assert_eq!(framing, Framing::U32);
+                assert!(!snapshot_on_connect);
+                assert!(!credit_flow_control);
+                assert!(!negotiate_version);
+                assert_eq!(bind, IpAddr::from([0, 0, 0, 0]));
+                assert_eq!(budget, MemoryBudget::default());
+            }
+            _ => panic!("expected tcp"),
+        }
+
+        let cli = Cli::try_parse_from(["perp_signal_hft", "--print-format-spec"]).unwrap();
+        assert!(cli.print_format_spec && cli.comm.is_none());
+        assert!(
+            Cli::try_parse_from(["perp_signal_hft", "--print-format-spec", "-a", "X"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_rest_rate_limits_must_be_positive() {
+        let parse = |flag: &str, value: &str| {
+            Cli::try_parse_from(["perp_signal_hft", "-a", "BTCUSDT", flag, value])
+        };
+        let cli = parse("--rest-max-rps", "2.5").unwrap();
+        assert_eq!(cli.rest_max_rps, Some(2.5));
+        for bad in ["0", "-1", "NaN", "inf", "fast"] {
+            assert!(parse("--rest-max-rps", bad).is_err(), "{}", bad);
+        }
+        assert_eq!(
+            parse("--rest-max-weight", "1200").unwrap().rest_max_weight,
+            Some(1200)
+        );
+        assert!(parse("--rest-max-weight", "0").is_err());
+    }
+
+    #[test]
+    fn test_duplicate_asset() {
+        let assets = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(duplicate_asset(&assets(&["BTCUSDT", "ETHUSDT"])), None);
+        assert_eq!(
+            duplicate_asset(&assets(&["BTCUSDT", "ETHUSDT", "BTCUSDT"])),
+            Some("BTCUSDT")
+        );
+        assert_eq!(
+            duplicate_asset(&assets(&["ethusdt", "BTCUSDT", "ETHUSDT"])),
+            Some("ETHUSDT")
+        );
+    }
+}
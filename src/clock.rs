@@ -0,0 +1,134 @@
+// std
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+static WALL_CLOCK: WallClock = WallClock::new();
+
+/// Time since `UNIX_EPOCH`, without ever failing. See `WallClock`.
+pub fn unix_now() -> Duration {
+    WALL_CLOCK.since_epoch()
+}
+
+/// Source of wall-clock time for timestamping, so time-dependent behaviour
+/// (latency budget, heartbeats, reference timestamps) can be driven by tests.
+pub trait Clock: Send + Sync {
+    /// Time since `UNIX_EPOCH`.
+    fn now(&self) -> Duration;
+}
+
+/// The real clock, via `unix_now`. Used everywhere unless a clock is injected.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        unix_now()
+    }
+}
+
+/// Clock that only moves when told to.
+#[derive(Debug, Default)]
+pub struct MockClock {
+    now_us: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new(now: Duration) -> Self {
+        Self {
+            now_us: AtomicU64::new(now.as_micros() as u64),
+        }
+    }
+
+    pub fn set(&self, now: Duration) {
+        self.now_us.store(now.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now_us
+            .fetch_add(by.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        Duration::from_micros(self.now_us.load(Ordering::Relaxed))
+    }
+}
+
+/// Wall clock that survives the system clock being stepped to before
+/// `UNIX_EPOCH` (seen on VMs after a bad NTP step).
+///
+/// Each good reading stores the offset between wall time and a monotonic clock.
+/// When `SystemTime` errors, time carries on from the last good reading using
+/// the monotonic clock instead. Before any good reading that is the time since
+/// the clock was first used, i.e. close to zero.
+pub struct WallClock {
+    base: OnceLock<Instant>,
+    /// Last good wall time minus monotonic time since `base`, in microseconds
+    offset_us: AtomicI64,
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WallClock {
+    pub const fn new() -> Self {
+        Self {
+            base: OnceLock::new(),
+            offset_us: AtomicI64::new(0),
+        }
+    }
+
+    pub fn since_epoch(&self) -> Duration {
+        self.resolve(SystemTime::now().duration_since(UNIX_EPOCH).ok())
+    }
+
+    /// Turn a wall-clock reading (`None` when it failed) into a time.
+    fn resolve(&self, reading: Option<Duration>) -> Duration {
+        let mono_us = self.base.get_or_init(Instant::now).elapsed().as_micros() as i64;
+        match reading {
+            Some(wall) => {
+                self.offset_us
+                    .store(wall.as_micros() as i64 - mono_us, Ordering::Relaxed);
+                wall
+            }
+            None => {
+                tracing::warn!("system clock is before UNIX_EPOCH, using monotonic fallback");
+                let us = mono_us + self.offset_us.load(Ordering::Relaxed);
+                Duration::from_micros(us.max(0) as u64)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backwards_jump_falls_back() {
+        let clock = WallClock::new();
+        // No good reading yet: no panic, just time since first use
+        assert!(clock.resolve(None) < Duration::from_secs(1));
+
+        let good = Duration::from_millis(1_700_000_000_000);
+        assert_eq!(clock.resolve(Some(good)), good);
+        std::thread::sleep(Duration::from_millis(5));
+        let fallback = clock.resolve(None);
+        assert!(
+            fallback >= good + Duration::from_millis(5),
+            "{:?}",
+            fallback
+        );
+        assert!(fallback < good + Duration::from_secs(1));
+
+        // Recovers once the system clock is sane again
+        let later = good + Duration::from_secs(60);
+        assert_eq!(clock.resolve(Some(later)), later);
+        assert!(unix_now() > Duration::ZERO);
+    }
+}
@@ -0,0 +1,150 @@
+// std
+use std::io;
+use std::path::Path;
+
+// external
+use serde::Deserialize;
+
+// internal
+use crate::cli::duplicate_asset;
+
+/// Most assets one process subscribes to.
+pub const MAX_ASSETS: usize = 10;
+
+/// Contents of the `--config` file, eg: `{"assets": ["BTCUSDT", "ETHUSDT"]}`.
+/// Read at startup and again on every SIGHUP.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub assets: Vec<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("reading config: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("parsing config: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("config lists no assets")]
+    NoAssets,
+
+    #[error("config lists {0} assets (max {MAX_ASSETS})")]
+    TooManyAssets(usize),
+
+    #[error("config lists {0} more than once")]
+    DuplicateAsset(String),
+}
+
+impl Config {
+    /// Read and validate the config at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse and validate a config, see `load`.
+    pub fn parse(json: &str) -> Result<Self, ConfigError> {
+        let config: Config = serde_json::from_str(json)?;
+        check_assets(&config.assets)?;
+        Ok(config)
+    }
+}
+
+/// The rules `--assets` follows too: one to `MAX_ASSETS` symbols, none twice.
+pub fn check_assets(assets: &[String]) -> Result<(), ConfigError> {
+    if assets.is_empty() {
+        return Err(ConfigError::NoAssets);
+    }
+    if assets.len() > MAX_ASSETS {
+        return Err(ConfigError::TooManyAssets(assets.len()));
+    }
+    if let Some(symbol) = duplicate_asset(assets) {
+        return Err(ConfigError::DuplicateAsset(symbol.to_string()));
+    }
+    Ok(())
+}
+
+/// Assets in `new` but not `old`, and in `old` but not `new`, in list order.
+pub fn diff_assets(old: &[String], new: &[String]) -> (Vec<String>, Vec<String>) {
+    let missing_from = |list: &[String], other: &[String]| {
+        list.iter()
+            .filter(|symbol| !other.contains(symbol))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    (missing_from(new, old), missing_from(old, new))
+}
+
+/// Spread `assets` over websocket shards already subscribed to `shards`. Assets
+/// kept stay on their shard, so no stream moves between connections, and each
+/// added asset goes to the shard with the fewest.
+pub fn reshard(shards: &[Vec<String>], assets: &[String]) -> Vec<Vec<String>> {
+    let mut resharded: Vec<Vec<String>> = shards
+        .iter()
+        .map(|shard| {
+            shard
+                .iter()
+                .filter(|symbol| assets.contains(symbol))
+                .cloned()
+                .collect()
+        })
+        .collect();
+    for symbol in assets {
+        if resharded.iter().flatten().any(|kept| kept == symbol) {
+            continue;
+        }
+        if let Some(shard) = resharded.iter_mut().min_by_key(|shard| shard.len()) {
+            shard.push(symbol.clone());
+        }
+    }
+    resharded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_validates_assets() {
+        let config = Config::parse(r#"{"assets": ["BTCUSDT", "ETHUSDT"]}"#).unwrap();
+        assert_eq!(config.assets, ["BTCUSDT", "ETHUSDT"]);
+
+        assert!(matches!(
+            Config::parse(r#"{"assets": []}"#),
+            Err(ConfigError::NoAssets)
+        ));
+        assert!(matches!(
+            Config::parse(r#"{"assets": ["BTCUSDT", "btcusdt"]}"#),
+            Err(ConfigError::DuplicateAsset(symbol)) if symbol == "btcusdt"
+        ));
+        let eleven: Vec<String> = (0..11).map(|i| format!("\"A{}USDT\"", i)).collect();
+        assert!(matches!(
+            Config::parse(&format!(r#"{{"assets": [{}]}}"#, eleven.join(","))),
+            Err(ConfigError::TooManyAssets(11))
+        ));
+        assert!(matches!(
+            Config::parse(r#"{"assets": ["BTCUSDT"], "asset": []}"#),
+            Err(ConfigError::Json(_))
+        ));
+
+        let (added, removed) = diff_assets(
+            &config.assets,
+            &["ETHUSDT".to_string(), "SOLUSDT".to_string()],
+        );
+        assert_eq!(
+            (added, removed),
+            (vec!["SOLUSDT".into()], vec!["BTCUSDT".into()])
+        );
+
+        let list = |symbols: &[&str]| symbols.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let shards = [list(&["BTCUSDT", "SOLUSDT"]), list(&["ETHUSDT"])];
+        assert_eq!(
+            reshard(
+                &shards,
+                &list(&["ETHUSDT", "SOLUSDT", "XRPUSDT", "BNBUSDT"])
+            ),
+            [list(&["SOLUSDT", "XRPUSDT"]), list(&["ETHUSDT", "BNBUSDT"])]
+        );
+    }
+}
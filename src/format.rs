@@ -1,8 +1,213 @@
 use std::collections::HashMap;
-use std::io::{Cursor, Read, Write};
+use std::io::{self, Cursor, Read, Write};
+use std::ops::RangeInclusive;
 
+/// Fixed-point scale of prices and quantities unless the header declares
+/// another, ie: a resolution of 1e-5.
+pub const DEFAULT_SCALE: f64 = 100000.0;
 
-const SCALE_FACTOR: f64 = 100000.0;
+/// Header versions. v2 appends an extension section to the v1 layout. v1 to v3
+/// predate `HEADER_MAGIC`; they're always read, but only written with legacy
+/// headers on, see `with_legacy_headers`; v1 whenever no extension is needed.
+const VERSION_V1: u8 = 1;
+const VERSION_V2: u8 = 2;
+/// v3 is v2 with a u16 asset count, written only for more assets than a packed
+/// byte can address; its records use wide asset ids, see `WIDE_ID_ESCAPE`.
+const VERSION_V3: u8 = 3;
+/// v4 is v3 preceded by `HEADER_MAGIC`, and what `write_header` writes without
+/// a header length; its records use wide asset ids only past `MAX_PACKED_ASSETS`
+/// assets.
+const VERSION_V4: u8 = 4;
+/// v5 is v4 with the header's total byte length, magic included, as a u32 right
+/// after the version, so a reader can step over it without parsing it. Written
+/// by default, see `with_header_length`.
+const VERSION_V5: u8 = 5;
+/// First header version that starts with `HEADER_MAGIC`; a consumer built
+/// before it needs legacy headers written, see `BinaryFormat::with_legacy_headers`.
+pub const HEADER_MAGIC_VERSION: u8 = VERSION_V4;
+/// Header versions this build reads.
+pub const SUPPORTED_VERSIONS: RangeInclusive<u8> = VERSION_V1..=VERSION_V5;
+
+/// First bytes of a v4 or later header, so a reader can tell a header from
+/// arbitrary bytes (eg: a SHM region nothing wrote yet) before trusting any of
+/// it. Not `STREAM_NAME`'s first bytes, so the stream magic isn't taken for one.
+pub const HEADER_MAGIC: [u8; 4] = *b"PSHD";
+
+/// v2 header extension tags, each followed by a varint length and the payload.
+/// Decoders skip tags they don't know.
+const EXT_ASSET_SCALES: u8 = 0x01;
+const EXT_FIELD_SCALES: u8 = 0x02;
+const EXT_TIMESTAMP_UNIT: u8 = 0x03;
+const EXT_RECORD_FLAGS: u8 = 0x04;
+const EXT_QUANTITY_SCALE: u8 = 0x05;
+
+/// `EXT_RECORD_FLAGS` bit: every record is preceded by its varint byte length.
+const RECORD_FLAG_LENGTH_PREFIXED: u8 = 0x01;
+/// `EXT_RECORD_FLAGS` bit: every record is `FIXED_RECORD_LEN` bytes of absolute
+/// values. Can't be combined with `RECORD_FLAG_LENGTH_PREFIXED`.
+const RECORD_FLAG_FIXED_WIDTH: u8 = 0x02;
+/// `EXT_RECORD_FLAGS` bit: every record is followed by the CRC-32 of its bytes,
+/// u32 LE, inside the length prefix if there is one. Can't be combined with
+/// `RECORD_FLAG_FIXED_WIDTH`.
+const RECORD_FLAG_CHECKSUM: u8 = 0x04;
+/// `EXT_RECORD_FLAGS` bit: a trade's quantity is a signed varint delta against
+/// the asset's previous quantity instead of an absolute unsigned varint. Can't
+/// be combined with `RECORD_FLAG_FIXED_WIDTH`.
+const RECORD_FLAG_QUANTITY_DELTA: u8 = 0x08;
+/// Every record flag this build knows.
+const RECORD_FLAGS_KNOWN: u8 = RECORD_FLAG_LENGTH_PREFIXED
+    | RECORD_FLAG_FIXED_WIDTH
+    | RECORD_FLAG_CHECKSUM
+    | RECORD_FLAG_QUANTITY_DELTA;
+/// Bytes a checksum adds to each record.
+const CHECKSUM_LEN: usize = 4;
+
+/// Size of every record in a fixed-width stream: packed asset id, kind,
+/// timestamp (u64), then an i64 and a u64 whose meaning depends on the kind.
+pub const FIXED_RECORD_LEN: usize = 1 + 1 + 8 + 8 + 8;
+
+/// Longest symbol a header can hold, in bytes: its length is a single byte.
+const MAX_SYMBOL_LEN: usize = u8::MAX as usize;
+
+/// Largest asset count a header can declare.
+const MAX_ASSETS: usize = u16::MAX as usize;
+
+/// Largest asset count of a v1 or v2 header, whose asset ids share the packed
+/// byte with the maker flag. Fixed-width records are limited to it too.
+const MAX_PACKED_ASSETS: usize = 127;
+
+/// Packed-byte id that, in a v3 stream, is followed by an unsigned varint the
+/// asset id exceeds it by. Ids below it stay in the packed byte alone, so the
+/// first 126 assets cost no more than in a v1 stream.
+const WIDE_ID_ESCAPE: u8 = 0x7E;
+
+/// Largest header extension payload read: per-asset scales for `MAX_ASSETS`
+/// assets, the biggest one there is. A longer one is a corrupt length.
+const MAX_EXTENSION_LEN: u64 = 8 * MAX_ASSETS as u64;
+
+/// Smallest header `BinaryFormat::from_header_frame` takes: version, asset
+/// count, one 1-byte symbol with its length, reference timestamp, price and
+/// quantity, as in a v1 header; a v4 one is longer still.
+const MIN_HEADER_LEN: usize = 1 + 1 + 2 + 8 + 8 + 8;
+
+/// Packed-byte asset id reserved for control records. Trades only ever put ids
+/// `0..=126` in the packed byte: v1 and v2 headers cap the asset count at 127,
+/// and v3 escapes larger ids with `WIDE_ID_ESCAPE`.
+const CONTROL_ID: u8 = 0x7F;
+
+/// Timestamp deltas beyond this (either direction) still encode fine, but are
+/// logged: a day without a trade, or a trade a day older than its predecessor,
+/// usually means a bad clock or a stale reference timestamp rather than a quiet
+/// market. Typical deltas fit in 1-2 varint bytes; an hour needs 4.
+const MAX_PLAUSIBLE_TS_DELTA_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Control record kinds, written right after the `CONTROL_ID` byte. Fixed-width
+/// records carry them in their kind byte, with `KIND_TRADE` for plain trades.
+const KIND_TRADE: u8 = 0x00;
+const KIND_KEYFRAME: u8 = 0x01;
+const KIND_HEARTBEAT: u8 = 0x02;
+const KIND_FUNDING: u8 = 0x03;
+const KIND_GAP: u8 = 0x04;
+const KIND_RESET: u8 = 0x05;
+
+/// First frame of every stream (each TCP connection, the SHM queue, a raw log
+/// file), ahead of `START`: protocol name then stream version. Consumers check
+/// it with `check_stream_magic` so the wrong port or file fails on the first
+/// frame instead of somewhere in the header.
+pub const STREAM_MAGIC: &[u8] = b"PSHFT\x01";
+const STREAM_NAME: &[u8] = b"PSHFT";
+pub const STREAM_VERSION: u8 = 1;
+
+/// Default scale for funding rates. Rates are around 1e-4, so the price scale
+/// would quantize most of them to zero.
+const DEFAULT_FUNDING_SCALE: f64 = 1e10;
+
+/// Unit of every timestamp in a stream: the header's reference timestamp,
+/// trades, keyframes, heartbeats and funding records. Declared in the header's
+/// `EXT_TIMESTAMP_UNIT` extension; without it a stream is in milliseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimestampUnit {
+    /// Binance's own resolution
+    #[default]
+    Millis,
+    /// For producers stamping trades with a local clock, eg: receive time
+    Micros,
+}
+
+impl TimestampUnit {
+    fn code(self) -> u8 {
+        match self {
+            TimestampUnit::Millis => 0,
+            TimestampUnit::Micros => 1,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self, BinaryFormatError> {
+        match code {
+            0 => Ok(TimestampUnit::Millis),
+            1 => Ok(TimestampUnit::Micros),
+            _ => Err(BinaryFormatError::UnsupportedTimestampUnit(code)),
+        }
+    }
+
+    /// Ticks of this unit per millisecond.
+    pub fn per_milli(self) -> u64 {
+        match self {
+            TimestampUnit::Millis => 1,
+            TimestampUnit::Micros => 1_000,
+        }
+    }
+
+    /// Convert a timestamp in this unit to microseconds.
+    pub fn to_micros(self, timestamp: u64) -> u64 {
+        timestamp.saturating_mul(1_000 / self.per_milli())
+    }
+
+    /// Convert a time since `UNIX_EPOCH` to a timestamp in this unit.
+    pub fn from_duration(self, since_epoch: std::time::Duration) -> u64 {
+        match self {
+            TimestampUnit::Millis => since_epoch.as_millis() as u64,
+            TimestampUnit::Micros => since_epoch.as_micros() as u64,
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            TimestampUnit::Millis => "ms",
+            TimestampUnit::Micros => "us",
+        }
+    }
+}
+
+/// A record field whose fixed-point scale is set per field rather than per
+/// asset, declared in the header's `EXT_FIELD_SCALES` extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScaledField {
+    /// `Record::Funding` rate
+    FundingRate,
+}
+
+impl ScaledField {
+    /// `(record kind, field index)` as written in the header.
+    fn key(self) -> (u8, u8) {
+        match self {
+            ScaledField::FundingRate => (KIND_FUNDING, 0),
+        }
+    }
+
+    fn from_key(key: (u8, u8)) -> Option<Self> {
+        match key {
+            (KIND_FUNDING, 0) => Some(ScaledField::FundingRate),
+            _ => None,
+        }
+    }
+
+    pub fn default_scale(self) -> f64 {
+        match self {
+            ScaledField::FundingRate => DEFAULT_FUNDING_SCALE,
+        }
+    }
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum BinaryFormatError {
@@ -15,20 +220,96 @@ pub enum BinaryFormatError {
     #[error("Invalid symbol: {0}")]
     InvalidSymbol(String),
 
+    #[error("Duplicate symbol: {0}")]
+    DuplicateSymbol(String),
+
     #[error("Invalid version: {0}")]
     InvalidVersion(u8),
 
     #[error("Invalid header length")]
     InvalidHeaderLength,
 
+    #[error("Invalid header: {0}")]
+    InvalidHeader(String),
+
     #[error("Insufficient data")]
     InsufficientData,
 
-    #[error("Too many assets (max 127)")]
+    #[error("Too many assets (max {MAX_ASSETS})")]
     TooManyAssets,
 
     #[error("Overflow error")]
     Overflow,
+
+    #[error("Self-check failed: {0}")]
+    SelfCheckFailed(String),
+
+    #[error("Invalid asset spec: {0}")]
+    InvalidAssetSpec(String),
+
+    #[error("Desync suspected: {0}")]
+    DesyncSuspected(String),
+
+    #[error("Not a perp_signal_hft stream, first frame starts {0:02x?}")]
+    BadStreamMagic(Vec<u8>),
+
+    #[error("Not a perp_signal_hft header, it starts {0:02x?}")]
+    BadMagic(Vec<u8>),
+
+    #[error("Unsupported stream version {0}, expected {STREAM_VERSION}")]
+    UnsupportedStreamVersion(u8),
+
+    #[error("Unsupported timestamp unit {0} in header")]
+    UnsupportedTimestampUnit(u8),
+
+    #[error("Unsupported record flags {0:#04x} in header")]
+    UnsupportedRecordFlags(u8),
+
+    #[error("Records are not length-prefixed in this stream")]
+    NotLengthPrefixed,
+
+    #[error("Records are not fixed-width in this stream")]
+    NotFixedWidth,
+
+    #[error("Checksum mismatch: record carries {expected:#010x}, its bytes sum to {actual:#010x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+}
+
+impl BinaryFormatError {
+    /// `InvalidVersion` as the error a consumer reports, since a header version
+    /// it doesn't know comes from a newer producer; any other error is handed back.
+    pub fn into_unsupported_version(self) -> Result<UnsupportedVersion, Self> {
+        match self {
+            BinaryFormatError::InvalidVersion(version) => Ok(UnsupportedVersion {
+                version,
+                supported: SUPPORTED_VERSIONS,
+            }),
+            e => Err(e),
+        }
+    }
+}
+
+/// A stream in a newer header version than this build reads.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error(
+    "stream is format v{version}, this build supports v{}-v{}; upgrade the consumer",
+    supported.start(),
+    supported.end()
+)]
+pub struct UnsupportedVersion {
+    pub version: u8,
+    pub supported: RangeInclusive<u8>,
+}
+
+/// Check a stream's first frame against `STREAM_MAGIC`.
+pub fn check_stream_magic(frame: &[u8]) -> Result<(), BinaryFormatError> {
+    match frame.strip_prefix(STREAM_NAME) {
+        Some([STREAM_VERSION]) => Ok(()),
+        Some([version]) => Err(BinaryFormatError::UnsupportedStreamVersion(*version)),
+        _ => Err(BinaryFormatError::BadStreamMagic(
+            frame.iter().take(8).copied().collect(),
+        )),
+    }
 }
 
 /// variable length integer encoding/decoding
@@ -97,14 +378,69 @@ pub mod varint {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Trade {
     pub symbol: String,
-    pub timestamp: u64,       // Timestamp in milliseconds
+    pub timestamp: u64,       // Timestamp in the stream's `TimestampUnit`
     pub price: f64,           // Trade price
     pub quantity: f64,        // Trade quantity
     pub is_buyer_maker: bool, // True for buyer maker, false otherwise
 }
 
+/// A single decoded record from the message stream
+#[derive(Debug, Clone)]
+pub enum Record {
+    /// Trade delta-encoded against the asset's previous state
+    Trade(Trade),
+    /// Trade carried with absolute values, re-seating the asset's delta state
+    Keyframe(Trade),
+    /// Liveness marker sent while no trades flow, carrying the producer's clock
+    Heartbeat(u64),
+    /// Funding rate for an asset, fixed-point at `ScaledField::FundingRate`'s scale
+    Funding {
+        symbol: String,
+        timestamp: u64,
+        rate: f64,
+    },
+    /// Trades of an asset the producer dropped, sent ahead of the keyframe that
+    /// resyncs it. `dropped` is approximate: a frame the transport lost after
+    /// encoding counts too, but drops the producer never saw don't.
+    Gap { symbol: String, dropped: u64 },
+    /// Every asset's state re-seated on fresh reference values, as if by a new
+    /// header, stamped with the reference timestamp. See `BinaryFormat::reset_state`.
+    Reset(u64),
+    /// Control record of a kind this decoder predates. Its payload was skipped
+    /// by length, so the stream stays in sync.
+    Unknown { kind: u8 },
+}
+
+impl Record {
+    /// The trade carried by this record, if any.
+    pub fn into_trade(self) -> Option<Trade> {
+        match self {
+            Record::Trade(trade) | Record::Keyframe(trade) => Some(trade),
+            Record::Heartbeat(_)
+            | Record::Funding { .. }
+            | Record::Gap { .. }
+            | Record::Reset(_)
+            | Record::Unknown { .. } => None,
+        }
+    }
+}
+
+/// Per-asset decode state reported by [`BinaryFormat::asset_stats`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssetStats {
+    pub symbol: String,
+    /// Trades (and keyframes) decoded since the header
+    pub decoded: u64,
+    pub last_timestamp: u64,
+    pub last_price: f64,
+    pub last_quantity: f64,
+    /// Sum of absolute price changes, starting from the header reference price
+    pub price_moved: f64,
+}
+
 /// Header information for the binary format
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -122,14 +458,45 @@ struct AssetState {
     last_timestamp: u64,
     last_price: f64,
     last_quantity: f64,
+    /// Side of the last trade; `None` until the asset trades after the header
+    last_is_buyer_maker: Option<bool>,
+    /// Trades decoded for this asset since the header
+    decoded: u64,
+    /// Sum of absolute price changes across decoded trades
+    price_moved: f64,
 }
 
 /// Binary format encoder/decoder for trade data
+#[derive(Clone)]
 pub struct BinaryFormat {
     version: u8,
     assets: Vec<String>,
-    asset_to_id: HashMap<String, u8>,
+    asset_to_id: HashMap<String, u16>,
     states: Vec<AssetState>,
+    /// Fixed-point scale of each asset's price, and its quantity unless
+    /// `quantity_scale` is set
+    scales: Vec<f64>,
+    /// Fixed-point scale of every asset's quantity, see `with_quantity_scale`
+    quantity_scale: Option<f64>,
+    /// Scale `with_assets` gives each asset, see `with_scale`
+    default_scale: f64,
+    /// Per-field scales that differ from the field's default
+    field_scales: HashMap<ScaledField, f64>,
+    timestamp_unit: TimestampUnit,
+    /// Every record carries a leading varint length, see `with_length_prefixed_records`
+    length_prefixed: bool,
+    /// Every record is `FIXED_RECORD_LEN` bytes, see `with_fixed_width_records`
+    fixed_width: bool,
+    /// Every record is followed by its CRC-32, see `with_checksum`
+    checksum: bool,
+    /// Trade quantities are deltas, see `with_quantity_deltas`
+    quantity_deltas: bool,
+    /// Headers from before `HEADER_MAGIC` are written, see `with_legacy_headers`
+    legacy_headers: bool,
+    /// The header states its own length, see `with_header_length`
+    header_length: bool,
+    /// Decoder kept in lockstep with the encoder when self-check is on
+    shadow: Option<Box<BinaryFormat>>,
 }
 
 impl Default for BinaryFormat {
@@ -141,22 +508,55 @@ impl Default for BinaryFormat {
             assets: vec![],
             asset_to_id,
             states: Vec::new(),
+            scales: Vec::new(),
+            quantity_scale: None,
+            default_scale: DEFAULT_SCALE,
+            field_scales: HashMap::new(),
+            timestamp_unit: TimestampUnit::default(),
+            length_prefixed: false,
+            fixed_width: false,
+            checksum: false,
+            quantity_deltas: false,
+            legacy_headers: false,
+            header_length: true,
+            shadow: None,
         }
     }
 }
 impl BinaryFormat {
     pub fn new() -> Self {
-        BinaryFormat::default()
+        Self::with_scale(DEFAULT_SCALE, DEFAULT_SCALE)
+    }
+
+    /// An encoder whose assets' prices are at `price_scale` and quantities at
+    /// `qty_scale`, both written to the header. A decoder takes its scales from
+    /// the header it reads, whatever it was made with. `write_header` rejects a
+    /// scale that isn't finite and positive.
+    pub fn with_scale(price_scale: f64, qty_scale: f64) -> Self {
+        BinaryFormat {
+            default_scale: price_scale,
+            quantity_scale: (qty_scale != price_scale).then_some(qty_scale),
+            ..BinaryFormat::default()
+        }
     }
+    /// Assets this encoder handles. Ids are positional: `assets[i]` goes out as
+    /// id `i`, and the header lists assets in the same order, so a decoder set up
+    /// by `read_header` maps ids back to the same symbols. See `asset_id`.
     pub fn with_assets(mut self, assets: Vec<String>) -> Result<Self, BinaryFormatError> {
         let asset_len = assets.len();
-        if asset_len > 127 {
+        if asset_len > MAX_ASSETS {
             return Err(BinaryFormatError::TooManyAssets);
         }
 
+        if let Some(asset) = assets.iter().find(|asset| asset.len() > MAX_SYMBOL_LEN) {
+            return Err(Self::symbol_too_long(asset));
+        }
         let mut asset_to_id = HashMap::new();
         for (idx, asset) in assets.iter().enumerate() {
-            asset_to_id.insert(asset.clone(), idx as u8);
+            // A repeat would leave the first id unreachable and mis-route trades
+            if asset_to_id.insert(asset.clone(), idx as u16).is_some() {
+                return Err(BinaryFormatError::DuplicateSymbol(asset.clone()));
+            }
         }
 
         self.assets = assets;
@@ -166,12 +566,257 @@ impl BinaryFormat {
                 last_timestamp: 0,
                 last_price: 0.0,
                 last_quantity: 0.0,
+                last_is_buyer_maker: None,
+                decoded: 0,
+                price_moved: 0.0,
             };
             asset_len
         ];
+        self.scales = vec![self.default_scale; asset_len];
+        self.sync_shadow();
+        Ok(self)
+    }
+
+    /// Start building an encoder and its header in one go, see `BinaryFormatBuilder`.
+    pub fn builder() -> BinaryFormatBuilder {
+        BinaryFormatBuilder::default()
+    }
+
+    /// Set the fixed-point scale of `field`, written to the header so decoders
+    /// use the same one. Call before `write_header`.
+    pub fn with_field_scale(
+        mut self,
+        field: ScaledField,
+        scale: f64,
+    ) -> Result<Self, BinaryFormatError> {
+        if !(scale.is_finite() && scale > 0.0) {
+            return Err(BinaryFormatError::InvalidAssetSpec(format!(
+                "{:?} scale {}",
+                field, scale
+            )));
+        }
+        if scale == field.default_scale() {
+            self.field_scales.remove(&field);
+        } else {
+            self.field_scales.insert(field, scale);
+        }
+        self.sync_shadow();
+        Ok(self)
+    }
+
+    /// Give quantities their own fixed-point scale, the same for every asset,
+    /// instead of each asset's price scale. Sizes rarely need as many decimals
+    /// as prices, and a coarser scale makes for shorter varints. Written to the
+    /// header so decoders use the same one. Call before `write_header`.
+    pub fn with_quantity_scale(mut self, scale: f64) -> Result<Self, BinaryFormatError> {
+        if !(scale.is_finite() && scale > 0.0) {
+            return Err(BinaryFormatError::InvalidAssetSpec(format!(
+                "quantity scale {}",
+                scale
+            )));
+        }
+        self.quantity_scale = Some(scale);
+        self.sync_shadow();
         Ok(self)
     }
 
+    pub fn field_scale(&self, field: ScaledField) -> f64 {
+        self.field_scales
+            .get(&field)
+            .copied()
+            .unwrap_or(field.default_scale())
+    }
+
+    /// Declare that timestamps handed to this encoder are in `unit`, written to
+    /// the header so decoders read them the same way. Call before `write_header`.
+    pub fn with_timestamp_unit(mut self, unit: TimestampUnit) -> Self {
+        self.timestamp_unit = unit;
+        self.sync_shadow();
+        self
+    }
+
+    /// Header version last written or read.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Unit of this stream's timestamps; for a decoder, as declared by the header.
+    pub fn timestamp_unit(&self) -> TimestampUnit {
+        self.timestamp_unit
+    }
+
+    /// Prefix every record with its varint byte length, flagged in the header, so
+    /// readers can step over records they don't care about (`skip_record`)
+    /// without decoding them, eg: to index a recording. Costs a byte per trade.
+    /// Call before `write_header`.
+    pub fn with_length_prefixed_records(mut self, enabled: bool) -> Self {
+        self.length_prefixed = enabled;
+        self.sync_shadow();
+        self
+    }
+
+    /// Whether records carry a length prefix; for a decoder, as flagged by the header.
+    pub fn length_prefixed_records(&self) -> bool {
+        self.length_prefixed
+    }
+
+    /// Write every record as `FIXED_RECORD_LEN` bytes of absolute fixed-point
+    /// values instead of varint deltas, flagged in the header. A trade takes
+    /// several times the bytes, but record `i` of a recording starts at
+    /// `i * FIXED_RECORD_LEN` past the header and decodes without the ones before
+    /// it, so an mmap'd file can be read by index (`read_fixed_record`). Can't be
+    /// combined with length-prefixed records, nor used with more than 127 assets.
+    /// Call before `write_header`.
+    pub fn with_fixed_width_records(mut self, enabled: bool) -> Self {
+        self.fixed_width = enabled;
+        self.sync_shadow();
+        self
+    }
+
+    /// Whether records are fixed-width; for a decoder, as flagged by the header.
+    pub fn fixed_width_records(&self) -> bool {
+        self.fixed_width
+    }
+
+    /// Follow every record with a CRC-32 of its bytes, flagged in the header, so
+    /// a decoder fails with `ChecksumMismatch` on a corrupted record instead of
+    /// returning a garbage trade. Costs 4 bytes per record. Can't be combined
+    /// with fixed-width records. Call before `write_header`.
+    pub fn with_checksum(mut self, enabled: bool) -> Self {
+        self.checksum = enabled;
+        self.sync_shadow();
+        self
+    }
+
+    /// Whether records carry a checksum; for a decoder, as flagged by the header.
+    pub fn has_checksum(&self) -> bool {
+        self.checksum
+    }
+
+    /// Encode each trade's quantity as a signed delta against the asset's
+    /// previous one, flagged in the header. Smaller for assets that keep trading
+    /// the same sizes, larger for ones whose sizes jump around; absolute is the
+    /// default. Can't be combined with fixed-width records. Call before
+    /// `write_header`.
+    pub fn with_quantity_deltas(mut self, enabled: bool) -> Self {
+        self.quantity_deltas = enabled;
+        self.sync_shadow();
+        self
+    }
+
+    /// Whether trade quantities are deltas; for a decoder, as flagged by the header.
+    pub fn quantity_deltas(&self) -> bool {
+        self.quantity_deltas
+    }
+
+    /// Write headers from before `HEADER_MAGIC` (v1 to v3), eg: to serve a
+    /// consumer built before it. Reading doesn't need it: a v1 to v3 header
+    /// starts with its version, which can't be taken for the magic, so old
+    /// recordings are always read. Off by default. Call before `write_header`.
+    pub fn with_legacy_headers(mut self, enabled: bool) -> Self {
+        self.legacy_headers = enabled;
+        self.sync_shadow();
+        self
+    }
+
+    /// Whether headers from before `HEADER_MAGIC` are written.
+    pub fn legacy_headers(&self) -> bool {
+        self.legacy_headers
+    }
+
+    /// Write a v5 header, which states its total byte length right after the
+    /// version, so a tool reading a recording can skip to the first record
+    /// without parsing the header (`header_len`). On by default; off, the v4
+    /// header is written. Consumers that predate v5 reject it; a TCP server
+    /// negotiating versions rewrites it for them. Legacy headers can't state
+    /// their length and ignore this. Call before `write_header`.
+    pub fn with_header_length(mut self, enabled: bool) -> Self {
+        self.header_length = enabled;
+        self.sync_shadow();
+        self
+    }
+
+    /// Whether the header states its length; for a decoder, whether it was v5.
+    pub fn has_header_length(&self) -> bool {
+        self.header_length
+    }
+
+    /// Byte length of the header starting at `cursor`'s position, which is left
+    /// where it was, eg: to seek from the start of a recording's header to its
+    /// first record. A v5 header's length is read straight off it; an older
+    /// header is parsed by a scratch decoder to find where it ends.
+    pub fn header_len<T: AsRef<[u8]>>(cursor: &Cursor<T>) -> Result<usize, BinaryFormatError> {
+        let data = cursor.get_ref().as_ref();
+        let header = data.get(cursor.position() as usize..).unwrap_or_default();
+        if let Some([VERSION_V5, a, b, c, d, ..]) = header.strip_prefix(&HEADER_MAGIC[..]) {
+            let len = u32::from_le_bytes([*a, *b, *c, *d]) as usize;
+            // Shorter than the magic, the version and the length themselves
+            if len < HEADER_MAGIC.len() + 1 + 4 {
+                return Err(BinaryFormatError::InvalidHeaderLength);
+            }
+            return Ok(len);
+        }
+        let mut rest = header;
+        BinaryFormat::new().read_header_slice(&mut rest)?;
+        Ok(header.len() - rest.len())
+    }
+
+    /// Debug mode: every encoded record is decoded again by a shadow decoder and
+    /// compared against the input, failing with `SelfCheckFailed` on a mismatch.
+    /// Off by default; when off the encode path only pays for an `Option` check.
+    pub fn with_self_check(mut self, enabled: bool) -> Self {
+        self.shadow = enabled.then(|| Box::new(BinaryFormat::new()));
+        self.sync_shadow();
+        self
+    }
+
+    /// Bound on how far a decoded price can be from the encoded one. The encoder
+    /// deltas against the price a decoder reconstructs, not the exact previous
+    /// price, and rounds, so the error stays within half a step instead of
+    /// building up. This is the whole step, which leaves room for f64 rounding.
+    /// With per-asset scales this is the coarsest asset's step.
+    pub fn price_resolution(&self) -> f64 {
+        let coarsest = self.scales.iter().copied().reduce(f64::min);
+        1.0 / coarsest.unwrap_or(DEFAULT_SCALE)
+    }
+
+    /// Fixed-point scale of `symbol`'s price: as given to the builder for an
+    /// encoder, as declared by the header for a decoder.
+    pub fn asset_scale(&self, symbol: &str) -> Option<f64> {
+        self.scales
+            .get(self.checked_id(symbol).ok()? as usize)
+            .copied()
+    }
+
+    /// Fixed-point scale of `symbol`'s quantity: the asset's price scale unless
+    /// `with_quantity_scale` or the header set one.
+    pub fn asset_quantity_scale(&self, symbol: &str) -> Option<f64> {
+        let id = self.checked_id(symbol).ok()?;
+        (usize::from(id) < self.scales.len()).then(|| self.quantity_scale_of(id.into()))
+    }
+
+    fn quantity_scale_of(&self, asset_id: usize) -> f64 {
+        self.quantity_scale.unwrap_or(self.scales[asset_id])
+    }
+
+    /// Bound on how far a decoded quantity can be from the encoded one.
+    pub fn quantity_resolution(&self) -> f64 {
+        match self.quantity_scale {
+            Some(scale) => 1.0 / scale,
+            None => self.price_resolution(),
+        }
+    }
+
+    // Reset the shadow decoder to the state a consumer has right after the header.
+    fn sync_shadow(&mut self) {
+        if self.shadow.is_none() {
+            return;
+        }
+        let mut shadow = self.clone();
+        shadow.shadow = None;
+        self.shadow = Some(Box::new(shadow));
+    }
+
     pub fn write_header(
         &mut self,
         buffer: &mut Vec<u8>,
@@ -179,11 +824,72 @@ impl BinaryFormat {
         reference_prices: &[f64],
         reference_quantities: &[f64],
     ) -> Result<(), BinaryFormatError> {
+        let mut record_flags = 0;
+        if self.length_prefixed {
+            record_flags |= RECORD_FLAG_LENGTH_PREFIXED;
+        }
+        if self.fixed_width {
+            record_flags |= RECORD_FLAG_FIXED_WIDTH;
+        }
+        if self.checksum {
+            record_flags |= RECORD_FLAG_CHECKSUM;
+        }
+        if self.quantity_deltas {
+            record_flags |= RECORD_FLAG_QUANTITY_DELTA;
+        }
+        if !Self::valid_record_flags(record_flags) {
+            return Err(BinaryFormatError::UnsupportedRecordFlags(record_flags));
+        }
+        let wide = self.assets.len() > MAX_PACKED_ASSETS;
+        if wide && self.fixed_width {
+            return Err(Self::fixed_width_too_many_assets());
+        }
+        // `with_scale` takes anything; a zero or NaN scale only shows up here
+        if let Some(scale) = (self.scales.iter())
+            .chain(&self.quantity_scale)
+            .find(|scale| !(scale.is_finite() && **scale > 0.0))
+        {
+            return Err(BinaryFormatError::InvalidAssetSpec(format!(
+                "scale {}",
+                scale
+            )));
+        }
+        let default_scales = self.scales.iter().all(|&scale| scale == DEFAULT_SCALE);
+        self.version = if !self.legacy_headers && self.header_length {
+            VERSION_V5
+        } else if !self.legacy_headers {
+            VERSION_V4
+        } else if wide {
+            VERSION_V3
+        } else if default_scales
+            && self.quantity_scale.is_none()
+            && self.field_scales.is_empty()
+            && self.timestamp_unit == TimestampUnit::Millis
+            && record_flags == 0
+        {
+            VERSION_V1
+        } else {
+            VERSION_V2
+        };
+        let start = buffer.len();
+        if self.version >= VERSION_V4 {
+            buffer.write_all(&HEADER_MAGIC)?;
+        }
         buffer.write_all(&[self.version])?;
-        buffer.write_all(&[self.assets.len() as u8])?;
+        if self.version == VERSION_V5 {
+            // Filled in once the rest of the header is written
+            buffer.write_all(&[0; 4])?;
+        }
+        if self.version >= VERSION_V3 {
+            buffer.write_all(&(self.assets.len() as u16).to_le_bytes())?;
+        } else {
+            buffer.write_all(&[self.assets.len() as u8])?;
+        }
 
         for asset in &self.assets {
-            buffer.write_all(&[asset.len() as u8])?;
+            // `with_assets` rejects these, but a truncated length corrupts the header
+            let len = u8::try_from(asset.len()).map_err(|_| Self::symbol_too_long(asset))?;
+            buffer.write_all(&[len])?;
             buffer.write_all(asset.as_bytes())?;
         }
 
@@ -197,6 +903,54 @@ impl BinaryFormat {
             buffer.write_all(&qty.to_le_bytes())?;
         }
 
+        if self.version >= VERSION_V2 {
+            let mut extensions = Vec::new();
+            if !default_scales {
+                let mut scales = Vec::with_capacity(8 * self.scales.len());
+                for scale in &self.scales {
+                    scales.write_all(&scale.to_le_bytes())?;
+                }
+                extensions.push((EXT_ASSET_SCALES, scales));
+            }
+            if !self.field_scales.is_empty() {
+                // Sorted so the header bytes don't depend on hash order
+                let mut fields: Vec<_> = self
+                    .field_scales
+                    .iter()
+                    .map(|(field, scale)| (field.key(), *scale))
+                    .collect();
+                fields.sort_by_key(|(key, _)| *key);
+                let mut payload = vec![fields.len() as u8];
+                for ((kind, field), scale) in fields {
+                    payload.write_all(&[kind, field])?;
+                    payload.write_all(&scale.to_le_bytes())?;
+                }
+                extensions.push((EXT_FIELD_SCALES, payload));
+            }
+            if self.timestamp_unit != TimestampUnit::Millis {
+                extensions.push((EXT_TIMESTAMP_UNIT, vec![self.timestamp_unit.code()]));
+            }
+            if record_flags != 0 {
+                extensions.push((EXT_RECORD_FLAGS, vec![record_flags]));
+            }
+            if let Some(scale) = self.quantity_scale {
+                extensions.push((EXT_QUANTITY_SCALE, scale.to_le_bytes().to_vec()));
+            }
+            buffer.write_all(&[extensions.len() as u8])?;
+            for (tag, payload) in extensions {
+                buffer.write_all(&[tag])?;
+                varint::encode_unsigned(payload.len() as u64, buffer)?;
+                buffer.write_all(&payload)?;
+            }
+        }
+
+        if self.version == VERSION_V5 {
+            let len = u32::try_from(buffer.len() - start)
+                .map_err(|_| BinaryFormatError::InvalidHeaderLength)?;
+            let at = start + HEADER_MAGIC.len() + 1;
+            buffer[at..at + 4].copy_from_slice(&len.to_le_bytes());
+        }
+
         self.states = reference_prices
             .iter()
             .zip(reference_quantities)
@@ -204,22 +958,134 @@ impl BinaryFormat {
                 last_timestamp: reference_timestamp,
                 last_price: *p,
                 last_quantity: *q,
+                last_is_buyer_maker: None,
+                decoded: 0,
+                price_moved: 0.0,
             })
             .collect();
+        self.sync_shadow();
 
         Ok(())
     }
 
     pub fn read_header(&mut self, cursor: &mut Cursor<&Vec<u8>>) -> Result<(), BinaryFormatError> {
-        let mut version = [0u8];
-        cursor.read_exact(&mut version)?;
-        if version[0] != self.version {
-            return Err(BinaryFormatError::InvalidVersion(version[0]));
+        self.read_header_from(cursor)
+    }
+
+    /// A decoder set up from `frame` if the frame is a whole header, for streams
+    /// where a header doesn't follow a START (eg: a recording, or a producer
+    /// re-heading without one).
+    ///
+    /// The rule: the frame starts with `HEADER_MAGIC` then a header version, is
+    /// at least `MIN_HEADER_LEN` bytes, a fresh decoder reads it as a header
+    /// with no bytes left over, and every symbol is non-empty. Control records
+    /// start with `CONTROL_ID`, never the magic. A trade of the magic's asset id
+    /// starts with its first byte, but would also need its deltas to spell out
+    /// the rest of it and then fit a header's layout to the byte. The byte
+    /// checks come first, so most records are ruled out without parsing.
+    /// Headers from before the magic are never taken for one.
+    pub fn from_header_frame(frame: &[u8]) -> Option<BinaryFormat> {
+        Self::header_frame(frame, false)
+    }
+
+    /// `from_header_frame` for a consumer of headers from before `HEADER_MAGIC`,
+    /// which may also start with a v1 to v3 version byte. Control records start
+    /// with `CONTROL_ID`, never a version. A trade of asset 1 to 3 (or a record
+    /// with a 1 to 3 byte length prefix) starts with one, but is shorter than
+    /// any header unless its deltas run to several varint bytes each, and would
+    /// still have to fit a header's layout to the byte.
+    pub fn from_legacy_header_frame(frame: &[u8]) -> Option<BinaryFormat> {
+        Self::header_frame(frame, true)
+    }
+
+    /// `from_header_frame` for a stream without frames (eg: a `RawLog` `.bin`),
+    /// where records rather than the end of a frame follow the header: the same
+    /// rule, bar the bytes left over. `data` is advanced past the header if it
+    /// starts with one, and left alone otherwise.
+    pub fn from_header_prefix(data: &mut &[u8]) -> Option<BinaryFormat> {
+        Self::header_prefix(data, false)
+    }
+
+    fn header_frame(frame: &[u8], legacy_headers: bool) -> Option<BinaryFormat> {
+        let mut rest = frame;
+        let decoder = Self::header_prefix(&mut rest, legacy_headers)?;
+        rest.is_empty().then_some(decoder)
+    }
+
+    fn header_prefix(data: &mut &[u8], legacy_headers: bool) -> Option<BinaryFormat> {
+        let version = match data.strip_prefix(&HEADER_MAGIC[..]) {
+            Some(rest) => rest.first()?,
+            None if legacy_headers => data.first()?,
+            None => return None,
+        };
+        if !SUPPORTED_VERSIONS.contains(version) || data.len() < MIN_HEADER_LEN {
+            return None;
+        }
+        let mut decoder = BinaryFormat::new();
+        let mut rest = *data;
+        decoder.read_header_slice(&mut rest).ok()?;
+        if decoder.assets.iter().any(String::is_empty) {
+            return None;
+        }
+        *data = rest;
+        Some(decoder)
+    }
+
+    /// `read_header` straight from a borrowed buffer, eg: a memory-mapped file,
+    /// advancing `data` past the header.
+    pub fn read_header_slice(&mut self, data: &mut &[u8]) -> Result<(), BinaryFormatError> {
+        self.read_header_from(data)
+    }
+
+    fn read_header_from(&mut self, reader: &mut impl Read) -> Result<(), BinaryFormatError> {
+        let cursor = &mut Counting { reader, count: 0 };
+        let mut first = [0u8];
+        cursor.read_exact(&mut first)?;
+        let version = if first[0] == HEADER_MAGIC[0] {
+            let mut magic = HEADER_MAGIC;
+            cursor.read_exact(&mut magic[1..])?;
+            if magic != HEADER_MAGIC {
+                return Err(BinaryFormatError::BadMagic(magic.to_vec()));
+            }
+            let mut version = [0u8];
+            cursor.read_exact(&mut version)?;
+            if version[0] < VERSION_V4 {
+                return Err(BinaryFormatError::InvalidHeader(format!(
+                    "v{} header after the magic, which only v4 on carry",
+                    version[0]
+                )));
+            }
+            version[0]
+        } else if (VERSION_V1..VERSION_V4).contains(&first[0]) {
+            first[0]
+        } else {
+            // Every newer version starts with the magic, so this is zeroed
+            // memory or garbage rather than a stream from a newer producer
+            return Err(BinaryFormatError::BadMagic(first.to_vec()));
+        };
+        if !SUPPORTED_VERSIONS.contains(&version) {
+            return Err(BinaryFormatError::InvalidVersion(version));
         }
+        let declared_len = if version == VERSION_V5 {
+            let mut len = [0u8; 4];
+            cursor.read_exact(&mut len)?;
+            Some(u32::from_le_bytes(len) as usize)
+        } else {
+            None
+        };
 
-        let mut asset_count = [0u8];
-        cursor.read_exact(&mut asset_count)?;
-        let asset_count = asset_count[0] as usize;
+        let asset_count = if version >= VERSION_V3 {
+            let mut asset_count = [0u8; 2];
+            cursor.read_exact(&mut asset_count)?;
+            u16::from_le_bytes(asset_count) as usize
+        } else {
+            let mut asset_count = [0u8];
+            cursor.read_exact(&mut asset_count)?;
+            asset_count[0] as usize
+        };
+        if version < VERSION_V3 && asset_count > MAX_PACKED_ASSETS {
+            return Err(BinaryFormatError::TooManyAssets);
+        }
 
         let mut assets = Vec::with_capacity(asset_count);
         for _ in 0..asset_count {
@@ -250,8 +1116,109 @@ impl BinaryFormat {
             cursor.read_exact(&mut qty_bytes)?;
             reference_quantities.push(f64::from_le_bytes(qty_bytes));
         }
+        // A NaN reference would turn every price decoded from it into NaN
+        for (field, values) in [
+            ("price", &reference_prices),
+            ("quantity", &reference_quantities),
+        ] {
+            if let Some((symbol, value)) = assets
+                .iter()
+                .zip(values)
+                .find(|(_, value)| !value.is_finite() || **value < 0.0)
+            {
+                return Err(BinaryFormatError::InvalidHeader(format!(
+                    "reference {} {} for {}",
+                    field, value, symbol
+                )));
+            }
+        }
+
+        // A header without `EXT_ASSET_SCALES` declares `DEFAULT_SCALE` for every
+        // asset; records are only ever decoded at the header's scales
+        let mut scales = vec![DEFAULT_SCALE; asset_count];
+        let mut quantity_scale = None;
+        let mut field_scales = HashMap::new();
+        let mut timestamp_unit = TimestampUnit::Millis;
+        let mut length_prefixed = false;
+        let mut fixed_width = false;
+        let mut checksum = false;
+        let mut quantity_deltas = false;
+        if version >= VERSION_V2 {
+            let mut ext_count = [0u8];
+            cursor.read_exact(&mut ext_count)?;
+            for _ in 0..ext_count[0] {
+                let mut tag = [0u8];
+                cursor.read_exact(&mut tag)?;
+                let len = varint::decode_unsigned(cursor)?;
+                if len > MAX_EXTENSION_LEN {
+                    return Err(BinaryFormatError::InvalidHeader(format!(
+                        "extension 0x{:02x} is {} bytes, at most {} expected",
+                        tag[0], len, MAX_EXTENSION_LEN
+                    )));
+                }
+                let mut payload = Vec::new();
+                read_len(cursor, len, &mut payload)?;
+                match tag[0] {
+                    EXT_ASSET_SCALES => scales = Self::read_scales(&payload, asset_count)?,
+                    EXT_FIELD_SCALES => field_scales = Self::read_field_scales(&payload)?,
+                    // Not skippable: deltas read in the wrong unit are off by 1000x
+                    EXT_TIMESTAMP_UNIT => match payload[..] {
+                        [code] => timestamp_unit = TimestampUnit::from_code(code)?,
+                        _ => return Err(BinaryFormatError::InvalidHeaderLength),
+                    },
+                    // Not skippable either: flags change how records are laid out
+                    EXT_RECORD_FLAGS => match payload[..] {
+                        [flags] if Self::valid_record_flags(flags) => {
+                            length_prefixed = flags & RECORD_FLAG_LENGTH_PREFIXED != 0;
+                            fixed_width = flags & RECORD_FLAG_FIXED_WIDTH != 0;
+                            checksum = flags & RECORD_FLAG_CHECKSUM != 0;
+                            quantity_deltas = flags & RECORD_FLAG_QUANTITY_DELTA != 0;
+                        }
+                        [flags] => return Err(BinaryFormatError::UnsupportedRecordFlags(flags)),
+                        _ => return Err(BinaryFormatError::InvalidHeaderLength),
+                    },
+                    EXT_QUANTITY_SCALE => {
+                        let scale = f64::from_le_bytes(
+                            payload[..]
+                                .try_into()
+                                .map_err(|_| BinaryFormatError::InvalidHeaderLength)?,
+                        );
+                        if !(scale.is_finite() && scale > 0.0) {
+                            return Err(BinaryFormatError::InvalidAssetSpec(format!(
+                                "quantity scale {} in header",
+                                scale
+                            )));
+                        }
+                        quantity_scale = Some(scale);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if fixed_width && asset_count > MAX_PACKED_ASSETS {
+            return Err(Self::fixed_width_too_many_assets());
+        }
+        // Readers skipping by the stated length would land mid-header or past it
+        if declared_len.is_some_and(|len| len != cursor.count) {
+            return Err(BinaryFormatError::InvalidHeaderLength);
+        }
 
         // Initialize the states and assets
+        self.version = version;
+        self.header_length = version == VERSION_V5;
+        self.asset_to_id = assets
+            .iter()
+            .enumerate()
+            .map(|(idx, asset)| (asset.clone(), idx as u16))
+            .collect();
+        self.scales = scales;
+        self.quantity_scale = quantity_scale;
+        self.field_scales = field_scales;
+        self.timestamp_unit = timestamp_unit;
+        self.length_prefixed = length_prefixed;
+        self.fixed_width = fixed_width;
+        self.checksum = checksum;
+        self.quantity_deltas = quantity_deltas;
         self.assets = assets;
         self.states = reference_prices
             .iter()
@@ -260,15 +1227,86 @@ impl BinaryFormat {
                 last_timestamp: reference_timestamp,
                 last_price: price,
                 last_quantity: qty,
+                last_is_buyer_maker: None,
+                decoded: 0,
+                price_moved: 0.0,
             })
             .collect();
         Ok(())
     }
 
+    fn symbol_too_long(symbol: &str) -> BinaryFormatError {
+        BinaryFormatError::InvalidSymbol(format!(
+            "{}... is {} bytes, the header holds at most {}",
+            &symbol[..symbol.floor_char_boundary(16)],
+            symbol.len(),
+            MAX_SYMBOL_LEN
+        ))
+    }
+
+    /// Known bits only, with fixed-width records on their own: they have no room
+    /// for a length prefix or a checksum, and no deltas.
+    fn valid_record_flags(flags: u8) -> bool {
+        flags & !RECORD_FLAGS_KNOWN == 0
+            && (flags & RECORD_FLAG_FIXED_WIDTH == 0 || flags == RECORD_FLAG_FIXED_WIDTH)
+    }
+
+    fn fixed_width_too_many_assets() -> BinaryFormatError {
+        BinaryFormatError::InvalidHeader(format!(
+            "fixed-width records address at most {} assets",
+            MAX_PACKED_ASSETS
+        ))
+    }
+
+    fn read_scales(payload: &[u8], asset_count: usize) -> Result<Vec<f64>, BinaryFormatError> {
+        if payload.len() != 8 * asset_count {
+            return Err(BinaryFormatError::InvalidHeaderLength);
+        }
+        payload
+            .chunks_exact(8)
+            .map(|chunk| {
+                let scale = f64::from_le_bytes(chunk.try_into().unwrap());
+                if scale.is_finite() && scale > 0.0 {
+                    Ok(scale)
+                } else {
+                    Err(BinaryFormatError::InvalidAssetSpec(format!(
+                        "scale {} in header",
+                        scale
+                    )))
+                }
+            })
+            .collect()
+    }
+
+    /// `EXT_FIELD_SCALES` payload: u8 count, then (u8 kind, u8 field, f64 scale)
+    /// per entry. Fields this decoder doesn't know are skipped.
+    fn read_field_scales(payload: &[u8]) -> Result<HashMap<ScaledField, f64>, BinaryFormatError> {
+        let Some((&count, entries)) = payload.split_first() else {
+            return Err(BinaryFormatError::InvalidHeaderLength);
+        };
+        if entries.len() != 10 * count as usize {
+            return Err(BinaryFormatError::InvalidHeaderLength);
+        }
+        let mut field_scales = HashMap::new();
+        for entry in entries.chunks_exact(10) {
+            let scale = f64::from_le_bytes(entry[2..].try_into().unwrap());
+            if !(scale.is_finite() && scale > 0.0) {
+                return Err(BinaryFormatError::InvalidAssetSpec(format!(
+                    "field scale {} in header",
+                    scale
+                )));
+            }
+            if let Some(field) = ScaledField::from_key((entry[0], entry[1])) {
+                field_scales.insert(field, scale);
+            }
+        }
+        Ok(field_scales)
+    }
+
     pub fn encode(&mut self, trade: &Trade) -> Result<Vec<u8>, BinaryFormatError> {
         let mut buffer = Vec::with_capacity(64);
         // Why did i set it to 64?
-        // 
+        //
         // Symbol:
         // Maximum of 32 bytes (including UTF-8 data and length byte, if the symbol length is up to 31 characters).
         // Timestamp:
@@ -283,85 +1321,972 @@ impl BinaryFormat {
         Ok(buffer)
     }
 
-    pub fn decode(&mut self, data: &Vec<u8>) -> Result<Trade, BinaryFormatError> {
-        let mut cursor = Cursor::new(data);
-        self.read_message(&mut cursor)
-    }
-
-    pub fn write_message(
+    /// `encode`, appending to `buffer` instead of allocating, eg: a buffer reused
+    /// across trades.
+    pub fn encode_into(
         &mut self,
         trade: &Trade,
         buffer: &mut Vec<u8>,
     ) -> Result<(), BinaryFormatError> {
-        let asset_id = *self
-            .asset_to_id
-            .get(&trade.symbol)
-            .ok_or_else(|| BinaryFormatError::InvalidSymbol(trade.symbol.clone()))?;
-
-        let packed_byte = if trade.is_buyer_maker {
-            asset_id | 0x80
-        } else {
-            asset_id & 0x7F
-        };
+        self.write_message(trade, buffer)
+    }
 
-        buffer.write_all(&[packed_byte])?;
+    /// Encode `trades` back to back into one buffer, the same bytes and delta
+    /// state updates as encoding them one at a time. All or nothing: on an
+    /// error every asset's state is left as it was before the batch.
+    pub fn encode_batch(&mut self, trades: &[Trade]) -> Result<Vec<u8>, BinaryFormatError> {
+        // Trades of a live feed take 4-8 bytes each
+        let mut buffer = Vec::with_capacity(8 * trades.len());
+        let saved = self.states.clone();
+        let shadow_saved = self.shadow.as_ref().map(|shadow| shadow.states.clone());
+        for trade in trades {
+            if let Err(e) = self.write_message(trade, &mut buffer) {
+                self.states = saved;
+                if let (Some(shadow), Some(states)) = (self.shadow.as_mut(), shadow_saved) {
+                    shadow.states = states;
+                }
+                return Err(e);
+            }
+        }
+        Ok(buffer)
+    }
 
-        let state = &mut self.states[asset_id as usize & 0x7F];
+    /// Encode `trade` as a keyframe carrying absolute values.
+    pub fn encode_keyframe(&mut self, trade: &Trade) -> Result<Vec<u8>, BinaryFormatError> {
+        let mut buffer = Vec::with_capacity(32);
+        self.write_keyframe(trade, &mut buffer)?;
+        Ok(buffer)
+    }
 
-        let ts_delta = (trade.timestamp as i64)
-            .checked_sub(state.last_timestamp as i64)
-            .ok_or(BinaryFormatError::Overflow)?;
+    /// Encode a heartbeat stamped with `timestamp`, in the stream's unit. Delta
+    /// state is untouched.
+    pub fn encode_heartbeat(&self, timestamp: u64) -> Result<Vec<u8>, BinaryFormatError> {
+        if self.fixed_width {
+            let mut buffer = Vec::with_capacity(FIXED_RECORD_LEN);
+            Self::write_fixed(CONTROL_ID, KIND_HEARTBEAT, timestamp, 0, 0, &mut buffer)?;
+            return Ok(buffer);
+        }
+        let mut buffer = Vec::with_capacity(12);
+        Self::write_control(KIND_HEARTBEAT, &timestamp.to_le_bytes(), &mut buffer)?;
+        self.finish_record(&mut buffer, 0)?;
+        Ok(buffer)
+    }
 
-        varint::encode_signed(ts_delta, buffer)?;
+    /// Encode a funding rate for `symbol`: packed asset id, timestamp (u64 LE),
+    /// then the rate as a signed varint at the `FundingRate` field scale, rounded
+    /// to nearest. Delta state is untouched.
+    pub fn encode_funding(
+        &self,
+        symbol: &str,
+        timestamp: u64,
+        rate: f64,
+    ) -> Result<Vec<u8>, BinaryFormatError> {
+        let asset_id = self.checked_id(symbol)?;
+        let fixed = to_fixed(rate, self.field_scale(ScaledField::FundingRate))?;
+        if self.fixed_width {
+            let mut buffer = Vec::with_capacity(FIXED_RECORD_LEN);
+            let packed_byte = Self::packed_byte(asset_id, false);
+            Self::write_fixed(packed_byte, KIND_FUNDING, timestamp, fixed, 0, &mut buffer)?;
+            return Ok(buffer);
+        }
 
-        let price_delta = ((trade.price - state.last_price) * SCALE_FACTOR) as i64;
-        varint::encode_signed(price_delta, buffer)?;
+        let mut payload = Vec::with_capacity(19);
+        self.write_asset(asset_id, false, &mut payload)?;
+        payload.write_all(&timestamp.to_le_bytes())?;
+        varint::encode_signed(fixed, &mut payload)?;
+        let mut buffer = Vec::with_capacity(22);
+        Self::write_control(KIND_FUNDING, &payload, &mut buffer)?;
+        self.finish_record(&mut buffer, 0)?;
+        Ok(buffer)
+    }
 
-        let qty_fixed = (trade.quantity * SCALE_FACTOR) as u64;
-        varint::encode_unsigned(qty_fixed, buffer)?;
+    /// Encode a gap of `dropped` trades for `symbol`: asset id, then the count
+    /// as an unsigned varint. Delta state is untouched; follow it with a keyframe.
+    pub fn encode_gap(&self, symbol: &str, dropped: u64) -> Result<Vec<u8>, BinaryFormatError> {
+        let asset_id = self.checked_id(symbol)?;
+        if self.fixed_width {
+            let mut buffer = Vec::with_capacity(FIXED_RECORD_LEN);
+            let packed_byte = Self::packed_byte(asset_id, false);
+            Self::write_fixed(packed_byte, KIND_GAP, 0, 0, dropped, &mut buffer)?;
+            return Ok(buffer);
+        }
+        let mut payload = Vec::with_capacity(11);
+        self.write_asset(asset_id, false, &mut payload)?;
+        varint::encode_unsigned(dropped, &mut payload)?;
+        let mut buffer = Vec::with_capacity(14);
+        Self::write_control(KIND_GAP, &payload, &mut buffer)?;
+        self.finish_record(&mut buffer, 0)?;
+        Ok(buffer)
+    }
+
+    /// Re-seat every asset on fresh reference values, eg: current mid prices, and
+    /// encode the reset record that does the same for a decoder. Meant for
+    /// periodic resyncs: a consumer that joined late or lost frames is back on
+    /// absolute prices from here, without a new header. `prices` and
+    /// `quantities` are in asset id order, like `write_header`'s.
+    ///
+    /// Unlike other control records a reset can't be skipped: deltas after it are
+    /// taken against its values, so only send it to consumers that know the kind.
+    /// Fixed-width records carry absolute values already and have no reset.
+    pub fn reset_state(
+        &mut self,
+        timestamp: u64,
+        prices: &[f64],
+        quantities: &[f64],
+    ) -> Result<Vec<u8>, BinaryFormatError> {
+        if self.fixed_width {
+            return Err(BinaryFormatError::UnsupportedRecordFlags(
+                RECORD_FLAG_FIXED_WIDTH,
+            ));
+        }
+        if prices.len() != self.assets.len() || quantities.len() != self.assets.len() {
+            return Err(BinaryFormatError::InvalidAssetSpec(format!(
+                "reset needs {} prices and quantities, got {} and {}",
+                self.assets.len(),
+                prices.len(),
+                quantities.len()
+            )));
+        }
+
+        let mut payload = Vec::with_capacity(10 + 16 * prices.len());
+        payload.write_all(&timestamp.to_le_bytes())?;
+        varint::encode_unsigned(prices.len() as u64, &mut payload)?;
+        for (price, quantity) in prices.iter().zip(quantities) {
+            payload.write_all(&price.to_le_bytes())?;
+            payload.write_all(&quantity.to_le_bytes())?;
+        }
+        let mut buffer = Vec::with_capacity(payload.len() + 4);
+        Self::write_control(KIND_RESET, &payload, &mut buffer)?;
+        self.finish_record(&mut buffer, 0)?;
+
+        // Decoding it re-seats the encoder exactly as it will the consumer's
+        // decoder, and validates the values on the way
+        self.read_record_from(&mut Cursor::new(&buffer))?;
+        if let Some(shadow) = self.shadow.as_mut() {
+            shadow.read_record_from(&mut Cursor::new(&buffer))?;
+        }
+        Ok(buffer)
+    }
+
+    pub fn decode(&mut self, data: &Vec<u8>) -> Result<Trade, BinaryFormatError> {
+        let mut cursor = Cursor::new(data);
+        self.read_message(&mut cursor)
+    }
+
+    /// Wire id of `symbol`: its position in the asset list given to `with_assets`,
+    /// or in the header for a decoder. Encoder and decoder agree on ids only
+    /// because the decoder takes its list from the encoder's header.
+    pub fn asset_id(&self, symbol: &str) -> Option<u16> {
+        self.checked_id(symbol).ok()
+    }
+
+    /// Symbol behind a wire id, the inverse of `asset_id`.
+    pub fn symbol_for_id(&self, id: u16) -> Option<&str> {
+        self.assets.get(id as usize).map(String::as_str)
+    }
+
+    /// Every asset, in wire id order.
+    pub fn symbols(&self) -> &[String] {
+        &self.assets
+    }
+
+    /// Price the next delta of `symbol` is taken against: its latest trade's,
+    /// or the header's reference price before the first trade.
+    pub fn last_price(&self, symbol: &str) -> Option<f64> {
+        let state = self.states.get(self.checked_id(symbol).ok()? as usize)?;
+        Some(state.last_price)
+    }
+
+    /// Decode statistics for every asset, in header order. Counts restart when a
+    /// new header is read.
+    pub fn asset_stats(&self) -> Vec<AssetStats> {
+        self.assets
+            .iter()
+            .zip(&self.states)
+            .map(|(symbol, state)| AssetStats {
+                symbol: symbol.clone(),
+                decoded: state.decoded,
+                last_timestamp: state.last_timestamp,
+                last_price: state.last_price,
+                last_quantity: state.last_quantity,
+                price_moved: state.price_moved,
+            })
+            .collect()
+    }
+
+    /// Latest trade of `symbol` since the header, with price and quantity as a
+    /// decoder reconstructs them. A keyframe of it brings a fresh decoder (set up
+    /// from the same header) to this one's state for that asset.
+    pub fn last_trade(&self, symbol: &str) -> Option<Trade> {
+        let state = self.states.get(self.checked_id(symbol).ok()? as usize)?;
+        Some(Trade {
+            symbol: symbol.to_string(),
+            timestamp: state.last_timestamp,
+            price: state.last_price,
+            quantity: state.last_quantity,
+            is_buyer_maker: state.last_is_buyer_maker?,
+        })
+    }
+
+    fn checked_id(&self, symbol: &str) -> Result<u16, BinaryFormatError> {
+        // Single-asset fast path: a string compare instead of hashing the symbol
+        if let [only] = self.assets.as_slice() {
+            return if only == symbol {
+                Ok(0)
+            } else {
+                Err(BinaryFormatError::InvalidSymbol(symbol.to_string()))
+            };
+        }
+        self.asset_to_id
+            .get(symbol)
+            .copied()
+            .ok_or_else(|| BinaryFormatError::InvalidSymbol(symbol.to_string()))
+    }
+
+    /// Packed byte of an asset that fits in it: id in bits 0-6, maker flag in bit 7.
+    fn packed_byte(asset_id: u16, is_buyer_maker: bool) -> u8 {
+        if is_buyer_maker {
+            asset_id as u8 | 0x80
+        } else {
+            asset_id as u8 & 0x7F
+        }
+    }
+
+    /// Whether asset ids past `WIDE_ID_ESCAPE` are escaped: in a v3 stream, or
+    /// in an encoder with more assets than a v1/v2 header holds.
+    fn wide_ids(&self) -> bool {
+        self.version == VERSION_V3 || self.assets.len() > MAX_PACKED_ASSETS
+    }
+
+    /// The packed byte of `asset_id`, followed by the rest of a wide id.
+    fn write_asset(
+        &self,
+        asset_id: u16,
+        is_buyer_maker: bool,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), BinaryFormatError> {
+        let escape = WIDE_ID_ESCAPE as u16;
+        if asset_id < escape || !self.wide_ids() {
+            buffer.write_all(&[Self::packed_byte(asset_id, is_buyer_maker)])?;
+            return Ok(());
+        }
+        buffer.write_all(&[Self::packed_byte(escape, is_buyer_maker)])?;
+        varint::encode_unsigned((asset_id - escape) as u64, buffer)?;
+        Ok(())
+    }
+
+    /// Control record: `CONTROL_ID`, kind byte, varint payload length, payload.
+    /// The length lets decoders step over the payload as a unit.
+    fn write_control(
+        kind: u8,
+        payload: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), BinaryFormatError> {
+        buffer.write_all(&[CONTROL_ID, kind])?;
+        varint::encode_unsigned(payload.len() as u64, buffer)?;
+        buffer.write_all(payload)?;
+        Ok(())
+    }
+
+    /// Fixed-width record: packed byte, kind, timestamp (u64 LE), then `value`
+    /// (i64 LE) and `amount` (u64 LE), whose meaning depends on the kind.
+    fn write_fixed(
+        packed_byte: u8,
+        kind: u8,
+        timestamp: u64,
+        value: i64,
+        amount: u64,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), BinaryFormatError> {
+        buffer.write_all(&[packed_byte, kind])?;
+        buffer.write_all(&timestamp.to_le_bytes())?;
+        buffer.write_all(&value.to_le_bytes())?;
+        buffer.write_all(&amount.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Fixed-width trade or keyframe: price and quantity at the asset's scale,
+    /// rounded to nearest. Absolute, so the asset's state is simply replaced.
+    fn write_fixed_trade(
+        &mut self,
+        kind: u8,
+        trade: &Trade,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), BinaryFormatError> {
+        let asset_id = self.checked_id(&trade.symbol)?;
+        let scale = self.scales[asset_id as usize];
+        let price = to_fixed(trade.price, scale)?;
+        let quantity_scale = self.quantity_scale_of(asset_id.into());
+        let quantity = u64::try_from(to_fixed(trade.quantity, quantity_scale)?)
+            .map_err(|_| BinaryFormatError::Overflow)?;
+        Self::write_fixed(
+            Self::packed_byte(asset_id, trade.is_buyer_maker),
+            kind,
+            trade.timestamp,
+            price,
+            quantity,
+            buffer,
+        )?;
+
+        let state = &mut self.states[asset_id as usize];
+        state.last_timestamp = trade.timestamp;
+        state.last_price = from_fixed(price, scale);
+        state.last_quantity = quantity as f64 / quantity_scale;
+        state.last_is_buyer_maker = Some(trade.is_buyer_maker);
+        Ok(())
+    }
+
+    /// Keyframe payload: packed asset id + maker byte, then timestamp (u64 LE),
+    /// price and quantity (f64 LE), all absolute. The decoder re-seats the asset's
+    /// state on these values, so subsequent deltas don't depend on anything the
+    /// consumer may have missed before it.
+    pub fn write_keyframe(
+        &mut self,
+        trade: &Trade,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), BinaryFormatError> {
+        self.checked_write(trade, buffer, Self::write_absolute)
+    }
+
+    pub fn write_message(
+        &mut self,
+        trade: &Trade,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), BinaryFormatError> {
+        self.checked_write(trade, buffer, Self::write_delta)
+    }
+
+    /// Run `write`, then replay its output through the shadow decoder if
+    /// self-check is on. A failed check rolls back the buffer and both states so
+    /// the encoder stays in step with what consumers have actually seen.
+    fn checked_write(
+        &mut self,
+        trade: &Trade,
+        buffer: &mut Vec<u8>,
+        write: fn(&mut Self, &Trade, &mut Vec<u8>) -> Result<(), BinaryFormatError>,
+    ) -> Result<(), BinaryFormatError> {
+        let start = buffer.len();
+        let Some(mut shadow) = self.shadow.take() else {
+            write(self, trade, buffer)?;
+            return self.finish_record(buffer, start);
+        };
+
+        let saved = (self.states.clone(), shadow.states.clone());
+        let result = write(self, trade, buffer)
+            .and_then(|_| self.finish_record(buffer, start))
+            .and_then(|_| {
+                let decoded = shadow.read_record_from(&mut Cursor::new(&buffer[start..]))?;
+                match decoded.into_trade() {
+                    Some(decoded) => self.compare(trade, &decoded),
+                    None => Err(BinaryFormatError::SelfCheckFailed(format!(
+                        "{} trade decoded as a non-trade record",
+                        trade.symbol
+                    ))),
+                }
+            });
+        if result.is_err() {
+            buffer.truncate(start);
+            self.states = saved.0;
+            shadow.states = saved.1;
+        }
+
+        self.shadow = Some(shadow);
+        result
+    }
+
+    /// Append the checksum of the record written at `buffer[start..]`, then put
+    /// its varint length in front of it, as the stream's record flags ask.
+    fn finish_record(&self, buffer: &mut Vec<u8>, start: usize) -> Result<(), BinaryFormatError> {
+        if self.checksum {
+            let crc = crc32(&buffer[start..]);
+            buffer.extend_from_slice(&crc.to_le_bytes());
+        }
+        if !self.length_prefixed {
+            return Ok(());
+        }
+        let mut prefix = Vec::with_capacity(2);
+        varint::encode_unsigned((buffer.len() - start) as u64, &mut prefix)?;
+        buffer.splice(start..start, prefix);
+        Ok(())
+    }
+
+    fn compare(&self, expected: &Trade, decoded: &Trade) -> Result<(), BinaryFormatError> {
+        // Written so a NaN on either side counts as a mismatch
+        let within = |a: f64, b: f64, resolution: f64| (a - b).abs() <= resolution;
+        let mismatch = if decoded.symbol != expected.symbol {
+            Some("symbol")
+        } else if decoded.timestamp != expected.timestamp {
+            Some("timestamp")
+        } else if decoded.is_buyer_maker != expected.is_buyer_maker {
+            Some("is_buyer_maker")
+        } else if !within(decoded.price, expected.price, self.price_resolution()) {
+            Some("price")
+        } else if !within(
+            decoded.quantity,
+            expected.quantity,
+            self.quantity_resolution(),
+        ) {
+            Some("quantity")
+        } else {
+            None
+        };
+
+        match mismatch {
+            Some(field) => Err(BinaryFormatError::SelfCheckFailed(format!(
+                "{} mismatch for {}: encoded {:?}, decoded {:?}",
+                field, expected.symbol, expected, decoded
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn write_absolute(
+        &mut self,
+        trade: &Trade,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), BinaryFormatError> {
+        if self.fixed_width {
+            return self.write_fixed_trade(KIND_KEYFRAME, trade, buffer);
+        }
+        let asset_id = self.checked_id(&trade.symbol)?;
 
+        let mut payload = Vec::with_capacity(25);
+        self.write_asset(asset_id, trade.is_buyer_maker, &mut payload)?;
+        payload.write_all(&trade.timestamp.to_le_bytes())?;
+        payload.write_all(&trade.price.to_le_bytes())?;
+        payload.write_all(&trade.quantity.to_le_bytes())?;
+        Self::write_control(KIND_KEYFRAME, &payload, buffer)?;
+
+        let state = &mut self.states[asset_id as usize];
         state.last_timestamp = trade.timestamp;
         state.last_price = trade.price;
         state.last_quantity = trade.quantity;
+        state.last_is_buyer_maker = Some(trade.is_buyer_maker);
+
+        Ok(())
+    }
+
+    fn write_delta(
+        &mut self,
+        trade: &Trade,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), BinaryFormatError> {
+        if self.fixed_width {
+            return self.write_fixed_trade(KIND_TRADE, trade, buffer);
+        }
+        let asset_id = self.checked_id(&trade.symbol)?;
+        let state = &self.states[asset_id as usize];
+
+        let ts_delta = (trade.timestamp as i64)
+            .checked_sub(state.last_timestamp as i64)
+            .ok_or(BinaryFormatError::Overflow)?;
+        if ts_delta.unsigned_abs() > MAX_PLAUSIBLE_TS_DELTA_MS * self.timestamp_unit.per_milli() {
+            tracing::warn!(
+                "implausible timestamp delta for {}: {} {}",
+                trade.symbol,
+                ts_delta,
+                self.timestamp_unit.suffix()
+            );
+        }
+
+        // Everything is range-checked before the first byte goes out, so an
+        // out-of-range feed value leaves neither the buffer nor the state half-written
+        let scale = self.scales[asset_id as usize];
+        let quantity_scale = self.quantity_scale_of(asset_id.into());
+        let price_delta = to_fixed(trade.price - state.last_price, scale)?;
+        let qty_fixed = u64::try_from(to_fixed(trade.quantity, quantity_scale)?)
+            .map_err(|_| BinaryFormatError::Overflow)?;
+        let qty_delta = if self.quantity_deltas {
+            let previous = previous_fixed(state.last_quantity, quantity_scale)?;
+            Some(
+                (qty_fixed as i64)
+                    .checked_sub(previous)
+                    .ok_or(BinaryFormatError::Overflow)?,
+            )
+        } else {
+            None
+        };
+
+        self.write_asset(asset_id, trade.is_buyer_maker, buffer)?;
+        varint::encode_signed(ts_delta, buffer)?;
+        varint::encode_signed(price_delta, buffer)?;
+        match qty_delta {
+            Some(qty_delta) => varint::encode_signed(qty_delta, buffer)?,
+            None => varint::encode_unsigned(qty_fixed, buffer)?,
+        };
+
+        let state = &mut self.states[asset_id as usize];
+        // Track what the decoder will reconstruct so quantization error doesn't accumulate
+        state.last_timestamp = trade.timestamp;
+        state.last_price += from_fixed(price_delta, scale);
+        state.last_quantity = qty_fixed as f64 / quantity_scale;
+        state.last_is_buyer_maker = Some(trade.is_buyer_maker);
 
         Ok(())
     }
 
+    /// Read the next trade, whether delta-encoded or a keyframe. Heartbeats and
+    /// other control records, including kinds this decoder doesn't know, are
+    /// skipped, so a frame holding only a heartbeat ends in an EOF error; use
+    /// `read_record` where control records can arrive on their own.
     pub fn read_message(
         &mut self,
         cursor: &mut Cursor<&Vec<u8>>,
     ) -> Result<Trade, BinaryFormatError> {
+        loop {
+            if let Some(trade) = self.read_record(cursor)?.into_trade() {
+                return Ok(trade);
+            }
+        }
+    }
+
+    /// Read the next record, telling delta-encoded trades and control records apart.
+    pub fn read_record(
+        &mut self,
+        cursor: &mut Cursor<&Vec<u8>>,
+    ) -> Result<Record, BinaryFormatError> {
+        self.read_record_from(cursor)
+    }
+
+    /// `read_record` straight from a borrowed buffer, eg: a memory-mapped file,
+    /// advancing `data` past the record. Nothing is copied out of `data` unless
+    /// records are length-prefixed.
+    pub fn read_record_slice(&mut self, data: &mut &[u8]) -> Result<Record, BinaryFormatError> {
+        self.read_record_from(data)
+    }
+
+    /// Every trade in `data`, whole records following the header this decoder
+    /// read, in order. Control records are applied and skipped. A decode error is
+    /// handed out as an `Err` item and ends the iteration, since the records
+    /// after it can't be found.
+    pub fn decode_all<'a>(
+        &'a mut self,
+        data: &'a [u8],
+    ) -> impl Iterator<Item = Result<Trade, BinaryFormatError>> + 'a {
+        let mut rest = data;
+        std::iter::from_fn(move || {
+            while !rest.is_empty() {
+                match self.read_record_slice(&mut rest) {
+                    Ok(record) => {
+                        if let Some(trade) = record.into_trade() {
+                            return Some(Ok(trade));
+                        }
+                    }
+                    Err(e) => {
+                        rest = &[];
+                        return Some(Err(e));
+                    }
+                }
+            }
+            None
+        })
+    }
+
+    /// The trades of `decode_all` as newline-delimited JSON, one object per
+    /// trade with `Trade`'s field names, for dumping a stream while debugging.
+    /// Stops at the first decode error, dropping the trades before it.
+    #[cfg(feature = "serde")]
+    pub fn decode_to_json(&mut self, data: &[u8]) -> Result<String, BinaryFormatError> {
+        let mut json = String::new();
+        for trade in self.decode_all(data) {
+            json += &serde_json::to_string(&trade?).expect("Trade serializes");
+            json.push('\n');
+        }
+        Ok(json)
+    }
+
+    /// Decode one trade from the front of a contiguous, unframed buffer.
+    ///
+    /// Returns `Ok(None)` when `data` ends partway through a record. Decoder state
+    /// is left untouched in that case, so the call can be retried once more bytes
+    /// have arrived. Otherwise returns the trade and the number of bytes consumed,
+    /// including any heartbeats skipped in front of it.
+    pub fn try_read_message(
+        &mut self,
+        data: &[u8],
+    ) -> Result<Option<(Trade, usize)>, BinaryFormatError> {
+        let mut cursor = Cursor::new(data);
+        loop {
+            match self.read_record_from(&mut cursor) {
+                Ok(record) => {
+                    if let Some(trade) = record.into_trade() {
+                        return Ok(Some((trade, cursor.position() as usize)));
+                    }
+                }
+                Err(BinaryFormatError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Step over the next record without decoding it, returning the bytes skipped
+    /// (prefix included). Only possible with length-prefixed or fixed-width
+    /// records. Delta state is untouched, so after skipping a trade that asset
+    /// decodes wrongly until its next keyframe; meant for scanning, eg: counting
+    /// records or finding keyframes to start from.
+    pub fn skip_record(&self, cursor: &mut Cursor<&Vec<u8>>) -> Result<usize, BinaryFormatError> {
+        let start = cursor.position();
+        let len = match (self.length_prefixed, self.fixed_width) {
+            (true, _) => varint::decode_unsigned(cursor)?,
+            (false, true) => FIXED_RECORD_LEN as u64,
+            (false, false) => return Err(BinaryFormatError::NotLengthPrefixed),
+        };
+        let end = cursor.position() + len;
+        if end > cursor.get_ref().len() as u64 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        cursor.set_position(end);
+        Ok((end - start) as usize)
+    }
+
+    /// Record `index` of a run of fixed-width records, eg: an mmap'd recording
+    /// past its header, decoded without reading any record before it.
+    pub fn read_fixed_record(
+        &mut self,
+        records: &[u8],
+        index: usize,
+    ) -> Result<Record, BinaryFormatError> {
+        if !self.fixed_width {
+            return Err(BinaryFormatError::NotFixedWidth);
+        }
+        let record = index
+            .checked_mul(FIXED_RECORD_LEN)
+            .and_then(|start| records.get(start..start.checked_add(FIXED_RECORD_LEN)?))
+            .ok_or(BinaryFormatError::InsufficientData)?;
+        self.read_fixed(&mut &record[..])
+    }
+
+    // All reads happen before any state is updated, so a short read never leaves
+    // an asset's state half-applied.
+    fn read_record_from(&mut self, reader: &mut impl Read) -> Result<Record, BinaryFormatError> {
+        if self.fixed_width {
+            return self.read_fixed(reader);
+        }
+        if self.length_prefixed {
+            let len = varint::decode_unsigned(reader)?;
+            let mut record = Vec::new();
+            read_len(reader, len, &mut record)?;
+            // Bytes past what this decoder reads belong to a newer layout
+            return self.read_unprefixed(&mut self.verified(&record)?);
+        }
+        if self.checksum {
+            let mut record = self.read_record_bytes(reader)?;
+            let mut crc = [0u8; CHECKSUM_LEN];
+            reader.read_exact(&mut crc)?;
+            record.extend_from_slice(&crc);
+            return self.read_unprefixed(&mut self.verified(&record)?);
+        }
+        self.read_unprefixed(reader)
+    }
+
+    /// `record` without its trailing checksum, once the checksum matches. As is
+    /// if records carry none.
+    fn verified<'a>(&self, record: &'a [u8]) -> Result<&'a [u8], BinaryFormatError> {
+        if !self.checksum {
+            return Ok(record);
+        }
+        let Some(body_len) = record.len().checked_sub(CHECKSUM_LEN) else {
+            return Err(BinaryFormatError::InsufficientData);
+        };
+        let (body, crc) = record.split_at(body_len);
+        let expected = u32::from_le_bytes(crc.try_into().unwrap());
+        let actual = crc32(body);
+        if expected != actual {
+            return Err(BinaryFormatError::ChecksumMismatch { expected, actual });
+        }
+        Ok(body)
+    }
+
+    /// The raw bytes of the next unprefixed record, found by its layout alone, so
+    /// its checksum can be checked before any of it is decoded.
+    fn read_record_bytes(&self, reader: &mut impl Read) -> Result<Vec<u8>, BinaryFormatError> {
+        let mut record = vec![0u8];
+        reader.read_exact(&mut record)?;
+        let packed_byte = record[0];
+        if packed_byte & 0x7F == CONTROL_ID {
+            let mut kind = [0u8];
+            reader.read_exact(&mut kind)?;
+            record.push(kind[0]);
+            // Unchecked until the CRC is, so only what arrives is allocated
+            let len = copy_varint(reader, &mut record)?;
+            read_len(reader, len, &mut record)?;
+            return Ok(record);
+        }
+        if packed_byte & 0x7F == WIDE_ID_ESCAPE && self.wide_ids() {
+            copy_varint(reader, &mut record)?;
+        }
+        // Timestamp delta, price delta, quantity
+        for _ in 0..3 {
+            copy_varint(reader, &mut record)?;
+        }
+        Ok(record)
+    }
+
+    fn read_unprefixed(&mut self, reader: &mut impl Read) -> Result<Record, BinaryFormatError> {
         let mut packed_byte = [0u8];
-        cursor.read_exact(&mut packed_byte)?;
+        reader.read_exact(&mut packed_byte)?;
         let packed_byte = packed_byte[0];
 
-        let is_buyer_maker = packed_byte & 0x80 != 0;
-        let asset_id = packed_byte & 0x7F;
+        if packed_byte & 0x7F == CONTROL_ID {
+            return self.read_control(reader);
+        }
+        self.read_trade(packed_byte, reader).map(Record::Trade)
+    }
+
+    fn read_control(&mut self, reader: &mut impl Read) -> Result<Record, BinaryFormatError> {
+        let mut kind = [0u8];
+        reader.read_exact(&mut kind)?;
+        let len = varint::decode_unsigned(reader)?;
+        let mut payload = Vec::new();
+        read_len(reader, len, &mut payload)?;
+        let mut payload = Cursor::new(&payload);
 
-        if asset_id as usize >= self.assets.len() {
+        match kind[0] {
+            KIND_KEYFRAME => self.read_keyframe(&mut payload).map(Record::Keyframe),
+            KIND_HEARTBEAT => {
+                let mut timestamp = [0u8; 8];
+                payload.read_exact(&mut timestamp)?;
+                Ok(Record::Heartbeat(u64::from_le_bytes(timestamp)))
+            }
+            KIND_FUNDING => {
+                let asset_id = self.read_asset(&mut payload)?.0;
+                let mut timestamp = [0u8; 8];
+                payload.read_exact(&mut timestamp)?;
+                let fixed = varint::decode_signed(&mut payload)?;
+                Ok(Record::Funding {
+                    symbol: self.assets[asset_id].clone(),
+                    timestamp: u64::from_le_bytes(timestamp),
+                    rate: from_fixed(fixed, self.field_scale(ScaledField::FundingRate)),
+                })
+            }
+            KIND_GAP => {
+                let asset_id = self.read_asset(&mut payload)?.0;
+                Ok(Record::Gap {
+                    symbol: self.assets[asset_id].clone(),
+                    dropped: varint::decode_unsigned(&mut payload)?,
+                })
+            }
+            KIND_RESET => self.read_reset(&mut payload),
+            // Newer producer: skip it whole rather than guess at its layout
+            kind => {
+                tracing::debug!("skipping control record of unknown kind {}", kind);
+                Ok(Record::Unknown { kind })
+            }
+        }
+    }
+
+    /// `KIND_RESET` payload: timestamp (u64 LE), varint asset count, then price
+    /// and quantity (f64 LE) per asset in id order. Read whole before any state
+    /// changes.
+    fn read_reset(&mut self, payload: &mut impl Read) -> Result<Record, BinaryFormatError> {
+        let mut word = [0u8; 8];
+        payload.read_exact(&mut word)?;
+        let timestamp = u64::from_le_bytes(word);
+        let count = varint::decode_unsigned(payload)?;
+        if count != self.assets.len() as u64 {
+            return Err(BinaryFormatError::DesyncSuspected(format!(
+                "reset for {} assets, the header has {}",
+                count,
+                self.assets.len()
+            )));
+        }
+        let mut values = Vec::with_capacity(self.assets.len());
+        for symbol in &self.assets {
+            payload.read_exact(&mut word)?;
+            let price = f64::from_le_bytes(word);
+            payload.read_exact(&mut word)?;
+            let quantity = f64::from_le_bytes(word);
+            if !(price.is_finite() && price >= 0.0 && quantity.is_finite() && quantity >= 0.0) {
+                return Err(BinaryFormatError::InvalidAssetSpec(format!(
+                    "reset to price {} quantity {} for {}",
+                    price, quantity, symbol
+                )));
+            }
+            values.push((price, quantity));
+        }
+
+        for (state, (price, quantity)) in self.states.iter_mut().zip(values) {
+            state.last_timestamp = timestamp;
+            state.last_price = price;
+            state.last_quantity = quantity;
+            state.last_is_buyer_maker = None;
+        }
+        Ok(Record::Reset(timestamp))
+    }
+
+    fn read_fixed(&mut self, reader: &mut impl Read) -> Result<Record, BinaryFormatError> {
+        let mut record = [0u8; FIXED_RECORD_LEN];
+        reader.read_exact(&mut record)?;
+        let [packed_byte, kind] = [record[0], record[1]];
+        let word = |at: usize| <[u8; 8]>::try_from(&record[at..at + 8]).unwrap();
+        let timestamp = u64::from_le_bytes(word(2));
+        let value = i64::from_le_bytes(word(10));
+        let amount = u64::from_le_bytes(word(18));
+
+        match kind {
+            KIND_TRADE | KIND_KEYFRAME => {
+                let asset_id = self.checked_asset_id((packed_byte & 0x7F) as u64)?;
+                let scale = self.scales[asset_id];
+                let trade = self.seat(
+                    asset_id,
+                    packed_byte & 0x80 != 0,
+                    timestamp,
+                    from_fixed(value, scale),
+                    amount as f64 / self.quantity_scale_of(asset_id),
+                );
+                Ok(match kind {
+                    KIND_TRADE => Record::Trade(trade),
+                    _ => Record::Keyframe(trade),
+                })
+            }
+            KIND_HEARTBEAT => Ok(Record::Heartbeat(timestamp)),
+            KIND_FUNDING => Ok(Record::Funding {
+                symbol: self.assets[self.checked_asset_id((packed_byte & 0x7F) as u64)?].clone(),
+                timestamp,
+                rate: from_fixed(value, self.field_scale(ScaledField::FundingRate)),
+            }),
+            KIND_GAP => Ok(Record::Gap {
+                symbol: self.assets[self.checked_asset_id((packed_byte & 0x7F) as u64)?].clone(),
+                dropped: amount,
+            }),
+            kind => {
+                tracing::debug!("skipping fixed-width record of unknown kind {}", kind);
+                Ok(Record::Unknown { kind })
+            }
+        }
+    }
+
+    /// Read a packed byte and the rest of a wide id, returning the asset id and
+    /// the maker flag.
+    fn read_asset(&self, reader: &mut impl Read) -> Result<(usize, bool), BinaryFormatError> {
+        let mut packed_byte = [0u8];
+        reader.read_exact(&mut packed_byte)?;
+        let asset_id = self.read_wide_id(packed_byte[0], reader)?;
+        Ok((asset_id, packed_byte[0] & 0x80 != 0))
+    }
+
+    /// Asset id of a `packed_byte` already read, reading the rest of it from
+    /// `reader` if it's escaped.
+    fn read_wide_id(
+        &self,
+        packed_byte: u8,
+        reader: &mut impl Read,
+    ) -> Result<usize, BinaryFormatError> {
+        let asset_id = (packed_byte & 0x7F) as u64;
+        if asset_id == WIDE_ID_ESCAPE as u64 && self.wide_ids() {
+            let rest = varint::decode_unsigned(reader)?;
+            return self.checked_asset_id(asset_id.saturating_add(rest));
+        }
+        self.checked_asset_id(asset_id)
+    }
+
+    fn checked_asset_id(&self, asset_id: u64) -> Result<usize, BinaryFormatError> {
+        if asset_id >= self.assets.len() as u64 {
             return Err(BinaryFormatError::InvalidAssetId(format!(
                 "Asset ID {} out of bounds (0 <= ID < {})",
                 asset_id,
                 self.assets.len()
             )));
         }
-        let state = &mut self.states[asset_id as usize];
+        Ok(asset_id as usize)
+    }
+
+    fn read_keyframe(&mut self, reader: &mut impl Read) -> Result<Trade, BinaryFormatError> {
+        let (asset_id, is_buyer_maker) = self.read_asset(reader)?;
 
-        let ts_delta = varint::decode_signed(cursor)?;
-        let timestamp = ((state.last_timestamp as i64) + ts_delta) as u64;
+        let mut word = [0u8; 8];
+        reader.read_exact(&mut word)?;
+        let timestamp = u64::from_le_bytes(word);
+        reader.read_exact(&mut word)?;
+        let price = f64::from_le_bytes(word);
+        reader.read_exact(&mut word)?;
+        let quantity = f64::from_le_bytes(word);
 
-        let price_delta = varint::decode_signed(cursor)?;
-        let price = state.last_price + (price_delta as f64 / SCALE_FACTOR);
+        Ok(self.seat(asset_id, is_buyer_maker, timestamp, price, quantity))
+    }
+
+    /// Re-seat an asset's state on a trade carried with absolute values.
+    fn seat(
+        &mut self,
+        asset_id: usize,
+        is_buyer_maker: bool,
+        timestamp: u64,
+        price: f64,
+        quantity: f64,
+    ) -> Trade {
+        let state = &mut self.states[asset_id];
+        state.decoded += 1;
+        state.price_moved += (price - state.last_price).abs();
+        state.last_timestamp = timestamp;
+        state.last_price = price;
+        state.last_quantity = quantity;
+        state.last_is_buyer_maker = Some(is_buyer_maker);
+
+        Trade {
+            symbol: self.assets[asset_id].clone(),
+            timestamp,
+            price,
+            quantity,
+            is_buyer_maker,
+        }
+    }
+
+    fn read_trade(
+        &mut self,
+        packed_byte: u8,
+        reader: &mut impl Read,
+    ) -> Result<Trade, BinaryFormatError> {
+        let is_buyer_maker = packed_byte & 0x80 != 0;
+        let asset_id = self.read_wide_id(packed_byte, reader)?;
+        let state = &mut self.states[asset_id];
+
+        let ts_delta = varint::decode_signed(reader)?;
+        // A negative or overflowing result means the stream and decoder disagree;
+        // wrapping would hand downstream latency math a garbage timestamp.
+        let timestamp = i64::try_from(state.last_timestamp)
+            .ok()
+            .and_then(|last| last.checked_add(ts_delta))
+            .and_then(|ts| u64::try_from(ts).ok())
+            .ok_or_else(|| {
+                BinaryFormatError::DesyncSuspected(format!(
+                    "{} timestamp {} + delta {} is out of range",
+                    self.assets[asset_id], state.last_timestamp, ts_delta
+                ))
+            })?;
 
-        let qty_fixed = varint::decode_unsigned(cursor)?;
-        let quantity = qty_fixed as f64 / SCALE_FACTOR;
+        let price_delta = varint::decode_signed(reader)?;
+        let scale = self.scales[asset_id];
+        let quantity_scale = self.quantity_scale.unwrap_or(scale);
+        let price = state.last_price + from_fixed(price_delta, scale);
+
+        let qty_fixed = if self.quantity_deltas {
+            let qty_delta = varint::decode_signed(reader)?;
+            previous_fixed(state.last_quantity, quantity_scale)
+                .ok()
+                .and_then(|previous| previous.checked_add(qty_delta))
+                .and_then(|qty| u64::try_from(qty).ok())
+                .ok_or_else(|| {
+                    BinaryFormatError::DesyncSuspected(format!(
+                        "{} quantity {} + delta {} is out of range",
+                        self.assets[asset_id], state.last_quantity, qty_delta
+                    ))
+                })?
+        } else {
+            varint::decode_unsigned(reader)?
+        };
+        let quantity = qty_fixed as f64 / quantity_scale;
 
+        state.decoded += 1;
+        state.price_moved += (price - state.last_price).abs();
         state.last_timestamp = timestamp;
         state.last_price = price;
         state.last_quantity = quantity;
+        state.last_is_buyer_maker = Some(is_buyer_maker);
 
         Ok(Trade {
-            symbol: self.assets[asset_id as usize].clone(),
+            symbol: self.assets[asset_id].clone(),
             timestamp,
             price,
             quantity,
@@ -370,17 +2295,508 @@ impl BinaryFormat {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// A quantity delta's base: the asset's last quantity back at `scale`. Both
+/// sides hold the same `last_quantity`, so they agree on it to the unit.
+fn previous_fixed(last_quantity: f64, scale: f64) -> Result<i64, BinaryFormatError> {
+    to_fixed(last_quantity, scale)
+}
 
-    #[test]
-    fn test_varint_encoding_decoding() {
-        let mut buffer = Vec::new();
+/// Reader that counts the bytes read through it.
+struct Counting<'a, R> {
+    reader: &'a mut R,
+    count: usize,
+}
 
-        // Values that are powers of 2 and edge cases
-        let unsigned_test_values = vec![
-            0u64,
+impl<R: Read> Read for Counting<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.count += n;
+        Ok(n)
+    }
+}
+
+/// Append the next `len` bytes of `reader` to `out`, or fail with
+/// `UnexpectedEof` if it ends first. `len` comes off the wire, so rather than
+/// allocating it up front, only the bytes that actually arrive are kept.
+fn read_len<R: Read>(reader: &mut R, len: u64, out: &mut Vec<u8>) -> Result<(), BinaryFormatError> {
+    let read = reader.take(len).read_to_end(out)?;
+    if read as u64 != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// Copy one varint from `reader` to `out`, returning its value.
+fn copy_varint<R: Read>(reader: &mut R, out: &mut Vec<u8>) -> Result<u64, BinaryFormatError> {
+    struct Copying<'a, R> {
+        reader: &'a mut R,
+        out: &'a mut Vec<u8>,
+    }
+    impl<R: Read> Read for Copying<'_, R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.reader.read(buf)?;
+            self.out.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+    }
+    varint::decode_unsigned(&mut Copying { reader, out })
+}
+
+/// CRC-32 (IEEE 802.3, as in zlib and PNG) of `bytes`.
+fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// `value` at `scale`, rounded to nearest, as the encoder writes prices and
+/// quantities. Truncating would pull every value toward zero, so a rising
+/// price would always decode a little low.
+pub fn to_fixed(value: f64, scale: f64) -> Result<i64, BinaryFormatError> {
+    let fixed = (value * scale).round();
+    // `as` saturates, so out-of-range values have to be caught first. i64::MAX
+    // rounds up to 2^63 as an f64, the first value that doesn't fit.
+    if !fixed.is_finite() || fixed.abs() >= i64::MAX as f64 {
+        return Err(BinaryFormatError::Overflow);
+    }
+    Ok(fixed as i64)
+}
+
+/// Fixed-point `value` at `scale` back to a float, as the decoder reads it.
+pub fn from_fixed(value: i64, scale: f64) -> f64 {
+    value as f64 / scale
+}
+
+/// Machine-readable description of the wire format, built from the constants the
+/// encoder and decoder use so it can't drift from the code. Meant for writing or
+/// validating consumers in other languages.
+pub fn spec() -> serde_json::Value {
+    serde_json::json!({
+        "versions": {
+            "written_by_default": VERSION_V5,
+            "supported": SUPPORTED_VERSIONS.collect::<Vec<_>>(),
+            "legacy": [VERSION_V1, VERSION_V2, VERSION_V3],
+        },
+        "byte_order": "little-endian",
+        "scale_factor": DEFAULT_SCALE,
+        "fixed_point": "price_delta = (price - previous decoded price) * scale, quantity = quantity * quantity scale (the asset scale unless the header sets one), both rounded to nearest; a value outside i64 is refused as an overflow",
+        "varint": {
+            "unsigned": "LEB128: 7 data bits per byte, low bits first, 0x80 set on all but the last byte, at most 10 bytes",
+            "signed": "zigzag then unsigned: (n << 1) ^ (n >> 63)",
+        },
+        "timestamp_unit": "milliseconds, or as declared by the timestamp_unit extension; applies to every timestamp field",
+        "max_assets": MAX_ASSETS,
+        "max_assets_before_v3": MAX_PACKED_ASSETS,
+        "wide_asset_ids": {
+            "versions": [VERSION_V3, VERSION_V4, VERSION_V5],
+            "v4": "only with more than max_assets_before_v3 assets",
+            "v5": "only with more than max_assets_before_v3 assets",
+            "escape": WIDE_ID_ESCAPE,
+            "summary": "every asset_id in a packed byte or control payload below the escape is the id itself; the escape is followed by an unsigned varint, and the id is escape + varint",
+            "fixed_width": "not allowed",
+        },
+        "header": {
+            "fields": [
+                { "name": "magic", "type": "4 B", "offset": 0 },
+                { "name": "version", "type": "u8", "offset": 4 },
+                { "name": "asset_count", "type": "u16", "offset": 5 },
+                { "name": "assets", "type": "asset_count x (u8 length, utf-8 symbol)", "offset": 7 },
+                { "name": "reference_timestamp", "type": "u64" },
+                { "name": "reference_prices", "type": "asset_count x f64" },
+                { "name": "reference_quantities", "type": "asset_count x f64" },
+            ],
+            "v3": "v2 with a u16 asset_count, written only for more than max_assets_before_v3 assets",
+            "v4": "v3 preceded by the magic; the fields above are v4's",
+            "v5": "v4 with a u32 total header length (magic included) right after the version, so asset_count is at offset 9; written by default",
+            "legacy": "v1 to v3 have no magic, so the version is at offset 0, and v1 and v2 have a u8 asset_count; read only on request",
+            "magic": String::from_utf8_lossy(&HEADER_MAGIC),
+            "magic_rule": "a header starting with the magic's first byte must carry the whole magic and a version from 4 on; any other first byte outside 1-3 is not a header",
+            "v2_extensions": {
+                "fields": [
+                    { "name": "extension_count", "type": "u8" },
+                    { "name": "extensions", "type": "extension_count x (u8 tag, varint length, payload)" },
+                ],
+                "unknown_tags": "skip",
+                "tags": {
+                    "asset_scales": { "tag": EXT_ASSET_SCALES, "payload": "asset_count x f64, replaces scale_factor per asset" },
+                    "field_scales": {
+                        "tag": EXT_FIELD_SCALES,
+                        "payload": "u8 count, then count x (u8 record kind, u8 field index, f64 scale); unknown fields skipped",
+                        "defaults": { "funding.rate": DEFAULT_FUNDING_SCALE },
+                    },
+                    "timestamp_unit": {
+                        "tag": EXT_TIMESTAMP_UNIT,
+                        "payload": "u8: 0 milliseconds, 1 microseconds; unknown units are an error",
+                    },
+                    "record_flags": {
+                        "tag": EXT_RECORD_FLAGS,
+                        "payload": "u8 bit flags; unknown bits are an error",
+                        "flags": {
+                            "length_prefixed": RECORD_FLAG_LENGTH_PREFIXED,
+                            "fixed_width": RECORD_FLAG_FIXED_WIDTH,
+                            "checksum": RECORD_FLAG_CHECKSUM,
+                            "quantity_delta": RECORD_FLAG_QUANTITY_DELTA,
+                        },
+                        "exclusive": "fixed_width can't be combined with any other flag",
+                    },
+                    "quantity_scale": {
+                        "tag": EXT_QUANTITY_SCALE,
+                        "payload": "f64, replaces every asset's scale for quantities only; decoders that predate it skip it and read quantities at the asset scale",
+                    },
+                },
+            },
+        },
+        "records": {
+            "length_prefix": "with the length_prefixed flag, every record (trade or control) is preceded by its byte length as an unsigned varint",
+            "checksum": "with the checksum flag, every record (trade or control) is followed by the CRC-32 (IEEE) of its bytes as a u32, inside the length prefix if any",
+            "fixed_width": {
+                "length": FIXED_RECORD_LEN,
+                "summary": "with the fixed_width flag, every record replaces the layouts below with this one; record i starts at i * length past the header",
+                "fields": [
+                    { "name": "packed", "type": "u8", "bits": { "asset_id": "0-6", "is_buyer_maker": "7" }, "offset": 0 },
+                    { "name": "kind", "type": "u8", "offset": 1 },
+                    { "name": "timestamp", "type": "u64", "offset": 2 },
+                    { "name": "value", "type": "i64", "offset": 10 },
+                    { "name": "amount", "type": "u64", "offset": 18 },
+                ],
+                "kinds": {
+                    "trade": { "kind": KIND_TRADE, "value": "price * scale", "amount": "quantity * scale" },
+                    "keyframe": { "kind": KIND_KEYFRAME, "value": "price * scale", "amount": "quantity * scale" },
+                    "heartbeat": { "kind": KIND_HEARTBEAT, "packed": CONTROL_ID },
+                    "funding": { "kind": KIND_FUNDING, "value": "rate at field 0's scale" },
+                    "gap": { "kind": KIND_GAP, "amount": "dropped" },
+                },
+                "fixed_point": "rounded to nearest; fields a kind doesn't use are zero",
+                "unknown_kinds": "skip",
+            },
+            "trade": {
+                "fields": [
+                    { "name": "packed", "type": "u8", "bits": { "asset_id": "0-6", "is_buyer_maker": "7" } },
+                    { "name": "asset_id_rest", "type": "unsigned varint, only after an escaped asset_id, see wide_asset_ids" },
+                    { "name": "timestamp_delta", "type": "signed varint" },
+                    { "name": "price_delta", "type": "signed varint" },
+                    { "name": "quantity", "type": "unsigned varint; with the quantity_delta flag, a signed varint added to the previous quantity * scale, rounded" },
+                ],
+            },
+            "control": {
+                "asset_id": CONTROL_ID,
+                "fields": [
+                    { "name": "packed", "type": "u8", "value": CONTROL_ID },
+                    { "name": "kind", "type": "u8" },
+                    { "name": "payload_length", "type": "unsigned varint" },
+                    { "name": "payload", "type": "payload_length bytes" },
+                ],
+                "unknown_kinds": "skip payload_length bytes; known kinds ignore trailing payload bytes",
+                "kinds": {
+                    "keyframe": {
+                        "kind": KIND_KEYFRAME,
+                        "payload": [
+                            { "name": "packed", "type": "u8", "bits": { "asset_id": "0-6", "is_buyer_maker": "7" } },
+                            { "name": "asset_id_rest", "type": "unsigned varint, only after an escaped asset_id, see wide_asset_ids" },
+                            { "name": "timestamp", "type": "u64" },
+                            { "name": "price", "type": "f64" },
+                            { "name": "quantity", "type": "f64" },
+                        ],
+                    },
+                    "heartbeat": {
+                        "kind": KIND_HEARTBEAT,
+                        "payload": [{ "name": "timestamp", "type": "u64" }],
+                    },
+                    "funding": {
+                        "kind": KIND_FUNDING,
+                        "payload": [
+                            { "name": "asset_id", "type": "u8, see wide_asset_ids" },
+                            { "name": "timestamp", "type": "u64" },
+                            { "name": "rate", "type": "signed varint, field 0, rounded to nearest" },
+                        ],
+                    },
+                    "gap": {
+                        "kind": KIND_GAP,
+                        "payload": [
+                            { "name": "asset_id", "type": "u8, see wide_asset_ids" },
+                            { "name": "dropped", "type": "unsigned varint" },
+                        ],
+                    },
+                    "reset": {
+                        "kind": KIND_RESET,
+                        "payload": [
+                            { "name": "timestamp", "type": "u64" },
+                            { "name": "asset_count", "type": "unsigned varint, must match the header" },
+                            { "name": "references", "type": "asset_count x (f64 price, f64 quantity), in asset id order" },
+                        ],
+                        "applies": "every asset's state becomes (timestamp, price, quantity), as after a header; not skippable, not in fixed-width streams",
+                    },
+                },
+            },
+        },
+        "framing": {
+            "magic": String::from_utf8_lossy(STREAM_NAME),
+            "stream_version": STREAM_VERSION,
+            "tcp": "u32 length prefix per frame: magic, \"START\", header, then one record per frame; a magic ending in 'V' switches every later frame to an unsigned varint length",
+            "shm": "u32 length prefix per ShmQueue message: magic, \"START\", header, then one record per message",
+        },
+    })
+}
+
+/// One-call setup of an encoder and its header from per-asset specs, so symbols,
+/// reference values and scales can't drift out of line with each other.
+#[derive(Debug)]
+pub struct BinaryFormatBuilder {
+    reference_timestamp: u64,
+    timestamp_unit: TimestampUnit,
+    length_prefixed: bool,
+    fixed_width: bool,
+    checksum: bool,
+    quantity_deltas: bool,
+    quantity_scale: Option<f64>,
+    header_length: bool,
+    assets: Vec<(String, f64, f64, f64)>,
+}
+
+impl Default for BinaryFormatBuilder {
+    fn default() -> Self {
+        Self {
+            reference_timestamp: 0,
+            timestamp_unit: TimestampUnit::default(),
+            length_prefixed: false,
+            fixed_width: false,
+            checksum: false,
+            quantity_deltas: false,
+            quantity_scale: None,
+            header_length: true,
+            assets: Vec::new(),
+        }
+    }
+}
+
+impl BinaryFormatBuilder {
+    pub fn reference_timestamp(mut self, reference_timestamp: u64) -> Self {
+        self.reference_timestamp = reference_timestamp;
+        self
+    }
+
+    /// Unit of `reference_timestamp` and of every timestamp encoded after it.
+    pub fn timestamp_unit(mut self, unit: TimestampUnit) -> Self {
+        self.timestamp_unit = unit;
+        self
+    }
+
+    /// See `BinaryFormat::with_length_prefixed_records`.
+    pub fn length_prefixed_records(mut self, enabled: bool) -> Self {
+        self.length_prefixed = enabled;
+        self
+    }
+
+    /// See `BinaryFormat::with_fixed_width_records`.
+    pub fn fixed_width_records(mut self, enabled: bool) -> Self {
+        self.fixed_width = enabled;
+        self
+    }
+
+    /// See `BinaryFormat::with_checksum`.
+    pub fn checksum(mut self, enabled: bool) -> Self {
+        self.checksum = enabled;
+        self
+    }
+
+    /// See `BinaryFormat::with_quantity_deltas`.
+    pub fn quantity_deltas(mut self, enabled: bool) -> Self {
+        self.quantity_deltas = enabled;
+        self
+    }
+
+    /// See `BinaryFormat::with_header_length`.
+    pub fn header_length(mut self, enabled: bool) -> Self {
+        self.header_length = enabled;
+        self
+    }
+
+    /// See `BinaryFormat::with_quantity_scale`; unset, each asset's quantities
+    /// share its scale.
+    pub fn quantity_scale(mut self, scale: f64) -> Self {
+        self.quantity_scale = Some(scale);
+        self
+    }
+
+    /// `(symbol, reference price, reference quantity, scale)` per asset, in id order.
+    pub fn assets(mut self, assets: Vec<(String, f64, f64, f64)>) -> Self {
+        self.assets = assets;
+        self
+    }
+
+    /// Validate the specs and return the encoder along with the header to send.
+    pub fn build(self) -> Result<(BinaryFormat, Vec<u8>), BinaryFormatError> {
+        let mut symbols = Vec::with_capacity(self.assets.len());
+        let mut prices = Vec::with_capacity(self.assets.len());
+        let mut quantities = Vec::with_capacity(self.assets.len());
+        let mut scales = Vec::with_capacity(self.assets.len());
+        for (symbol, price, quantity, scale) in self.assets {
+            let invalid = |what: &str| {
+                Err(BinaryFormatError::InvalidAssetSpec(format!(
+                    "{} for {:?}",
+                    what, symbol
+                )))
+            };
+            if symbol.is_empty() || symbol.len() > MAX_SYMBOL_LEN {
+                return invalid("symbol must be 1-255 bytes");
+            }
+            if symbols.contains(&symbol) {
+                return invalid("duplicate symbol");
+            }
+            if !price.is_finite() || price < 0.0 {
+                return invalid("reference price must be finite and non-negative");
+            }
+            if !quantity.is_finite() || quantity < 0.0 {
+                return invalid("reference quantity must be finite and non-negative");
+            }
+            if !scale.is_finite() || scale <= 0.0 {
+                return invalid("scale must be finite and positive");
+            }
+            symbols.push(symbol);
+            prices.push(price);
+            quantities.push(quantity);
+            scales.push(scale);
+        }
+
+        let mut encoder = BinaryFormat::new()
+            .with_assets(symbols)?
+            .with_timestamp_unit(self.timestamp_unit)
+            .with_length_prefixed_records(self.length_prefixed)
+            .with_fixed_width_records(self.fixed_width)
+            .with_checksum(self.checksum)
+            .with_quantity_deltas(self.quantity_deltas)
+            .with_header_length(self.header_length);
+        if let Some(scale) = self.quantity_scale {
+            encoder = encoder.with_quantity_scale(scale)?;
+        }
+        encoder.scales = scales;
+        let mut header = Vec::new();
+        encoder.write_header(&mut header, self.reference_timestamp, &prices, &quantities)?;
+        Ok((encoder, header))
+    }
+}
+
+/// Decoder for an unframed byte stream, eg: a recording read in chunks, fed
+/// whatever chunks the bytes arrive in. A record (or the header) cut short at
+/// the end of a chunk, partway through a varint or a symbol, is kept and read
+/// again from its start once the rest arrives, so no bytes are lost and decoder
+/// state only ever moves by whole records.
+///
+/// After an error the stream is out of step; start over with a new decoder.
+#[derive(Default)]
+pub struct StreamDecoder {
+    /// Unset until the header has been read
+    decoder: Option<BinaryFormat>,
+    /// Bytes received but not yet decoded, at most one partial record (or
+    /// header) after each `push_bytes`
+    pending: Vec<u8>,
+}
+
+impl StreamDecoder {
+    /// A decoder for a stream that starts with its header.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A decoder for the records following a header `decoder` has already read.
+    pub fn with_decoder(decoder: BinaryFormat) -> Self {
+        Self {
+            decoder: Some(decoder),
+            pending: Vec::new(),
+        }
+    }
+
+    /// The underlying decoder, once the header has been read.
+    pub fn decoder(&self) -> Option<&BinaryFormat> {
+        self.decoder.as_ref()
+    }
+
+    /// Bytes held back as the start of a record that hasn't fully arrived.
+    pub fn pending_bytes(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Append `data` and decode every trade it completes, skipping heartbeats and
+    /// other control records.
+    pub fn push_bytes(&mut self, data: &[u8]) -> Result<Vec<Trade>, BinaryFormatError> {
+        self.pending.extend_from_slice(data);
+        let mut trades = Vec::new();
+        let mut consumed = 0;
+        let res = self.decode_pending(&mut consumed, &mut trades);
+        self.pending.drain(..consumed);
+        res.map(|()| trades)
+    }
+
+    fn decode_pending(
+        &mut self,
+        consumed: &mut usize,
+        trades: &mut Vec<Trade>,
+    ) -> Result<(), BinaryFormatError> {
+        let decoder = match self.decoder.as_mut() {
+            Some(decoder) => decoder,
+            None => {
+                // A fresh decoder per attempt, so a short header leaves nothing behind
+                let mut decoder = BinaryFormat::new();
+                let mut rest = &self.pending[..];
+                match decoder.read_header_slice(&mut rest) {
+                    Ok(()) => {}
+                    Err(BinaryFormatError::IoError(e))
+                        if e.kind() == io::ErrorKind::UnexpectedEof =>
+                    {
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                }
+                *consumed = self.pending.len() - rest.len();
+                self.decoder.insert(decoder)
+            }
+        };
+        loop {
+            let mut cursor = Cursor::new(&self.pending[*consumed..]);
+            match decoder.read_record_from(&mut cursor) {
+                Ok(record) => {
+                    *consumed += cursor.position() as usize;
+                    trades.extend(record.into_trade());
+                }
+                Err(BinaryFormatError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint_encoding_decoding() {
+        let mut buffer = Vec::new();
+
+        // Values that are powers of 2 and edge cases
+        let unsigned_test_values = vec![
+            0u64,
             127u64,     // 1-byte limit
             128u64,     // 2-byte transition point
             16384u64,   // 3-byte transition point
@@ -391,249 +2807,2264 @@ mod tests {
         for value in unsigned_test_values {
             buffer.clear();
 
-            // Encode the unsigned value
-            varint::encode_unsigned(value, &mut buffer).unwrap();
+            // Encode the unsigned value
+            varint::encode_unsigned(value, &mut buffer).unwrap();
+
+            // Decode the unsigned value
+            let decoded = varint::decode_unsigned(&mut Cursor::new(&buffer)).unwrap();
+
+            // Assert the original matches the decoded value
+            assert_eq!(
+                decoded, value,
+                "Unsigned varint failed for value: {}",
+                value
+            );
+        }
+
+        // Assert specific binary encodings for some unsigned values
+        buffer.clear();
+        varint::encode_unsigned(127, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![0x7F]); // Single byte for 127
+
+        buffer.clear();
+        varint::encode_unsigned(128, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![0x80, 0x01]); // Two bytes for 128
+
+        buffer.clear();
+        varint::encode_unsigned(300, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![0xAC, 0x02]); // Two bytes for 300
+
+        // --- Test signed varint encoding/decoding ---
+        // Test values including edge cases, negatives, and zero
+        let signed_test_values = vec![
+            0i64,
+            1i64,
+            -1i64,    // Smallest negative
+            63i64,    // Zigzag-encoded as 126
+            -64i64,   // Zigzag-encoded as 127
+            64i64,    // Zigzag-encoded as 128 (2-byte transition)
+            -64i64,   // Zigzag-encoded as 129
+            1023i64,  // Zigzag-encoded as 2046
+            -1024i64, // Zigzag-encoded as 2047
+            i64::MAX, // Maximum possible i64
+            i64::MIN, // Most negative i64 (edge case)
+        ];
+
+        for value in signed_test_values {
+            buffer.clear();
+
+            // Encode the signed value
+            varint::encode_signed(value, &mut buffer).unwrap();
+
+            // Decode the signed value
+            let decoded = varint::decode_signed(&mut Cursor::new(&buffer)).unwrap();
+
+            // Assert the original matches the decoded value
+            assert_eq!(decoded, value, "Signed varint failed for value: {}", value);
+        }
+
+        // Assert specific binary encodings for some signed values
+        buffer.clear();
+        varint::encode_signed(0, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![0x00]); // Special case: 0
+
+        buffer.clear();
+        varint::encode_signed(-1, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![0x01]); // Negative numbers zigzag encoded correctly
+
+        buffer.clear();
+        varint::encode_signed(1, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![0x02]);
+
+        buffer.clear();
+        varint::encode_signed(-2, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![0x03]); // Negative value -2
+
+        buffer.clear();
+        varint::encode_signed(i64::MAX, &mut buffer).unwrap();
+        assert_eq!(buffer.len(), 10); // Maximum i64 uses 10 bytes in varint encoding
+
+        buffer.clear();
+        varint::encode_signed(i64::MIN, &mut buffer).unwrap();
+        assert_eq!(buffer.len(), 10); // Minimum i64 also uses 10 bytes
+    }
+
+    #[test]
+    fn test_header_write_and_read() {
+        let assets = vec![
+            "BTCUSDT".to_string(),
+            "ETHUSDT".to_string(),
+            "SOLUSDT".to_string(),
+        ];
+
+        let mut encoder = BinaryFormat::new().with_assets(assets.clone()).unwrap();
+        let mut buffer = Vec::new();
+
+        // Reference values
+        let reference_timestamp = 1700000000000;
+        let reference_prices = vec![45000.0, 2500.5, 120.75];
+        let reference_quantities = vec![1.0, 10.0, 100.0];
+
+        // Write header
+        encoder
+            .write_header(
+                &mut buffer,
+                reference_timestamp,
+                &reference_prices,
+                &reference_quantities,
+            )
+            .unwrap();
+
+        // Read header
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&buffer)).unwrap();
+
+        // Assert that the header values are correct
+        assert_eq!(decoder.assets, assets);
+        assert_eq!(decoder.states.len(), assets.len());
+        assert_eq!(decoder.states[0].last_timestamp, reference_timestamp);
+        assert_eq!(decoder.states[0].last_price, reference_prices[0]);
+        assert_eq!(decoder.states[0].last_quantity, reference_quantities[0]);
+    }
+
+    #[test]
+    fn test_stream_magic() {
+        check_stream_magic(STREAM_MAGIC).unwrap();
+        assert!(matches!(
+            check_stream_magic(b"PSHFT\x02"),
+            Err(BinaryFormatError::UnsupportedStreamVersion(2))
+        ));
+        for bad in [
+            &b"START"[..],
+            b"",
+            b"PSHFT",
+            b"PSHFT\x01\x01",
+            b"\x01\x03BTC",
+        ] {
+            assert!(
+                matches!(
+                    check_stream_magic(bad),
+                    Err(BinaryFormatError::BadStreamMagic(_))
+                ),
+                "{:?} accepted",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_header_len_skips_to_the_first_record() {
+        let trade = Trade {
+            symbol: "ETHUSDT".to_string(),
+            timestamp: 1700000001000,
+            price: 2501.0,
+            quantity: 2.0,
+            is_buyer_maker: true,
+        };
+        for header_length in [true, false] {
+            let (mut encoder, header) = BinaryFormat::builder()
+                .reference_timestamp(1700000000000)
+                .header_length(header_length)
+                .assets(vec![
+                    ("BTCUSDT".to_string(), 45000.0, 1.0, DEFAULT_SCALE),
+                    ("ETHUSDT".to_string(), 2500.0, 10.0, DEFAULT_SCALE),
+                ])
+                .build()
+                .unwrap();
+            let version = if header_length {
+                VERSION_V5
+            } else {
+                VERSION_V4
+            };
+            assert_eq!(header[..4], HEADER_MAGIC);
+            assert_eq!(header[4], version);
+
+            // A recording: the stream magic, the header, then its records
+            let mut file = STREAM_MAGIC.to_vec();
+            file.extend_from_slice(&header);
+            file.extend(encoder.encode(&trade).unwrap());
+            let mut cursor = Cursor::new(&file);
+            cursor.set_position(STREAM_MAGIC.len() as u64);
+            let len = BinaryFormat::header_len(&cursor).unwrap();
+            assert_eq!(len, header.len());
+            assert_eq!(cursor.position(), STREAM_MAGIC.len() as u64);
+
+            // Skipping it leaves records a decoder set up from the header can read
+            let mut decoder = BinaryFormat::new();
+            decoder.read_header(&mut cursor).unwrap();
+            assert_eq!(decoder.has_header_length(), header_length);
+            assert_eq!(cursor.position() as usize, STREAM_MAGIC.len() + len);
+            let decoded = decoder.read_message(&mut cursor).unwrap();
+            assert_eq!(decoded.symbol, "ETHUSDT");
+            assert_eq!(decoded.price, 2501.0);
+        }
+
+        // Legacy headers can't state their length, and are parsed to find it
+        let mut legacy = BinaryFormat::new()
+            .with_legacy_headers(true)
+            .with_header_length(true)
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap();
+        let mut header = Vec::new();
+        legacy
+            .write_header(&mut header, 1700000000000, &[45000.0], &[1.0])
+            .unwrap();
+        assert_eq!(header[0], VERSION_V1);
+        assert_eq!(
+            BinaryFormat::header_len(&Cursor::new(&header)).unwrap(),
+            header.len()
+        );
+
+        // A stated length that disagrees with the header is refused
+        let (_, mut header) = BinaryFormat::builder()
+            .header_length(true)
+            .assets(vec![("BTCUSDT".to_string(), 45000.0, 1.0, DEFAULT_SCALE)])
+            .build()
+            .unwrap();
+        header[HEADER_MAGIC.len() + 1] += 1;
+        let mut decoder = BinaryFormat::new();
+        assert!(matches!(
+            decoder.read_header(&mut Cursor::new(&header)),
+            Err(BinaryFormatError::InvalidHeaderLength)
+        ));
+    }
+
+    #[test]
+    fn test_more_than_127_assets_round_trip() {
+        let assets: Vec<String> = (0..300).map(|i| format!("A{}USDT", i)).collect();
+        let prices: Vec<f64> = (0..300).map(|i| 100.0 + i as f64).collect();
+        let mut encoder = BinaryFormat::new()
+            .with_header_length(false)
+            .with_assets(assets.clone())
+            .unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1700000000000, &prices, &[1.0; 300])
+            .unwrap();
+        assert_eq!(header[..4], HEADER_MAGIC);
+        assert_eq!(header[4], VERSION_V4);
+        assert_eq!(header[5..7], 300u16.to_le_bytes());
+
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+        assert_eq!(decoder.symbols(), &assets[..]);
+        assert_eq!(decoder.asset_id("A299USDT"), Some(299));
+
+        // Either side of the escape, with the maker flag both ways
+        for id in [0usize, 1, 125, 126, 127, 200, 299] {
+            let trade = Trade {
+                symbol: assets[id].clone(),
+                timestamp: 1700000000000 + id as u64,
+                price: prices[id] + 0.5,
+                quantity: 2.0,
+                is_buyer_maker: id % 2 == 1,
+            };
+            let encoded = encoder.encode(&trade).unwrap();
+            assert_eq!(encoded[0] & 0x7F, id.min(WIDE_ID_ESCAPE as usize) as u8);
+            let decoded = decoder.decode(&encoded).unwrap();
+            assert_eq!(decoded.symbol, trade.symbol);
+            assert_eq!(decoded.is_buyer_maker, trade.is_buyer_maker);
+            assert!((decoded.price - trade.price).abs() <= decoder.price_resolution() / 2.0);
+
+            let keyframe = encoder.encode_keyframe(&trade).unwrap();
+            let record = decoder.read_record(&mut Cursor::new(&keyframe)).unwrap();
+            assert!(matches!(record, Record::Keyframe(t) if t.symbol == trade.symbol));
+            let gap = encoder.encode_gap(&trade.symbol, 7).unwrap();
+            match decoder.read_record(&mut Cursor::new(&gap)).unwrap() {
+                Record::Gap { symbol, dropped } => assert_eq!((symbol, dropped), (trade.symbol, 7)),
+                other => panic!("expected a gap, got {:?}", other),
+            }
+        }
+
+        // Up to 127 assets ids stay in the packed byte alone
+        let mut narrow = BinaryFormat::new()
+            .with_header_length(false)
+            .with_assets(assets[..127].to_vec())
+            .unwrap();
+        let mut header = Vec::new();
+        narrow
+            .write_header(&mut header, 1700000000000, &prices[..127], &[1.0; 127])
+            .unwrap();
+        assert_eq!(header[4..7], [VERSION_V4, 127, 0]);
+        let trade = Trade {
+            symbol: assets[126].clone(),
+            timestamp: 1700000000001,
+            price: prices[126],
+            quantity: 1.0,
+            is_buyer_maker: true,
+        };
+        let encoded = narrow.encode(&trade).unwrap();
+        assert_eq!(encoded[0], 126 | 0x80);
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+        assert_eq!(decoder.decode(&encoded).unwrap().symbol, trade.symbol);
+
+        // Fixed-width records have no room for a wide id
+        let mut fixed = BinaryFormat::new()
+            .with_assets(assets.clone())
+            .unwrap()
+            .with_fixed_width_records(true);
+        assert!(matches!(
+            fixed.write_header(&mut Vec::new(), 0, &prices, &[1.0; 300]),
+            Err(BinaryFormatError::InvalidHeader(_))
+        ));
+        let too_many: Vec<String> = (0..=MAX_ASSETS).map(|i| format!("A{}", i)).collect();
+        assert!(matches!(
+            BinaryFormat::new().with_assets(too_many),
+            Err(BinaryFormatError::TooManyAssets)
+        ));
+    }
+
+    #[test]
+    fn test_symbol_longer_than_255_bytes_is_rejected() {
+        let long = "X".repeat(300);
+        let assets = vec!["BTCUSDT".to_string(), long.clone()];
+        match BinaryFormat::new().with_assets(assets) {
+            Err(BinaryFormatError::InvalidSymbol(msg)) => {
+                assert_eq!(
+                    msg,
+                    "XXXXXXXXXXXXXXXX... is 300 bytes, the header holds at most 255"
+                )
+            }
+            Err(e) => panic!("expected InvalidSymbol, got {:?}", e),
+            Ok(_) => panic!("accepted a 300 byte symbol"),
+        }
+
+        // 255 bytes still fit, and round-trip
+        let longest = "Y".repeat(255);
+        let mut encoder = BinaryFormat::new()
+            .with_assets(vec![longest.clone()])
+            .unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 0, &[1.0], &[1.0])
+            .unwrap();
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+        assert_eq!(decoder.symbols(), &[longest]);
+    }
+
+    #[test]
+    fn test_header_without_magic_is_not_a_newer_version() {
+        let mut decoder = BinaryFormat::new();
+        // Zeroed memory, eg: a SHM region nothing wrote yet, and other garbage
+        for garbage in [vec![0u8; 64], vec![9u8; 64]] {
+            match decoder.read_header(&mut Cursor::new(&garbage)) {
+                Err(BinaryFormatError::BadMagic(start)) => assert_eq!(start, garbage[..1]),
+                other => panic!("expected BadMagic, got {:?}", other.err()),
+            }
+        }
+        let mut wrong = b"PSHX".to_vec();
+        wrong.extend([VERSION_V4; 60]);
+        assert!(matches!(
+            decoder.read_header(&mut Cursor::new(&wrong)),
+            Err(BinaryFormatError::BadMagic(start)) if start == b"PSHX"
+        ));
+        // The stream magic frame isn't a header either
+        assert!(matches!(
+            decoder.read_header(&mut Cursor::new(&STREAM_MAGIC.to_vec())),
+            Err(BinaryFormatError::BadMagic(_))
+        ));
+        assert!(BinaryFormat::from_header_frame(&wrong).is_none());
+
+        // Behind the magic, an unknown version is a newer producer's
+        let mut newer = HEADER_MAGIC.to_vec();
+        newer.extend([9u8; 60]);
+        assert!(matches!(
+            decoder.read_header(&mut Cursor::new(&newer)),
+            Err(BinaryFormatError::InvalidVersion(9))
+        ));
+    }
+
+    #[test]
+    fn test_legacy_headers_written_on_request() {
+        let trade = Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: 1700000000001,
+            price: 45001.0,
+            quantity: 1.5,
+            is_buyer_maker: false,
+        };
+        let mut encoder = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap()
+            .with_legacy_headers(true);
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1700000000000, &[45000.0], &[1.0])
+            .unwrap();
+        // A v1 header, starting with its version as consumers before the magic expect
+        assert_eq!(header[..2], [VERSION_V1, 1]);
+        let encoded = encoder.encode(&trade).unwrap();
+
+        // Read by default, but not taken for a header mid-stream
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+        assert_eq!(decoder.version(), VERSION_V1);
+        assert!(!decoder.legacy_headers());
+        assert!(BinaryFormat::from_header_frame(&header).is_none());
+        assert!(BinaryFormat::from_legacy_header_frame(&header).is_some());
+        let decoded = decoder.decode(&encoded).unwrap();
+        assert_eq!(
+            (decoded.timestamp, decoded.price),
+            (trade.timestamp, trade.price)
+        );
+
+        // A legacy decoder still reads a current header
+        let mut current = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap();
+        let mut header = Vec::new();
+        current
+            .write_header(&mut header, 1700000000000, &[45000.0], &[1.0])
+            .unwrap();
+        let mut legacy = BinaryFormat::new().with_legacy_headers(true);
+        legacy.read_header(&mut Cursor::new(&header)).unwrap();
+        assert_eq!(legacy.version(), VERSION_V5);
+        let decoded = legacy.decode(&current.encode(&trade).unwrap()).unwrap();
+        assert_eq!(
+            (decoded.timestamp, decoded.price),
+            (trade.timestamp, trade.price)
+        );
+    }
+
+    #[test]
+    fn test_asset_ids_follow_header_order() {
+        let assets: Vec<String> = ["SOLUSDT", "BTCUSDT", "ETHUSDT"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let mut encoder = BinaryFormat::new().with_assets(assets.clone()).unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(
+                &mut header,
+                1700000000000,
+                &[120.0, 45000.0, 2500.0],
+                &[1.0; 3],
+            )
+            .unwrap();
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+
+        for (idx, symbol) in assets.iter().enumerate() {
+            assert_eq!(encoder.asset_id(symbol), Some(idx as u16));
+            assert_eq!(decoder.asset_id(symbol), Some(idx as u16));
+            assert_eq!(decoder.symbol_for_id(idx as u16), Some(symbol.as_str()));
+        }
+        assert_eq!(decoder.asset_id("XRPUSDT"), None);
+        assert_eq!(decoder.symbol_for_id(3), None);
+
+        // The id on the wire is the header position, whatever order trades come in
+        let trade = Trade {
+            symbol: "ETHUSDT".to_string(),
+            timestamp: 1700000000001,
+            price: 2501.0,
+            quantity: 2.0,
+            is_buyer_maker: false,
+        };
+        let encoded = encoder.encode(&trade).unwrap();
+        assert_eq!(encoded[0] & 0x7F, 2);
+        assert_eq!(decoder.decode(&encoded).unwrap().symbol, "ETHUSDT");
+    }
+
+    #[test]
+    fn test_duplicate_asset_rejected() {
+        let assets = ["BTCUSDT", "ETHUSDT", "BTCUSDT"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        match BinaryFormat::new().with_assets(assets) {
+            Err(BinaryFormatError::DuplicateSymbol(symbol)) => assert_eq!(symbol, "BTCUSDT"),
+            other => panic!("expected DuplicateSymbol, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_corrupt_header_rejected() {
+        let mut encoder = BinaryFormat::new()
+            .with_header_length(false)
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.5])
+            .unwrap();
+        // magic, version, count, symbol length, symbol, timestamp, then the price
+        let price_at = HEADER_MAGIC.len() + 4 + "BTCUSDT".len() + 8;
+        assert_eq!(header[price_at..price_at + 8], 45000.0f64.to_le_bytes());
+
+        let corrupt = |at: usize, bytes: &[u8]| {
+            let mut header = header.clone();
+            header[at..at + bytes.len()].copy_from_slice(bytes);
+            BinaryFormat::new().read_header(&mut Cursor::new(&header))
+        };
+        match corrupt(price_at, &f64::NAN.to_le_bytes()) {
+            Err(BinaryFormatError::InvalidHeader(msg)) => {
+                assert_eq!(msg, "reference price NaN for BTCUSDT")
+            }
+            other => panic!("expected InvalidHeader, got {:?}", other),
+        }
+        assert!(matches!(
+            corrupt(price_at + 8, &(-1.5f64).to_le_bytes()),
+            Err(BinaryFormatError::InvalidHeader(_))
+        ));
+        corrupt(price_at, &0.0f64.to_le_bytes()).unwrap();
+
+        // A v1 header's asset count is capped by the packed byte
+        let mut legacy = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap()
+            .with_legacy_headers(true);
+        let mut header = Vec::new();
+        legacy
+            .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.5])
+            .unwrap();
+        header[1] = 200;
+        assert!(matches!(
+            BinaryFormat::new().read_header(&mut Cursor::new(&header)),
+            Err(BinaryFormatError::TooManyAssets)
+        ));
+    }
+
+    #[test]
+    fn test_sub_step_deltas_do_not_drift() {
+        // Alternating +0.8 and -0.6 steps: each move lands off the grid, and
+        // truncating them would decode low every time, 0.3 steps on average
+        let step = 1.0 / DEFAULT_SCALE;
+        let mut encoder = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1700000000000, &[100.0], &[1.0])
+            .unwrap();
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+
+        let mut price = 100.0;
+        let mut total_error = 0.0;
+        let count = 1000;
+        for i in 0..count {
+            price += if i % 2 == 0 { 0.8 } else { -0.6 } * step;
+            let trade = Trade {
+                symbol: "BTCUSDT".to_string(),
+                timestamp: 1700000000001 + i as u64,
+                price,
+                quantity: 1.0,
+                is_buyer_maker: false,
+            };
+            let decoded = decoder.decode(&encoder.encode(&trade).unwrap()).unwrap();
+            let error = decoded.price - price;
+            assert!(error.abs() <= 0.5 * step * (1.0 + 1e-6), "{}: {}", i, error);
+            total_error += error;
+        }
+        assert!((total_error / count as f64).abs() < 0.01 * step);
+    }
+
+    #[test]
+    fn test_single_trade_encoding_and_decoding() {
+        let assets = vec![
+            "BTCUSDT".to_string(),
+            "ETHUSDT".to_string(),
+            "SOLUSDT".to_string(),
+        ];
+
+        let mut encoder = BinaryFormat::new().with_assets(assets.clone()).unwrap();
+        let mut buffer = Vec::new();
+
+        // Write header
+        let reference_timestamp = 1700000000000;
+        let reference_prices = vec![45000.0, 2500.5, 120.75];
+        let reference_quantities = vec![1.0, 10.0, 100.0];
+        encoder
+            .write_header(
+                &mut buffer,
+                reference_timestamp,
+                &reference_prices,
+                &reference_quantities,
+            )
+            .unwrap();
+
+        // Encode a single trade
+        let trade = Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: 1700000001000, // Delta = +1000 ms
+            price: 45001.0,           // Delta = +1.0
+            quantity: 1.5,            // Delta = +0.5
+            is_buyer_maker: true,
+        };
+
+        let encoded_trade = encoder.encode(&trade).unwrap();
+        buffer.extend_from_slice(&encoded_trade);
+
+        // Decode the trade
+        let mut decoder = BinaryFormat::new();
+        let mut cursor = Cursor::new(&buffer);
+        decoder.read_header(&mut cursor).unwrap();
+        let decoded_trade = decoder.read_message(&mut cursor).unwrap();
+
+        // Compare decoded trade with the original
+        assert_eq!(decoded_trade.symbol, trade.symbol);
+        assert_eq!(decoded_trade.timestamp, trade.timestamp);
+        assert!((decoded_trade.price - trade.price).abs() <= decoder.price_resolution() / 2.0);
+        assert!(
+            (decoded_trade.quantity - trade.quantity).abs() <= decoder.quantity_resolution() / 2.0
+        );
+        assert_eq!(decoded_trade.is_buyer_maker, trade.is_buyer_maker);
+    }
+
+    #[test]
+    fn test_batch_trade_encoding_and_decoding() {
+        let assets = vec![
+            "BTCUSDT".to_string(),
+            "ETHUSDT".to_string(),
+            "SOLUSDT".to_string(),
+        ];
+
+        let mut encoder = BinaryFormat::new().with_assets(assets.clone()).unwrap();
+        let mut buffer = Vec::new();
+
+        // Write header
+        let reference_timestamp = 1700000000000;
+        let reference_prices = vec![45000.0, 2500.5, 120.75];
+        let reference_quantities = vec![1.0, 10.0, 100.0];
+        encoder
+            .write_header(
+                &mut buffer,
+                reference_timestamp,
+                &reference_prices,
+                &reference_quantities,
+            )
+            .unwrap();
+
+        // Prepare a batch of trades
+        let trades = vec![
+            Trade {
+                symbol: "BTCUSDT".to_string(),
+                timestamp: 1700000001000, // Delta = +1000 ms
+                price: 45001.0,           // Delta = +1.0
+                quantity: 1.5,            // Delta = +0.5
+                is_buyer_maker: true,
+            },
+            Trade {
+                symbol: "ETHUSDT".to_string(),
+                timestamp: 1700000002000, // Delta = +2000 ms
+                price: 2501.5,            // Delta = +1.0
+                quantity: 10.5,           // Delta = +0.5
+                is_buyer_maker: false,
+            },
+            Trade {
+                symbol: "SOLUSDT".to_string(),
+                timestamp: 1700000003000, // Delta = +3000 ms
+                price: 121.0,             // Delta = +0.25
+                quantity: 100.25,         // Delta = +0.25
+                is_buyer_maker: true,
+            },
+        ];
+
+        for trade in &trades {
+            let encoded_trade = encoder.encode(trade).unwrap();
+            buffer.extend_from_slice(&encoded_trade);
+        }
+
+        // Decode trades
+        let mut decoder = BinaryFormat::new();
+        let mut records = &buffer[..];
+        decoder.read_header_slice(&mut records).unwrap();
+
+        let decoded_trades: Vec<Trade> = decoder
+            .decode_all(records)
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        // Verify decoded trades
+        assert_eq!(trades.len(), decoded_trades.len());
+        for (original, decoded) in trades.iter().zip(decoded_trades.iter()) {
+            assert_eq!(original.symbol, decoded.symbol);
+            assert_eq!(original.timestamp, decoded.timestamp);
+            assert!((original.price - decoded.price).abs() <= decoder.price_resolution() / 2.0);
+            assert!(
+                (original.quantity - decoded.quantity).abs() <= decoder.quantity_resolution() / 2.0
+            );
+            assert_eq!(original.is_buyer_maker, decoded.is_buyer_maker);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_decode_to_json_keeps_websocket_meanings() {
+        use crate::binance::TradeMessage;
+
+        let ws = r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1700000000100,"T":1700000000099,"s":"BTCUSDT","t":5001,"p":"45000.10","q":"0.250","X":"MARKET","m":true}}"#;
+        let trade = TradeMessage::from_ws_text(ws).unwrap().to_trade().unwrap();
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1700000000000)
+            .assets(vec![("BTCUSDT".to_string(), 45000.0, 1.0, DEFAULT_SCALE)])
+            .build()
+            .unwrap();
+        let mut records = Vec::new();
+        encoder.encode_into(&trade, &mut records).unwrap();
+        records.extend(encoder.encode_heartbeat(1700000000200).unwrap());
+        encoder.encode_into(&trade, &mut records).unwrap();
+
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+        let json = decoder.decode_to_json(&records).unwrap();
+        let lines: Vec<&str> = json.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", json);
+
+        let value: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(value["symbol"], "BTCUSDT"); // s
+        assert_eq!(value["timestamp"], 1700000000099u64); // T, trade time
+        assert_eq!(value["price"], 45000.1); // p
+        assert_eq!(value["quantity"], 0.25); // q
+        assert_eq!(value["is_buyer_maker"], true); // m
+        assert_eq!(value.as_object().unwrap().len(), 5);
+
+        // And back, for tools reading a dump
+        let back: Trade = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(back.price, 45000.1);
+    }
+
+    #[test]
+    fn test_encode_batch_matches_one_at_a_time() {
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let mut batched = BinaryFormat::new().with_assets(assets).unwrap();
+        let mut header = Vec::new();
+        batched
+            .write_header(&mut header, 1700000000000, &[45000.0, 2500.0], &[1.0, 1.0])
+            .unwrap();
+        let mut single = batched.clone();
+
+        let trades: Vec<Trade> = (0..50u64)
+            .map(|i| Trade {
+                symbol: if i % 3 == 0 { "ETHUSDT" } else { "BTCUSDT" }.to_string(),
+                timestamp: 1700000000000 + i * 7,
+                price: if i % 3 == 0 { 2500.0 } else { 45000.0 } + (i as f64 * 0.37).sin(),
+                quantity: 0.001 * (i + 1) as f64,
+                is_buyer_maker: i % 2 == 0,
+            })
+            .collect();
+        let batch = batched.encode_batch(&trades).unwrap();
+        let mut expected = Vec::new();
+        for trade in &trades {
+            single.encode_into(trade, &mut expected).unwrap();
+        }
+        assert_eq!(batch, expected);
+
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+        let decoded: Vec<Trade> = decoder
+            .decode_all(&batch)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(decoded.len(), trades.len());
+        for (decoded, trade) in decoded.iter().zip(&trades) {
+            assert_eq!(decoded.symbol, trade.symbol);
+            assert_eq!(decoded.timestamp, trade.timestamp);
+            assert!((decoded.price - trade.price).abs() <= decoder.price_resolution() / 2.0);
+        }
+
+        // A failing batch leaves the state as it found it
+        let mut bad = trades[..2].to_vec();
+        bad.push(Trade {
+            symbol: "XRPUSDT".to_string(),
+            ..trades[0].clone()
+        });
+        assert!(matches!(
+            batched.encode_batch(&bad),
+            Err(BinaryFormatError::InvalidSymbol(_))
+        ));
+        assert_eq!(
+            batched.encode(&trades[0]).unwrap(),
+            single.encode(&trades[0]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_decode_all_ends_at_the_buffer_or_an_error() {
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1700000000000, &[45000.0, 2500.0], &[1.0, 1.0])
+            .unwrap();
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+
+        let mut records = Vec::new();
+        for (i, symbol) in ["BTCUSDT", "ETHUSDT", "BTCUSDT"].into_iter().enumerate() {
+            let trade = Trade {
+                symbol: symbol.to_string(),
+                timestamp: 1700000000001 + i as u64,
+                price: 100.0 + i as f64,
+                quantity: 1.0,
+                is_buyer_maker: false,
+            };
+            records.extend_from_slice(&encoder.encode(&trade).unwrap());
+        }
+        // A trailing control record ends it cleanly rather than in an EOF error
+        records.extend_from_slice(&encoder.encode_heartbeat(1700000000005).unwrap());
+
+        let timestamps: Vec<u64> = decoder
+            .decode_all(&records)
+            .map(|trade| trade.unwrap().timestamp)
+            .collect();
+        assert_eq!(timestamps, [1700000000001, 1700000000002, 1700000000003]);
+        assert_eq!(decoder.decode_all(&[]).count(), 0);
+
+        // An asset id past the header's is one error, then the end
+        let mut bad = encoder
+            .encode(&Trade {
+                symbol: "ETHUSDT".to_string(),
+                timestamp: 1700000000006,
+                price: 2501.0,
+                quantity: 1.0,
+                is_buyer_maker: false,
+            })
+            .unwrap();
+        bad.extend_from_slice(&[0x05, 0, 0, 0, 0, 0, 0]);
+        let results: Vec<_> = decoder.decode_all(&bad).collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            results[1],
+            Err(BinaryFormatError::InvalidAssetId(_))
+        ));
+    }
+
+    #[test]
+    fn test_try_read_message_partial_buffer() {
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets.clone()).unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1700000000000, &[45000.0, 2500.5], &[1.0, 10.0])
+            .unwrap();
+
+        let first = Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: 1700000001000,
+            price: 45001.0,
+            quantity: 1.5,
+            is_buyer_maker: true,
+        };
+        let second = Trade {
+            symbol: "ETHUSDT".to_string(),
+            timestamp: 1700000002000,
+            price: 2501.5,
+            quantity: 10.5,
+            is_buyer_maker: false,
+        };
+        let mut stream = encoder.encode(&first).unwrap();
+        let first_len = stream.len();
+        stream.extend_from_slice(&encoder.encode(&second).unwrap());
+
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+
+        // One byte short of the first record: nothing consumed, state untouched
+        assert!(
+            decoder
+                .try_read_message(&stream[..first_len - 1])
+                .unwrap()
+                .is_none()
+        );
+        assert!(decoder.try_read_message(&[]).unwrap().is_none());
+
+        // Exactly enough bytes for the first record
+        let (trade, consumed) = decoder
+            .try_read_message(&stream[..first_len])
+            .unwrap()
+            .unwrap();
+        assert_eq!(consumed, first_len);
+        assert_eq!(trade.symbol, first.symbol);
+        assert_eq!(trade.timestamp, first.timestamp);
+
+        // The remainder holds exactly one more record
+        let rest = &stream[consumed..];
+        assert!(
+            decoder
+                .try_read_message(&rest[..rest.len() - 1])
+                .unwrap()
+                .is_none()
+        );
+        let (trade, consumed) = decoder.try_read_message(rest).unwrap().unwrap();
+        assert_eq!(consumed, rest.len());
+        assert_eq!(trade.symbol, second.symbol);
+        assert_eq!(trade.timestamp, second.timestamp);
+        assert!((trade.price - second.price).abs() <= decoder.price_resolution() / 2.0);
+
+        // Malformed data is still a real error
+        assert!(decoder.try_read_message(&[0x05]).is_err());
+    }
+
+    #[test]
+    fn test_stream_decoder_resumes_split_records() {
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
+        let mut stream = Vec::new();
+        encoder
+            .write_header(&mut stream, 1700000000000, &[45000.0, 2500.5], &[1.0, 10.0])
+            .unwrap();
+        let header_len = stream.len();
+        // Large moves make multi-byte varints to split inside
+        let trades: Vec<Trade> = (0..6u64)
+            .map(|i| Trade {
+                symbol: if i % 2 == 0 { "BTCUSDT" } else { "ETHUSDT" }.to_string(),
+                timestamp: 1700000000000 + i * 3_600_000,
+                price: if i % 2 == 0 { 45000.0 } else { 2500.5 } + i as f64 * 1234.5,
+                quantity: 1.0 + i as f64,
+                is_buyer_maker: i % 3 == 0,
+            })
+            .collect();
+        for (i, trade) in trades.iter().enumerate() {
+            stream.extend_from_slice(&encoder.encode(trade).unwrap());
+            if i == 2 {
+                stream.extend_from_slice(&encoder.encode_heartbeat(1700000000000).unwrap());
+            }
+        }
+
+        let check = |decoded: &[Trade]| {
+            assert_eq!(decoded.len(), trades.len());
+            for (decoded, trade) in decoded.iter().zip(&trades) {
+                assert_eq!(decoded.symbol, trade.symbol);
+                assert_eq!(decoded.timestamp, trade.timestamp);
+                assert!((decoded.price - trade.price).abs() < 1e-4);
+            }
+        };
+
+        // Two chunks split at every offset, header included
+        for split in 0..=stream.len() {
+            let mut decoder = StreamDecoder::new();
+            let mut decoded = decoder.push_bytes(&stream[..split]).unwrap();
+            assert_eq!(decoder.decoder().is_some(), split >= header_len);
+            decoded.extend(decoder.push_bytes(&stream[split..]).unwrap());
+            check(&decoded);
+            assert_eq!(decoder.pending_bytes(), 0);
+        }
+
+        // One byte at a time
+        let mut decoder = StreamDecoder::new();
+        let mut decoded = Vec::new();
+        for byte in &stream {
+            decoded.extend(decoder.push_bytes(std::slice::from_ref(byte)).unwrap());
+        }
+        check(&decoded);
+
+        // Garbage is still an error
+        let mut decoder = StreamDecoder::new();
+        assert!(decoder.push_bytes(&[0x09]).is_err());
+    }
+
+    #[test]
+    fn test_self_check() {
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let mut encoder = BinaryFormat::new()
+            .with_assets(assets)
+            .unwrap()
+            .with_self_check(true);
+        let mut buffer = Vec::new();
+        encoder
+            .write_header(&mut buffer, 1700000000000, &[45000.0, 2500.5], &[1.0, 10.0])
+            .unwrap();
+
+        let trades = [
+            ("BTCUSDT", 1700000001000, 45001.0, 1.5, true),
+            ("ETHUSDT", 1700000001500, 2499.25, 12.0, false),
+            ("BTCUSDT", 1700000002000, 44999.5, 0.001, false),
+        ];
+        for (symbol, timestamp, price, quantity, is_buyer_maker) in trades {
+            let trade = Trade {
+                symbol: symbol.to_string(),
+                timestamp,
+                price,
+                quantity,
+                is_buyer_maker,
+            };
+            encoder.encode(&trade).unwrap();
+        }
+        let keyframe = Trade {
+            symbol: "ETHUSDT".to_string(),
+            timestamp: 1700000003000,
+            price: 2510.0,
+            quantity: 3.0,
+            is_buyer_maker: true,
+        };
+        encoder.encode_keyframe(&keyframe).unwrap();
+
+        // A price that fits the fixed-point varint but is too large for an f64
+        // to hold to the scale's resolution doesn't round-trip
+        let broken = Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: 1700000004000,
+            price: 3522925410458.562,
+            quantity: 1.0,
+            is_buyer_maker: false,
+        };
+        assert!(matches!(
+            encoder.encode(&broken),
+            Err(BinaryFormatError::SelfCheckFailed(_))
+        ));
+        // One past what an i64 holds is refused before the self-check sees it
+        let broken = Trade {
+            price: 1e20,
+            ..broken
+        };
+        assert!(matches!(
+            encoder.encode(&broken),
+            Err(BinaryFormatError::Overflow)
+        ));
+
+        // The failed trade was rolled back, so the stream carries on cleanly
+        let next = Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: 1700000005000,
+            price: 45002.0,
+            quantity: 1.0,
+            is_buyer_maker: false,
+        };
+        encoder.encode(&next).unwrap();
+    }
+
+    #[test]
+    fn test_negative_and_large_timestamp_deltas() {
+        let assets = vec!["BTCUSDT".to_string()];
+        let reference_timestamp = 1700000000000;
+        let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
+        let mut buffer = Vec::new();
+        encoder
+            .write_header(&mut buffer, reference_timestamp, &[45000.0], &[1.0])
+            .unwrap();
+
+        // Trade executed before the reference stats were fetched
+        let early = Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: reference_timestamp - 750,
+            price: 44999.0,
+            quantity: 0.5,
+            is_buyer_maker: false,
+        };
+        // Then an hour of silence
+        let late = Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: early.timestamp + 60 * 60 * 1000,
+            price: 45100.0,
+            quantity: 2.0,
+            is_buyer_maker: true,
+        };
+
+        buffer.extend_from_slice(&encoder.encode(&early).unwrap());
+        let late_encoded = encoder.encode(&late).unwrap();
+        // packed byte + 4-byte ts delta + price delta + qty
+        assert_eq!(late_encoded.len(), 1 + 4 + 4 + 3);
+        buffer.extend_from_slice(&late_encoded);
+
+        let mut decoder = BinaryFormat::new();
+        let mut cursor = Cursor::new(&buffer);
+        decoder.read_header(&mut cursor).unwrap();
+        for expected in [&early, &late] {
+            let decoded = decoder.read_message(&mut cursor).unwrap();
+            assert_eq!(decoded.timestamp, expected.timestamp);
+            assert!((decoded.price - expected.price).abs() <= decoder.price_resolution() / 2.0);
+            assert_eq!(decoded.is_buyer_maker, expected.is_buyer_maker);
+        }
+    }
+
+    #[test]
+    fn test_decoded_within_resolution() {
+        use rand::{Rng, SeedableRng, rngs::StdRng};
+
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets.clone()).unwrap();
+        let mut buffer = Vec::new();
+        encoder
+            .write_header(&mut buffer, 1700000000000, &[45000.0, 2500.0], &[1.0, 1.0])
+            .unwrap();
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&buffer)).unwrap();
+
+        // A long random walk: any accumulated drift would show up by the end
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut prices = [45000.0, 2500.0];
+        for i in 0..10_000u64 {
+            let idx = (i % 2) as usize;
+            prices[idx] += rng.random_range(-5.0..5.0);
+            let trade = Trade {
+                symbol: assets[idx].clone(),
+                timestamp: 1700000000000 + i,
+                price: prices[idx],
+                quantity: rng.random_range(0.0..50.0),
+                is_buyer_maker: rng.random(),
+            };
+            let decoded = decoder.decode(&encoder.encode(&trade).unwrap()).unwrap();
+            assert!((decoded.price - trade.price).abs() <= decoder.price_resolution() / 2.0);
+            assert!(
+                (decoded.quantity - trade.quantity).abs() <= decoder.quantity_resolution() / 2.0
+            );
+        }
+    }
+
+    #[test]
+    fn test_heartbeat_skipped_by_read_message() {
+        let mut encoder = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap();
+        let mut buffer = Vec::new();
+        encoder
+            .write_header(&mut buffer, 1700000000000, &[45000.0], &[1.0])
+            .unwrap();
+        let header_len = buffer.len();
+
+        let trade = Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: 1700000001000,
+            price: 45001.0,
+            quantity: 1.5,
+            is_buyer_maker: false,
+        };
+        let heartbeat = encoder.encode_heartbeat(1700000000500).unwrap();
+        buffer.extend_from_slice(&heartbeat);
+        buffer.extend_from_slice(&encoder.encode(&trade).unwrap());
+
+        let mut decoder = BinaryFormat::new();
+        let mut cursor = Cursor::new(&buffer);
+        decoder.read_header(&mut cursor).unwrap();
+        match decoder.read_record(&mut cursor.clone()).unwrap() {
+            Record::Heartbeat(ts) => assert_eq!(ts, 1700000000500),
+            other => panic!("expected heartbeat, got {:?}", other),
+        }
+        assert_eq!(
+            decoder.read_message(&mut cursor).unwrap().timestamp,
+            trade.timestamp
+        );
+
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&buffer)).unwrap();
+        let stream = &buffer[header_len..];
+        assert!(decoder.try_read_message(&heartbeat).unwrap().is_none());
+        let (decoded, consumed) = decoder.try_read_message(stream).unwrap().unwrap();
+        assert_eq!(consumed, stream.len());
+        assert_eq!(decoded.timestamp, trade.timestamp);
+    }
+
+    #[test]
+    fn test_unknown_record_kind_skipped() {
+        let mut encoder = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap();
+        let mut buffer = Vec::new();
+        encoder
+            .write_header(&mut buffer, 1700000000000, &[45000.0], &[1.0])
+            .unwrap();
+        let header_len = buffer.len();
+
+        let trade = |timestamp: u64, price: f64| Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp,
+            price,
+            quantity: 0.25,
+            is_buyer_maker: true,
+        };
+        buffer.extend_from_slice(&encoder.encode(&trade(1700000000001, 45001.0)).unwrap());
+        // A record kind from some future producer, eg: a quote
+        let mut unknown = Vec::new();
+        BinaryFormat::write_control(0x42, &[0x81, 0xFF, 0x00, 0x7F], &mut unknown).unwrap();
+        buffer.extend_from_slice(&unknown);
+        buffer.extend_from_slice(&encoder.encode(&trade(1700000000002, 45002.5)).unwrap());
+
+        let mut decoder = BinaryFormat::new();
+        let mut cursor = Cursor::new(&buffer);
+        decoder.read_header(&mut cursor).unwrap();
+        let mut records = cursor.clone();
+        assert!(decoder.clone().read_record(&mut records).is_ok());
+        assert!(matches!(
+            decoder.clone().read_record(&mut records).unwrap(),
+            Record::Unknown { kind: 0x42 }
+        ));
+
+        let first = decoder.read_message(&mut cursor).unwrap();
+        let second = decoder.read_message(&mut cursor).unwrap();
+        assert_eq!(first.timestamp, 1700000000001);
+        assert_eq!((second.timestamp, second.price), (1700000000002, 45002.5));
+        assert_eq!(cursor.position() as usize, buffer.len());
+
+        // Same through the unframed path
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&buffer)).unwrap();
+        let stream = &buffer[header_len..];
+        let (_, used) = decoder.try_read_message(stream).unwrap().unwrap();
+        let (second, rest) = decoder.try_read_message(&stream[used..]).unwrap().unwrap();
+        assert_eq!(second.price, 45002.5);
+        assert_eq!(used + rest, stream.len());
+    }
+
+    #[test]
+    fn test_unknown_record_kind_with_a_huge_length_is_an_error() {
+        let mut encoder = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1700000000000, &[45000.0], &[1.0])
+            .unwrap();
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+
+        // An unknown kind is skipped by its length, here the largest varint
+        let mut unknown = Vec::new();
+        BinaryFormat::write_control(0x42, &[1, 2, 3], &mut unknown).unwrap();
+        assert_eq!(unknown[2], 3);
+        let mut corrupted = unknown[..2].to_vec();
+        corrupted.extend([0xFF; 9]);
+        corrupted.push(0x01);
+        corrupted.extend_from_slice(&unknown[3..]);
+        match decoder.read_record(&mut Cursor::new(&corrupted)) {
+            Err(BinaryFormatError::IoError(e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof)
+            }
+            other => panic!("expected a short read, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_builder_matches_manual_header() {
+        let reference_timestamp = 1700000000000;
+        let (_, header) = BinaryFormat::builder()
+            .reference_timestamp(reference_timestamp)
+            .assets(vec![
+                ("BTCUSDT".to_string(), 45000.0, 1.0, 100000.0),
+                ("ETHUSDT".to_string(), 2500.5, 10.0, 100000.0),
+            ])
+            .build()
+            .unwrap();
+
+        let mut manual = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()])
+            .unwrap();
+        let mut manual_header = Vec::new();
+        manual
+            .write_header(
+                &mut manual_header,
+                reference_timestamp,
+                &[45000.0, 2500.5],
+                &[1.0, 10.0],
+            )
+            .unwrap();
+        assert_eq!(header, manual_header);
+
+        let bad = [
+            ("BTCUSDT".to_string(), f64::NAN, 1.0, 100000.0),
+            ("BTCUSDT".to_string(), 45000.0, 1.0, 0.0),
+            (String::new(), 45000.0, 1.0, 100000.0),
+        ];
+        for spec in bad {
+            let err = BinaryFormat::builder().assets(vec![spec]).build();
+            assert!(matches!(err, Err(BinaryFormatError::InvalidAssetSpec(_))));
+        }
+        let dup = BinaryFormat::builder()
+            .assets(vec![
+                ("BTCUSDT".to_string(), 45000.0, 1.0, 100000.0),
+                ("BTCUSDT".to_string(), 45000.0, 1.0, 100000.0),
+            ])
+            .build();
+        assert!(matches!(dup, Err(BinaryFormatError::InvalidAssetSpec(_))));
+    }
+
+    #[test]
+    fn test_per_asset_scale_round_trip() {
+        // PEPE-like asset quoted in tiny prices needs a much finer scale than BTC
+        let (mut encoder, mut buffer) = BinaryFormat::builder()
+            .header_length(false)
+            .reference_timestamp(1700000000000)
+            .assets(vec![
+                ("BTCUSDT".to_string(), 45000.0, 1.0, 100.0),
+                ("1000PEPEUSDT".to_string(), 0.0123456, 1000.0, 1e9),
+            ])
+            .build()
+            .unwrap();
+        assert_eq!(buffer[HEADER_MAGIC.len()], VERSION_V4);
+
+        let trades = [
+            Trade {
+                symbol: "BTCUSDT".to_string(),
+                timestamp: 1700000001000,
+                price: 45000.574,
+                quantity: 0.25,
+                is_buyer_maker: false,
+            },
+            Trade {
+                symbol: "1000PEPEUSDT".to_string(),
+                timestamp: 1700000001001,
+                price: 0.012349871,
+                quantity: 1234.0,
+                is_buyer_maker: true,
+            },
+        ];
+        for trade in &trades {
+            buffer.extend_from_slice(&encoder.encode(trade).unwrap());
+        }
+
+        let mut decoder = BinaryFormat::new();
+        let mut cursor = Cursor::new(&buffer);
+        decoder.read_header(&mut cursor).unwrap();
+        assert_eq!(decoder.price_resolution(), 0.01);
+        let btc = decoder.read_message(&mut cursor).unwrap();
+        assert!((btc.price - 45000.574).abs() <= 0.005);
+        let pepe = decoder.read_message(&mut cursor).unwrap();
+        assert!((pepe.price - 0.012349871).abs() <= 0.5e-9);
+        assert_eq!(pepe.quantity, 1234.0);
+    }
+
+    #[test]
+    fn test_separate_quantity_scale_round_trip() {
+        let trade = Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: 1700000001000,
+            price: 45000.12345678,
+            quantity: 0.123,
+            is_buyer_maker: false,
+        };
+        let encode = |quantity_scale: Option<f64>, fixed_width: bool| {
+            let mut builder = BinaryFormat::builder()
+                .reference_timestamp(1700000000000)
+                .fixed_width_records(fixed_width)
+                .assets(vec![("BTCUSDT".to_string(), 45000.0, 0.0, 1e8)]);
+            if let Some(scale) = quantity_scale {
+                builder = builder.quantity_scale(scale);
+            }
+            let (mut encoder, header) = builder.build().unwrap();
+            let record = encoder.encode(&trade).unwrap();
+            (header, record)
+        };
+
+        // Thousandths are all a BTC size needs, so the quantity varint shrinks
+        let (_, shared) = encode(None, false);
+        let (header, record) = encode(Some(1e3), false);
+        assert!(record.len() < shared.len());
+
+        for (header, record) in [(header, record), encode(Some(1e3), true)] {
+            let mut decoder = BinaryFormat::new();
+            decoder.read_header(&mut Cursor::new(&header)).unwrap();
+            assert_eq!(decoder.asset_scale("BTCUSDT"), Some(1e8));
+            assert_eq!(decoder.asset_quantity_scale("BTCUSDT"), Some(1e3));
+            assert_eq!(decoder.quantity_resolution(), 1e-3);
+            let decoded = decoder.decode(&record).unwrap();
+            assert!((decoded.price - trade.price).abs() <= 0.5e-8);
+            assert!((decoded.quantity - trade.quantity).abs() <= 0.5e-3);
+        }
+
+        // Without one quantities stay at the asset scale and the header carries
+        // no extension for it: setting one adds exactly the tag, length and f64
+        let (header, _) = encode(None, false);
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+        assert_eq!(decoder.asset_quantity_scale("BTCUSDT"), Some(1e8));
+        assert_eq!(encode(Some(1e3), false).0.len(), header.len() + 1 + 1 + 8);
+    }
+
+    #[test]
+    fn test_with_scale_and_fixed_point_helpers() {
+        assert_eq!(to_fixed(45000.123456, DEFAULT_SCALE).unwrap(), 4500012346);
+        assert_eq!(from_fixed(4500012346, DEFAULT_SCALE), 45000.12346);
+        assert!(matches!(
+            to_fixed(1e20, DEFAULT_SCALE),
+            Err(BinaryFormatError::Overflow)
+        ));
+
+        let header = |encoder: BinaryFormat| {
+            let mut encoder = encoder.with_assets(vec!["BTCUSDT".to_string()])?;
+            let mut header = Vec::new();
+            encoder.write_header(&mut header, 1700000000000, &[45000.0], &[1.0])?;
+            Ok::<_, BinaryFormatError>((encoder, header))
+        };
+        // `new` is `with_scale` at the defaults, down to the header bytes
+        let (_, default) = header(BinaryFormat::new()).unwrap();
+        let (_, explicit) = header(BinaryFormat::with_scale(DEFAULT_SCALE, DEFAULT_SCALE)).unwrap();
+        assert_eq!(default, explicit);
+        assert_eq!(&default[..4], &HEADER_MAGIC);
+
+        let (mut encoder, header_bytes) = header(BinaryFormat::with_scale(100.0, 1000.0)).unwrap();
+        let trade = Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: 1700000001000,
+            price: 45000.126,
+            quantity: 0.0125,
+            is_buyer_maker: false,
+        };
+        let record = encoder.encode(&trade).unwrap();
+        let mut decoder = BinaryFormat::new();
+        decoder
+            .read_header(&mut Cursor::new(&header_bytes))
+            .unwrap();
+        assert_eq!(decoder.asset_scale("BTCUSDT"), Some(100.0));
+        assert_eq!(decoder.asset_quantity_scale("BTCUSDT"), Some(1000.0));
+        let decoded = decoder.decode(&record).unwrap();
+        // The helpers reproduce the decoder's numbers exactly
+        let delta = to_fixed(trade.price - 45000.0, 100.0).unwrap();
+        assert_eq!(decoded.price, 45000.0 + from_fixed(delta, 100.0));
+        assert_eq!(
+            decoded.quantity,
+            from_fixed(to_fixed(0.0125, 1000.0).unwrap(), 1000.0)
+        );
+
+        assert!(matches!(
+            header(BinaryFormat::with_scale(0.0, DEFAULT_SCALE)),
+            Err(BinaryFormatError::InvalidAssetSpec(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_uses_header_scale() {
+        let trade = Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: 1700000001000,
+            price: 45012.34,
+            quantity: 0.25,
+            is_buyer_maker: false,
+        };
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1700000000000)
+            .assets(vec![("BTCUSDT".to_string(), 45000.0, 1.0, 100.0)])
+            .build()
+            .unwrap();
+        let record = encoder.encode(&trade).unwrap();
+
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+        assert_eq!(decoder.asset_scale("BTCUSDT"), Some(100.0));
+        let decoded = decoder.decode(&record).unwrap();
+        assert!((decoded.price - trade.price).abs() <= decoder.price_resolution() / 2.0);
+        assert!((decoded.quantity - trade.quantity).abs() <= decoder.quantity_resolution() / 2.0);
+
+        // A decoder assuming the default scale, eg: built before per-asset
+        // scales, reads the same record 1000x off
+        let mut stale = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap();
+        let mut default_header = Vec::new();
+        stale
+            .write_header(&mut default_header, 1700000000000, &[45000.0], &[1.0])
+            .unwrap();
+        stale
+            .read_header(&mut Cursor::new(&default_header))
+            .unwrap();
+        assert_eq!(stale.asset_scale("BTCUSDT"), Some(DEFAULT_SCALE));
+        let misread = stale.decode(&record).unwrap();
+        assert!((misread.price - trade.price).abs() > 10.0);
+        assert!((misread.quantity - trade.quantity).abs() > 0.2);
+    }
+
+    #[test]
+    fn test_header_extension_length_is_bounded() {
+        let (_, header) = BinaryFormat::builder()
+            .reference_timestamp(1700000000000)
+            .timestamp_unit(TimestampUnit::Micros)
+            .assets(vec![("BTCUSDT".to_string(), 45000.0, 1.0, DEFAULT_SCALE)])
+            .build()
+            .unwrap();
+        // The only extension, last: tag, one-byte length, unit code
+        let at = header.len() - 3;
+        assert_eq!(header[at..], [EXT_TIMESTAMP_UNIT, 1, 1]);
+        let with_len = |len: &[u8]| {
+            let mut header = header[..=at].to_vec();
+            header.extend_from_slice(len);
+            header.push(1);
+            let mut decoder = BinaryFormat::new();
+            decoder.read_header_slice(&mut &header[..]).unwrap_err()
+        };
+
+        // Past any real extension: refused without reading, let alone allocating
+        let err = with_len(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]);
+        assert!(
+            matches!(err, BinaryFormatError::InvalidHeader(_)),
+            "{:?}",
+            err
+        );
+        // Plausible but longer than the header: a short read
+        let err = with_len(&[0x80, 0x01]);
+        assert!(
+            matches!(&err, BinaryFormatError::IoError(e) if e.kind() == io::ErrorKind::UnexpectedEof),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_microsecond_timestamps_round_trip() {
+        let reference = 1_700_000_000_000_000;
+        let (mut encoder, mut buffer) = BinaryFormat::builder()
+            .header_length(false)
+            .reference_timestamp(reference)
+            .timestamp_unit(TimestampUnit::Micros)
+            .assets(vec![("BTCUSDT".to_string(), 45000.0, 1.0, DEFAULT_SCALE)])
+            .build()
+            .unwrap();
+        assert_eq!(buffer[HEADER_MAGIC.len()], VERSION_V4);
+        let header_len = buffer.len();
+
+        // Sub-millisecond gaps, a keyframe and a heartbeat, all in micros
+        let timestamps = [reference + 1, reference + 251, reference + 1_250_999];
+        for (i, &timestamp) in timestamps.iter().enumerate() {
+            let trade = Trade {
+                symbol: "BTCUSDT".to_string(),
+                timestamp,
+                price: 45000.5,
+                quantity: 0.1,
+                is_buyer_maker: false,
+            };
+            let frame = if i == 1 {
+                encoder.encode_keyframe(&trade)
+            } else {
+                encoder.encode(&trade)
+            };
+            buffer.extend_from_slice(&frame.unwrap());
+        }
+        buffer.extend_from_slice(&encoder.encode_heartbeat(reference + 2_000_000).unwrap());
+
+        let mut decoder = BinaryFormat::new();
+        let mut cursor = Cursor::new(&buffer);
+        decoder.read_header(&mut cursor).unwrap();
+        assert_eq!(decoder.timestamp_unit(), TimestampUnit::Micros);
+        for &timestamp in &timestamps {
+            let trade = decoder
+                .read_record(&mut cursor)
+                .unwrap()
+                .into_trade()
+                .unwrap();
+            assert_eq!(trade.timestamp, timestamp);
+            assert_eq!(
+                decoder.timestamp_unit().to_micros(trade.timestamp),
+                timestamp
+            );
+        }
+        assert!(matches!(
+            decoder.read_record(&mut cursor).unwrap(),
+            Record::Heartbeat(ts) if ts == reference + 2_000_000
+        ));
+
+        // Millisecond streams keep the v1 header (legacy) and convert up
+        let mut header = Vec::new();
+        let mut millis = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap()
+            .with_legacy_headers(true);
+        millis.write_header(&mut header, 0, &[1.0], &[1.0]).unwrap();
+        assert_eq!(header[0], VERSION_V1);
+        assert_eq!(
+            millis.timestamp_unit().to_micros(1_700_000_000_000),
+            reference
+        );
+
+        // A unit this decoder doesn't know is rejected, not guessed
+        let mut header = buffer[..header_len].to_vec();
+        *header.last_mut().unwrap() = 9;
+        assert!(matches!(
+            BinaryFormat::new().read_header(&mut Cursor::new(&header)),
+            Err(BinaryFormatError::UnsupportedTimestampUnit(9))
+        ));
+    }
+
+    #[test]
+    fn test_reset_state_recovers_a_drifted_decoder() {
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1700000000000, &[45000.0, 2500.0], &[1.0, 1.0])
+            .unwrap();
+        let mut in_sync = BinaryFormat::new();
+        in_sync.read_header(&mut Cursor::new(&header)).unwrap();
+        let mut lossy = in_sync.clone();
+
+        let trade = |symbol: &str, timestamp: u64, price: f64| Trade {
+            symbol: symbol.to_string(),
+            timestamp,
+            price,
+            quantity: 2.0,
+            is_buyer_maker: false,
+        };
+        // The lossy decoder misses the first trade and drifts by its delta
+        let missed = encoder
+            .encode(&trade("BTCUSDT", 1700000000001, 45100.0))
+            .unwrap();
+        in_sync.decode(&missed).unwrap();
+        let next = encoder
+            .encode(&trade("BTCUSDT", 1700000000002, 45110.0))
+            .unwrap();
+        assert_eq!(in_sync.decode(&next).unwrap().price, 45110.0);
+        assert_eq!(lossy.decode(&next).unwrap().price, 45010.0);
+
+        let reset = encoder
+            .reset_state(1700000000010, &[45200.0, 2600.0], &[1.0, 3.0])
+            .unwrap();
+        assert_eq!(reset[..2], [CONTROL_ID, KIND_RESET]);
+        for decoder in [&mut in_sync, &mut lossy] {
+            let record = decoder.read_record(&mut Cursor::new(&reset)).unwrap();
+            assert!(matches!(record, Record::Reset(1700000000010)));
+            assert_eq!(decoder.last_price("BTCUSDT"), Some(45200.0));
+            assert!(decoder.last_trade("ETHUSDT").is_none());
+        }
+
+        // Both decode the same absolute prices from here
+        for (symbol, price) in [("BTCUSDT", 45201.5), ("ETHUSDT", 2599.0)] {
+            let encoded = encoder
+                .encode(&trade(symbol, 1700000000011, price))
+                .unwrap();
+            for decoder in [&mut in_sync, &mut lossy] {
+                let decoded = decoder.decode(&encoded).unwrap();
+                assert_eq!(decoded.timestamp, 1700000000011);
+                assert!((decoded.price - price).abs() <= decoder.price_resolution() / 2.0);
+            }
+        }
+
+        assert!(matches!(
+            encoder.reset_state(0, &[1.0], &[1.0]),
+            Err(BinaryFormatError::InvalidAssetSpec(_))
+        ));
+    }
+
+    #[test]
+    fn test_scaled_values_past_i64_are_overflow_errors() {
+        // At scale 1 the fixed-point value is the f64 itself. The largest f64
+        // below 2^63 fits an i64; 2^63 is the first one that doesn't.
+        let largest = 9223372036854774784.0;
+        let first_out = 9223372036854775808.0;
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1700000000000)
+            .assets(vec![("BTCUSDT".to_string(), 0.0, 0.0, 1.0)])
+            .build()
+            .unwrap();
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+        let trade = |price: f64, quantity: f64| Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: 1700000000001,
+            price,
+            quantity,
+            is_buyer_maker: false,
+        };
+
+        for (price, quantity) in [
+            (first_out, 1.0),
+            (-first_out, 1.0),
+            (f64::NAN, 1.0),
+            (1.0, first_out),
+            (1.0, -1.0),
+        ] {
+            let mut buffer = vec![0xAA];
+            assert!(matches!(
+                encoder.encode_into(&trade(price, quantity), &mut buffer),
+                Err(BinaryFormatError::Overflow)
+            ));
+            assert_eq!(buffer, [0xAA], "{price} x {quantity} wrote bytes");
+        }
+
+        // Rejected trades left the state alone, so the boundary values still
+        // delta from the reference and decode exactly
+        for (price, quantity) in [(largest, largest), (0.0, 0.0), (-largest, 1.0)] {
+            let bytes = encoder.encode(&trade(price, quantity)).unwrap();
+            let decoded = decoder.decode(&bytes).unwrap();
+            assert_eq!((decoded.price, decoded.quantity), (price, quantity));
+        }
+    }
+
+    #[test]
+    fn test_quantity_deltas_round_trip() {
+        let quantities = [0.5, 0.5, 0.5, 0.25, 3.00001, 0.5, 0.5];
+        let encode = |deltas: bool| {
+            let (mut encoder, header) = BinaryFormat::builder()
+                .reference_timestamp(1700000000000)
+                .quantity_deltas(deltas)
+                // A reference off the scale's grid still gives both sides one base
+                .assets(vec![(
+                    "BTCUSDT".to_string(),
+                    45000.0,
+                    1.234567891,
+                    DEFAULT_SCALE,
+                )])
+                .build()
+                .unwrap();
+            let records: Vec<Vec<u8>> = quantities
+                .iter()
+                .enumerate()
+                .map(|(i, &quantity)| {
+                    let trade = Trade {
+                        symbol: "BTCUSDT".to_string(),
+                        timestamp: 1700000000001 + i as u64,
+                        price: 45000.0,
+                        quantity,
+                        is_buyer_maker: false,
+                    };
+                    // A keyframe midway re-seats the quantity with a raw f64
+                    if i == 3 {
+                        encoder.encode_keyframe(&trade).unwrap()
+                    } else {
+                        encoder.encode(&trade).unwrap()
+                    }
+                })
+                .collect();
+            (header, records)
+        };
+
+        let (header, deltas) = encode(true);
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+        assert!(decoder.quantity_deltas());
+        for (record, &quantity) in deltas.iter().zip(&quantities) {
+            let decoded = decoder.decode(record).unwrap();
+            assert!((decoded.quantity - quantity).abs() <= decoder.quantity_resolution() / 2.0);
+        }
+
+        // A repeated size takes one byte instead of 50000's three
+        let (_, absolute) = encode(false);
+        assert_eq!(deltas[2].len(), absolute[2].len() - 2);
+        assert_eq!(deltas[6].len(), absolute[6].len() - 2);
+    }
 
-            // Decode the unsigned value
-            let decoded = varint::decode_unsigned(&mut Cursor::new(&buffer)).unwrap();
+    #[test]
+    fn test_checksummed_record_with_a_huge_length_is_an_error() {
+        let (encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1700000000000)
+            .checksum(true)
+            .assets(vec![("BTCUSDT".to_string(), 45000.0, 1.0, DEFAULT_SCALE)])
+            .build()
+            .unwrap();
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
 
-            // Assert the original matches the decoded value
-            assert_eq!(
-                decoded, value,
-                "Unsigned varint failed for value: {}",
-                value
-            );
+        // A heartbeat is id, kind, a one-byte payload length, then the payload.
+        // Corrupt the length to the largest varint: it can't be allocated up
+        // front, and the checksum that would catch it comes after the payload.
+        let heartbeat = encoder.encode_heartbeat(1700000000000).unwrap();
+        assert_eq!(heartbeat[2], 8);
+        let mut corrupted = heartbeat[..2].to_vec();
+        corrupted.extend([0xFF; 9]);
+        corrupted.push(0x01);
+        corrupted.extend_from_slice(&heartbeat[3..]);
+        match decoder.read_record(&mut Cursor::new(&corrupted)) {
+            Err(BinaryFormatError::IoError(e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof)
+            }
+            other => panic!("expected a short read, got {:?}", other),
         }
+    }
 
-        // Assert specific binary encodings for some unsigned values
-        buffer.clear();
-        varint::encode_unsigned(127, &mut buffer).unwrap();
-        assert_eq!(buffer, vec![0x7F]); // Single byte for 127
+    #[test]
+    fn test_checksum_catches_a_flipped_byte() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
 
-        buffer.clear();
-        varint::encode_unsigned(128, &mut buffer).unwrap();
-        assert_eq!(buffer, vec![0x80, 0x01]); // Two bytes for 128
+        let trades: Vec<Trade> = (0..3u64)
+            .map(|i| Trade {
+                symbol: "BTCUSDT".to_string(),
+                timestamp: 1700000000001 + i,
+                price: 45000.5 + i as f64,
+                quantity: 1.5,
+                is_buyer_maker: i == 1,
+            })
+            .collect();
+        for length_prefixed in [false, true] {
+            let (mut encoder, header) = BinaryFormat::builder()
+                .reference_timestamp(1700000000000)
+                .length_prefixed_records(length_prefixed)
+                .checksum(true)
+                .assets(vec![("BTCUSDT".to_string(), 45000.0, 1.0, DEFAULT_SCALE)])
+                .build()
+                .unwrap();
+            let mut decoder = BinaryFormat::new();
+            decoder.read_header(&mut Cursor::new(&header)).unwrap();
+            assert!(decoder.has_checksum());
 
-        buffer.clear();
-        varint::encode_unsigned(300, &mut buffer).unwrap();
-        assert_eq!(buffer, vec![0xAC, 0x02]); // Two bytes for 300
+            // Every record kind still round-trips
+            let heartbeat = encoder.encode_heartbeat(1700000000000).unwrap();
+            let record = decoder.read_record(&mut Cursor::new(&heartbeat)).unwrap();
+            assert!(matches!(record, Record::Heartbeat(1700000000000)));
+            let first = encoder.encode(&trades[0]).unwrap();
+            assert_eq!(decoder.decode(&first).unwrap().price, 45000.5);
 
-        // --- Test signed varint encoding/decoding ---
-        // Test values including edge cases, negatives, and zero
-        let signed_test_values = vec![
-            0i64,
-            1i64,
-            -1i64,    // Smallest negative
-            63i64,    // Zigzag-encoded as 126
-            -64i64,   // Zigzag-encoded as 127
-            64i64,    // Zigzag-encoded as 128 (2-byte transition)
-            -64i64,   // Zigzag-encoded as 129
-            1023i64,  // Zigzag-encoded as 2046
-            -1024i64, // Zigzag-encoded as 2047
-            i64::MAX, // Maximum possible i64
-            i64::MIN, // Most negative i64 (edge case)
-        ];
+            // A flipped bit anywhere in the record is caught before decoding
+            let second = encoder.encode(&trades[1]).unwrap();
+            for at in 0..second.len() {
+                let mut corrupted = second.clone();
+                corrupted[at] ^= 0x04;
+                match decoder.decode(&corrupted) {
+                    Err(BinaryFormatError::ChecksumMismatch { .. }) => {}
+                    // A corrupted prefix can misstate the length instead
+                    Err(BinaryFormatError::IoError(_)) if length_prefixed && at == 0 => {}
+                    other => panic!("byte {} flipped: {:?}", at, other),
+                }
+            }
 
-        for value in signed_test_values {
-            buffer.clear();
+            // Nothing of a rejected record was applied
+            let decoded = decoder.decode(&second).unwrap();
+            assert_eq!(decoded.price, 45001.5);
+            assert!(decoded.is_buyer_maker);
+            let third = encoder.encode(&trades[2]).unwrap();
+            assert_eq!(decoder.decode(&third).unwrap().price, 45002.5);
+        }
 
-            // Encode the signed value
-            varint::encode_signed(value, &mut buffer).unwrap();
+        // Fixed-width records have no room for one
+        assert!(matches!(
+            BinaryFormat::builder()
+                .fixed_width_records(true)
+                .checksum(true)
+                .assets(vec![("BTCUSDT".to_string(), 45000.0, 1.0, DEFAULT_SCALE)])
+                .build(),
+            Err(BinaryFormatError::UnsupportedRecordFlags(_))
+        ));
+    }
 
-            // Decode the signed value
-            let decoded = varint::decode_signed(&mut Cursor::new(&buffer)).unwrap();
+    #[test]
+    fn test_skip_length_prefixed_records() {
+        let (encoder, mut buffer) = BinaryFormat::builder()
+            .header_length(false)
+            .reference_timestamp(1700000000000)
+            .length_prefixed_records(true)
+            .assets(vec![
+                ("BTCUSDT".to_string(), 45000.0, 1.0, DEFAULT_SCALE),
+                ("ETHUSDT".to_string(), 2500.0, 1.0, DEFAULT_SCALE),
+            ])
+            .build()
+            .unwrap();
+        assert_eq!(buffer[HEADER_MAGIC.len()], VERSION_V4);
+        let mut encoder = encoder.with_self_check(true);
+        let header_len = buffer.len();
 
-            // Assert the original matches the decoded value
-            assert_eq!(decoded, value, "Signed varint failed for value: {}", value);
+        let trade = |symbol: &str, timestamp: u64, price: f64| Trade {
+            symbol: symbol.to_string(),
+            timestamp,
+            price,
+            quantity: 0.5,
+            is_buyer_maker: false,
+        };
+        let records = [
+            encoder.encode(&trade("BTCUSDT", 1700000000001, 45001.0)),
+            encoder.encode_heartbeat(1700000000002),
+            encoder.encode(&trade("ETHUSDT", 1700000000003, 2501.0)),
+            encoder.encode_funding("BTCUSDT", 1700000000004, -0.0001),
+            encoder.encode_keyframe(&trade("BTCUSDT", 1700000000005, 45010.0)),
+        ]
+        .map(Result::unwrap);
+        for record in &records {
+            // Each record leads with its own length
+            assert_eq!(record[0] as usize, record.len() - 1);
+            buffer.extend_from_slice(record);
         }
 
-        // Assert specific binary encodings for some signed values
-        buffer.clear();
-        varint::encode_signed(0, &mut buffer).unwrap();
-        assert_eq!(buffer, vec![0x00]); // Special case: 0
+        // Step over everything but the keyframe without decoding
+        let mut decoder = BinaryFormat::new();
+        let mut cursor = Cursor::new(&buffer);
+        decoder.read_header(&mut cursor).unwrap();
+        assert!(decoder.length_prefixed_records());
+        for record in &records[..4] {
+            assert_eq!(decoder.skip_record(&mut cursor).unwrap(), record.len());
+        }
+        match decoder.read_record(&mut cursor).unwrap() {
+            Record::Keyframe(t) => assert_eq!((t.timestamp, t.price), (1700000000005, 45010.0)),
+            other => panic!("expected keyframe, got {:?}", other),
+        }
+        assert_eq!(cursor.position() as usize, buffer.len());
+        assert!(decoder.skip_record(&mut cursor).is_err());
 
-        buffer.clear();
-        varint::encode_signed(-1, &mut buffer).unwrap();
-        assert_eq!(buffer, vec![0x01]); // Negative numbers zigzag encoded correctly
+        // Decoding in full still works, and unprefixed streams can't be skipped
+        let mut decoder = BinaryFormat::new();
+        let mut cursor = Cursor::new(&buffer);
+        decoder.read_header(&mut cursor).unwrap();
+        let prices: Vec<f64> = (0..3)
+            .map(|_| decoder.read_message(&mut cursor).unwrap().price)
+            .collect();
+        assert_eq!(prices, vec![45001.0, 2501.0, 45010.0]);
+        let stream = &buffer[header_len..];
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&buffer)).unwrap();
+        assert!(decoder.try_read_message(&stream[..3]).unwrap().is_none());
+        assert!(matches!(
+            BinaryFormat::new().skip_record(&mut Cursor::new(&buffer)),
+            Err(BinaryFormatError::NotLengthPrefixed)
+        ));
+    }
 
-        buffer.clear();
-        varint::encode_signed(1, &mut buffer).unwrap();
-        assert_eq!(buffer, vec![0x02]);
+    #[test]
+    fn test_spec_reflects_constants() {
+        let spec = spec();
+        assert_eq!(spec["versions"]["written_by_default"], VERSION_V5);
+        assert_eq!(
+            spec["versions"]["supported"],
+            serde_json::json!([VERSION_V1, VERSION_V2, VERSION_V3, VERSION_V4, VERSION_V5])
+        );
+        assert_eq!(spec["header"]["magic"], "PSHD");
+        assert_eq!(spec["scale_factor"], DEFAULT_SCALE);
+        assert_eq!(spec["max_assets"], MAX_ASSETS);
+        assert_eq!(spec["records"]["control"]["asset_id"], CONTROL_ID);
+        assert_eq!(
+            spec["records"]["control"]["kinds"]["keyframe"]["kind"],
+            KIND_KEYFRAME
+        );
+        assert_eq!(
+            spec["records"]["control"]["kinds"]["heartbeat"]["kind"],
+            KIND_HEARTBEAT
+        );
 
-        buffer.clear();
-        varint::encode_signed(-2, &mut buffer).unwrap();
-        assert_eq!(buffer, vec![0x03]); // Negative value -2
+        // What a default encoder writes agrees with the spec
+        let mut header = Vec::new();
+        BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap()
+            .write_header(&mut header, 0, &[1.0], &[1.0])
+            .unwrap();
+        assert_eq!(header[..4], HEADER_MAGIC);
+        assert_eq!(spec["versions"]["written_by_default"], header[4]);
+    }
 
-        buffer.clear();
-        varint::encode_signed(i64::MAX, &mut buffer).unwrap();
-        assert_eq!(buffer.len(), 10); // Maximum i64 uses 10 bytes in varint encoding
+    #[test]
+    fn test_timestamp_underflow_rejected() {
+        let mut decoder = BinaryFormat::new();
+        let mut header = Vec::new();
+        BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap()
+            .write_header(&mut header, 1_000, &[45000.0], &[1.0])
+            .unwrap();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
 
-        buffer.clear();
-        varint::encode_signed(i64::MIN, &mut buffer).unwrap();
-        assert_eq!(buffer.len(), 10); // Minimum i64 also uses 10 bytes
+        // Delta of -2000 ms against a reference of 1000 ms
+        let mut record = vec![0x00];
+        varint::encode_signed(-2_000, &mut record).unwrap();
+        varint::encode_signed(0, &mut record).unwrap();
+        varint::encode_unsigned(100000, &mut record).unwrap();
+        assert!(matches!(
+            decoder.decode(&record),
+            Err(BinaryFormatError::DesyncSuspected(_))
+        ));
+
+        // State untouched, so a sane delta still decodes against the reference
+        let mut record = vec![0x00];
+        varint::encode_signed(500, &mut record).unwrap();
+        varint::encode_signed(0, &mut record).unwrap();
+        varint::encode_unsigned(100000, &mut record).unwrap();
+        assert_eq!(decoder.decode(&record).unwrap().timestamp, 1_500);
     }
 
     #[test]
-    fn test_header_write_and_read() {
-        let assets = vec![
-            "BTCUSDT".to_string(),
-            "ETHUSDT".to_string(),
-            "SOLUSDT".to_string(),
-        ];
+    fn test_single_asset_fast_path_matches_general() {
+        let header = |assets: Vec<&str>| {
+            let assets: Vec<String> = assets.into_iter().map(String::from).collect();
+            let n = assets.len();
+            let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
+            let mut header = Vec::new();
+            encoder
+                .write_header(
+                    &mut header,
+                    1_700_000_000_000,
+                    &vec![45000.0; n],
+                    &vec![1.0; n],
+                )
+                .unwrap();
+            (encoder, header)
+        };
+        let (mut single, single_header) = header(vec!["BTCUSDT"]);
+        let (mut general, _) = header(vec!["BTCUSDT", "ETHUSDT"]);
 
-        let mut encoder = BinaryFormat::new().with_assets(assets.clone()).unwrap();
-        let mut buffer = Vec::new();
+        let mut decoder = BinaryFormat::new();
+        decoder
+            .read_header(&mut Cursor::new(&single_header))
+            .unwrap();
+        for i in 0..50u64 {
+            let trade = Trade {
+                symbol: "BTCUSDT".to_string(),
+                timestamp: 1_700_000_000_000 + i * 7,
+                price: 45000.0 + (i as f64) * 0.37,
+                quantity: 0.1 + (i as f64) * 0.01,
+                is_buyer_maker: i.is_multiple_of(2),
+            };
+            let bytes = single.encode(&trade).unwrap();
+            assert_eq!(bytes, general.encode(&trade).unwrap());
+            let decoded = decoder.decode(&bytes).unwrap();
+            assert_eq!(decoded.symbol, "BTCUSDT");
+            assert_eq!(decoded.timestamp, trade.timestamp);
+            assert_eq!(decoded.is_buyer_maker, trade.is_buyer_maker);
+        }
 
-        // Reference values
-        let reference_timestamp = 1700000000000;
-        let reference_prices = vec![45000.0, 2500.5, 120.75];
-        let reference_quantities = vec![1.0, 10.0, 100.0];
+        let other = Trade {
+            symbol: "ETHUSDT".to_string(),
+            timestamp: 1_700_000_001_000,
+            price: 2500.0,
+            quantity: 1.0,
+            is_buyer_maker: false,
+        };
+        assert!(matches!(
+            single.encode(&other),
+            Err(BinaryFormatError::InvalidSymbol(_))
+        ));
+        let foreign = general.encode(&other).unwrap();
+        assert!(matches!(
+            decoder.decode(&foreign),
+            Err(BinaryFormatError::InvalidAssetId(_))
+        ));
+    }
 
-        // Write header
+    #[test]
+    fn test_funding_rate_round_trips_at_field_scale() {
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let mut encoder = BinaryFormat::new()
+            .with_header_length(false)
+            .with_assets(assets)
+            .unwrap()
+            .with_field_scale(ScaledField::FundingRate, 1e8)
+            .unwrap();
+        let mut header = Vec::new();
         encoder
             .write_header(
-                &mut buffer,
-                reference_timestamp,
-                &reference_prices,
-                &reference_quantities,
+                &mut header,
+                1_700_000_000_000,
+                &[45000.0, 2500.0],
+                &[1.0, 1.0],
             )
             .unwrap();
+        assert_eq!(header[HEADER_MAGIC.len()], VERSION_V4);
 
-        // Read header
         let mut decoder = BinaryFormat::new();
-        decoder.read_header(&mut Cursor::new(&buffer)).unwrap();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+        assert_eq!(decoder.field_scale(ScaledField::FundingRate), 1e8);
+        // Asset scales weren't touched, so that extension is left out
+        assert_eq!(decoder.price_resolution(), 1.0 / DEFAULT_SCALE);
 
-        // Assert that the header values are correct
-        assert_eq!(decoder.assets, assets);
-        assert_eq!(decoder.states.len(), assets.len());
-        assert_eq!(decoder.states[0].last_timestamp, reference_timestamp);
-        assert_eq!(decoder.states[0].last_price, reference_prices[0]);
-        assert_eq!(decoder.states[0].last_quantity, reference_quantities[0]);
+        for (symbol, rate) in [("BTCUSDT", 0.00012345), ("ETHUSDT", -0.0000075)] {
+            let record = encoder
+                .encode_funding(symbol, 1_700_000_000_500, rate)
+                .unwrap();
+            match decoder.read_record(&mut Cursor::new(&record)).unwrap() {
+                Record::Funding {
+                    symbol: decoded,
+                    timestamp,
+                    rate: decoded_rate,
+                } => {
+                    assert_eq!(decoded, symbol);
+                    assert_eq!(timestamp, 1_700_000_000_500);
+                    assert!((decoded_rate - rate).abs() <= 0.5e-8, "{}", decoded_rate);
+                    assert_ne!(decoded_rate, 0.0);
+                }
+                other => panic!("expected funding, got {:?}", other),
+            }
+        }
+
+        // Under the price scale the same rate would come out as 0.00012
+        assert_eq!((0.00012345 * DEFAULT_SCALE) as i64, 12);
+        // A funding record doesn't disturb trade decoding
+        let trade = Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: 1_700_000_001_000,
+            price: 45001.0,
+            quantity: 0.5,
+            is_buyer_maker: false,
+        };
+        let record = encoder.encode(&trade).unwrap();
+        assert_eq!(decoder.decode(&record).unwrap().timestamp, trade.timestamp);
+
+        assert!(
+            BinaryFormat::new()
+                .with_field_scale(ScaledField::FundingRate, 0.0)
+                .is_err()
+        );
     }
 
     #[test]
-    fn test_single_trade_encoding_and_decoding() {
-        let assets = vec![
-            "BTCUSDT".to_string(),
-            "ETHUSDT".to_string(),
-            "SOLUSDT".to_string(),
-        ];
-
-        let mut encoder = BinaryFormat::new().with_assets(assets.clone()).unwrap();
+    fn test_asset_stats_track_decoded_trades() {
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
         let mut buffer = Vec::new();
-
-        // Write header
-        let reference_timestamp = 1700000000000;
-        let reference_prices = vec![45000.0, 2500.5, 120.75];
-        let reference_quantities = vec![1.0, 10.0, 100.0];
         encoder
-            .write_header(
-                &mut buffer,
-                reference_timestamp,
-                &reference_prices,
-                &reference_quantities,
-            )
+            .write_header(&mut buffer, 1700000000000, &[45000.0, 2500.0], &[1.0, 10.0])
             .unwrap();
 
-        // Encode a single trade
-        let trade = Trade {
-            symbol: "BTCUSDT".to_string(),
-            timestamp: 1700000001000, // Delta = +1000 ms
-            price: 45001.0,           // Delta = +1.0
-            quantity: 1.5,            // Delta = +0.5
-            is_buyer_maker: true,
+        let trade = |symbol: &str, timestamp: u64, price: f64| Trade {
+            symbol: symbol.to_string(),
+            timestamp,
+            price,
+            quantity: 2.0,
+            is_buyer_maker: false,
         };
+        buffer.extend(
+            encoder
+                .encode(&trade("BTCUSDT", 1700000000001, 45002.0))
+                .unwrap(),
+        );
+        buffer.extend(
+            encoder
+                .encode(&trade("BTCUSDT", 1700000000002, 45001.0))
+                .unwrap(),
+        );
+        buffer.extend(
+            encoder
+                .encode_keyframe(&trade("BTCUSDT", 1700000000003, 45004.0))
+                .unwrap(),
+        );
 
-        let encoded_trade = encoder.encode(&trade).unwrap();
-        buffer.extend_from_slice(&encoded_trade);
-
-        // Decode the trade
         let mut decoder = BinaryFormat::new();
         let mut cursor = Cursor::new(&buffer);
         decoder.read_header(&mut cursor).unwrap();
-        let decoded_trade = decoder.read_message(&mut cursor).unwrap();
+        for _ in 0..3 {
+            decoder.read_message(&mut cursor).unwrap();
+        }
 
-        // Compare decoded trade with the original
-        assert_eq!(decoded_trade.symbol, trade.symbol);
-        assert_eq!(decoded_trade.timestamp, trade.timestamp);
-        assert!((decoded_trade.price - trade.price).abs() < 0.01);
-        assert!((decoded_trade.quantity - trade.quantity).abs() < 0.00001);
-        assert_eq!(decoded_trade.is_buyer_maker, trade.is_buyer_maker);
+        let stats = decoder.asset_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].symbol, "BTCUSDT");
+        assert_eq!(stats[0].decoded, 3);
+        assert_eq!(stats[0].last_timestamp, 1700000000003);
+        assert!((stats[0].last_price - 45004.0).abs() <= decoder.price_resolution() / 2.0);
+        assert!((stats[0].last_quantity - 2.0).abs() <= decoder.quantity_resolution() / 2.0);
+        // 45000 -> 45002 -> 45001 -> 45004
+        assert!((stats[0].price_moved - 6.0).abs() <= 3.0 * decoder.price_resolution());
+
+        // Untraded assets keep their header reference values
+        assert_eq!(stats[1].symbol, "ETHUSDT");
+        assert_eq!(stats[1].decoded, 0);
+        assert_eq!(stats[1].last_price, 2500.0);
+        assert_eq!(stats[1].price_moved, 0.0);
     }
 
     #[test]
-    fn test_batch_trade_encoding_and_decoding() {
-        let assets = vec![
-            "BTCUSDT".to_string(),
-            "ETHUSDT".to_string(),
-            "SOLUSDT".to_string(),
-        ];
-
-        let mut encoder = BinaryFormat::new().with_assets(assets.clone()).unwrap();
-        let mut buffer = Vec::new();
-
-        // Write header
-        let reference_timestamp = 1700000000000;
-        let reference_prices = vec![45000.0, 2500.5, 120.75];
-        let reference_quantities = vec![1.0, 10.0, 100.0];
-        encoder
-            .write_header(
-                &mut buffer,
-                reference_timestamp,
-                &reference_prices,
-                &reference_quantities,
-            )
+    fn test_fixed_width_records_by_index() {
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1_700_000_000_000)
+            .fixed_width_records(true)
+            .assets(vec![
+                ("BTCUSDT".to_string(), 45000.0, 1.0, 100_000.0),
+                ("ETHUSDT".to_string(), 2500.0, 10.0, 100_000.0),
+            ])
+            .build()
             .unwrap();
+        let trade = |i: u64| Trade {
+            symbol: ["BTCUSDT", "ETHUSDT"][i as usize % 2].to_string(),
+            timestamp: 1_700_000_000_000 + i * 13,
+            price: [45000.0, 2500.0][i as usize % 2] + i as f64 * 0.37,
+            quantity: 0.001 + i as f64 * 0.5,
+            is_buyer_maker: i % 3 == 1,
+        };
 
-        // Prepare a batch of trades
-        let trades = vec![
-            Trade {
-                symbol: "BTCUSDT".to_string(),
-                timestamp: 1700000001000, // Delta = +1000 ms
-                price: 45001.0,           // Delta = +1.0
-                quantity: 1.5,            // Delta = +0.5
-                is_buyer_maker: true,
-            },
-            Trade {
-                symbol: "ETHUSDT".to_string(),
-                timestamp: 1700000002000, // Delta = +2000 ms
-                price: 2501.5,            // Delta = +1.0
-                quantity: 10.5,           // Delta = +0.5
-                is_buyer_maker: false,
-            },
-            Trade {
-                symbol: "SOLUSDT".to_string(),
-                timestamp: 1700000003000, // Delta = +3000 ms
-                price: 121.0,             // Delta = +0.25
-                quantity: 100.25,         // Delta = +0.25
-                is_buyer_maker: true,
-            },
-        ];
-
-        for trade in &trades {
-            let encoded_trade = encoder.encode(trade).unwrap();
-            buffer.extend_from_slice(&encoded_trade);
+        // A recording: header, then records back to back
+        let mut file = header.clone();
+        for i in 0..100 {
+            let record = match i {
+                40 => encoder.encode_heartbeat(1_700_000_000_520).unwrap(),
+                41 => encoder.encode_gap("ETHUSDT", 7).unwrap(),
+                _ => encoder.encode(&trade(i)).unwrap(),
+            };
+            assert_eq!(record.len(), FIXED_RECORD_LEN);
+            file.extend_from_slice(&record);
         }
 
-        // Decode trades
         let mut decoder = BinaryFormat::new();
-        let mut cursor = Cursor::new(&buffer);
-        decoder.read_header(&mut cursor).unwrap();
+        let mut data = file.as_slice();
+        decoder.read_header_slice(&mut data).unwrap();
+        assert!(decoder.fixed_width_records());
+        assert_eq!(data.len(), 100 * FIXED_RECORD_LEN);
 
-        let mut decoded_trades = Vec::new();
-        while cursor.position() < buffer.len() as u64 {
-            let decoded_trade = decoder.read_message(&mut cursor).unwrap();
-            decoded_trades.push(decoded_trade);
+        // Any index, in any order, without replaying the records before it
+        for i in [77, 3, 99, 0, 42] {
+            let decoded = decoder
+                .read_fixed_record(data, i)
+                .unwrap()
+                .into_trade()
+                .unwrap();
+            let expected = trade(i as u64);
+            assert_eq!(decoded.symbol, expected.symbol);
+            assert_eq!(decoded.timestamp, expected.timestamp);
+            assert_eq!(decoded.is_buyer_maker, expected.is_buyer_maker);
+            assert!((decoded.price - expected.price).abs() <= decoder.price_resolution() / 2.0);
+            assert!(
+                (decoded.quantity - expected.quantity).abs() <= decoder.quantity_resolution() / 2.0
+            );
         }
+        assert!(matches!(
+            decoder.read_fixed_record(data, 40).unwrap(),
+            Record::Heartbeat(1_700_000_000_520)
+        ));
+        assert!(matches!(
+            decoder.read_fixed_record(data, 41).unwrap(),
+            Record::Gap { symbol, dropped: 7 } if symbol == "ETHUSDT"
+        ));
+        assert!(matches!(
+            decoder.read_fixed_record(data, 100),
+            Err(BinaryFormatError::InsufficientData)
+        ));
 
-        // Verify decoded trades
-        assert_eq!(trades.len(), decoded_trades.len());
-        for (original, decoded) in trades.iter().zip(decoded_trades.iter()) {
-            assert_eq!(original.symbol, decoded.symbol);
-            assert_eq!(original.timestamp, decoded.timestamp);
-            assert!((original.price - decoded.price).abs() < 0.01);
-            assert!((original.quantity - decoded.quantity).abs() < 0.00001);
-            assert_eq!(original.is_buyer_maker, decoded.is_buyer_maker);
+        // Sequential reads and skipping agree with the index
+        let mut sequential = BinaryFormat::new();
+        let mut cursor = Cursor::new(&file);
+        sequential.read_header(&mut cursor).unwrap();
+        assert_eq!(
+            sequential.skip_record(&mut cursor).unwrap(),
+            FIXED_RECORD_LEN
+        );
+        let second = sequential.read_message(&mut cursor).unwrap();
+        assert_eq!(second.timestamp, trade(1).timestamp);
+
+        // Both layout flags at once is not a layout
+        let both = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap()
+            .with_length_prefixed_records(true)
+            .with_fixed_width_records(true)
+            .write_header(&mut Vec::new(), 0, &[1.0], &[1.0]);
+        assert!(matches!(
+            both,
+            Err(BinaryFormatError::UnsupportedRecordFlags(0x03))
+        ));
+        let mut varint = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap();
+        assert!(matches!(
+            varint.read_fixed_record(data, 0),
+            Err(BinaryFormatError::NotFixedWidth)
+        ));
+    }
+
+    #[test]
+    fn test_header_frame_detection() {
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1_700_000_000_000)
+            .assets(vec![
+                ("BTCUSDT".to_string(), 45000.0, 1.0, 100_000.0),
+                ("ETHUSDT".to_string(), 2500.0, 1.0, 100_000.0),
+            ])
+            .build()
+            .unwrap();
+        let decoder = BinaryFormat::from_header_frame(&header).unwrap();
+        assert_eq!(decoder.symbol_for_id(1), Some("ETHUSDT"));
+
+        // Records, including asset 1 trades that start with a version byte
+        let trade = |symbol: &str, timestamp: u64, price: f64| Trade {
+            symbol: symbol.to_string(),
+            timestamp,
+            price,
+            quantity: 12.5,
+            is_buyer_maker: false,
+        };
+        let records = [
+            encoder
+                .encode(&trade("ETHUSDT", 1_700_000_000_001, 2501.0))
+                .unwrap(),
+            encoder
+                .encode(&trade("ETHUSDT", 1_800_000_000_000, 9999.0))
+                .unwrap(),
+            encoder
+                .encode_keyframe(&trade("BTCUSDT", 1_800_000_000_001, 46000.0))
+                .unwrap(),
+            encoder.encode_heartbeat(1_800_000_000_002).unwrap(),
+        ];
+        assert_eq!(records[0][0], 1);
+        for record in &records {
+            assert!(BinaryFormat::from_header_frame(record).is_none());
+        }
+
+        // Not a whole header
+        let mut trailing = header.clone();
+        trailing.push(0);
+        for frame in [&header[..header.len() - 1], &trailing, b"START", b""] {
+            assert!(BinaryFormat::from_header_frame(frame).is_none());
         }
     }
 }
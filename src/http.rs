@@ -0,0 +1,416 @@
+// std
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// external
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+// internal
+use crate::format::{BinaryFormat, Trade};
+use crate::ipc::tcp::SharedHeader;
+use crate::metrics::Metrics;
+use crate::pipeline::PipelineControl;
+use crate::recent::RecentTrades;
+
+/// Upper bound on the request head we are willing to buffer.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Shared state the debug HTTP endpoint reads from.
+#[derive(Clone)]
+pub struct HttpState {
+    pub recent: Arc<RecentTrades>,
+    pub metrics: Arc<Metrics>,
+    pub control: Arc<PipelineControl>,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// Minimal HTTP/1.1 server for debugging and ops. One request per connection.
+pub async fn serve(bind_addr: &str, state: HttpState) -> Result<(), std::io::Error> {
+    let listener = TcpListener::bind(bind_addr).await?;
+    tracing::info!("HTTP endpoint listening on {}", bind_addr);
+
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(socket, state).await {
+                tracing::debug!("http client {} error: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut socket: TcpStream, state: HttpState) -> Result<(), std::io::Error> {
+    let Some((method, target)) = read_request_line(&mut socket).await? else {
+        return Ok(());
+    };
+
+    let response = route(&method, &target, &state);
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(response.body.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+/// Read the request head and return its method and target. `None` if the client
+/// hung up first.
+async fn read_request_line(
+    socket: &mut TcpStream,
+) -> Result<Option<(String, String)>, std::io::Error> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST_SIZE {
+            break;
+        }
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+    Ok(Some((method, target)))
+}
+
+/// Trade stream for demos and dashboards: each client gets every trade from the
+/// broadcast, decoded, as JSON. `GET /trades` sends one JSON object per line,
+/// `GET /events` the same objects as Server-Sent Events. The response has no
+/// length and runs until either side closes. Not meant for the latency-sensitive
+/// path; a client that falls behind the broadcast just skips trades.
+pub async fn serve_trades(
+    listener: TcpListener,
+    header: SharedHeader,
+    broadcaster: broadcast::Sender<Vec<u8>>,
+) -> Result<(), std::io::Error> {
+    tracing::info!("Trade stream listening on {}", listener.local_addr()?);
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let header = header.clone();
+        let broadcaster = broadcaster.clone();
+        tokio::spawn(async move {
+            if let Err(e) = stream_trades(socket, header, broadcaster).await {
+                tracing::debug!("trade stream client {} error: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn stream_trades(
+    mut socket: TcpStream,
+    header: SharedHeader,
+    broadcaster: broadcast::Sender<Vec<u8>>,
+) -> Result<(), std::io::Error> {
+    let Some((method, target)) = read_request_line(&mut socket).await? else {
+        return Ok(());
+    };
+    let path = target.split('?').next().unwrap_or_default();
+    let (content_type, prefix, suffix) = match (method.as_str(), path) {
+        ("GET", "/trades") => ("application/x-ndjson", "", "\n"),
+        ("GET", "/events") => ("text/event-stream", "data: ", "\n\n"),
+        _ => {
+            let body = r#"{"error":"not found"}"#;
+            let head = format!(
+                "HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            socket.write_all(head.as_bytes()).await?;
+            socket.write_all(body.as_bytes()).await?;
+            return socket.shutdown().await;
+        }
+    };
+
+    // Read together with subscribing, see `tcp::publish_header`
+    let (header, mut sub) = {
+        let header = header.read().unwrap();
+        (header.clone(), broadcaster.subscribe())
+    };
+    // The broadcast closing is what ends the stream, so don't hold it open
+    drop(broadcaster);
+    let mut decoder = BinaryFormat::new();
+    decoder
+        .read_header(&mut std::io::Cursor::new(&header))
+        .map_err(std::io::Error::other)?;
+
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        content_type
+    );
+    socket.write_all(head.as_bytes()).await?;
+
+    // A START frame announces that the next frame is a fresh header
+    let mut expect_header = false;
+    loop {
+        let frame = match sub.recv().await {
+            Ok(frame) => frame,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                tracing::warn!("trade stream client lagged by {} msgs", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if frame == b"START" {
+            expect_header = true;
+            continue;
+        }
+        let mut cursor = std::io::Cursor::new(&frame);
+        if std::mem::take(&mut expect_header) {
+            decoder = BinaryFormat::new();
+            decoder
+                .read_header(&mut cursor)
+                .map_err(std::io::Error::other)?;
+            continue;
+        }
+        let trade = match decoder.read_record(&mut cursor) {
+            Ok(record) => match record.into_trade() {
+                Some(trade) => trade,
+                None => continue,
+            },
+            Err(e) => {
+                tracing::warn!("trade stream dropped undecodable frame: {}", e);
+                continue;
+            }
+        };
+        let line = format!("{}{}{}", prefix, trade_json(&trade), suffix);
+        socket.write_all(line.as_bytes()).await?;
+    }
+    socket.shutdown().await
+}
+
+pub fn route(method: &str, target: &str, state: &HttpState) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params: HashMap<String, String> = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+
+    match (method, path) {
+        ("GET", "/recent") => recent(&params, state),
+        ("GET", "/metrics") => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: state.metrics.render(),
+        },
+        ("GET", "/control") => control_status(state),
+        ("POST", "/control/pause") => {
+            tracing::info!("pipeline paused via HTTP");
+            state.control.pause();
+            control_status(state)
+        }
+        ("POST", "/control/resume") => {
+            tracing::info!("pipeline resumed via HTTP");
+            state.control.resume();
+            control_status(state)
+        }
+        ("POST", "/control/mute") => mute(&params, state, true),
+        ("POST", "/control/unmute") => mute(&params, state, false),
+        (
+            _,
+            "/recent" | "/metrics" | "/control" | "/control/pause" | "/control/resume"
+            | "/control/mute" | "/control/unmute",
+        ) => Response::json(405, serde_json::json!({ "error": "method not allowed" })),
+        _ => Response::json(404, serde_json::json!({ "error": "not found" })),
+    }
+}
+
+/// `GET /recent?symbol=BTCUSDT` - the last trades forwarded for that symbol, oldest first.
+fn recent(params: &HashMap<String, String>, state: &HttpState) -> Response {
+    let Some(symbol) = params.get("symbol") else {
+        return Response::json(400, serde_json::json!({ "error": "missing symbol" }));
+    };
+    match state.recent.latest(symbol) {
+        Some(trades) => Response::json(
+            200,
+            serde_json::json!({
+                "symbol": symbol,
+                "trades": trades.iter().map(trade_json).collect::<Vec<_>>(),
+            }),
+        ),
+        None => Response::json(
+            404,
+            serde_json::json!({ "error": format!("unknown symbol {}", symbol) }),
+        ),
+    }
+}
+
+/// `POST /control/mute?symbol=ETHUSDT` (or `/control/unmute`) - stop or resume
+/// forwarding one asset.
+fn mute(params: &HashMap<String, String>, state: &HttpState, muted: bool) -> Response {
+    let Some(symbol) = params.get("symbol") else {
+        return Response::json(400, serde_json::json!({ "error": "missing symbol" }));
+    };
+    if state.recent.latest(symbol).is_none() {
+        return Response::json(
+            404,
+            serde_json::json!({ "error": format!("unknown symbol {}", symbol) }),
+        );
+    }
+    if muted {
+        tracing::info!("{} muted via HTTP", symbol);
+        state.control.mute(symbol);
+    } else {
+        tracing::info!("{} unmuted via HTTP", symbol);
+        state.control.unmute(symbol);
+    }
+    control_status(state)
+}
+
+fn control_status(state: &HttpState) -> Response {
+    Response::json(
+        200,
+        serde_json::json!({
+            "paused": state.control.is_paused(),
+            "muted": state.control.muted(),
+        }),
+    )
+}
+
+fn trade_json(trade: &Trade) -> serde_json::Value {
+    serde_json::json!({
+        "symbol": trade.symbol,
+        "timestamp": trade.timestamp,
+        "price": trade.price,
+        "quantity": trade.quantity,
+        "is_buyer_maker": trade.is_buyer_maker,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_route() {
+        let recent = Arc::new(RecentTrades::new(&["BTCUSDT".to_string()], 2));
+        recent.record(&Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp: 1,
+            price: 45000.0,
+            quantity: 0.5,
+            is_buyer_maker: true,
+        });
+        let state = HttpState {
+            recent,
+            metrics: Arc::new(Metrics::new()),
+            control: Arc::new(PipelineControl::new()),
+        };
+
+        let res = route("GET", "/recent?symbol=BTCUSDT", &state);
+        assert_eq!(res.status, 200);
+        let body: serde_json::Value = serde_json::from_str(&res.body).unwrap();
+        assert_eq!(body["trades"][0]["price"], 45000.0);
+
+        assert_eq!(route("GET", "/recent?symbol=XRPUSDT", &state).status, 404);
+        assert_eq!(route("GET", "/recent", &state).status, 400);
+        assert_eq!(route("POST", "/recent", &state).status, 405);
+
+        assert_eq!(
+            route("POST", "/control/pause", &state).body,
+            r#"{"muted":[],"paused":true}"#
+        );
+        assert!(state.control.is_paused());
+        assert_eq!(route("GET", "/control/pause", &state).status, 405);
+        route("POST", "/control/resume", &state);
+        assert!(!state.control.is_paused());
+
+        let res = route("POST", "/control/mute?symbol=BTCUSDT", &state);
+        assert_eq!(res.body, r#"{"muted":["BTCUSDT"],"paused":false}"#);
+        assert!(state.control.is_muted("BTCUSDT"));
+        assert_eq!(
+            route("POST", "/control/mute?symbol=XRPUSDT", &state).status,
+            404
+        );
+        route("POST", "/control/unmute?symbol=BTCUSDT", &state);
+        assert!(!state.control.is_muted("BTCUSDT"));
+    }
+
+    #[tokio::test]
+    async fn test_trade_stream_sends_json_lines() {
+        use tokio::io::AsyncBufReadExt;
+
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1_700_000_000_000)
+            .assets(vec![("BTCUSDT".to_string(), 45000.0, 1.0, 100_000.0)])
+            .build()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, _) = broadcast::channel(16);
+        let shared = Arc::new(std::sync::RwLock::new(header));
+        tokio::spawn(serve_trades(listener, shared, tx.clone()));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /trades HTTP/1.1\r\nHost: test\r\n\r\n")
+            .await
+            .unwrap();
+        let mut reader = tokio::io::BufReader::new(client);
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("HTTP/1.1 200"), "{}", line);
+        // Headers were written after subscribing
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+        }
+
+        for (i, price) in [45001.5, 45002.25].into_iter().enumerate() {
+            let trade = Trade {
+                symbol: "BTCUSDT".to_string(),
+                timestamp: 1_700_000_000_100 + i as u64,
+                price,
+                quantity: 0.5,
+                is_buyer_maker: i == 1,
+            };
+            tx.send(encoder.encode(&trade).unwrap()).unwrap();
+        }
+        tx.send(encoder.encode_heartbeat(1_700_000_000_200).unwrap())
+            .unwrap();
+
+        for (i, price) in [45001.5, 45002.25].into_iter().enumerate() {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(json["symbol"], "BTCUSDT");
+            assert_eq!(json["timestamp"], 1_700_000_000_100 + i as u64);
+            assert!((json["price"].as_f64().unwrap() - price).abs() < 1e-4);
+            assert_eq!(json["is_buyer_maker"], i == 1);
+        }
+    }
+}
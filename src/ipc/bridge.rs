@@ -0,0 +1,244 @@
+// std
+use std::collections::VecDeque;
+use std::io;
+
+// external
+use tokio::sync::broadcast;
+
+// internal
+use crate::format::{STREAM_MAGIC, check_stream_magic};
+use crate::ipc::shm_queue::{ShmQueue, WaitStrategy};
+use crate::ipc::tcp::{self, SharedHeader};
+
+/// Default for `Bridge::with_max_pending`.
+pub const DEFAULT_MAX_PENDING: usize = 4096;
+
+/// Fan-out relay: the single consumer of one `ShmQueue`, copying every frame
+/// into other queues and optionally a TCP broadcast, so more consumers can
+/// share one producer (and one Binance connection).
+///
+/// Each output queue has its own backlog. A full queue only delays its own
+/// frames; once its backlog holds `max_pending` frames, further frames for it
+/// are dropped and counted (in the queue's `dropped` too, where its consumer
+/// sees them; frames still in the backlog aren't), and its consumer desyncs until the next keyframe or
+/// header, the same as behind a full producer queue.
+pub struct Bridge {
+    upstream: ShmQueue,
+    downstreams: Vec<Downstream>,
+    tcp: Option<TcpFanout>,
+    max_pending: usize,
+}
+
+struct Downstream {
+    queue: ShmQueue,
+    pending: VecDeque<Vec<u8>>,
+    dropped: u64,
+}
+
+impl Downstream {
+    /// Push what the queue has room for, oldest first.
+    fn flush(&mut self) {
+        while let Some(frame) = self.pending.front() {
+            if !self.queue.try_push(frame) {
+                return;
+            }
+            self.pending.pop_front();
+        }
+    }
+}
+
+/// TCP output: clients get the magic, START and header from `tcp::serve`, so
+/// those frames update the shared header instead of being broadcast as-is.
+struct TcpFanout {
+    header: SharedHeader,
+    tx: broadcast::Sender<Vec<u8>>,
+    /// The last frame was START, so the next one is a header
+    expect_header: bool,
+}
+
+impl Bridge {
+    pub fn new(upstream: ShmQueue, downstreams: Vec<ShmQueue>) -> Self {
+        Self {
+            upstream,
+            downstreams: downstreams
+                .into_iter()
+                .map(|queue| Downstream {
+                    queue,
+                    pending: VecDeque::new(),
+                    dropped: 0,
+                })
+                .collect(),
+            tcp: None,
+            max_pending: DEFAULT_MAX_PENDING,
+        }
+    }
+
+    /// Frames held per output queue while it is full.
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// Also fan out to the clients of a `tcp::serve` using `header` and `tx`.
+    pub fn with_tcp(mut self, header: SharedHeader, tx: broadcast::Sender<Vec<u8>>) -> Self {
+        self.tcp = Some(TcpFanout {
+            header,
+            tx,
+            expect_header: false,
+        });
+        self
+    }
+
+    /// Frames dropped per output queue, in the order given to `new`.
+    pub fn dropped(&self) -> Vec<u64> {
+        self.downstreams.iter().map(|d| d.dropped).collect()
+    }
+
+    /// Relay the stream magic, START and header, returning the header. Call
+    /// first; a stream not starting with `STREAM_MAGIC` fails with `InvalidData`.
+    pub fn forward_handshake(&mut self, wait: WaitStrategy) -> io::Result<Vec<u8>> {
+        let magic = self.upstream.pop_blocking(wait)?;
+        check_stream_magic(&magic).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.forward(magic);
+        loop {
+            let frame = self.upstream.pop_blocking(wait)?;
+            let start = frame == b"START";
+            self.forward(frame);
+            if start {
+                break;
+            }
+        }
+        let header = self.upstream.pop_blocking(wait)?;
+        self.forward(header.clone());
+        Ok(header)
+    }
+
+    /// Relay everything currently in the upstream queue, returning the frame count.
+    pub fn pump(&mut self) -> io::Result<usize> {
+        let mut frames = 0;
+        while let Some(frame) = self.upstream.pop()? {
+            self.forward(frame);
+            frames += 1;
+        }
+        Ok(frames)
+    }
+
+    /// Relay until an error. Backlogs are retried as frames arrive, so with an
+    /// idle upstream they wait for the next frame (at most a heartbeat away).
+    pub fn run(&mut self, wait: WaitStrategy) -> io::Result<()> {
+        loop {
+            let frame = self.upstream.pop_blocking(wait)?;
+            self.forward(frame);
+            self.pump()?;
+        }
+    }
+
+    fn forward(&mut self, frame: Vec<u8>) {
+        if let Some(tcp) = &mut self.tcp {
+            if frame == b"START" {
+                tcp.expect_header = true;
+            } else if std::mem::take(&mut tcp.expect_header) {
+                tcp::publish_header(&tcp.header, frame.clone(), &tcp.tx, None);
+            } else if frame != STREAM_MAGIC {
+                let _ = tcp.tx.send(frame.clone());
+            }
+        }
+
+        for (idx, downstream) in self.downstreams.iter_mut().enumerate() {
+            downstream.flush();
+            if downstream.pending.is_empty() && downstream.queue.try_push(&frame) {
+                continue;
+            }
+            if downstream.pending.len() < self.max_pending {
+                downstream.pending.push_back(frame.clone());
+            } else {
+                downstream.dropped += 1;
+                downstream.queue.record_drop();
+                tracing::warn!(
+                    "bridge output {} full, dropped a frame ({} so far)",
+                    idx,
+                    downstream.dropped
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{BinaryFormat, Trade};
+
+    fn queue(label: &str, capacity: u32) -> (ShmQueue, String) {
+        let name = format!(
+            "perp_signal_hft_test_bridge_{}_{}",
+            label,
+            std::process::id()
+        );
+        (ShmQueue::create(&name, capacity).unwrap(), name)
+    }
+
+    fn drain(queue: &ShmQueue) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| queue.pop().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_bridge_copies_frames_intact() {
+        let (producer, a) = queue("a", 1 << 16);
+        let (upstream, _) = queue("a", 1 << 16);
+        let (downstream, b) = queue("b", 1 << 16);
+        let (consumer, _) = queue("b", 1 << 16);
+        // Too small for the whole stream, to show it doesn't hold up queue b
+        let (tiny, c) = queue("c", 256);
+        let (tiny_consumer, _) = queue("c", 256);
+
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1_700_000_000_000)
+            .assets(vec![("BTCUSDT".to_string(), 45000.0, 1.0, 100_000.0)])
+            .build()
+            .unwrap();
+        let mut sent = vec![STREAM_MAGIC.to_vec(), b"START".to_vec(), header];
+        for i in 0..50 {
+            let trade = Trade {
+                symbol: "BTCUSDT".to_string(),
+                timestamp: 1_700_000_000_000 + i,
+                price: 45000.0 + i as f64 * 0.5,
+                quantity: 0.01,
+                is_buyer_maker: i % 2 == 0,
+            };
+            sent.push(encoder.encode(&trade).unwrap());
+        }
+        for frame in &sent {
+            producer.push(frame).unwrap();
+        }
+
+        let mut bridge = Bridge::new(upstream, vec![downstream, tiny]).with_max_pending(4);
+        let header = bridge.forward_handshake(WaitStrategy::Spin).unwrap();
+        assert_eq!(header, sent[2]);
+        assert_eq!(bridge.pump().unwrap(), 50);
+
+        assert_eq!(drain(&consumer), sent);
+        // The tiny queue took what fit, held four more, and dropped the rest
+        let got = drain(&tiny_consumer);
+        assert_eq!(got, sent[..got.len()]);
+        assert_eq!(
+            got.len() as u64 + 4 + bridge.dropped()[1],
+            sent.len() as u64
+        );
+        assert_eq!(bridge.dropped()[0], 0);
+        // Only frames given up on reach the queues' own counts, not retries
+        assert_eq!(consumer.dropped(), 0);
+        assert_eq!(tiny_consumer.dropped(), bridge.dropped()[1]);
+
+        // Room again: the backlog goes out ahead of newer frames
+        producer.push(b"LAST").unwrap();
+        bridge.pump().unwrap();
+        let resumed = drain(&tiny_consumer);
+        assert_eq!(resumed.len(), 5);
+        assert_eq!(resumed.last().unwrap(), b"LAST");
+
+        for name in [a, b, c] {
+            std::fs::remove_file(format!("/dev/shm/{}", name)).unwrap();
+        }
+    }
+}
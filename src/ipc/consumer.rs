@@ -0,0 +1,680 @@
+// std
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+// internal
+use crate::format::{BinaryFormat, BinaryFormatError, Record, Trade, UnsupportedVersion};
+use crate::ipc::framing::Framing;
+use crate::ipc::shm_queue::{ShmQueue, WaitStrategy};
+use crate::ipc::tcp_client::{DEFAULT_INITIAL_BACKOFF, DEFAULT_MAX_BACKOFF};
+use crate::rawlog::{MmapRecordingReader, RawLogError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Format error: {0}")]
+    Format(BinaryFormatError),
+    #[error(transparent)]
+    UnsupportedVersion(UnsupportedVersion),
+}
+
+impl From<BinaryFormatError> for DecodeError {
+    fn from(e: BinaryFormatError) -> Self {
+        e.into_unsupported_version()
+            .map_or_else(DecodeError::Format, DecodeError::UnsupportedVersion)
+    }
+}
+
+/// What `TradeConsumer` does after handing out a `DecodeError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorPolicy {
+    /// Drop the bad frame and keep decoding. Cheapest, but a lost delta leaves
+    /// its asset's prices off until the asset's next keyframe.
+    Skip,
+    /// Drop the bad frame, then hold back each asset's trades until a keyframe
+    /// (or a new header) re-seats it, so no trade decoded off a lost delta gets out.
+    Resync,
+    /// Drop the connection and redo the handshake on a fresh one, eg: to pick up
+    /// a restarted producer's header. Also applies to errors reading frames.
+    Reconnect,
+}
+
+/// Where `TradeConsumer` reads frames: the magic, START and header handshake
+/// followed by one record per frame.
+pub trait FrameSource {
+    /// Next frame, waiting for one.
+    fn next_frame(&mut self) -> io::Result<Vec<u8>>;
+
+    /// Start over on a fresh connection. Frames after it run up to a START and
+    /// header before records resume.
+    fn reconnect(&mut self) -> io::Result<()>;
+}
+
+/// A SHM queue consumer. There is nothing to reconnect to, so `reconnect` only
+/// drops the rest of the stream up to the producer's next START and header.
+pub struct ShmSource {
+    queue: ShmQueue,
+    wait: WaitStrategy,
+}
+
+impl ShmSource {
+    pub fn new(queue: ShmQueue, wait: WaitStrategy) -> Self {
+        Self { queue, wait }
+    }
+
+    pub fn queue(&self) -> &ShmQueue {
+        &self.queue
+    }
+}
+
+impl FrameSource for ShmSource {
+    fn next_frame(&mut self) -> io::Result<Vec<u8>> {
+        self.queue.pop_blocking(self.wait)
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Blocking client of the TCP fan-out server. Connects on the first frame and
+/// after `reconnect`, backing off exponentially between failed attempts.
+pub struct TcpSource {
+    addr: String,
+    stream: Option<TcpStream>,
+    /// `None` until the connection's magic frame announces it
+    framing: Option<Framing>,
+    backoff: Duration,
+}
+
+impl TcpSource {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            stream: None,
+            framing: None,
+            backoff: DEFAULT_INITIAL_BACKOFF,
+        }
+    }
+
+    fn connect(&mut self) -> io::Result<&mut TcpStream> {
+        if self.stream.is_none() {
+            let stream = TcpStream::connect(&self.addr).inspect_err(|_| {
+                thread::sleep(self.backoff);
+                self.backoff = (self.backoff * 2).min(DEFAULT_MAX_BACKOFF);
+            })?;
+            stream.set_nodelay(true)?;
+            self.backoff = DEFAULT_INITIAL_BACKOFF;
+            tracing::info!("connected to {}", self.addr);
+            self.stream = Some(stream);
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+}
+
+impl FrameSource for TcpSource {
+    fn next_frame(&mut self) -> io::Result<Vec<u8>> {
+        let framing = self.framing;
+        let stream = self.connect()?;
+        if let Some(framing) = framing {
+            return framing.read_frame(stream);
+        }
+        // The magic always has a `u32` length; a bad one is left to the consumer
+        let magic = Framing::U32.read_frame(stream)?;
+        self.framing = Some(Framing::from_magic(&magic).unwrap_or_default());
+        Ok(magic)
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        self.stream = None;
+        self.framing = None;
+        Ok(())
+    }
+}
+
+/// Decodes the trade stream of a `FrameSource`, riding out bad frames.
+///
+/// Errors are handed out as `Err` items and counted in `errors`, then the
+/// `ErrorPolicy` decides how decoding carries on. A START mid-stream (a
+/// restarted producer or a new epoch) is followed by its header as usual. A
+/// header without a START ahead of it, in the handshake or mid-stream, is taken
+/// too, by `BinaryFormat::from_header_frame`'s rule. An error reading a frame
+/// ends the iteration unless the policy is `Reconnect`.
+pub struct TradeConsumer<S> {
+    source: S,
+    decoder: BinaryFormat,
+    policy: ErrorPolicy,
+    /// Recording handed out ahead of the source, see `with_replay`
+    replay: Option<MmapRecordingReader>,
+    /// Latest replayed timestamp per asset, in micros, until the live stream
+    /// passes it
+    replayed: HashMap<String, u64>,
+    /// Handshake to do before the next record: `Some(true)` when the stream
+    /// magic has to come first
+    handshake: Option<bool>,
+    /// Assets held back until their next keyframe, under `ErrorPolicy::Resync`
+    stale: HashSet<String>,
+    errors: u64,
+    skipped: u64,
+    reconnects: u64,
+    done: bool,
+}
+
+impl<S: FrameSource> TradeConsumer<S> {
+    pub fn new(source: S, policy: ErrorPolicy) -> Self {
+        Self {
+            source,
+            decoder: BinaryFormat::new(),
+            policy,
+            replay: None,
+            replayed: HashMap::new(),
+            handshake: Some(true),
+            stale: HashSet::new(),
+            errors: 0,
+            skipped: 0,
+            reconnects: 0,
+            done: false,
+        }
+    }
+
+    /// Warm start: hand out the trades of the recording at `path` (eg: a
+    /// `RawLog` `.bin`) before connecting to the source.
+    ///
+    /// The replay ends at the recording's end, or at a record that doesn't
+    /// decode, such as one the recorder is still writing. The live stream then
+    /// starts at its own handshake, and its header replaces the recording's.
+    /// Timestamps are reconciled per asset, in microseconds so streams in
+    /// different units compare: a live trade no later than that asset's last
+    /// replayed trade was already handed out (the recording and the live stream
+    /// overlap) and is dropped, counted in `skipped`. Trades sharing the last
+    /// replayed timestamp are dropped with it.
+    pub fn with_replay(mut self, path: impl AsRef<Path>) -> Result<Self, RawLogError> {
+        self.replay = Some(MmapRecordingReader::open(path)?);
+        Ok(self)
+    }
+
+    /// Still handing out the recording given to `with_replay`.
+    pub fn replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    /// Decoder for the current header, eg: to look up its timestamp unit. The
+    /// recording's while replaying.
+    pub fn decoder(&self) -> &BinaryFormat {
+        match &self.replay {
+            Some(replay) => replay.decoder(),
+            None => &self.decoder,
+        }
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    /// Errors handed out so far.
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Trades held back while their asset waited for a keyframe.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Times the policy reconnected the source.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    /// Next record of any kind; `None` once a frame can't be read and the
+    /// policy isn't `Reconnect`.
+    pub fn next_record(&mut self) -> Option<Result<Record, DecodeError>> {
+        if self.done {
+            return None;
+        }
+        if let Some(trade) = self.next_replayed() {
+            return Some(Ok(Record::Trade(trade)));
+        }
+        let result = self.try_next_record();
+        if let Err(e) = &result {
+            self.errors += 1;
+            self.recover(e);
+        }
+        Some(result)
+    }
+
+    fn try_next_record(&mut self) -> Result<Record, DecodeError> {
+        loop {
+            if let Some(require_magic) = self.handshake {
+                self.read_handshake(require_magic)?;
+            }
+            let frame = self.source.next_frame()?;
+            if frame == b"START" {
+                self.read_header()?;
+                continue;
+            }
+            if frame.starts_with(b"PSHFT") {
+                Framing::from_magic(&frame)?;
+                continue;
+            }
+            if let Some(decoder) = BinaryFormat::from_header_frame(&frame) {
+                self.set_decoder(decoder);
+                continue;
+            }
+            let record = self.decoder.read_record(&mut io::Cursor::new(&frame))?;
+            match &record {
+                Record::Trade(trade) | Record::Keyframe(trade) if self.already_replayed(trade) => {
+                    self.skipped += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            match &record {
+                Record::Keyframe(trade) => {
+                    self.stale.remove(&trade.symbol);
+                }
+                Record::Trade(trade) if self.stale.contains(&trade.symbol) => {
+                    self.skipped += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            return Ok(record);
+        }
+    }
+
+    /// Next trade of the recording, `None` once it's exhausted.
+    fn next_replayed(&mut self) -> Option<Trade> {
+        let replay = self.replay.as_mut()?;
+        match replay.next() {
+            Some(Ok(trade)) => {
+                let micros = replay.decoder().timestamp_unit().to_micros(trade.timestamp);
+                self.replayed.insert(trade.symbol.clone(), micros);
+                return Some(trade);
+            }
+            Some(Err(e)) => tracing::warn!("replay ends at a bad record: {}", e),
+            None => {}
+        }
+        tracing::info!("replay done, switching to the live stream");
+        self.replay = None;
+        None
+    }
+
+    /// Whether the recording already covered `trade`, see `with_replay`.
+    fn already_replayed(&mut self, trade: &Trade) -> bool {
+        let Some(&last) = self.replayed.get(&trade.symbol) else {
+            return false;
+        };
+        if self.decoder.timestamp_unit().to_micros(trade.timestamp) <= last {
+            return true;
+        }
+        self.replayed.remove(&trade.symbol);
+        false
+    }
+
+    /// Magic first if `require_magic`, then anything up to START and the
+    /// header, or up to a header frame without a START.
+    fn read_handshake(&mut self, require_magic: bool) -> Result<(), DecodeError> {
+        if require_magic {
+            Framing::from_magic(&self.source.next_frame()?)?;
+        }
+        loop {
+            let frame = self.source.next_frame()?;
+            if frame == b"START" {
+                break;
+            }
+            if frame.starts_with(b"PSHFT") {
+                Framing::from_magic(&frame)?;
+            }
+            if let Some(decoder) = BinaryFormat::from_header_frame(&frame) {
+                self.set_decoder(decoder);
+                return Ok(());
+            }
+        }
+        self.read_header()
+    }
+
+    fn read_header(&mut self) -> Result<(), DecodeError> {
+        // Until the header decodes, the next frames can't be read as records
+        self.handshake = Some(false);
+        let header = self.source.next_frame()?;
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut io::Cursor::new(&header))?;
+        self.set_decoder(decoder);
+        Ok(())
+    }
+
+    fn set_decoder(&mut self, decoder: BinaryFormat) {
+        self.decoder = decoder;
+        self.stale.clear();
+        self.handshake = None;
+    }
+
+    fn recover(&mut self, error: &DecodeError) {
+        match self.policy {
+            ErrorPolicy::Reconnect => {
+                self.reconnects += 1;
+                if let Err(e) = self.source.reconnect() {
+                    tracing::error!("reconnect failed: {}", e);
+                    self.done = true;
+                }
+                self.handshake = Some(true);
+            }
+            _ if matches!(error, DecodeError::Io(_)) => self.done = true,
+            ErrorPolicy::Skip => {}
+            ErrorPolicy::Resync => {
+                self.stale = self.decoder.symbols().iter().cloned().collect();
+            }
+        }
+    }
+}
+
+impl<S: FrameSource> Iterator for TradeConsumer<S> {
+    type Item = Result<Trade, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_record()? {
+                Ok(record) => {
+                    if let Some(trade) = record.into_trade() {
+                        return Some(Ok(trade));
+                    }
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{HEADER_MAGIC, STREAM_MAGIC};
+    use std::collections::VecDeque;
+
+    /// Connections replayed in order, one per `reconnect`.
+    struct Scripted {
+        connections: VecDeque<VecDeque<Vec<u8>>>,
+    }
+
+    impl FrameSource for Scripted {
+        fn next_frame(&mut self) -> io::Result<Vec<u8>> {
+            self.connections
+                .front_mut()
+                .and_then(VecDeque::pop_front)
+                .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+        }
+
+        fn reconnect(&mut self) -> io::Result<()> {
+            self.connections.pop_front();
+            Ok(())
+        }
+    }
+
+    fn trade(symbol: &str, timestamp: u64, price: f64) -> Trade {
+        Trade {
+            symbol: symbol.to_string(),
+            timestamp,
+            price,
+            quantity: 0.5,
+            is_buyer_maker: false,
+        }
+    }
+
+    /// A connection of magic, START, header, `trade` and then `more`, with a
+    /// corrupt frame in between.
+    fn connection(reference_price: f64, more: &[(bool, Trade)]) -> VecDeque<Vec<u8>> {
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1_700_000_000_000)
+            .assets(vec![
+                ("BTCUSDT".to_string(), reference_price, 1.0, 100_000.0),
+                ("ETHUSDT".to_string(), 3000.0, 1.0, 100_000.0),
+            ])
+            .build()
+            .unwrap();
+        let mut frames = vec![STREAM_MAGIC.to_vec(), b"START".to_vec(), header];
+        let first = trade("BTCUSDT", 1_700_000_000_000, reference_price + 1.0);
+        frames.push(encoder.encode(&first).unwrap());
+        // A delta the consumer never sees, then a frame it can't decode
+        encoder
+            .encode(&trade("BTCUSDT", 1_700_000_000_001, reference_price + 9.0))
+            .unwrap();
+        frames.push(vec![0xff; 3]);
+        for (keyframe, trade) in more {
+            let frame = match keyframe {
+                true => encoder.encode_keyframe(trade),
+                false => encoder.encode(trade),
+            };
+            frames.push(frame.unwrap());
+        }
+        frames.into()
+    }
+
+    fn consumer(
+        connections: Vec<VecDeque<Vec<u8>>>,
+        policy: ErrorPolicy,
+    ) -> TradeConsumer<Scripted> {
+        let source = Scripted {
+            connections: connections.into(),
+        };
+        TradeConsumer::new(source, policy)
+    }
+
+    /// Trades handed out as (ms past the reference, price), with `None` for
+    /// each error.
+    fn drain(consumer: &mut TradeConsumer<Scripted>) -> Vec<Option<(u64, f64)>> {
+        let mut got: Vec<_> = consumer
+            .by_ref()
+            .map(|item| {
+                item.ok()
+                    .map(|trade| (trade.timestamp - 1_700_000_000_000, trade.price))
+            })
+            .collect();
+        // The end of the script surfaces as a final read error
+        assert!(got.pop().unwrap().is_none());
+        got
+    }
+
+    #[test]
+    fn test_newer_header_version_is_reported() {
+        let mut frames = connection(45000.0, &[]);
+        // A header from a format this build doesn't know yet
+        frames[2][HEADER_MAGIC.len()] = 6;
+        let mut consumer = consumer(vec![frames], ErrorPolicy::Skip);
+        let err = consumer.next().unwrap().unwrap_err();
+        assert!(matches!(
+            err,
+            DecodeError::UnsupportedVersion(UnsupportedVersion { version: 6, .. })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "stream is format v6, this build supports v1-v5; upgrade the consumer"
+        );
+    }
+
+    #[test]
+    fn test_decode_error_policies() {
+        let more = [
+            (false, trade("BTCUSDT", 1_700_000_000_002, 45010.0)),
+            (false, trade("ETHUSDT", 1_700_000_000_003, 3001.0)),
+            (true, trade("BTCUSDT", 1_700_000_000_004, 45011.0)),
+            (false, trade("BTCUSDT", 1_700_000_000_005, 45012.0)),
+        ];
+
+        // Everything past the bad frame, BTC decoded off the lost delta until its keyframe
+        let mut skip = consumer(vec![connection(45000.0, &more)], ErrorPolicy::Skip);
+        assert_eq!(
+            drain(&mut skip),
+            [
+                Some((0, 45001.0)),
+                None,
+                Some((1, 45002.0)),
+                Some((3, 3001.0)),
+                Some((4, 45011.0)),
+                Some((5, 45012.0)),
+            ]
+        );
+        assert_eq!(skip.errors(), 2);
+
+        // The bad frame could have been any asset's, so each waits for its keyframe
+        let mut resync = consumer(vec![connection(45000.0, &more)], ErrorPolicy::Resync);
+        assert_eq!(
+            drain(&mut resync),
+            [
+                Some((0, 45001.0)),
+                None,
+                Some((4, 45011.0)),
+                Some((5, 45012.0)),
+            ]
+        );
+        assert_eq!(resync.skipped(), 2);
+
+        // The rest of the first connection is dropped, the second has a new header
+        let connections = vec![connection(45000.0, &more), connection(46000.0, &[])];
+        let mut reconnect = consumer(connections, ErrorPolicy::Reconnect);
+        assert_eq!(reconnect.next().unwrap().unwrap().price, 45001.0);
+        assert!(reconnect.next().unwrap().is_err());
+        assert_eq!(reconnect.next().unwrap().unwrap().price, 46001.0);
+        assert_eq!(reconnect.reconnects(), 1);
+        assert_eq!(reconnect.errors(), 1);
+    }
+
+    #[test]
+    fn test_header_without_start() {
+        let epoch = |reference_price: f64| {
+            BinaryFormat::builder()
+                .reference_timestamp(1_700_000_000_000)
+                .assets(vec![(
+                    "BTCUSDT".to_string(),
+                    reference_price,
+                    1.0,
+                    100_000.0,
+                )])
+                .build()
+                .unwrap()
+        };
+        let (mut first, first_header) = epoch(45000.0);
+        let (mut second, second_header) = epoch(50000.0);
+        // A re-header with no START, then one with
+        let frames = vec![
+            STREAM_MAGIC.to_vec(),
+            first_header,
+            first
+                .encode(&trade("BTCUSDT", 1_700_000_000_001, 45001.0))
+                .unwrap(),
+            second_header.clone(),
+            second
+                .encode(&trade("BTCUSDT", 1_700_000_000_002, 50002.0))
+                .unwrap(),
+            b"START".to_vec(),
+            second_header,
+            epoch(50000.0)
+                .0
+                .encode(&trade("BTCUSDT", 1_700_000_000_003, 50003.0))
+                .unwrap(),
+        ];
+
+        let mut consumer = consumer(vec![frames.into()], ErrorPolicy::Skip);
+        assert_eq!(
+            drain(&mut consumer),
+            [Some((1, 45001.0)), Some((2, 50002.0)), Some((3, 50003.0))]
+        );
+        assert_eq!(consumer.errors(), 1);
+    }
+
+    #[test]
+    fn test_replay_then_live() {
+        use crate::format::TimestampUnit;
+        use crate::rawlog::RawLog;
+
+        let ms = 1_700_000_000_000;
+        // Recorded in micros, live in millis
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(ms * 1000)
+            .timestamp_unit(TimestampUnit::Micros)
+            .assets(vec![
+                ("BTCUSDT".to_string(), 45000.0, 1.0, 100_000.0),
+                ("ETHUSDT".to_string(), 3000.0, 1.0, 100_000.0),
+            ])
+            .build()
+            .unwrap();
+        let prefix = std::env::temp_dir().join(format!(
+            "perp_signal_hft_test_replay_{}",
+            std::process::id()
+        ));
+        let log = RawLog::create(&prefix).unwrap();
+        log.record_frame(b"START");
+        log.record_frame(&header);
+        for (symbol, micros) in [("BTCUSDT", 0), ("ETHUSDT", 1500), ("BTCUSDT", 2500)] {
+            let frame = encoder
+                .encode(&trade(symbol, ms * 1000 + micros, 1.0))
+                .unwrap();
+            log.record_frame(&frame);
+        }
+        // Killed mid-write
+        let frame = encoder
+            .encode(&trade("BTCUSDT", ms * 1000 + 2600, 1.0))
+            .unwrap();
+        log.record_frame(&frame[..frame.len() - 1]);
+        drop(log);
+
+        let (mut live_encoder, live_header) = BinaryFormat::builder()
+            .reference_timestamp(ms)
+            .assets(vec![
+                ("BTCUSDT".to_string(), 46000.0, 1.0, 100_000.0),
+                ("ETHUSDT".to_string(), 3100.0, 1.0, 100_000.0),
+            ])
+            .build()
+            .unwrap();
+        let mut live = vec![STREAM_MAGIC.to_vec(), b"START".to_vec(), live_header];
+        // The first two were recorded already, so the live stream picks up after them
+        for (symbol, millis) in [
+            ("BTCUSDT", 2),
+            ("ETHUSDT", 1),
+            ("BTCUSDT", 3),
+            ("ETHUSDT", 2),
+        ] {
+            live.push(
+                live_encoder
+                    .encode(&trade(symbol, ms + millis, 1.0))
+                    .unwrap(),
+            );
+        }
+
+        let (_, bin) = RawLog::paths(&prefix);
+        let mut consumer = consumer(vec![live.into()], ErrorPolicy::Skip)
+            .with_replay(&bin)
+            .unwrap();
+        let mut got = Vec::new();
+        while let Some(Ok(trade)) = consumer.next() {
+            let unit = consumer.decoder().timestamp_unit();
+            got.push((trade.symbol, unit.to_micros(trade.timestamp) - ms * 1000));
+            if got.len() == 3 {
+                assert!(consumer.replaying());
+            }
+        }
+        assert!(!consumer.replaying());
+        assert_eq!(consumer.decoder().timestamp_unit(), TimestampUnit::Millis);
+        let got: Vec<_> = got.iter().map(|(s, t)| (s.as_str(), *t)).collect();
+        assert_eq!(
+            got,
+            [
+                ("BTCUSDT", 0),
+                ("ETHUSDT", 1500),
+                ("BTCUSDT", 2500),
+                ("BTCUSDT", 3000),
+                ("ETHUSDT", 2000),
+            ]
+        );
+        assert_eq!(consumer.skipped(), 2);
+
+        let (jsonl, _) = RawLog::paths(&prefix);
+        std::fs::remove_file(jsonl).unwrap();
+        std::fs::remove_file(bin).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+// std
+use std::io::{self, Read};
+
+// external
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// internal
+use crate::format::{BinaryFormatError, STREAM_MAGIC, check_stream_magic};
+
+/// Appended to `STREAM_MAGIC` when the frames after the magic carry varint lengths.
+pub const VARINT_FRAMING_FLAG: u8 = b'V';
+
+/// A varint length takes 7 bits per byte, so a `u32` needs at most 5.
+pub const MAX_LEN_PREFIX: usize = 5;
+
+/// Length prefix of each frame on a byte stream (TCP). SHM queues keep their own
+/// fixed `u32` slot lengths and a recording is unframed, so this only applies
+/// to sockets.
+///
+/// The magic frame is always sent with a `u32` length, so a client can read it
+/// before knowing the framing; a trailing `VARINT_FRAMING_FLAG` on it switches
+/// every later frame to varint lengths. A client predating varint framing
+/// rejects that magic rather than misreading the stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Framing {
+    /// Fixed 4-byte little-endian length
+    #[default]
+    U32,
+    /// LEB128 length: a single byte for frames under 128 bytes, which covers
+    /// nearly every trade
+    Varint,
+}
+
+impl Framing {
+    /// The magic frame announcing this framing.
+    pub fn magic(self) -> Vec<u8> {
+        let mut magic = STREAM_MAGIC.to_vec();
+        if self == Framing::Varint {
+            magic.push(VARINT_FRAMING_FLAG);
+        }
+        magic
+    }
+
+    /// Framing announced by a stream's magic frame, see `magic`.
+    pub fn from_magic(frame: &[u8]) -> Result<Self, BinaryFormatError> {
+        match frame.split_last() {
+            Some((&VARINT_FRAMING_FLAG, magic)) if magic.len() == STREAM_MAGIC.len() => {
+                check_stream_magic(magic)?;
+                Ok(Framing::Varint)
+            }
+            _ => {
+                check_stream_magic(frame)?;
+                Ok(Framing::U32)
+            }
+        }
+    }
+
+    /// Append the length prefix of a `len`-byte frame to `out`.
+    pub fn write_len(self, len: usize, out: &mut Vec<u8>) {
+        let len = len as u32;
+        match self {
+            Framing::U32 => out.extend_from_slice(&len.to_le_bytes()),
+            Framing::Varint => {
+                let mut value = len;
+                while value >= 0x80 {
+                    out.push(value as u8 | 0x80);
+                    value >>= 7;
+                }
+                out.push(value as u8);
+            }
+        }
+    }
+
+    /// Read one length-prefixed frame.
+    pub fn read_frame(self, reader: &mut impl Read) -> io::Result<Vec<u8>> {
+        let len = match self {
+            Framing::U32 => {
+                let mut len_buf = [0u8; 4];
+                reader.read_exact(&mut len_buf)?;
+                u32::from_le_bytes(len_buf)
+            }
+            Framing::Varint => {
+                let mut len = VarintLen::default();
+                let mut byte = [0u8];
+                loop {
+                    reader.read_exact(&mut byte)?;
+                    if let Some(len) = len.push(byte[0])? {
+                        break len;
+                    }
+                }
+            }
+        };
+        let mut buf = vec![0u8; len as usize];
+        reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Async `read_frame`.
+    pub async fn read_frame_async(
+        self,
+        reader: &mut (impl AsyncRead + Unpin),
+    ) -> io::Result<Vec<u8>> {
+        let len = match self {
+            Framing::U32 => reader.read_u32_le().await?,
+            Framing::Varint => {
+                let mut len = VarintLen::default();
+                loop {
+                    if let Some(len) = len.push(reader.read_u8().await?)? {
+                        break len;
+                    }
+                }
+            }
+        };
+        let mut buf = vec![0u8; len as usize];
+        reader.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+}
+
+/// A varint length prefix read a byte at a time.
+#[derive(Default)]
+struct VarintLen {
+    value: u64,
+    bytes: usize,
+}
+
+impl VarintLen {
+    /// Add the next byte, returning the length once it is complete.
+    fn push(&mut self, byte: u8) -> io::Result<Option<u32>> {
+        self.value |= ((byte & 0x7f) as u64) << (7 * self.bytes);
+        self.bytes += 1;
+        if byte & 0x80 == 0 {
+            return match u32::try_from(self.value) {
+                Ok(len) => Ok(Some(len)),
+                Err(_) => Err(bad_len()),
+            };
+        }
+        match self.bytes < MAX_LEN_PREFIX {
+            true => Ok(None),
+            false => Err(bad_len()),
+        }
+    }
+}
+
+fn bad_len() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "frame length prefix overflows u32",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frames_round_trip_in_both_framings() {
+        let frames: Vec<Vec<u8>> = [0usize, 1, 4, 127, 128, 300, 70_000]
+            .iter()
+            .map(|&len| (0..len).map(|i| i as u8).collect())
+            .collect();
+
+        for (framing, prefix_of_4) in [(Framing::U32, 4), (Framing::Varint, 1)] {
+            assert_eq!(Framing::from_magic(&framing.magic()).unwrap(), framing);
+
+            let mut stream = Vec::new();
+            for frame in &frames {
+                framing.write_len(frame.len(), &mut stream);
+                stream.extend_from_slice(frame);
+            }
+            let mut prefix = Vec::new();
+            framing.write_len(4, &mut prefix);
+            assert_eq!(prefix.len(), prefix_of_4);
+
+            let mut reader = io::Cursor::new(&stream);
+            let mut async_reader = stream.as_slice();
+            for frame in &frames {
+                assert_eq!(&framing.read_frame(&mut reader).unwrap(), frame);
+                assert_eq!(
+                    &framing.read_frame_async(&mut async_reader).await.unwrap(),
+                    frame
+                );
+            }
+            assert!(framing.read_frame(&mut reader).is_err());
+        }
+
+        // Not the magic of any framing, or a version this build doesn't read
+        for bad in [&b"PSHFT\x01X"[..], b"PSHFT\x01VV", b"START"] {
+            assert!(Framing::from_magic(bad).is_err());
+        }
+        assert!(matches!(
+            Framing::from_magic(b"PSHFT\x02V"),
+            Err(BinaryFormatError::UnsupportedStreamVersion(2))
+        ));
+        let overlong = [0xffu8; MAX_LEN_PREFIX + 1];
+        assert!(Framing::Varint.read_frame(&mut &overlong[..]).is_err());
+    }
+}
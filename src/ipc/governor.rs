@@ -0,0 +1,327 @@
+// std
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// external
+use tokio::sync::Notify;
+
+/// Default for `--max-buffered-bytes`.
+pub const DEFAULT_MAX_BUFFERED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Memory bound for TCP fan-out, flattened into the `tcp` subcommand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::Args)]
+pub struct MemoryBudget {
+    /// Cap on bytes queued for all TCP clients together; past it the client
+    /// with the largest queue is disconnected
+    #[clap(long = "max-buffered-bytes", default_value_t = DEFAULT_MAX_BUFFERED_BYTES)]
+    pub max_bytes: usize,
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self {
+            max_bytes: DEFAULT_MAX_BUFFERED_BYTES,
+        }
+    }
+}
+
+/// Coarse bound on memory held for TCP clients.
+///
+/// Each client drains the broadcast into its own backlog as fast as it can, so
+/// the broadcast ring only holds frames for as long as that takes, and all
+/// per-client buffering lives in backlogs registered here. A frame that would
+/// take the backlogs' total over the cap is admitted only after disconnecting
+/// the client with the largest backlog, as often as it takes; the others keep
+/// an intact stream. Dropping frames from a backlog instead would leave its
+/// client decoding deltas against the wrong prices, or even without a header.
+/// A frame larger than the cap on its own is never admitted: its client is
+/// disconnected, and the frame counted (`with_oversized`). Not counted: the
+/// one frame each client is currently writing, and kernel socket buffers.
+#[derive(Debug)]
+pub struct MemoryGovernor {
+    budget: MemoryBudget,
+    state: Mutex<State>,
+    /// Highest `in_flight` so far
+    peak: Arc<AtomicU64>,
+    /// Frames refused for being larger than the cap on their own
+    oversized: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    in_flight: usize,
+    next_id: u64,
+    clients: HashMap<u64, Backlog>,
+}
+
+#[derive(Debug, Default)]
+struct Backlog {
+    frames: VecDeque<Vec<u8>>,
+    bytes: usize,
+    shed: bool,
+    closed: bool,
+    wake: Arc<Notify>,
+    cut: Arc<Notify>,
+}
+
+impl Backlog {
+    /// Disconnect: free everything queued and wake the writer to notice.
+    /// Returns the bytes freed.
+    fn shed(&mut self) -> usize {
+        let freed = self.bytes;
+        self.frames.clear();
+        self.bytes = 0;
+        self.shed = true;
+        self.wake.notify_one();
+        self.cut.notify_one();
+        freed
+    }
+
+    fn pop_front(&mut self) -> Option<Vec<u8>> {
+        let frame = self.frames.pop_front()?;
+        self.bytes -= frame.len();
+        Some(frame)
+    }
+}
+
+impl MemoryGovernor {
+    pub fn new(budget: MemoryBudget) -> Self {
+        Self {
+            budget,
+            state: Mutex::new(State::default()),
+            peak: Arc::default(),
+            oversized: Arc::default(),
+        }
+    }
+
+    /// Track the peak of `in_flight` in `peak`, eg: `Metrics::peak_queued_bytes`.
+    pub fn with_peak(mut self, peak: Arc<AtomicU64>) -> Self {
+        self.peak = peak;
+        self
+    }
+
+    /// Count frames larger than the cap in `oversized`, eg:
+    /// `Metrics::tcp_frames_oversized`.
+    pub fn with_oversized(mut self, oversized: Arc<AtomicU64>) -> Self {
+        self.oversized = oversized;
+        self
+    }
+
+    /// Frames refused so far for being larger than the cap on their own.
+    pub fn oversized_frames(&self) -> u64 {
+        self.oversized.load(Ordering::Relaxed)
+    }
+
+    /// Bytes queued across all client backlogs.
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    /// Clients registered and not yet dropped.
+    pub fn clients(&self) -> usize {
+        self.state.lock().unwrap().clients.len()
+    }
+
+    /// Start tracking a backlog for a new client.
+    pub fn register(self: &Arc<Self>) -> ClientBacklog {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        let backlog = Backlog::default();
+        let (wake, cut) = (backlog.wake.clone(), backlog.cut.clone());
+        state.clients.insert(id, backlog);
+        ClientBacklog {
+            governor: self.clone(),
+            id,
+            wake,
+            cut,
+        }
+    }
+
+    fn push(&self, id: u64, frame: Vec<u8>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.clients.get(&id).is_none_or(|b| b.shed) {
+            return false;
+        }
+        let len = frame.len();
+        if len > self.budget.max_bytes {
+            // No amount of shedding makes room
+            self.oversized.fetch_add(1, Ordering::Relaxed);
+            let freed = state.clients.get_mut(&id).unwrap().shed();
+            state.in_flight -= freed;
+            tracing::warn!(
+                "disconnecting client {}: a {} byte frame is larger than the memory cap",
+                id,
+                len
+            );
+            return false;
+        }
+        while state.in_flight + len > self.budget.max_bytes {
+            // The frame fits the cap, so whatever is over it is queued somewhere
+            let (&victim, _) = state
+                .clients
+                .iter()
+                .filter(|(_, b)| b.bytes > 0)
+                .max_by_key(|(_, b)| b.bytes)
+                .unwrap();
+            let freed = state.clients.get_mut(&victim).unwrap().shed();
+            state.in_flight -= freed;
+            if victim == id {
+                tracing::warn!("memory cap reached, disconnecting client {}", id);
+                return false;
+            }
+            tracing::warn!(
+                "memory cap reached, shed {} bytes queued for client {}",
+                freed,
+                victim
+            );
+        }
+        let backlog = state.clients.get_mut(&id).unwrap();
+        backlog.frames.push_back(frame);
+        backlog.bytes += len;
+        backlog.wake.notify_one();
+        state.in_flight += len;
+        self.peak
+            .fetch_max(state.in_flight as u64, Ordering::Relaxed);
+        true
+    }
+
+    /// `Some(frame)`, `None` if there is nothing queued yet, or `Err(())` once
+    /// the client is done: shed, or closed with everything written.
+    fn try_pop(&self, id: u64) -> Result<Option<Vec<u8>>, ()> {
+        let mut state = self.state.lock().unwrap();
+        let backlog = state.clients.get_mut(&id).ok_or(())?;
+        if backlog.shed {
+            return Err(());
+        }
+        match backlog.pop_front() {
+            Some(frame) => {
+                state.in_flight -= frame.len();
+                Ok(Some(frame))
+            }
+            None if backlog.closed => Err(()),
+            None => Ok(None),
+        }
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut Backlog)) {
+        if let Some(backlog) = self.state.lock().unwrap().clients.get_mut(&id) {
+            f(backlog);
+            backlog.wake.notify_one();
+        }
+    }
+
+    fn is_shed(&self, id: u64) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .clients
+            .get(&id)
+            .is_some_and(|b| b.shed)
+    }
+
+    fn unregister(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(backlog) = state.clients.remove(&id) {
+            state.in_flight -= backlog.bytes;
+        }
+    }
+}
+
+/// One client's queue of frames waiting to be written, accounted against its
+/// `MemoryGovernor`. Dropping it releases whatever is still queued.
+#[derive(Debug)]
+pub struct ClientBacklog {
+    governor: Arc<MemoryGovernor>,
+    id: u64,
+    wake: Arc<Notify>,
+    cut: Arc<Notify>,
+}
+
+impl ClientBacklog {
+    /// Queue a frame, disconnecting the slowest client if needed. Returns
+    /// `false` once this client has been disconnected by the governor.
+    pub fn push(&self, frame: Vec<u8>) -> bool {
+        self.governor.push(self.id, frame)
+    }
+
+    /// Wait for the next frame to write. `None` once the client was shed, or
+    /// after `close` once the backlog is empty.
+    pub async fn pop(&self) -> Option<Vec<u8>> {
+        loop {
+            match self.governor.try_pop(self.id) {
+                Ok(Some(frame)) => return Some(frame),
+                Ok(None) => self.wake.notified().await,
+                Err(()) => return None,
+            }
+        }
+    }
+
+    /// No more frames will be pushed; `pop` drains what is left, then ends.
+    pub fn close(&self) {
+        self.governor.update(self.id, |b| b.closed = true);
+    }
+
+    /// Whether the governor disconnected this client.
+    pub fn is_shed(&self) -> bool {
+        self.governor.is_shed(self.id)
+    }
+
+    /// Resolves once the governor disconnects this client, for abandoning a
+    /// write that is stuck on a full socket.
+    pub async fn shed(&self) {
+        self.cut.notified().await
+    }
+}
+
+impl Drop for ClientBacklog {
+    fn drop(&mut self) {
+        self.governor.unregister(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn governor(max_bytes: usize) -> Arc<MemoryGovernor> {
+        Arc::new(MemoryGovernor::new(MemoryBudget { max_bytes }))
+    }
+
+    #[tokio::test]
+    async fn test_slowest_client_is_disconnected() {
+        // The slowest client is cut off, the other keeps its frames
+        let gov = governor(10);
+        let (slow, fast) = (gov.register(), gov.register());
+        assert!(slow.push(vec![1; 4]));
+        assert!(slow.push(vec![2; 4]));
+        assert!(fast.push(vec![3; 2]));
+        assert_eq!(gov.in_flight(), 10);
+        assert!(fast.push(vec![4; 3]));
+        assert!(slow.is_shed());
+        assert!(!slow.push(vec![5; 1]));
+        assert_eq!(slow.pop().await, None);
+        assert_eq!(gov.in_flight(), 5);
+        assert_eq!(fast.pop().await, Some(vec![3; 2]));
+        assert_eq!(gov.in_flight(), 3);
+        drop(slow);
+        assert_eq!(gov.clients(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_frame_larger_than_the_cap_disconnects_its_client() {
+        let gov = governor(10);
+        let (big, other) = (gov.register(), gov.register());
+        assert!(big.push(vec![1; 4]));
+        assert!(other.push(vec![2; 4]));
+        assert!(!big.push(vec![3; 11]));
+        assert!(big.is_shed());
+        assert_eq!(big.pop().await, None);
+        assert_eq!(gov.oversized_frames(), 1);
+        // Only the pushing client's backlog is freed
+        assert!(!other.is_shed());
+        assert_eq!(gov.in_flight(), 4);
+        assert_eq!(other.pop().await, Some(vec![2; 4]));
+    }
+}
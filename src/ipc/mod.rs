@@ -1,2 +1,8 @@
+pub mod bridge;
+pub mod consumer;
+pub mod framing;
+pub mod governor;
 pub mod shm_queue;
 pub mod tcp;
+pub mod tcp_client;
+pub mod transcode;
@@ -1,18 +1,97 @@
 // std
-use std::os::unix::fs::OpenOptionsExt;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::{fs::OpenOptions, io, ptr};
+use std::fs::Permissions;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use std::{fs::OpenOptions, hint, io, ptr, thread, thread::Thread};
 // external
 use memmap2::{MmapMut, MmapOptions};
 
 const HEADER_SIZE: usize = 4096;
+/// Queue files are only ever readable/writable by the owning user.
+const FILE_MODE: u32 = 0o600;
+
+/// An existing queue file whose header breaks the queue's invariants, eg: left
+/// behind by a producer that crashed mid-init. The file has to be deleted and
+/// recreated; attaching to it would read garbage offsets.
+#[derive(Debug, thiserror::Error)]
+#[error("corrupt SHM queue {path}: {reason}; delete it and recreate the queue")]
+pub struct CorruptQueue {
+    pub path: String,
+    pub reason: String,
+}
+
+/// How `pop_blocking` waits while the queue is empty. Trades latency against
+/// CPU: a message is picked up at most one poll interval late.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitStrategy {
+    /// Busy-poll. Lowest latency, but keeps a core at 100% even when idle.
+    Spin,
+    /// Busy-poll `spins` times, then sleep `sleep` between polls. Bursts are
+    /// picked up at spin latency, idle periods cost little CPU.
+    Hybrid { spins: u32, sleep: Duration },
+    /// Sleep `sleep` between polls. Near-zero CPU when idle, up to `sleep` (plus
+    /// scheduler wake-up) of added latency per message.
+    Block { sleep: Duration },
+    /// Busy-poll `spins` times, then park the thread for up to `timeout` per
+    /// poll. A push through the same `ShmQueue`, or through one that was given
+    /// this thread with `register_consumer`, unparks it at once, so idle periods
+    /// cost near-zero CPU without the `Block` latency.
+    ///
+    /// Same process only: `Thread` handles live in process memory, not in the
+    /// queue file, so a producer in another process never wakes the consumer
+    /// and every message waits out the `timeout`. Use eventfd across processes.
+    Park { spins: u32, timeout: Duration },
+}
+
+/// `--wait` values for the consumer bins, see `WaitStrategy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum WaitMode {
+    Spin,
+    Hybrid,
+    Block,
+}
+
+/// Consumer wait flags, flattened into the SHM consumer bins' options.
+#[derive(Debug, Clone, clap::Args)]
+pub struct WaitOpts {
+    /// How to wait for messages: spin (lowest latency, a full core),
+    /// hybrid (spin, then sleep) or block (sleep between polls, least CPU)
+    #[clap(long, value_enum, default_value_t = WaitMode::Spin)]
+    pub wait: WaitMode,
+
+    /// Polls before hybrid mode starts sleeping
+    #[clap(long, default_value_t = 10_000)]
+    pub spin_count: u32,
+
+    /// Sleep between polls in hybrid and block modes, in microseconds
+    #[clap(long, default_value_t = 100)]
+    pub sleep_us: u64,
+}
+
+impl WaitOpts {
+    pub fn strategy(&self) -> WaitStrategy {
+        let sleep = Duration::from_micros(self.sleep_us);
+        match self.wait {
+            WaitMode::Spin => WaitStrategy::Spin,
+            WaitMode::Hybrid => WaitStrategy::Hybrid {
+                spins: self.spin_count,
+                sleep,
+            },
+            WaitMode::Block => WaitStrategy::Block { sleep },
+        }
+    }
+}
 
 #[repr(C)]
 struct QueueHeader {
     capacity: u32,   // buffer size in bytes
     head: AtomicU32, // read cursor
     tail: AtomicU32, // write cursor
-    _pad: [u8; HEADER_SIZE - 12],
+    _reserved: u32,
+    dropped: AtomicU64, // pushes rejected because the queue was full
+    _pad: [u8; HEADER_SIZE - 24],
 }
 
 pub struct ShmQueue {
@@ -20,22 +99,48 @@ pub struct ShmQueue {
     header: *mut QueueHeader,
     buf_off: usize,
     capacity: u32,
+    /// Thread to unpark after a push, see `WaitStrategy::Park`. Set once so the
+    /// push path reads it without taking a lock.
+    consumer: OnceLock<Thread>,
 }
 
 impl ShmQueue {
     /// Create or open an SPSC queue in /dev/shm with given name and capacity
+    ///
+    /// The file is always left with `0o600` permissions. `mode` only applies when
+    /// the file is created, so permissions are set again explicitly to tighten a
+    /// pre-existing file. An existing queue is attached to as-is and never
+    /// truncated, since the other side of the queue may still rely on its
+    /// contents. Opening an existing queue with a different capacity fails with
+    /// `InvalidInput`, and one with an inconsistent header fails with `InvalidData`
+    /// wrapping a `CorruptQueue`.
     pub fn create(name: &str, capacity: u32) -> io::Result<Self> {
         let path = format!("/dev/shm/{}", name);
         let file = OpenOptions::new()
             .create(true)
-            .truncate(true)
+            .truncate(false)
             .read(true)
             .write(true)
-            .mode(0o600)
+            .mode(FILE_MODE)
             .open(&path)?;
+        file.set_permissions(Permissions::from_mode(FILE_MODE))?;
 
         let total_size = HEADER_SIZE + capacity as usize;
-        file.set_len(total_size as u64)?;
+        match file.metadata()?.len() {
+            0 => file.set_len(total_size as u64)?,
+            len if len == total_size as u64 => {}
+            len => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} exists with capacity {} (requested {})",
+                        path,
+                        len.saturating_sub(HEADER_SIZE as u64),
+                        capacity
+                    ),
+                ));
+            }
+        }
 
         let mut mmap = unsafe { MmapOptions::new().len(total_size).map_mut(&file)? };
         let header_ptr = mmap.as_mut_ptr() as *mut QueueHeader;
@@ -45,6 +150,9 @@ impl ShmQueue {
                 (*header_ptr).capacity = capacity;
                 (*header_ptr).head = AtomicU32::new(0);
                 (*header_ptr).tail = AtomicU32::new(0);
+                (*header_ptr).dropped = AtomicU64::new(0);
+            } else {
+                Self::validate(&path, &*header_ptr, capacity)?;
             }
         }
 
@@ -53,11 +161,103 @@ impl ShmQueue {
             header: header_ptr,
             buf_off: HEADER_SIZE,
             capacity,
+            consumer: OnceLock::new(),
         })
     }
 
-    /// Push a message (length-prefixed) into the queue
+    /// Check an existing header: capacity as requested and `head <= tail <= head + capacity`.
+    fn validate(path: &str, header: &QueueHeader, capacity: u32) -> io::Result<()> {
+        let head = header.head.load(Ordering::Acquire);
+        let tail = header.tail.load(Ordering::Acquire);
+        let reason = if header.capacity != capacity {
+            format!(
+                "header capacity {} (file sized for {})",
+                header.capacity, capacity
+            )
+        } else if tail.wrapping_sub(head) > capacity {
+            // Cursors only grow, so `head > tail` also lands here
+            format!(
+                "head {} and tail {} exceed capacity {}",
+                head, tail, capacity
+            )
+        } else {
+            return Ok(());
+        };
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            CorruptQueue {
+                path: path.to_string(),
+                reason,
+            },
+        ))
+    }
+
+    /// Push a message (length-prefixed) into the queue. A full queue rejects the
+    /// message and counts it in `dropped`.
+    ///
+    /// Never waits, so it is safe to call on an async runtime thread; use
+    /// `push_async` to wait for room from async code.
     pub fn push(&self, data: &[u8]) -> io::Result<()> {
+        if self.try_write(data) {
+            return Ok(());
+        }
+        self.reject()
+    }
+
+    /// Push a message if it fits, returning whether it did. Unlike `push`, a
+    /// full queue isn't counted in `dropped`, for a caller that holds on to the
+    /// message to retry; one that then gives up on it calls `record_drop`.
+    pub fn try_push(&self, data: &[u8]) -> bool {
+        self.try_write(data)
+    }
+
+    /// Count a message given up on in `dropped`, see `try_push`.
+    pub fn record_drop(&self) {
+        unsafe { &*self.header }
+            .dropped
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Push a message, retrying per `wait` while the queue is full. Gives up
+    /// after `timeout`, rejecting the message as `push` does, so a dead consumer
+    /// can't stall the producer forever.
+    pub fn push_blocking(
+        &self,
+        data: &[u8],
+        wait: WaitStrategy,
+        timeout: Duration,
+    ) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut polls: u32 = 0;
+        while !self.try_write(data) {
+            if Instant::now() >= deadline {
+                return self.reject();
+            }
+            idle(wait, &mut polls);
+        }
+        Ok(())
+    }
+
+    /// `push_blocking` for async callers. Tries once in place, and only moves
+    /// to a blocking thread to retry when the queue is full, so neither the
+    /// waiting nor the retries hold up the runtime.
+    pub async fn push_async(
+        self: &Arc<Self>,
+        data: Vec<u8>,
+        wait: WaitStrategy,
+        timeout: Duration,
+    ) -> io::Result<()> {
+        if self.try_write(&data) {
+            return Ok(());
+        }
+        let queue = self.clone();
+        tokio::task::spawn_blocking(move || queue.push_blocking(&data, wait, timeout))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Write one message if it fits, without counting a drop when it doesn't.
+    fn try_write(&self, data: &[u8]) -> bool {
         let cap = self.capacity;
         let header = unsafe { &*self.header };
         let tail = header.tail.load(Ordering::Relaxed);
@@ -65,14 +265,52 @@ impl ShmQueue {
         let free = cap + head - tail;
         let needed = 4 + data.len() as u32;
         if needed > free {
-            return Err(io::Error::new(io::ErrorKind::Other, "Queue full"));
+            return false;
         }
         self.write_at(tail & (cap - 1), &(data.len() as u32).to_le_bytes());
         self.write_at((tail & (cap - 1)) + 4, data);
         header.tail.store(tail + needed, Ordering::Release);
+        if let Some(consumer) = self.consumer.get() {
+            consumer.unpark();
+        }
+        true
+    }
+
+    /// Unpark `thread` after every push through this handle, for a consumer
+    /// waiting with `WaitStrategy::Park` on its own handle to the same queue.
+    /// Only works within one process, see `WaitStrategy::Park`. A handle has
+    /// one consumer for good: registering the same thread again is a no-op,
+    /// another one fails with `AlreadyExists`, since it would never be woken.
+    pub fn register_consumer(&self, thread: Thread) -> io::Result<()> {
+        let id = thread.id();
+        let registered = self.consumer.get_or_init(|| thread);
+        if registered.id() != id {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} is already this queue's consumer", registered.id()),
+            ));
+        }
         Ok(())
     }
 
+    fn reject(&self) -> io::Result<()> {
+        self.record_drop();
+        Err(io::Error::other("Queue full"))
+    }
+
+    /// Messages the producer could not push because the queue was full, over the
+    /// queue file's lifetime. Lives in the shared header so consumers can see it.
+    pub fn dropped(&self) -> u64 {
+        unsafe { &*self.header }.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Bytes pushed and not yet popped, length prefixes included.
+    pub fn unread_bytes(&self) -> u32 {
+        let header = unsafe { &*self.header };
+        let head = header.head.load(Ordering::Acquire);
+        header.tail.load(Ordering::Acquire) - head
+    }
+
     /// Pop a message, returning None if empty
     pub fn pop(&self) -> io::Result<Option<Vec<u8>>> {
         let cap = self.capacity;
@@ -91,6 +329,23 @@ impl ShmQueue {
         Ok(Some(data))
     }
 
+    /// Pop a message, waiting for one according to `wait`. With
+    /// `WaitStrategy::Park` the calling thread registers itself as this
+    /// handle's consumer first, failing as `register_consumer` does if another
+    /// thread already is.
+    pub fn pop_blocking(&self, wait: WaitStrategy) -> io::Result<Vec<u8>> {
+        if let WaitStrategy::Park { .. } = wait {
+            self.register_consumer(thread::current())?;
+        }
+        let mut polls: u32 = 0;
+        loop {
+            if let Some(data) = self.pop()? {
+                return Ok(data);
+            }
+            idle(wait, &mut polls);
+        }
+    }
+
     /// write bytes at offset (may wrap)
     fn write_at(&self, offset: u32, bytes: &[u8]) {
         let cap = self.capacity as usize;
@@ -140,8 +395,323 @@ impl ShmQueue {
     }
 }
 
+/// Wait once between polls per `wait`; `polls` counts a hybrid wait's spins.
+fn idle(wait: WaitStrategy, polls: &mut u32) {
+    match wait {
+        WaitStrategy::Spin => hint::spin_loop(),
+        WaitStrategy::Hybrid { spins, sleep } => {
+            if *polls < spins {
+                *polls += 1;
+                hint::spin_loop();
+            } else {
+                thread::sleep(sleep);
+            }
+        }
+        WaitStrategy::Block { sleep } => thread::sleep(sleep),
+        WaitStrategy::Park { spins, timeout } => {
+            if *polls < spins {
+                *polls += 1;
+                hint::spin_loop();
+            } else {
+                // A push that lands before this still wakes it: the unpark
+                // token makes the park return straight away
+                thread::park_timeout(timeout);
+            }
+        }
+    }
+}
+
 // SAFETY: ShmQueue only contains an mmap and a raw pointer into that mmap, which is safe to send
 // across threads as long as both sides agree on the shared memory region.
 unsafe impl Send for ShmQueue {}
 // Multiple readers/writers coordinate via atomics, so Sync is also safe.
 unsafe impl Sync for ShmQueue {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_tightens_permissions_and_keeps_contents() {
+        let name = format!("perp_signal_hft_test_mode_{}", std::process::id());
+        let path = format!("/dev/shm/{}", name);
+        let capacity = 4096;
+
+        let mode_of = |path: &str| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        // Pre-existing, world-readable file as if another tool had created it
+        std::fs::File::create(&path).unwrap();
+        std::fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+
+        let producer = ShmQueue::create(&name, capacity).unwrap();
+        assert_eq!(mode_of(&path), FILE_MODE);
+        producer.push(b"hello").unwrap();
+
+        std::fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        let consumer = ShmQueue::create(&name, capacity).unwrap();
+        assert_eq!(mode_of(&path), FILE_MODE);
+
+        // Attaching must not wipe what the producer already pushed
+        assert_eq!(consumer.pop().unwrap(), Some(b"hello".to_vec()));
+
+        let err = ShmQueue::create(&name, capacity * 2).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_attach_rejects_corrupt_header() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let name = format!("perp_signal_hft_test_corrupt_{}", std::process::id());
+        let path = format!("/dev/shm/{}", name);
+        let capacity = 4096;
+        drop(ShmQueue::create(&name, capacity).unwrap());
+
+        let corrupt = |head: u32, tail: u32| {
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::Start(4)).unwrap();
+            file.write_all(&head.to_le_bytes()).unwrap();
+            file.write_all(&tail.to_le_bytes()).unwrap();
+        };
+
+        // head past tail
+        corrupt(100, 10);
+        let err = ShmQueue::create(&name, capacity).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let inner = err
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<CorruptQueue>()
+            .unwrap();
+        assert_eq!(inner.path, path);
+
+        // more unread bytes than the buffer holds
+        corrupt(0, capacity + 1);
+        let err = ShmQueue::create(&name, capacity).err().unwrap();
+        assert!(err.get_ref().unwrap().is::<CorruptQueue>());
+
+        corrupt(8, 8);
+        assert!(ShmQueue::create(&name, capacity).is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// CPU time consumed by the calling thread so far.
+    fn thread_cpu_time() -> Duration {
+        let stat = std::fs::read_to_string("/proc/thread-self/schedstat").unwrap();
+        let ns = stat.split_whitespace().next().unwrap().parse().unwrap();
+        Duration::from_nanos(ns)
+    }
+
+    #[test]
+    fn test_block_wait_idles_cheaply() {
+        let name = format!("perp_signal_hft_test_wait_{}", std::process::id());
+        let queue = std::sync::Arc::new(ShmQueue::create(&name, 4096).unwrap());
+
+        let consumer = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                let before = thread_cpu_time();
+                let data = queue
+                    .pop_blocking(WaitStrategy::Block {
+                        sleep: Duration::from_millis(1),
+                    })
+                    .unwrap();
+                (data, thread_cpu_time() - before)
+            })
+        };
+
+        let idle = Duration::from_millis(300);
+        thread::sleep(idle);
+        queue.push(b"wake").unwrap();
+        let (data, cpu) = consumer.join().unwrap();
+        assert_eq!(data, b"wake");
+        // Spinning would burn close to the whole idle period
+        assert!(cpu < idle / 4, "blocked consumer used {:?} of CPU", cpu);
+
+        std::fs::remove_file(format!("/dev/shm/{}", name)).unwrap();
+    }
+
+    #[test]
+    fn test_park_wait_is_woken_by_push() {
+        let name = format!("perp_signal_hft_test_park_{}", std::process::id());
+        let producer = ShmQueue::create(&name, 4096).unwrap();
+        let queue = ShmQueue::create(&name, 4096).unwrap();
+        // Long enough that only an unpark explains a timely wake-up
+        let timeout = Duration::from_secs(10);
+
+        let consumer = thread::spawn(move || {
+            let wait = WaitStrategy::Park { spins: 0, timeout };
+            let before = thread_cpu_time();
+            let first = queue.pop_blocking(wait).unwrap();
+            let cpu = thread_cpu_time() - before;
+            let woke = Instant::now();
+            let second = queue.pop_blocking(wait).unwrap();
+            (first, cpu, second, woke.elapsed())
+        });
+        producer
+            .register_consumer(consumer.thread().clone())
+            .unwrap();
+
+        let idle = Duration::from_millis(300);
+        thread::sleep(idle);
+        producer.push(b"wake").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        producer.push(b"again").unwrap();
+
+        let (first, cpu, second, waited) = consumer.join().unwrap();
+        assert_eq!(first, b"wake");
+        assert_eq!(second, b"again");
+        assert!(cpu < idle / 4, "parked consumer used {:?} of CPU", cpu);
+        assert!(waited < timeout / 2, "unpark took {:?}", waited);
+
+        std::fs::remove_file(format!("/dev/shm/{}", name)).unwrap();
+    }
+
+    #[test]
+    fn test_another_consumer_is_refused() {
+        let name = format!("perp_signal_hft_test_register_{}", std::process::id());
+        let producer = ShmQueue::create(&name, 4096).unwrap();
+        let other = thread::spawn(|| {});
+        producer.register_consumer(thread::current()).unwrap();
+        // Again from the same thread, as every parked `pop_blocking` does
+        producer.register_consumer(thread::current()).unwrap();
+        let err = producer
+            .register_consumer(other.thread().clone())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        other.join().unwrap();
+        assert_eq!(
+            producer.consumer.get().unwrap().id(),
+            thread::current().id()
+        );
+        let park = WaitStrategy::Park {
+            spins: 0,
+            timeout: Duration::from_millis(1),
+        };
+        producer.push(b"hello").unwrap();
+        assert_eq!(producer.pop_blocking(park).unwrap(), b"hello");
+        let popped = thread::scope(|s| s.spawn(|| producer.pop_blocking(park)).join().unwrap());
+        assert_eq!(popped.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        std::fs::remove_file(format!("/dev/shm/{}", name)).unwrap();
+    }
+
+    /// FNV-1a, to tell a torn message from an intact one.
+    fn checksum(bytes: &[u8]) -> u64 {
+        bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    #[test]
+    fn test_concurrent_push_pop_never_tears() {
+        // Millions more under `cargo test --release`, for a longer race
+        const MESSAGES: u64 = if cfg!(debug_assertions) {
+            1_000_000
+        } else {
+            5_000_000
+        };
+
+        let name = format!("perp_signal_hft_test_torn_{}", std::process::id());
+        // Small, so the queue fills up and messages wrap around its end all the time.
+        // Separate mappings of the file, as in separate processes
+        let producer = ShmQueue::create(&name, 1024).unwrap();
+        let queue = ShmQueue::create(&name, 1024).unwrap();
+
+        let consumer = thread::spawn(move || {
+            let wait = WaitStrategy::Block {
+                sleep: Duration::from_micros(10),
+            };
+            for seq in 0..MESSAGES {
+                let message = queue.pop_blocking(wait).unwrap();
+                // Sequence number, filler, then the checksum of both
+                let (body, sum) = message.split_at(message.len() - 8);
+                assert_eq!(
+                    checksum(body).to_le_bytes(),
+                    sum,
+                    "message {} torn: {} bytes",
+                    seq,
+                    message.len()
+                );
+                assert_eq!(body[..8], seq.to_le_bytes(), "message out of order");
+            }
+            assert_eq!(queue.pop().unwrap(), None);
+        });
+
+        let mut rng = 0x9e3779b97f4a7c15u64;
+        let mut message = Vec::with_capacity(80);
+        for seq in 0..MESSAGES {
+            // xorshift, for lengths from 16 to 79 bytes and varied filler
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            message.clear();
+            message.extend_from_slice(&seq.to_le_bytes());
+            message.extend((0..rng % 64).map(|i| (rng >> (i % 56)) as u8));
+            message.extend_from_slice(&checksum(&message).to_le_bytes());
+            let wait = WaitStrategy::Block {
+                sleep: Duration::from_micros(10),
+            };
+            producer
+                .push_blocking(&message, wait, Duration::from_secs(10))
+                .unwrap();
+        }
+        consumer.join().unwrap();
+        assert_eq!(producer.dropped(), 0);
+
+        std::fs::remove_file(format!("/dev/shm/{}", name)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_push_async_waits_off_the_runtime() {
+        let name = format!("perp_signal_hft_test_push_async_{}", std::process::id());
+        let queue = Arc::new(ShmQueue::create(&name, 64).unwrap());
+        let mut filled = 0;
+        while queue.try_write(b"fill") {
+            filled += 1;
+        }
+        let wait = WaitStrategy::Block {
+            sleep: Duration::from_millis(1),
+        };
+
+        // Retries until the queue drains, while the runtime keeps running
+        let push = {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                queue
+                    .push_async(b"late".to_vec(), wait, Duration::from_secs(5))
+                    .await
+            })
+        };
+        let mut ticks = 0;
+        for _ in 0..10 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            ticks += 1;
+        }
+        assert_eq!(ticks, 10);
+        assert!(!push.is_finished());
+
+        for _ in 0..filled {
+            assert_eq!(queue.pop().unwrap().unwrap(), b"fill");
+        }
+        push.await.unwrap().unwrap();
+        assert_eq!(queue.pop().unwrap().unwrap(), b"late");
+        // Retrying isn't dropping
+        assert_eq!(queue.dropped(), 0);
+
+        // A consumer that never drains makes it give up and count the drop
+        while queue.try_write(b"fill") {}
+        assert!(
+            queue
+                .push_async(b"late".to_vec(), wait, Duration::from_millis(20))
+                .await
+                .is_err()
+        );
+        assert_eq!(queue.dropped(), 1);
+
+        std::fs::remove_file(format!("/dev/shm/{}", name)).unwrap();
+    }
+}
@@ -1,29 +1,280 @@
 // std
+use std::io::{self, Cursor};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 // external
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio::sync::broadcast;
 
+// internal
+use crate::format::{BinaryFormat, BinaryFormatError, SUPPORTED_VERSIONS};
+use crate::ipc::framing::{Framing, MAX_LEN_PREFIX};
+use crate::ipc::governor::{ClientBacklog, MemoryGovernor};
+use crate::ipc::transcode::Transcoder;
+
+/// Size of a credit grant, the only thing a client ever sends: a little-endian
+/// `u32` count of further frames it is ready for. See `ServeOptions::credit_flow_control`.
+pub const CREDIT_GRANT_LEN: usize = 4;
+
+/// Start of a version hello, which a client sends on connecting to say the
+/// highest header version it reads: these three bytes, then the version as a
+/// `u8`. See `ServeOptions::negotiate_version`.
+pub const VERSION_HELLO_PREFIX: &[u8; 3] = b"PSV";
+
+/// How long a server negotiating versions waits for a client's hello before
+/// taking it for a client that predates them.
+pub const VERSION_HELLO_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The hello announcing `version`, see `VERSION_HELLO_PREFIX`.
+pub fn version_hello(version: u8) -> [u8; 4] {
+    let [p, s, v] = *VERSION_HELLO_PREFIX;
+    [p, s, v, version]
+}
+
+/// Why a client connection ended early, telling a client that went away apart
+/// from a real failure.
+#[derive(Debug, thiserror::Error)]
+pub enum TcpProtocolError {
+    #[error("client closed the connection during the handshake")]
+    ClosedDuringHandshake,
+    #[error("handshake failed: {0}")]
+    Handshake(io::Error),
+    #[error("client closed the connection")]
+    Closed,
+    #[error("write failed: {0}")]
+    Io(io::Error),
+    #[error("disconnected, over the TCP memory budget")]
+    OverBudget,
+    #[error("transcoding for the client failed: {0}")]
+    Transcode(BinaryFormatError),
+}
+
+impl TcpProtocolError {
+    /// The client went away on its own, not worth more than an info log.
+    pub fn is_disconnect(&self) -> bool {
+        matches!(self, Self::ClosedDuringHandshake | Self::Closed)
+    }
+
+    fn handshake(e: io::Error) -> Self {
+        match closed_by_peer(&e) {
+            true => Self::ClosedDuringHandshake,
+            false => Self::Handshake(e),
+        }
+    }
+
+    fn stream(e: io::Error) -> Self {
+        match closed_by_peer(&e) {
+            true => Self::Closed,
+            false => Self::Io(e),
+        }
+    }
+}
+
+fn closed_by_peer(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Per-server settings for `serve`.
+#[derive(Clone, Default)]
+pub struct ServeOptions {
+    /// Keyframes of each asset's latest trade follow the header; feed it
+    /// through `publish_frame`
+    pub snapshot: Option<SharedSnapshot>,
+    /// Credit-based flow control: past the handshake (magic, START, header and
+    /// any keyframes, which are always sent), each frame spends one credit the
+    /// client granted. Without credits frames wait in the client's backlog,
+    /// counted against the `MemoryGovernor`, rather than being skipped, since a
+    /// skipped delta would corrupt every later one.
+    pub credit_flow_control: bool,
+    /// Length prefix of every frame after the magic, announced in the magic
+    /// frame itself, see `Framing`
+    pub framing: Framing,
+    /// Clients connected right now: counted from accept until the client's task
+    /// ends, however it ends. Unlike the broadcast's `receiver_count()` this
+    /// leaves out other subscribers to the same sender, such as the JSON trade
+    /// stream's clients. Share it to read it elsewhere, eg: `Metrics::tcp_clients`.
+    pub clients: Arc<AtomicUsize>,
+    /// Times a client fell behind the broadcast ring and lost frames, eg:
+    /// `Metrics::tcp_lag_events`
+    pub lag_events: Arc<AtomicU64>,
+    /// Read each client's version hello and transcode the stream down to the
+    /// header version it reads, so clients of two versions share one pipeline
+    /// during a format upgrade. A client that sends no hello within
+    /// `VERSION_HELLO_TIMEOUT` gets v1, the only version older clients read.
+    pub negotiate_version: bool,
+}
+
+impl ServeOptions {
+    /// Clients connected right now, see `clients`.
+    pub fn client_count(&self) -> usize {
+        self.clients.load(Ordering::Relaxed)
+    }
+}
+
+/// A client counted in `ServeOptions::clients` until dropped.
+struct ConnectedClient(Arc<AtomicUsize>);
+
+impl ConnectedClient {
+    fn new(clients: &Arc<AtomicUsize>) -> Self {
+        clients.fetch_add(1, Ordering::Relaxed);
+        Self(clients.clone())
+    }
+}
+
+impl Drop for ConnectedClient {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Header handed to each new client. Replace it through `publish_header` so
+/// clients joining later get the current one rather than the startup one.
+pub type SharedHeader = Arc<RwLock<Vec<u8>>>;
+
+/// Latest trade per asset as seen on the broadcast, sent to each new client as
+/// keyframes right after the header, so an illiquid asset has a fresh price
+/// without waiting for its next trade. Kept by decoding every broadcast frame,
+/// which reconstructs exactly the encoder's per-asset state.
+pub type SharedSnapshot = Arc<Mutex<Snapshot>>;
+
+pub struct Snapshot {
+    decoder: BinaryFormat,
+    /// The last frame was START, so the next one is a header
+    expect_header: bool,
+}
+
+impl Snapshot {
+    pub fn new(header: &[u8]) -> Result<Self, BinaryFormatError> {
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header.to_vec()))?;
+        Ok(Self {
+            decoder,
+            expect_header: false,
+        })
+    }
+
+    fn observe(&mut self, frame: &[u8]) {
+        if frame == b"START" {
+            self.expect_header = true;
+            return;
+        }
+        let data = frame.to_vec();
+        let mut cursor = Cursor::new(&data);
+        let res = if std::mem::take(&mut self.expect_header) {
+            self.decoder.read_header(&mut cursor)
+        } else {
+            self.decoder.read_record(&mut cursor).map(drop)
+        };
+        if let Err(e) = res {
+            tracing::warn!("snapshot out of sync with the broadcast: {}", e);
+        }
+    }
+
+    /// A keyframe per asset that traded since the header, in id order.
+    pub fn keyframes(&mut self) -> Vec<Vec<u8>> {
+        let trades: Vec<_> = self
+            .decoder
+            .symbols()
+            .iter()
+            .filter_map(|symbol| self.decoder.last_trade(symbol))
+            .collect();
+        // Re-seats each asset at the state it already has, so nothing changes
+        trades
+            .iter()
+            .filter_map(|trade| self.decoder.encode_keyframe(trade).ok())
+            .collect()
+    }
+}
+
+/// Broadcast a record, updating `snapshot` in the same step so a client
+/// connecting meanwhile gets a snapshot that the next frame it sees follows on
+/// from.
+pub fn publish_frame(
+    snapshot: &SharedSnapshot,
+    frame: Vec<u8>,
+    broadcaster: &broadcast::Sender<Vec<u8>>,
+) {
+    let mut snapshot = snapshot.lock().unwrap();
+    snapshot.observe(&frame);
+    let _ = broadcaster.send(frame);
+}
+
+/// Make `new` the current header and broadcast START + `new` to connected
+/// clients, telling them to reset their decoder before the next record.
+///
+/// The write lock is held across the broadcast, and clients read the header and
+/// subscribe under the read lock, so a client connecting meanwhile either gets
+/// the old header and then START + `new` as frames, or `new` as its handshake
+/// header followed only by frames sent after it. It never sees records from one
+/// header after the other. Call it from the task that broadcasts the records.
+pub fn publish_header(
+    header: &SharedHeader,
+    new: Vec<u8>,
+    broadcaster: &broadcast::Sender<Vec<u8>>,
+    snapshot: Option<&SharedSnapshot>,
+) {
+    let mut current = header.write().unwrap();
+    *current = new.clone();
+    let _snapshot = snapshot.map(|snapshot| {
+        let mut snapshot = snapshot.lock().unwrap();
+        snapshot.observe(b"START");
+        snapshot.observe(&new);
+        snapshot
+    });
+    let _ = broadcaster.send(b"START".to_vec());
+    let _ = broadcaster.send(new);
+}
+
+/// Bind `bind_addr` (IPv4 or IPv6) and fan out `header` and broadcast frames to every client.
+/// Frames queued for slow clients are bounded by `governor`.
 pub async fn serve(
-    bind_addr: &str,
-    header: Vec<u8>,
+    bind_addr: SocketAddr,
+    header: SharedHeader,
     broadcaster: broadcast::Sender<Vec<u8>>,
+    governor: Arc<MemoryGovernor>,
+    opts: ServeOptions,
 ) -> Result<(), std::io::Error> {
     let listener = TcpListener::bind(bind_addr).await?;
-    tracing::info!("TCP server listening on {}", bind_addr);
+    tracing::info!("TCP server listening on {}", listener.local_addr()?);
+    serve_listener(listener, header, broadcaster, governor, opts).await
+}
 
+/// Accept loop over an already-bound listener.
+pub async fn serve_listener(
+    listener: TcpListener,
+    header: SharedHeader,
+    broadcaster: broadcast::Sender<Vec<u8>>,
+    governor: Arc<MemoryGovernor>,
+    opts: ServeOptions,
+) -> Result<(), std::io::Error> {
     loop {
         let (socket, peer) = listener.accept().await?;
         tracing::info!("New client: {}", peer);
 
         let header = header.clone();
         let broadcaster_clone = broadcaster.clone();
+        let opts = opts.clone();
+        let backlog = governor.register();
+        let connected = ConnectedClient::new(&opts.clients);
         tokio::spawn(async move {
-            if let Err(e) = handshake_and_serve(socket, peer, header, broadcaster_clone).await {
-                tracing::error!("client {} error: {}", peer, e);
+            let served =
+                handshake_and_serve(socket, peer, header, broadcaster_clone, opts, backlog);
+            match served.await {
+                Ok(()) => {}
+                Err(e) if e.is_disconnect() => tracing::info!("client {}: {}", peer, e),
+                Err(e) => tracing::error!("client {} error: {}", peer, e),
             }
+            drop(connected);
             tracing::info!("client {} disconnected", peer);
         });
     }
@@ -32,35 +283,835 @@ pub async fn serve(
 async fn handshake_and_serve(
     mut socket: tokio::net::TcpStream,
     peer: SocketAddr,
-    header: Vec<u8>,
+    header: SharedHeader,
     broadcaster: broadcast::Sender<Vec<u8>>,
-) -> Result<(), std::io::Error> {
-    socket.set_nodelay(true)?;
-    let start = b"START";
+    opts: ServeOptions,
+    backlog: ClientBacklog,
+) -> Result<(), TcpProtocolError> {
+    // Before subscribing, so the broadcast doesn't pile up behind the wait.
+    // Passthrough streams have no header to transcode
+    let mut transcoder = None;
+    if opts.negotiate_version && !header.read().unwrap().is_empty() {
+        let version = read_version_hello(&mut socket)
+            .await
+            .map_err(TcpProtocolError::handshake)?;
+        tracing::info!("client {} reads header versions up to {:?}", peer, version);
+        transcoder = Some(Transcoder::new(
+            version.unwrap_or(*SUPPORTED_VERSIONS.start()),
+        ));
+    }
+    // Subscribed together with the header and snapshot reads, see
+    // `publish_header` and `publish_frame`
+    let (header, keyframes, mut sub) = {
+        let header = header.read().unwrap();
+        let mut snapshot = opts
+            .snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.lock().unwrap());
+        let keyframes = snapshot.as_mut().map_or_else(Vec::new, |s| s.keyframes());
+        (header.clone(), keyframes, broadcaster.subscribe())
+    };
+    let (header, keyframes) = match transcoder.as_mut() {
+        Some(transcoder) => {
+            let header = transcoder
+                .header(&header)
+                .map_err(TcpProtocolError::Transcode)?;
+            let mut transcoded = Vec::with_capacity(keyframes.len());
+            for keyframe in &keyframes {
+                let keyframe = transcoder
+                    .frame(keyframe)
+                    .map_err(TcpProtocolError::Transcode)?;
+                transcoded.extend(keyframe);
+            }
+            (header, transcoded)
+        }
+        None => (header, keyframes),
+    };
+    // The broadcast closing is what ends the client, so don't hold it open
+    drop(broadcaster);
     socket
-        .write_all(&(start.len() as u32).to_le_bytes())
-        .await?;
-    socket.write_all(start).await?;
+        .set_nodelay(true)
+        .map_err(TcpProtocolError::Handshake)?;
+    // The magic always has a `u32` length, it tells the client the framing of the rest
+    let framing = opts.framing;
+    let magic = framing.magic();
+    let handshake = [b"START", header.as_slice()]
+        .into_iter()
+        .chain(keyframes.iter().map(Vec::as_slice))
+        .map(|frame| (framing, frame));
+    let mut prefix = Vec::with_capacity(MAX_LEN_PREFIX);
+    for (frame_framing, frame) in std::iter::once((Framing::U32, magic.as_slice())).chain(handshake)
+    {
+        prefix.clear();
+        frame_framing.write_len(frame.len(), &mut prefix);
+        socket
+            .write_all(&prefix)
+            .await
+            .map_err(TcpProtocolError::handshake)?;
+        socket
+            .write_all(frame)
+            .await
+            .map_err(TcpProtocolError::handshake)?;
+    }
 
-    socket
-        .write_all(&(header.len() as u32).to_le_bytes())
-        .await?;
-    socket.write_all(&header).await?;
-    let mut sub = broadcaster.subscribe();
+    // Drain the broadcast into the backlog while the socket is written from it,
+    // so a slow socket costs governed backlog rather than broadcast lag
+    let pump = async {
+        loop {
+            match sub.recv().await {
+                Ok(msg) => {
+                    if !backlog.push(msg) {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("{} lagged by {} msgs", peer, skipped);
+                    opts.lag_events.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+        backlog.close();
+        std::future::pending::<()>().await
+    };
+    let write = async {
+        let mut credits = 0u64;
+        loop {
+            // Grants are only read once needed; until then they wait in the socket
+            if opts.credit_flow_control && credits == 0 {
+                let mut grant = [0u8; CREDIT_GRANT_LEN];
+                socket
+                    .read_exact(&mut grant)
+                    .await
+                    .map_err(TcpProtocolError::stream)?;
+                credits = u32::from_le_bytes(grant) as u64;
+                continue;
+            }
+            let Some(mut msg) = backlog.pop().await else {
+                break;
+            };
+            if let Some(transcoder) = transcoder.as_mut() {
+                match transcoder
+                    .frame(&msg)
+                    .map_err(TcpProtocolError::Transcode)?
+                {
+                    Some(transcoded) => msg = transcoded,
+                    None => continue,
+                }
+            }
+            prefix.clear();
+            framing.write_len(msg.len(), &mut prefix);
+            socket
+                .write_all(&prefix)
+                .await
+                .map_err(TcpProtocolError::stream)?;
+            socket
+                .write_all(&msg)
+                .await
+                .map_err(TcpProtocolError::stream)?;
+            credits = credits.saturating_sub(1);
+        }
+        Ok(())
+    };
+    let res = tokio::select! {
+        res = write => res,
+        _ = backlog.shed() => Ok(()),
+        _ = pump => unreachable!(),
+    };
+    if backlog.is_shed() {
+        return Err(TcpProtocolError::OverBudget);
+    }
+    res
+}
 
-    loop {
-        match sub.recv().await {
-            Ok(msg) => {
-                socket.write_all(&(msg.len() as u32).to_le_bytes()).await?;
-                socket.write_all(&msg).await?;
+/// The version in the client's hello, or `None` when none arrives within
+/// `VERSION_HELLO_TIMEOUT`. Anything else the client sent first, eg: a credit
+/// grant, stays unread.
+async fn read_version_hello(socket: &mut tokio::net::TcpStream) -> io::Result<Option<u8>> {
+    let mut hello = [0u8; 4];
+    let peek = async {
+        loop {
+            let n = socket.peek(&mut hello).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let seen = n.min(VERSION_HELLO_PREFIX.len());
+            if hello[..seen] != VERSION_HELLO_PREFIX[..seen] {
+                return Ok(None);
+            }
+            if n == hello.len() {
+                return Ok(Some(hello[3]));
+            }
+            // Part of a hello, wait for the rest
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    };
+    match tokio::time::timeout(VERSION_HELLO_TIMEOUT, peek).await {
+        Ok(Ok(Some(version))) => {
+            socket.read_exact(&mut hello).await?;
+            Ok(Some(version))
+        }
+        Ok(res) => res,
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::STREAM_MAGIC;
+    use crate::ipc::governor::MemoryBudget;
+    use std::time::Duration;
+
+    fn governor() -> Arc<MemoryGovernor> {
+        Arc::new(MemoryGovernor::new(MemoryBudget::default()))
+    }
+
+    async fn read_frame(stream: &mut tokio::net::TcpStream) -> Vec<u8> {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).await.unwrap();
+        let mut buf = vec![0u8; u32::from_le_bytes(len) as usize];
+        stream.read_exact(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_serve_on_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.ip().is_loopback());
+
+        let (tx, _) = broadcast::channel(16);
+        let header = Arc::new(RwLock::new(b"HEADER".to_vec()));
+        tokio::spawn(serve_listener(
+            listener,
+            header,
+            tx,
+            governor(),
+            ServeOptions::default(),
+        ));
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_frame(&mut client).await, STREAM_MAGIC);
+        assert_eq!(read_frame(&mut client).await, b"START");
+        assert_eq!(read_frame(&mut client).await, b"HEADER");
+    }
+
+    #[tokio::test]
+    async fn test_client_count_tracks_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, _) = broadcast::channel(16);
+        let opts = ServeOptions::default();
+        tokio::spawn(serve_listener(
+            listener,
+            Arc::new(RwLock::new(b"HEADER".to_vec())),
+            tx.clone(),
+            governor(),
+            opts.clone(),
+        ));
+        let wait_for = |count: usize| {
+            let opts = opts.clone();
+            async move {
+                for _ in 0..200 {
+                    if opts.client_count() == count {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                panic!("{} clients, expected {}", opts.client_count(), count);
+            }
+        };
+        assert_eq!(opts.client_count(), 0);
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            read_frame(&mut client).await;
+            clients.push(client);
+        }
+        wait_for(3).await;
+        // Every client past its handshake also holds a broadcast receiver
+        assert_eq!(tx.receiver_count(), 3);
+
+        // Noticed on the next broadcast frame, which fails to write
+        drop(clients.pop());
+        let reset = clients.pop().unwrap();
+        reset.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(reset);
+        for _ in 0..3 {
+            let _ = tx.send(b"TRADE".to_vec());
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        wait_for(1).await;
+        assert_eq!(tx.receiver_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_frames_buffered_without_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // As in `handle_trades_tcp`: the channel's own receiver is dropped at once
+        let (tx, _) = broadcast::channel(16);
+        let opts = ServeOptions::default();
+        tokio::spawn(serve_listener(
+            listener,
+            Arc::new(RwLock::new(b"HEADER".to_vec())),
+            tx.clone(),
+            governor(),
+            opts.clone(),
+        ));
+
+        assert_eq!(tx.receiver_count(), 0);
+        for _ in 0..20 {
+            assert!(tx.send(b"TRADE".to_vec()).is_err());
+        }
+        assert_eq!(tx.len(), 0);
+
+        // A client holds frames back only until it has read them, or left
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        for _ in 0..3 {
+            read_frame(&mut client).await;
+        }
+        while tx.receiver_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        tx.send(b"TRADE".to_vec()).unwrap();
+        assert_eq!(read_frame(&mut client).await, b"TRADE");
+        client.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(client);
+        while opts.client_count() > 0 {
+            let _ = tx.send(b"TRADE".to_vec());
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(tx.receiver_count(), 0);
+        assert_eq!(tx.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_late_client_gets_current_header() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, _) = broadcast::channel(16);
+        let header: SharedHeader = Arc::new(RwLock::new(b"HEADER1".to_vec()));
+        tokio::spawn(serve_listener(
+            listener,
+            header.clone(),
+            tx.clone(),
+            governor(),
+            ServeOptions::default(),
+        ));
+
+        let mut early = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_frame(&mut early).await, STREAM_MAGIC);
+        assert_eq!(read_frame(&mut early).await, b"START");
+        assert_eq!(read_frame(&mut early).await, b"HEADER1");
+        // The handshake is written after subscribing, so `early` is subscribed now
+        tx.send(b"TRADE1".to_vec()).unwrap();
+
+        publish_header(&header, b"HEADER2".to_vec(), &tx, None);
+        tx.send(b"TRADE2".to_vec()).unwrap();
+
+        let mut late = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_frame(&mut late).await, STREAM_MAGIC);
+        assert_eq!(read_frame(&mut late).await, b"START");
+        assert_eq!(read_frame(&mut late).await, b"HEADER2");
+        tx.send(b"TRADE3".to_vec()).unwrap();
+        assert_eq!(read_frame(&mut late).await, b"TRADE3");
+
+        for expected in [&b"TRADE1"[..], b"START", b"HEADER2", b"TRADE2", b"TRADE3"] {
+            assert_eq!(read_frame(&mut early).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_keyframes_on_connect() {
+        use crate::format::{Record, Trade};
+
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1_700_000_000_000)
+            .assets(vec![
+                ("BTCUSDT".to_string(), 45000.0, 1.0, 100_000.0),
+                ("ETHUSDT".to_string(), 2500.0, 1.0, 100_000.0),
+            ])
+            .build()
+            .unwrap();
+        let trade = |symbol: &str, timestamp: u64, price: f64| Trade {
+            symbol: symbol.to_string(),
+            timestamp,
+            price,
+            quantity: 0.5,
+            is_buyer_maker: timestamp.is_multiple_of(2),
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, _) = broadcast::channel(16);
+        let shared: SharedHeader = Arc::new(RwLock::new(header.clone()));
+        let snapshot = Arc::new(Mutex::new(Snapshot::new(&header).unwrap()));
+        tokio::spawn(serve_listener(
+            listener,
+            shared,
+            tx.clone(),
+            governor(),
+            ServeOptions {
+                snapshot: Some(snapshot.clone()),
+                ..Default::default()
+            },
+        ));
+
+        // Trades go out before the client connects, the last one per asset counts
+        for t in [
+            trade("BTCUSDT", 1_700_000_000_001, 45001.25),
+            trade("ETHUSDT", 1_700_000_000_002, 2501.5),
+            trade("BTCUSDT", 1_700_000_000_003, 45002.75),
+        ] {
+            publish_frame(&snapshot, encoder.encode(&t).unwrap(), &tx);
+        }
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_frame(&mut client).await, STREAM_MAGIC);
+        assert_eq!(read_frame(&mut client).await, b"START");
+        let mut decoder = BinaryFormat::new();
+        decoder
+            .read_header(&mut Cursor::new(&read_frame(&mut client).await))
+            .unwrap();
+        for (symbol, timestamp, price) in [
+            ("BTCUSDT", 1_700_000_000_003, 45002.75),
+            ("ETHUSDT", 1_700_000_000_002, 2501.5),
+        ] {
+            match decoder.read_record(&mut Cursor::new(&read_frame(&mut client).await)) {
+                Ok(Record::Keyframe(t)) => {
+                    assert_eq!((t.symbol.as_str(), t.timestamp), (symbol, timestamp));
+                    assert_eq!(t.is_buyer_maker, timestamp.is_multiple_of(2));
+                    assert!((t.price - price).abs() <= decoder.price_resolution());
+                }
+                other => panic!("expected a keyframe, got {:?}", other),
+            }
+        }
+
+        // The live stream's deltas follow on from the snapshot
+        let live = trade("ETHUSDT", 1_700_000_000_004, 2499.0);
+        publish_frame(&snapshot, encoder.encode(&live).unwrap(), &tx);
+        let decoded = decoder
+            .read_message(&mut Cursor::new(&read_frame(&mut client).await))
+            .unwrap();
+        assert_eq!(decoded.timestamp, live.timestamp);
+        assert!((decoded.price - live.price).abs() <= decoder.price_resolution());
+    }
+
+    #[tokio::test]
+    async fn test_clients_decode_identical_streams() {
+        use crate::format::{Record, Trade};
+
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1_700_000_000_000)
+            .assets(vec![
+                ("BTCUSDT".to_string(), 45000.0, 1.0, 100_000.0),
+                ("ETHUSDT".to_string(), 2500.0, 1.0, 100_000.0),
+            ])
+            .build()
+            .unwrap();
+        let trades: Vec<Trade> = (0..20u64)
+            .map(|i| Trade {
+                symbol: ["BTCUSDT", "ETHUSDT"][i as usize % 2].to_string(),
+                timestamp: 1_700_000_000_000 + i,
+                price: [45000.0, 2500.0][i as usize % 2] + i as f64 * 0.25,
+                quantity: 0.5 + i as f64,
+                is_buyer_maker: i % 3 == 0,
+            })
+            .collect();
+        let key = |t: &Trade| {
+            let Trade {
+                symbol,
+                timestamp,
+                price,
+                quantity,
+                is_buyer_maker,
+            } = t.clone();
+            (symbol, timestamp, price, quantity, is_buyer_maker)
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, _) = broadcast::channel(64);
+        let shared: SharedHeader = Arc::new(RwLock::new(header.clone()));
+        let snapshot = Arc::new(Mutex::new(Snapshot::new(&header).unwrap()));
+        tokio::spawn(serve_listener(
+            listener,
+            shared,
+            tx.clone(),
+            governor(),
+            ServeOptions {
+                snapshot: Some(snapshot.clone()),
+                ..Default::default()
+            },
+        ));
+
+        // Each client has to get the magic, START and header before anything else
+        let connect = || async {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            assert_eq!(read_frame(&mut client).await, STREAM_MAGIC);
+            assert_eq!(read_frame(&mut client).await, b"START");
+            let frame = read_frame(&mut client).await;
+            assert_eq!(frame, header);
+            let mut decoder = BinaryFormat::new();
+            decoder.read_header(&mut Cursor::new(&frame)).unwrap();
+            (client, decoder)
+        };
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            clients.push(connect().await);
+        }
+
+        let (before, after) = trades.split_at(12);
+        for trade in before {
+            publish_frame(&snapshot, encoder.encode(trade).unwrap(), &tx);
+        }
+        let mut late = connect().await;
+        for trade in after {
+            publish_frame(&snapshot, encoder.encode(trade).unwrap(), &tx);
+        }
+
+        let mut streams = Vec::new();
+        for (client, decoder) in &mut clients {
+            let mut decoded = Vec::new();
+            for _ in 0..trades.len() {
+                let frame = read_frame(client).await;
+                decoded.push(key(&decoder
+                    .read_message(&mut Cursor::new(&frame))
+                    .unwrap()));
+            }
+            streams.push(decoded);
+        }
+        assert_eq!(streams[0], streams[1]);
+        assert_eq!(streams[0], streams[2]);
+        for (got, sent) in streams[0].iter().zip(&trades) {
+            let (symbol, timestamp, price, _, is_buyer_maker) = got;
+            assert_eq!(
+                (symbol, *timestamp, *is_buyer_maker),
+                (&sent.symbol, sent.timestamp, sent.is_buyer_maker)
+            );
+            assert!((price - sent.price).abs() <= encoder.price_resolution());
+        }
+
+        // The late client starts from each asset's latest trade, then follows
+        // the same stream
+        let (client, decoder) = &mut late;
+        for sent in &streams[0][10..12] {
+            let frame = read_frame(client).await;
+            match decoder.read_record(&mut Cursor::new(&frame)).unwrap() {
+                Record::Keyframe(trade) => assert_eq!(&key(&trade), sent),
+                other => panic!("expected a keyframe, got {:?}", other),
             }
-            Err(broadcast::error::RecvError::Lagged(skipped)) => {
-                // Should disconnect clients who are lagging more than a defined threshold.
-                tracing::warn!("{} lagged by {} msgs", peer, skipped);
+        }
+        for sent in &streams[0][12..] {
+            let frame = read_frame(client).await;
+            let trade = decoder.read_message(&mut Cursor::new(&frame)).unwrap();
+            assert_eq!(&key(&trade), sent);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_closing_mid_handshake_is_a_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, _) = broadcast::channel(16);
+        // Far more than the socket buffers take, so the handshake write blocks
+        let header = Arc::new(RwLock::new(vec![0u8; 32 * 1024 * 1024]));
+
+        let client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (socket, peer) = listener.accept().await.unwrap();
+        let served = tokio::spawn(handshake_and_serve(
+            socket,
+            peer,
+            header,
+            tx,
+            ServeOptions::default(),
+            governor().register(),
+        ));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // Reset rather than FIN, like a client process that died
+        client.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(client);
+
+        let err = served.await.unwrap().unwrap_err();
+        assert!(
+            matches!(err, TcpProtocolError::ClosedDuringHandshake),
+            "{:?}",
+            err
+        );
+        assert!(err.is_disconnect());
+        assert!(!TcpProtocolError::OverBudget.is_disconnect());
+    }
+
+    #[tokio::test]
+    async fn test_credit_flow_control() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, _) = broadcast::channel(16);
+        let header = Arc::new(RwLock::new(b"HEADER".to_vec()));
+        let opts = ServeOptions {
+            credit_flow_control: true,
+            ..Default::default()
+        };
+        tokio::spawn(serve_listener(
+            listener,
+            header,
+            tx.clone(),
+            governor(),
+            opts,
+        ));
+
+        // The handshake needs no credits
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_frame(&mut client).await, STREAM_MAGIC);
+        assert_eq!(read_frame(&mut client).await, b"START");
+        assert_eq!(read_frame(&mut client).await, b"HEADER");
+        for i in 0..5u8 {
+            tx.send(vec![i; 3]).unwrap();
+        }
+
+        client.write_all(&2u32.to_le_bytes()).await.unwrap();
+        assert_eq!(read_frame(&mut client).await, [0; 3]);
+        assert_eq!(read_frame(&mut client).await, [1; 3]);
+        // Out of credits: the rest waits on the server, nothing is skipped
+        let mut byte = [0u8];
+        let idle = tokio::time::timeout(Duration::from_millis(50), client.read(&mut byte));
+        assert!(idle.await.is_err());
+
+        client.write_all(&3u32.to_le_bytes()).await.unwrap();
+        for i in 2..5u8 {
+            assert_eq!(read_frame(&mut client).await, [i; 3]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_bounded_with_stalled_clients() {
+        const CAP: usize = 1024 * 1024;
+        const FRAME: usize = 64 * 1024;
+        const FRAMES: usize = 400;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, _) = broadcast::channel(1024);
+        let header: SharedHeader = Arc::new(RwLock::new(b"HEADER".to_vec()));
+        let governor = Arc::new(MemoryGovernor::new(MemoryBudget { max_bytes: CAP }));
+        tokio::spawn(serve_listener(
+            listener,
+            header,
+            tx.clone(),
+            governor.clone(),
+            ServeOptions::default(),
+        ));
+
+        // Three clients that never read past the handshake, one that keeps up
+        let mut stalled = Vec::new();
+        for _ in 0..3 {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            assert_eq!(read_frame(&mut client).await, STREAM_MAGIC);
+            stalled.push(client);
+        }
+        let mut healthy = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_frame(&mut healthy).await, STREAM_MAGIC);
+        assert_eq!(read_frame(&mut healthy).await, b"START");
+        assert_eq!(read_frame(&mut healthy).await, b"HEADER");
+        let reader = tokio::spawn(async move {
+            for i in 0..FRAMES {
+                assert_eq!(read_frame(&mut healthy).await, vec![i as u8; FRAME]);
             }
-            Err(broadcast::error::RecvError::Closed) => break,
+        });
+        while governor.clients() < 4 {
+            tokio::task::yield_now().await;
+        }
+
+        let mut peak = 0;
+        for i in 0..FRAMES {
+            tx.send(vec![i as u8; FRAME]).unwrap();
+            tokio::task::yield_now().await;
+            peak = peak.max(governor.in_flight());
+        }
+        reader.await.unwrap();
+        assert!(peak <= CAP, "{} bytes in flight", peak);
+
+        // 25 MiB is more than loopback socket buffers take, so every stalled
+        // client had to be shed; the healthy one got everything
+        assert_eq!(governor.clients(), 1);
+        for mut client in stalled {
+            let mut buf = vec![0u8; FRAME];
+            while client.read(&mut buf).await.unwrap() > 0 {}
         }
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_varint_framing_negotiated_in_handshake() {
+        use crate::format::Trade;
+        use crate::ipc::consumer::{ErrorPolicy, TcpSource, TradeConsumer};
+        use crate::ipc::tcp_client::TcpTradeClient;
+
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1_700_000_000_000)
+            .assets(vec![
+                ("BTCUSDT".to_string(), 45000.0, 1.0, 100_000.0),
+                ("ETHUSDT".to_string(), 2500.0, 1.0, 100_000.0),
+            ])
+            .build()
+            .unwrap();
+        let trade = |symbol: &str, timestamp: u64, price: f64| Trade {
+            symbol: symbol.to_string(),
+            timestamp,
+            price,
+            quantity: 0.5,
+            is_buyer_maker: false,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, _) = broadcast::channel(16);
+        let shared: SharedHeader = Arc::new(RwLock::new(header.clone()));
+        let snapshot = Arc::new(Mutex::new(Snapshot::new(&header).unwrap()));
+        tokio::spawn(serve_listener(
+            listener,
+            shared,
+            tx.clone(),
+            governor(),
+            ServeOptions {
+                snapshot: Some(snapshot.clone()),
+                framing: Framing::Varint,
+                ..Default::default()
+            },
+        ));
+        for t in [
+            trade("BTCUSDT", 1_700_000_000_001, 45001.0),
+            trade("ETHUSDT", 1_700_000_000_002, 2501.0),
+        ] {
+            publish_frame(&snapshot, encoder.encode(&t).unwrap(), &tx);
+        }
+
+        // The magic keeps its u32 length, every later frame has a 1-byte one
+        let mut raw = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_frame(&mut raw).await, Framing::Varint.magic());
+        assert_eq!(raw.read_u8().await.unwrap(), 5);
+        let mut start = [0u8; 5];
+        raw.read_exact(&mut start).await.unwrap();
+        assert_eq!(&start, b"START");
+
+        let blocking = tokio::task::spawn_blocking(move || {
+            let consumer = TradeConsumer::new(TcpSource::new(addr.to_string()), ErrorPolicy::Skip);
+            consumer
+                .take(2)
+                .map(|t| t.unwrap())
+                .map(|t| (t.symbol, t.timestamp))
+                .collect::<Vec<_>>()
+        });
+        let mut client = TcpTradeClient::new(addr.to_string()).with_max_attempts(1);
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let t = client.next_trade().await.unwrap();
+            received.push((t.symbol, t.timestamp));
+        }
+        let expected = vec![
+            ("BTCUSDT".to_string(), 1_700_000_000_001),
+            ("ETHUSDT".to_string(), 1_700_000_000_002),
+        ];
+        assert_eq!(received, expected);
+        assert_eq!(blocking.await.unwrap(), expected);
+
+        // Live frames after the snapshot are varint-framed as well
+        let live = trade("BTCUSDT", 1_700_000_000_003, 44999.5);
+        publish_frame(&snapshot, encoder.encode(&live).unwrap(), &tx);
+        let decoded = client.next_trade().await.unwrap();
+        assert_eq!(decoded.timestamp, live.timestamp);
+        assert!((decoded.price - live.price).abs() <= client.decoder().price_resolution());
+    }
+
+    #[tokio::test]
+    async fn test_v1_v2_and_v4_clients_share_a_v5_stream() {
+        use crate::format::{TimestampUnit, Trade};
+        use crate::ipc::tcp_client::TcpTradeClient;
+
+        // Micros and a finer scale, which only v2 headers on can declare
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1_700_000_000_000_000)
+            .timestamp_unit(TimestampUnit::Micros)
+            .assets(vec![
+                ("BTCUSDT".to_string(), 45000.0, 1.0, 100_000_000.0),
+                ("ETHUSDT".to_string(), 2500.0, 1.0, 100_000_000.0),
+            ])
+            .build()
+            .unwrap();
+        assert_eq!(header[4], 5);
+        let trade = |symbol: &str, timestamp: u64, price: f64| Trade {
+            symbol: symbol.to_string(),
+            timestamp,
+            price,
+            quantity: 0.123456,
+            is_buyer_maker: true,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, _) = broadcast::channel(16);
+        let shared: SharedHeader = Arc::new(RwLock::new(header.clone()));
+        let snapshot = Arc::new(Mutex::new(Snapshot::new(&header).unwrap()));
+        tokio::spawn(serve_listener(
+            listener,
+            shared,
+            tx.clone(),
+            governor(),
+            ServeOptions {
+                snapshot: Some(snapshot.clone()),
+                negotiate_version: true,
+                ..Default::default()
+            },
+        ));
+        for t in [
+            trade("BTCUSDT", 1_700_000_000_001_500, 45001.123456),
+            trade("ETHUSDT", 1_700_000_000_002_500, 2501.654321),
+        ] {
+            publish_frame(&snapshot, encoder.encode(&t).unwrap(), &tx);
+        }
+
+        let mut v1 = TcpTradeClient::new(addr.to_string())
+            .with_max_attempts(1)
+            .with_version_hello(1);
+        let mut v2 = TcpTradeClient::new(addr.to_string())
+            .with_max_attempts(1)
+            .with_version_hello(2);
+        let mut v4 = TcpTradeClient::new(addr.to_string())
+            .with_max_attempts(1)
+            .with_version_hello(4);
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let new = v2.next_trade().await.unwrap();
+            // Only the header is rewritten for v2 and v4, so their records match
+            let v4_trade = v4.next_trade().await.unwrap();
+            assert_eq!(
+                (v4_trade.timestamp, v4_trade.price),
+                (new.timestamp, new.price)
+            );
+            received.push((v1.next_trade().await.unwrap(), new));
+        }
+        assert_eq!(v1.decoder().version(), 1);
+        assert_eq!(v1.decoder().timestamp_unit(), TimestampUnit::Millis);
+        assert_eq!(v2.decoder().version(), 2);
+        assert_eq!(v4.decoder().version(), 4);
+        assert!(!v4.decoder().has_header_length());
+
+        // Live deltas after the snapshot keyframes are transcoded too
+        let live = trade("BTCUSDT", 1_700_000_000_003_500, 44999.987654);
+        publish_frame(&snapshot, encoder.encode(&live).unwrap(), &tx);
+        received.push((
+            v1.next_trade().await.unwrap(),
+            v2.next_trade().await.unwrap(),
+        ));
+
+        for (old, new) in received {
+            assert_eq!(old.symbol, new.symbol);
+            assert_eq!(old.timestamp, new.timestamp / 1_000);
+            assert!((old.price - new.price).abs() <= v1.decoder().price_resolution());
+            assert!((old.quantity - new.quantity).abs() <= v1.decoder().quantity_resolution());
+            assert_eq!(old.is_buyer_maker, new.is_buyer_maker);
+        }
+    }
 }
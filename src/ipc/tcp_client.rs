@@ -0,0 +1,306 @@
+// std
+use std::io::Cursor;
+use std::time::Duration;
+
+// external
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+// internal
+use crate::format::{
+    BinaryFormat, BinaryFormatError, HEADER_MAGIC_VERSION, Trade, UnsupportedVersion,
+};
+use crate::ipc::framing::Framing;
+use crate::ipc::tcp::version_hello;
+
+/// Defaults for `TcpTradeClient::with_backoff`, also the blocking consumer's.
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum TcpClientError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Format error: {0}")]
+    Format(BinaryFormatError),
+    #[error(transparent)]
+    UnsupportedVersion(UnsupportedVersion),
+    #[error("Expected START or a header, got {0} bytes")]
+    Handshake(usize),
+    #[error("Gave up after {0} connection attempts")]
+    RetriesExhausted(u32),
+}
+
+impl From<BinaryFormatError> for TcpClientError {
+    fn from(e: BinaryFormatError) -> Self {
+        e.into_unsupported_version()
+            .map_or_else(TcpClientError::Format, TcpClientError::UnsupportedVersion)
+    }
+}
+
+/// Reads trades from the TCP fan-out server, riding out disconnects.
+///
+/// On EOF or a connection error, partial frames are discarded and the client
+/// reconnects with exponential backoff, redoes the magic/START/header handshake and
+/// starts a fresh decoder. Trades sent while disconnected are lost; check
+/// `reconnects` to notice.
+pub struct TcpTradeClient {
+    addr: String,
+    stream: Option<TcpStream>,
+    /// Announced by the current connection's magic
+    framing: Framing,
+    decoder: BinaryFormat,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_attempts: Option<u32>,
+    /// Sent in a version hello on connecting, see `with_version_hello`
+    hello_version: Option<u8>,
+    reconnects: u64,
+}
+
+impl TcpTradeClient {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            stream: None,
+            framing: Framing::U32,
+            decoder: BinaryFormat::new(),
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            max_attempts: None,
+            hello_version: None,
+            reconnects: 0,
+        }
+    }
+
+    /// Backoff starts at `initial` and doubles up to `max` between failed attempts.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Give up after this many consecutive failed connection attempts. Unlimited by default.
+    pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Tell the server, on every connect, to send header version `version` at most,
+    /// eg: the latest in `SUPPORTED_VERSIONS`. Only for servers run with
+    /// `ServeOptions::negotiate_version`; any other reads the hello as a credit grant.
+    pub fn with_version_hello(mut self, version: u8) -> Self {
+        self.hello_version = Some(version);
+        self
+    }
+
+    /// Times the connection was re-established after the first one.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    /// Decoder for the current connection, eg: to look up the header's assets.
+    pub fn decoder(&self) -> &BinaryFormat {
+        &self.decoder
+    }
+
+    /// Next trade from the server, reconnecting as often as needed.
+    pub async fn next_trade(&mut self) -> Result<Trade, TcpClientError> {
+        loop {
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                None => {
+                    let stream = self.connect().await?;
+                    self.stream.insert(stream)
+                }
+            };
+
+            match self.framing.read_frame_async(stream).await {
+                Ok(frame) => {
+                    // A re-header, with or without a START ahead of it
+                    if frame == b"START" {
+                        continue;
+                    }
+                    if let Some(decoder) = self.header_frame(&frame) {
+                        self.decoder = decoder;
+                        continue;
+                    }
+                    let record = self.decoder.read_record(&mut Cursor::new(&frame))?;
+                    if let Some(trade) = record.into_trade() {
+                        return Ok(trade);
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("connection to {} lost: {}", self.addr, e);
+                    self.stream = None;
+                    self.reconnects += 1;
+                }
+            }
+        }
+    }
+
+    async fn connect(&mut self) -> Result<TcpStream, TcpClientError> {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.handshake().await {
+                Ok(stream) => return Ok(stream),
+                Err(TcpClientError::Io(e)) => {
+                    if self.max_attempts.is_some_and(|max| attempt >= max) {
+                        return Err(TcpClientError::RetriesExhausted(attempt));
+                    }
+                    tracing::warn!(
+                        "connecting to {} failed (attempt #{}) – retrying in {:?}: {}",
+                        self.addr,
+                        attempt,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.max_backoff);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn handshake(&mut self) -> Result<TcpStream, TcpClientError> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        stream.set_nodelay(true)?;
+        if let Some(version) = self.hello_version {
+            stream.write_all(&version_hello(version)).await?;
+        }
+
+        let magic = Framing::U32.read_frame_async(&mut stream).await?;
+        let framing = Framing::from_magic(&magic)?;
+        // START then the header, or a header alone, see `BinaryFormat::from_header_frame`
+        let start = framing.read_frame_async(&mut stream).await?;
+        self.decoder = match self.header_frame(&start) {
+            Some(decoder) => decoder,
+            None if start == b"START" => {
+                let header = framing.read_frame_async(&mut stream).await?;
+                let mut decoder = BinaryFormat::new();
+                decoder.read_header(&mut Cursor::new(&header))?;
+                decoder
+            }
+            None => return Err(TcpClientError::Handshake(start.len())),
+        };
+        self.framing = framing;
+
+        tracing::info!("connected to {}", self.addr);
+        Ok(stream)
+    }
+
+    /// A client announcing a version from before `HEADER_MAGIC` gets headers without it.
+    fn legacy_headers(&self) -> bool {
+        self.hello_version
+            .is_some_and(|version| version < HEADER_MAGIC_VERSION)
+    }
+
+    fn header_frame(&self, frame: &[u8]) -> Option<BinaryFormat> {
+        if self.legacy_headers() {
+            BinaryFormat::from_legacy_header_frame(frame)
+        } else {
+            BinaryFormat::from_header_frame(frame)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::STREAM_MAGIC;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    async fn write_frame(stream: &mut TcpStream, frame: &[u8]) {
+        stream
+            .write_all(&(frame.len() as u32).to_le_bytes())
+            .await
+            .unwrap();
+        stream.write_all(frame).await.unwrap();
+    }
+
+    fn trade(timestamp: u64, price: f64) -> Trade {
+        Trade {
+            symbol: "BTCUSDT".to_string(),
+            timestamp,
+            price,
+            quantity: 0.5,
+            is_buyer_maker: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_mid_stream_drop() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            for (connection, price) in [(0u64, 45001.0), (1, 46001.0)] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut encoder = BinaryFormat::new()
+                    .with_assets(vec!["BTCUSDT".to_string()])
+                    .unwrap();
+                let mut header = Vec::new();
+                let reference_price = 45000.0 + connection as f64 * 1000.0;
+                encoder
+                    .write_header(&mut header, 1_700_000_000_000, &[reference_price], &[1.0])
+                    .unwrap();
+                write_frame(&mut socket, STREAM_MAGIC).await;
+                write_frame(&mut socket, b"START").await;
+                write_frame(&mut socket, &header).await;
+                let encoded = encoder
+                    .encode(&trade(1_700_000_000_000 + connection, price))
+                    .unwrap();
+                write_frame(&mut socket, &encoded).await;
+
+                if connection == 0 {
+                    // Length prefix promising more than is ever sent, then hang up
+                    socket.write_all(&100u32.to_le_bytes()).await.unwrap();
+                    socket.write_all(&[0x01, 0x02]).await.unwrap();
+                }
+            }
+        });
+
+        let mut client = TcpTradeClient::new(addr.to_string())
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(50))
+            .with_max_attempts(5);
+
+        let first = client.next_trade().await.unwrap();
+        assert_eq!(first.timestamp, 1_700_000_000_000);
+        assert!((first.price - 45001.0).abs() <= client.decoder().price_resolution());
+        assert_eq!(client.reconnects(), 0);
+
+        let second = client.next_trade().await.unwrap();
+        assert_eq!(second.timestamp, 1_700_000_000_001);
+        assert!((second.price - 46001.0).abs() <= client.decoder().price_resolution());
+        assert_eq!(client.reconnects(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_wrong_magic() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // Eg: something else listening on the port
+            let (mut socket, _) = listener.accept().await.unwrap();
+            write_frame(&mut socket, b"HTTP/1.1 400").await;
+            let (mut socket, _) = listener.accept().await.unwrap();
+            write_frame(&mut socket, b"PSHFT\x09").await;
+        });
+
+        let mut client = TcpTradeClient::new(addr.to_string()).with_max_attempts(1);
+        assert!(matches!(
+            client.next_trade().await,
+            Err(TcpClientError::Format(BinaryFormatError::BadStreamMagic(_)))
+        ));
+        assert!(matches!(
+            client.next_trade().await,
+            Err(TcpClientError::Format(
+                BinaryFormatError::UnsupportedStreamVersion(9)
+            ))
+        ));
+    }
+}
@@ -0,0 +1,140 @@
+// internal
+use crate::format::{BinaryFormat, BinaryFormatError, HEADER_MAGIC_VERSION, Record, TimestampUnit};
+
+/// Re-encodes a stream for a client that reads an older header version than the
+/// producer writes, frame by frame: START passes through, a header is swapped for
+/// one at the client's version, and each record is decoded and encoded again.
+///
+/// A v4 client gets a v5 header without its stated length, and a client from
+/// before the header magic (v1 to v3) the same header without the magic when
+/// that fits its version; either way the records pass through untouched. When it
+/// doesn't, eg: v2 extensions for a v1 client, the downgrade keeps the assets
+/// and reference values but drops every extension: prices and quantities go
+/// back to the default scale and timestamps to milliseconds, so a client gets
+/// the producer's values to within v1's resolution. Streams at or below the
+/// client's version pass through untouched.
+pub struct Transcoder {
+    version: u8,
+    /// Decoder of the producer's stream and the encoder of the client's, unset
+    /// while passing through
+    codecs: Option<(BinaryFormat, BinaryFormat)>,
+    /// The last frame was START, so the next one is a header
+    expect_header: bool,
+}
+
+impl Transcoder {
+    /// Transcode to header `version`, the highest the client reads.
+    pub fn new(version: u8) -> Self {
+        Self {
+            version,
+            codecs: None,
+            expect_header: false,
+        }
+    }
+
+    /// Header version the client gets.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// The client's header in place of the producer's `header`.
+    pub fn header(&mut self, header: &[u8]) -> Result<Vec<u8>, BinaryFormatError> {
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header_slice(&mut &header[..])?;
+        if decoder.version() <= self.version {
+            self.codecs = None;
+            return Ok(header.to_vec());
+        }
+
+        // Right after a header, each asset's last values are its reference ones
+        let references = decoder.asset_stats();
+        let prices: Vec<f64> = references.iter().map(|stats| stats.last_price).collect();
+        let quantities: Vec<f64> = references.iter().map(|s| s.last_quantity).collect();
+
+        // Records are laid out the same either way, so only the header changes
+        let mut legacy = decoder
+            .clone()
+            .with_legacy_headers(self.version < HEADER_MAGIC_VERSION)
+            .with_header_length(false);
+        let mut client_header = Vec::new();
+        legacy.write_header(
+            &mut client_header,
+            references.first().map_or(0, |stats| stats.last_timestamp),
+            &prices,
+            &quantities,
+        )?;
+        if legacy.version() <= self.version {
+            self.codecs = None;
+            return Ok(client_header);
+        }
+
+        let reference_timestamp = references.first().map_or(0, |stats| {
+            to_millis(decoder.timestamp_unit(), stats.last_timestamp)
+        });
+        let mut encoder = BinaryFormat::new()
+            .with_legacy_headers(true)
+            .with_assets(decoder.symbols().to_vec())?;
+        let mut client_header = Vec::new();
+        encoder.write_header(
+            &mut client_header,
+            reference_timestamp,
+            &prices,
+            &quantities,
+        )?;
+        // Only a v3 stream's asset count is beyond older clients
+        if encoder.version() > self.version {
+            return Err(BinaryFormatError::TooManyAssets);
+        }
+        self.codecs = Some((decoder, encoder));
+        Ok(client_header)
+    }
+
+    /// What to send the client in place of the broadcast `frame`, if anything: a
+    /// record kind the producer's decoder doesn't know can't be re-encoded.
+    pub fn frame(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, BinaryFormatError> {
+        if frame == b"START" {
+            self.expect_header = true;
+            return Ok(Some(frame.to_vec()));
+        }
+        if std::mem::take(&mut self.expect_header) {
+            return self.header(frame).map(Some);
+        }
+        let Some((decoder, encoder)) = self.codecs.as_mut() else {
+            return Ok(Some(frame.to_vec()));
+        };
+        let unit = decoder.timestamp_unit();
+        let frame = match decoder.read_record_slice(&mut &frame[..])? {
+            Record::Trade(mut trade) => {
+                trade.timestamp = to_millis(unit, trade.timestamp);
+                encoder.encode(&trade)?
+            }
+            Record::Keyframe(mut trade) => {
+                trade.timestamp = to_millis(unit, trade.timestamp);
+                encoder.encode_keyframe(&trade)?
+            }
+            Record::Heartbeat(timestamp) => encoder.encode_heartbeat(to_millis(unit, timestamp))?,
+            Record::Funding {
+                symbol,
+                timestamp,
+                rate,
+            } => encoder.encode_funding(&symbol, to_millis(unit, timestamp), rate)?,
+            Record::Gap { symbol, dropped } => encoder.encode_gap(&symbol, dropped)?,
+            Record::Reset(timestamp) => {
+                // The decoder just took on the reset's values
+                let references = decoder.asset_stats();
+                let prices: Vec<f64> = references.iter().map(|s| s.last_price).collect();
+                let quantities: Vec<f64> = references.iter().map(|s| s.last_quantity).collect();
+                encoder.reset_state(to_millis(unit, timestamp), &prices, &quantities)?
+            }
+            Record::Unknown { kind } => {
+                tracing::debug!("not transcoding record kind {:#04x}", kind);
+                return Ok(None);
+            }
+        };
+        Ok(Some(frame))
+    }
+}
+
+fn to_millis(unit: TimestampUnit, timestamp: u64) -> u64 {
+    unit.to_micros(timestamp) / 1_000
+}
@@ -1,4 +1,11 @@
 pub mod binance;
 pub mod cli;
+pub mod clock;
+pub mod config;
 pub mod format;
+pub mod http;
 pub mod ipc;
+pub mod metrics;
+pub mod pipeline;
+pub mod rawlog;
+pub mod recent;
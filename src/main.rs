@@ -1,145 +1,37 @@
 // std
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
 // external
 use clap::Parser;
-use tokio::sync::{broadcast, mpsc::UnboundedReceiver};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
 
 // internal
-use perp_signal_hft::binance::{BinanceClient, BinanceError, BinanceWebsocket, TradeMessage};
+use perp_signal_hft::binance::{BinanceClient, BinanceWebsocket, BinanceWebsocketConfig};
 use perp_signal_hft::cli::Cli;
-use perp_signal_hft::format::{BinaryFormat, BinaryFormatError};
-use perp_signal_hft::ipc::shm_queue::ShmQueue;
-use perp_signal_hft::ipc::tcp;
-
-#[derive(Debug, thiserror::Error)]
-pub enum PipelineError {
-    #[error("BinanceTrade error: {0}")]
-    BinanceError(#[from] BinanceError),
-    #[error("Format error: {0}")]
-    Format(#[from] BinaryFormatError),
-    #[error("IO error: {0}")]
-    Io(#[from] std::io::Error),
-    #[error("Time error: {0}")]
-    Time(#[from] std::time::SystemTimeError),
-}
-
-async fn initialize_encoder(assets: Vec<String>) -> Result<(BinaryFormat, Vec<u8>), PipelineError> {
-    tracing::info!(
-        "Initializing encoder for {} assets: {:?}",
-        assets.len(),
-        assets
-    );
-
-    let asset_len = assets.len();
-
-    tracing::debug!("Fetching price/quantity stats from Binance");
-    let pnqs = BinanceClient::new()
-        .avg_stats_batch(assets.clone(), asset_len)
-        .await;
-
-    tracing::debug!("Received {} price/qty pairs from Binance", pnqs.len());
-    let mut prices = Vec::with_capacity(pnqs.len());
-    let mut qtys = Vec::with_capacity(pnqs.len());
-    for pnq in pnqs {
-        prices.push(pnq.price);
-        qtys.push(pnq.qty);
-    }
-    let ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
-    let mut encoder = BinaryFormat::new().with_assets(assets)?;
-    let mut header = Vec::new();
-    encoder.write_header(&mut header, ts, &prices, &qtys)?;
-    tracing::info!(
-        "Encoder initialized successfully with {} byte header",
-        header.len()
-    );
-    Ok((encoder, header))
-}
-
-/// Generic handler: applies `callback` to the header and every encoded trade.
-async fn handle_trades<F, Fut>(
-    mut encoder: BinaryFormat,
-    header: Vec<u8>,
-    mut rx: UnboundedReceiver<TradeMessage>,
-    callback: F,
-) where
-    F: Fn(Vec<u8>) -> Fut + Send + Sync + 'static,
-    Fut: std::future::Future<Output = ()> + Send,
-{
-    tracing::info!("Starting trade processing pipeline");
-    callback(b"START".to_vec()).await;
-    callback(header.clone()).await;
-    tracing::info!("Header sent, waiting for trades");
-    while let Some(msg) = rx.recv().await {
-        match msg.to_trade(){
-            Ok(trade) => match encoder.encode(&trade) {
-                Ok(bin) => callback(bin).await,
-                Err(e) => tracing::error!("encode error: {}", e),
-            },
-            Err(e) => tracing::error!("failed to obtain trade, invalid trade params: {}", e.to_string())
-        }
-    }
-}
-
-/// SHM-based pipeline: writes header and trades into shared memory queue.
-pub async fn handle_trades_shm(
-    assets: Vec<String>,
-    name: String,
-    capacity: u32,
-    rx: UnboundedReceiver<TradeMessage>,
-) -> Result<(), PipelineError> {
-    tracing::info!(
-        "Setting up SHM queue: name='{}', capacity={} bytes",
-        name,
-        capacity
-    );
-    let queue = Arc::new(ShmQueue::create(&name, capacity)?);
-    tracing::info!("SHM queue created successfully");
-    let (encoder, header) = initialize_encoder(assets).await?;
-
-    let callback = {
-        move |data: Vec<u8>| {
-            let queue = queue.clone();
-            async move {
-                if let Err(e) = queue.push(&data) {
-                    tracing::error!("SHM push failed: {}", e);
-                }
-            }
-        }
-    };
-    handle_trades(encoder, header, rx, callback).await;
-    Ok(())
-}
-
-/// TCP-based pipeline: broadcasts START, header, and trades to all connected clients.
-pub async fn handle_trades_tcp(
-    assets: Vec<String>,
-    bind_addr: String,
-    rx: UnboundedReceiver<TradeMessage>,
-) -> Result<(), PipelineError> {
-    tracing::info!("Setting up TCP server on {}", bind_addr);
-    let (encoder, header) = initialize_encoder(assets).await?;
-
-    let (tx, _) = broadcast::channel::<Vec<u8>>(100);
-
-    let tx_clone = tx.clone();
-    let header_clone = header.clone();
-    tokio::spawn(async move {
-        handle_trades(encoder, header_clone, rx, move |data| {
-            let _ = tx_clone.send(data);
-            async {}
-        })
-        .await;
-    });
-
-    tracing::info!("Starting TCP server");
-    tcp::serve(&bind_addr, header, tx).await?;
-    Ok(())
-}
+use perp_signal_hft::config::{self, Config, MAX_ASSETS};
+use perp_signal_hft::http::{self, HttpState};
+use perp_signal_hft::ipc::tcp::ServeOptions;
+use perp_signal_hft::metrics::Metrics;
+use perp_signal_hft::pipeline::{
+    LatencySlo, PipelineControl, PipelineOptions, handle_trades_shm, handle_trades_tcp,
+};
+use perp_signal_hft::rawlog::RawLog;
+use perp_signal_hft::recent::RecentTrades;
 
 #[tokio::main(flavor = "current_thread")]
 pub async fn main() {
+    let mut cli = Cli::parse();
+    if cli.print_format_spec {
+        println!("{:#}", perp_signal_hft::format::spec());
+        return;
+    }
+    let comm = cli.take_comm();
+    cli.check_assets();
+
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
         .with_target(true)
@@ -149,56 +41,297 @@ pub async fn main() {
 
     tracing::info!("🚀 Starting perp_signal_hft");
 
-    let cli = Cli::parse();
-
-    if cli.assets.len() > 10 {
-        tracing::error!("Too many assets: {} (max 10)", cli.assets.len());
+    if let Some(path) = &cli.config {
+        match Config::load(path) {
+            Ok(config) => cli.assets = config.assets,
+            Err(e) => {
+                tracing::error!("Failed to load config {:?}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if cli.assets.len() > MAX_ASSETS {
+        tracing::error!("Too many assets: {} (max {})", cli.assets.len(), MAX_ASSETS);
         std::process::exit(1);
     }
-    tracing::info!(
-        "Configuration: assets={:?}, comm={:?}",
-        cli.assets,
-        cli.comm
-    );
+    tracing::info!("Configuration: assets={:?}, comm={:?}", cli.assets, comm);
 
     let assets = cli.assets;
     let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
 
-    tracing::info!("Starting Binance WebSocket connection");
-    let assets_clone = assets.clone();
-    let b_handle = tokio::spawn(async move {
-        BinanceWebsocket::start(tx, &assets_clone)
-            .await
-            .expect("websocket failed");
+    let metrics = Arc::new(Metrics::new());
+    let control = Arc::new(PipelineControl::new());
+    let recent = cli.http_addr.map(|addr| {
+        let recent = Arc::new(RecentTrades::new(&assets, cli.recent_depth));
+        let state = HttpState {
+            recent: recent.clone(),
+            metrics: metrics.clone(),
+            control: control.clone(),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(&addr.to_string(), state).await {
+                tracing::error!("HTTP endpoint failed: {}", e);
+            }
+        });
+        recent
     });
+    let client = BinanceClient::for_market(cli.market)
+        .with_api_key_from_env()
+        .with_reference_strategy(cli.reference_strategy);
+    let limits = match cli.rest_max_rps {
+        Some(rps) => client.with_max_rps(rps),
+        None => Ok(client),
+    }
+    .and_then(|client| match cli.rest_max_weight {
+        Some(weight) => client.with_max_weight_per_minute(weight),
+        None => Ok(client),
+    });
+    let mut client = match limits {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("Invalid REST rate limit: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(local) = cli.local_address {
+        client = match client.with_local_address(local) {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::error!("Failed to bind REST client to {}: {}", local, e);
+                std::process::exit(1);
+            }
+        };
+    }
+    for asset in &assets {
+        match client.funding_rate(asset).await {
+            Ok(rate) => {
+                tracing::info!("{} funding rate {:+.4}%", asset, rate * 100.0);
+                metrics.set_funding_rate(asset, rate);
+            }
+            Err(e) => tracing::warn!("funding rate for {} unavailable: {}", asset, e),
+        }
+    }
+    let mut opts = PipelineOptions {
+        client,
+        recent,
+        metrics,
+        idle_timeout: cli.idle_timeout_secs.map(Duration::from_secs),
+        max_trades: cli.max_trades,
+        latency_budget: cli.latency_budget_ms.map(Duration::from_millis),
+        latency_slo: cli.latency_slo_us.map(|us| LatencySlo {
+            threshold: Duration::from_micros(us),
+            window: Duration::from_secs(cli.latency_slo_window_secs),
+        }),
+        encode_timing: cli.encode_timing_secs.map(Duration::from_secs),
+        max_rate_per_asset: cli.max_rate_per_asset,
+        gap_keyframe: cli.gap_keyframe_pct.map(|pct| pct / 100.0),
+        gap_records: cli.gap_records,
+        control,
+        pause_policy: cli.pause_policy,
+        strict_symbols: cli.strict_symbols,
+        passthrough: cli.passthrough,
+        trade_stream_addr: cli.trade_stream_addr,
+        ..Default::default()
+    };
+    if let Some(prefix) = &cli.raw_log {
+        match RawLog::create(prefix) {
+            Ok(raw_log) => opts.raw_log = Some(Arc::new(raw_log)),
+            Err(e) => {
+                tracing::error!("Failed to create raw log {:?}: {}", prefix, e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let comm_type = match &cli.comm {
+    tracing::info!(
+        "Starting {} Binance WebSocket connection(s) ({:?})",
+        cli.ws_shards,
+        cli.market
+    );
+    let sharded = cli.ws_shards > 1;
+    let mut shard_txs = Vec::new();
+    let b_handles: Vec<_> = (0..cli.ws_shards)
+        .map(|shard| {
+            let shard_assets: Vec<String> = assets
+                .iter()
+                .skip(shard as usize)
+                .step_by(cli.ws_shards as usize)
+                .cloned()
+                .collect();
+            let (shard_tx, shard_assets) = watch::channel(shard_assets);
+            shard_txs.push(shard_tx);
+            let ws_config = BinanceWebsocketConfig {
+                market: cli.market,
+                max_reconnects: cli.max_reconnects,
+                initial_backoff: Duration::from_millis(cli.reconnect_backoff_ms),
+                max_backoff: Duration::from_millis(cli.reconnect_max_backoff_ms),
+                shard: sharded.then_some(shard),
+                local_address: cli.local_address,
+                passthrough: cli.passthrough,
+                reconnects: opts.metrics.ws_reconnects.clone(),
+                ..Default::default()
+            };
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = BinanceWebsocket::start_watched(tx, shard_assets, &ws_config).await
+                {
+                    tracing::error!("Binance websocket {} failed, exiting: {}", shard, e);
+                    std::process::exit(1);
+                }
+            })
+        })
+        .collect();
+    drop(tx);
+    if let Some(path) = cli.config.clone() {
+        let (control, passthrough) = (opts.control.clone(), opts.passthrough);
+        tokio::spawn(reload_on_sighup(path, shard_txs, control, passthrough));
+    }
+
+    let comm_type = match &comm {
         perp_signal_hft::cli::Comm::Shm { name, .. } => format!("SHM ({})", name),
-        perp_signal_hft::cli::Comm::Tcp { port } => format!("TCP (port {})", port),
+        perp_signal_hft::cli::Comm::Tcp { port, bind, .. } => {
+            format!("TCP ({})", SocketAddr::new(*bind, *port))
+        }
     };
     tracing::info!("Using {} communication method", comm_type);
 
-    let t_handle = match cli.comm {
-        perp_signal_hft::cli::Comm::Shm { name, capacity } => tokio::spawn(async move {
-            handle_trades_shm(assets, name, capacity, rx)
-                .await
-                .expect("SHM handler failed");
-        }),
-        perp_signal_hft::cli::Comm::Tcp { port } => {
-            let bind_address = format!("0.0.0.0:{}", port);
-            tokio::spawn(async move {
-                handle_trades_tcp(assets, bind_address, rx)
-                    .await
-                    .expect("TCP handler failed");
-            })
+    let metrics = opts.metrics.clone();
+    let t_handle = match comm {
+        perp_signal_hft::cli::Comm::Shm {
+            name,
+            capacity,
+            heartbeat_secs,
+            block_when_full_ms,
+        } => {
+            opts.heartbeat_interval = heartbeat_secs.map(Duration::from_secs);
+            let block_when_full = block_when_full_ms.map(Duration::from_millis);
+            tokio::spawn(handle_trades_shm(
+                assets,
+                name,
+                capacity,
+                block_when_full,
+                rx,
+                opts,
+            ))
+        }
+        perp_signal_hft::cli::Comm::Tcp {
+            port,
+            bind,
+            budget,
+            snapshot_on_connect,
+            credit_flow_control,
+            framing,
+            negotiate_version,
+        } => {
+            let bind_address = SocketAddr::new(bind, port);
+            tokio::spawn(handle_trades_tcp(
+                assets,
+                bind_address,
+                budget,
+                snapshot_on_connect,
+                ServeOptions {
+                    credit_flow_control,
+                    framing,
+                    negotiate_version,
+                    ..Default::default()
+                },
+                rx,
+                opts,
+            ))
         }
     };
 
     tracing::info!("All components started, processing trades...");
 
-    let (b_res, t_res) = tokio::join!(b_handle, t_handle);
-    if let Err(e) = b_res {
-        tracing::error!("binance websocket handle panicked {}", e);
+    tokio::spawn(async move {
+        for res in futures::future::join_all(b_handles).await {
+            if let Err(e) = res {
+                tracing::error!("binance websocket handle panicked {}", e);
+            }
+        }
+    });
+    // The pipeline ends on its own once the feed closes, after --max-trades or
+    // with an error, eg: --idle-timeout-secs
+    let code = tokio::select! {
+        res = t_handle => match res.expect("trade signal handler panicked") {
+            Ok(()) => 0,
+            Err(e) => {
+                tracing::error!("{} handler failed, exiting: {}", comm_type, e);
+                1
+            }
+        },
+        _ = tokio::signal::ctrl_c() => {
+            tracing::info!("SIGINT received, shutting down");
+            0
+        }
+    };
+    tracing::info!("Run summary:\n{}", metrics.summary());
+    std::process::exit(code);
+}
+
+/// Re-read the config at `path` on every SIGHUP and switch to its assets. The
+/// pipeline publishes a header for the new list first; only then do the
+/// websocket shards subscribe to added assets and drop removed ones, so no
+/// trade arrives that its header can't encode. An invalid config, or a header
+/// that fails to build, is logged and the running assets are kept.
+async fn reload_on_sighup(
+    path: PathBuf,
+    shards: Vec<watch::Sender<Vec<String>>>,
+    control: Arc<PipelineControl>,
+    passthrough: bool,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!("Failed to listen for SIGHUP, config reload is off: {}", e);
+            return;
+        }
+    };
+    let mut header_assets = control.header_assets();
+    while hangup.recv().await.is_some() {
+        let current: Vec<Vec<String>> = shards.iter().map(|tx| tx.borrow().clone()).collect();
+        let assets: Vec<String> = current.concat();
+        let config = match Config::load(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!("Reloading {:?} failed, keeping {:?}: {}", path, assets, e);
+                continue;
+            }
+        };
+        let (added, removed) = config::diff_assets(&assets, &config.assets);
+        if added.is_empty() && removed.is_empty() {
+            tracing::info!("Reloaded {:?}, assets unchanged", path);
+            continue;
+        }
+        tracing::info!(
+            "Reloaded {:?}: adding {:?}, removing {:?}",
+            path,
+            added,
+            removed
+        );
+
+        // Passthrough has no header to wait for
+        if !passthrough {
+            control.reload_assets(config.assets.clone());
+            let published = header_assets.wait_for(|published| *published == config.assets);
+            match tokio::time::timeout(RELOAD_TIMEOUT, published).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(_)) => return,
+                Err(_) => {
+                    tracing::error!(
+                        "No header for {:?} published, keeping {:?}",
+                        config.assets,
+                        assets
+                    );
+                    continue;
+                }
+            }
+        }
+        for (tx, shard_assets) in shards.iter().zip(config::reshard(&current, &config.assets)) {
+            tx.send_replace(shard_assets);
+        }
     }
-    t_res.expect("trade signal handler panicked");
 }
+
+/// How long `reload_on_sighup` waits for the pipeline to publish the new header.
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(30);
@@ -0,0 +1,494 @@
+// std
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Upper bounds, in bytes, of the `trade_frame_bytes` histogram buckets. The
+/// encoder reserves 64 bytes per frame, so anything above that reallocates.
+pub const FRAME_SIZE_BUCKETS: [u64; 7] = [2, 4, 8, 16, 32, 64, 128];
+
+/// Upper bounds, in nanoseconds, of the `encode_ns` histogram buckets.
+pub const ENCODE_NS_BUCKETS: [u64; 8] = [100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000];
+
+/// Upper bounds, in microseconds, of the `latency_us` histogram buckets.
+pub const LATENCY_US_BUCKETS: [u64; 10] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000, 100_000,
+];
+
+/// Pipeline counters, rendered in Prometheus text format on `/metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub trades_forwarded: AtomicU64,
+    pub trades_dropped_stale: AtomicU64,
+    pub trades_dropped_paused: AtomicU64,
+    pub trades_dropped_muted: AtomicU64,
+    pub trades_dropped_sink: AtomicU64,
+    pub trades_dropped_rate: AtomicU64,
+    pub keyframes_emitted: AtomicU64,
+    pub heartbeats_emitted: AtomicU64,
+    /// Gap records sent ahead of resync keyframes, see `PipelineOptions::gap_records`
+    pub gaps_emitted: AtomicU64,
+    /// Latency SLO windows whose p99 exceeded the SLO
+    pub latency_slo_breaches: AtomicU64,
+    /// p99 receive-to-sink latency over the last full SLO window, in microseconds
+    pub latency_p99_us: AtomicU64,
+    /// TCP clients connected right now, shared with `tcp::ServeOptions::clients`
+    pub tcp_clients: Arc<AtomicUsize>,
+    /// Times a TCP client fell behind the broadcast and lost frames, shared
+    /// with `tcp::ServeOptions::lag_events`
+    pub tcp_lag_events: Arc<AtomicU64>,
+    /// Frames larger than `--max-buffered-bytes` on their own, refused for a
+    /// TCP client, shared with `MemoryGovernor::with_oversized`
+    pub tcp_frames_oversized: Arc<AtomicU64>,
+    /// Websocket connections re-established after their first, shared with
+    /// `BinanceWebsocketConfig::reconnects`
+    pub ws_reconnects: Arc<AtomicU64>,
+    /// Most bytes ever waiting for consumers at once: unread in the SHM queue, or
+    /// queued across TCP client backlogs (`MemoryGovernor::with_peak`)
+    pub peak_queued_bytes: Arc<AtomicU64>,
+    /// Trades forwarded per symbol
+    asset_trades: Mutex<BTreeMap<String, u64>>,
+    /// Receive-to-sink latency of each forwarded trade per `LATENCY_US_BUCKETS`
+    /// bucket, laid out like `frame_sizes`, with the lowest and highest seen
+    latencies: [AtomicU64; LATENCY_US_BUCKETS.len() + 1],
+    latency_us: AtomicU64,
+    latency_range: Mutex<Option<(u64, u64)>>,
+    /// Funding rate per symbol, sampled at startup
+    funding_rates: Mutex<BTreeMap<String, f64>>,
+    /// Trades received per websocket shard, when the assets are sharded
+    shard_trades: Mutex<BTreeMap<u16, u64>>,
+    /// Encoded trade frames per `FRAME_SIZE_BUCKETS` bucket, not cumulative;
+    /// the last slot counts frames above every bound
+    frame_sizes: [AtomicU64; FRAME_SIZE_BUCKETS.len() + 1],
+    frame_bytes: AtomicU64,
+    /// p50 and p99 time to encode a trade over the last encode timing window,
+    /// in nanoseconds, see `PipelineOptions::encode_timing`
+    pub encode_p50_ns: AtomicU64,
+    pub encode_p99_ns: AtomicU64,
+    /// Timed encodes per `ENCODE_NS_BUCKETS` bucket, laid out like `frame_sizes`
+    encode_times: [AtomicU64; ENCODE_NS_BUCKETS.len() + 1],
+    encode_ns: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_funding_rate(&self, symbol: &str, rate: f64) {
+        self.funding_rates
+            .lock()
+            .unwrap()
+            .insert(symbol.to_string(), rate);
+    }
+
+    pub fn inc_shard_trades(&self, shard: u16) {
+        *self.shard_trades.lock().unwrap().entry(shard).or_default() += 1;
+    }
+
+    pub fn shard_trades(&self, shard: u16) -> u64 {
+        self.shard_trades
+            .lock()
+            .unwrap()
+            .get(&shard)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Count a trade forwarded for `symbol`.
+    pub fn inc_asset_trades(&self, symbol: &str) {
+        let mut asset_trades = self.asset_trades.lock().unwrap();
+        match asset_trades.get_mut(symbol) {
+            Some(count) => *count += 1,
+            None => {
+                asset_trades.insert(symbol.to_string(), 1);
+            }
+        }
+    }
+
+    /// Record the receive-to-sink latency of one forwarded trade.
+    pub fn observe_latency(&self, micros: u64) {
+        let bucket = LATENCY_US_BUCKETS
+            .iter()
+            .position(|&bound| micros <= bound)
+            .unwrap_or(LATENCY_US_BUCKETS.len());
+        Self::inc(&self.latencies[bucket]);
+        self.latency_us.fetch_add(micros, Ordering::Relaxed);
+        let mut range = self.latency_range.lock().unwrap();
+        *range = Some(match *range {
+            Some((min, max)) => (min.min(micros), max.max(micros)),
+            None => (micros, micros),
+        });
+    }
+
+    /// End-of-run report of the counters so far, see `Summary`.
+    pub fn summary(&self) -> Summary {
+        let latencies: Vec<u64> = self
+            .latencies
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let latency = self.latency_range.lock().unwrap().map(|(min, max)| {
+            // The bucket bound a percentile falls under, capped by what was seen
+            let count: u64 = latencies.iter().sum();
+            let percentile = |p: u64| {
+                let rank = (count * p).div_ceil(100);
+                let mut seen = 0;
+                let bucket = latencies.iter().position(|&n| {
+                    seen += n;
+                    seen >= rank
+                });
+                bucket
+                    .and_then(|bucket| LATENCY_US_BUCKETS.get(bucket))
+                    .map_or(max, |&bound| bound.min(max))
+                    .max(min)
+            };
+            LatencySummary {
+                min,
+                p50: percentile(50),
+                p99: percentile(99),
+                max,
+            }
+        });
+        Summary {
+            trades_forwarded: self.trades_forwarded.load(Ordering::Relaxed),
+            asset_trades: self.asset_trades.lock().unwrap().clone(),
+            frame_bytes: self.frame_bytes.load(Ordering::Relaxed),
+            ws_reconnects: self.ws_reconnects.load(Ordering::Relaxed),
+            peak_queued_bytes: self.peak_queued_bytes.load(Ordering::Relaxed),
+            tcp_lag_events: self.tcp_lag_events.load(Ordering::Relaxed),
+            latency,
+        }
+    }
+
+    /// Record the size of one encoded trade frame.
+    pub fn observe_frame_size(&self, bytes: usize) {
+        let bucket = FRAME_SIZE_BUCKETS
+            .iter()
+            .position(|&bound| bytes as u64 <= bound)
+            .unwrap_or(FRAME_SIZE_BUCKETS.len());
+        Self::inc(&self.frame_sizes[bucket]);
+        self.frame_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Frames observed so far and their total size in bytes.
+    pub fn frame_size_totals(&self) -> (u64, u64) {
+        let count = self
+            .frame_sizes
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum();
+        (count, self.frame_bytes.load(Ordering::Relaxed))
+    }
+
+    /// Record the time one trade took to encode.
+    pub fn observe_encode_time(&self, nanos: u64) {
+        let bucket = ENCODE_NS_BUCKETS
+            .iter()
+            .position(|&bound| nanos <= bound)
+            .unwrap_or(ENCODE_NS_BUCKETS.len());
+        Self::inc(&self.encode_times[bucket]);
+        self.encode_ns.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Encodes timed so far and their total time in nanoseconds.
+    pub fn encode_time_totals(&self) -> (u64, u64) {
+        let count = self
+            .encode_times
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum();
+        (count, self.encode_ns.load(Ordering::Relaxed))
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "trades_forwarded_total",
+                "Trades encoded and handed to the sink",
+                &self.trades_forwarded,
+            ),
+            (
+                "trades_dropped_stale_total",
+                "Trades dropped for exceeding the latency budget",
+                &self.trades_dropped_stale,
+            ),
+            (
+                "trades_dropped_paused_total",
+                "Trades dropped while the pipeline was paused",
+                &self.trades_dropped_paused,
+            ),
+            (
+                "trades_dropped_muted_total",
+                "Trades dropped because their asset was muted",
+                &self.trades_dropped_muted,
+            ),
+            (
+                "trades_dropped_sink_total",
+                "Trades encoded but rejected by the sink, eg: a full SHM queue",
+                &self.trades_dropped_sink,
+            ),
+            (
+                "trades_dropped_rate_total",
+                "Trades dropped by the per-asset rate limit",
+                &self.trades_dropped_rate,
+            ),
+            (
+                "keyframes_emitted_total",
+                "Keyframes emitted to resync consumers",
+                &self.keyframes_emitted,
+            ),
+            (
+                "heartbeats_emitted_total",
+                "Heartbeats emitted while no trades flowed",
+                &self.heartbeats_emitted,
+            ),
+            (
+                "gaps_emitted_total",
+                "Gap records emitted ahead of resync keyframes",
+                &self.gaps_emitted,
+            ),
+            (
+                "latency_slo_breaches_total",
+                "Latency SLO windows whose p99 exceeded the SLO",
+                &self.latency_slo_breaches,
+            ),
+            (
+                "ws_reconnects_total",
+                "Websocket connections re-established after their first",
+                &self.ws_reconnects,
+            ),
+            (
+                "tcp_lag_events_total",
+                "Times a TCP client fell behind the broadcast and lost frames",
+                &self.tcp_lag_events,
+            ),
+            (
+                "tcp_frames_oversized_total",
+                "Frames larger than the TCP memory cap, refused for a client",
+                &self.tcp_frames_oversized,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP perp_signal_hft_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE perp_signal_hft_{} counter", name);
+            let _ = writeln!(
+                out,
+                "perp_signal_hft_{} {}",
+                name,
+                value.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP perp_signal_hft_latency_p99_us p99 receive-to-sink latency over the last SLO window"
+        );
+        let _ = writeln!(out, "# TYPE perp_signal_hft_latency_p99_us gauge");
+        let _ = writeln!(
+            out,
+            "perp_signal_hft_latency_p99_us {}",
+            self.latency_p99_us.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP perp_signal_hft_tcp_clients TCP clients connected"
+        );
+        let _ = writeln!(out, "# TYPE perp_signal_hft_tcp_clients gauge");
+        let _ = writeln!(
+            out,
+            "perp_signal_hft_tcp_clients {}",
+            self.tcp_clients.load(Ordering::Relaxed)
+        );
+        render_histogram(
+            &mut out,
+            "trade_frame_bytes",
+            "Size of each encoded trade frame",
+            &FRAME_SIZE_BUCKETS,
+            &self.frame_sizes,
+            self.frame_size_totals(),
+        );
+        let (count, micros) = (
+            self.latencies
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .sum(),
+            self.latency_us.load(Ordering::Relaxed),
+        );
+        render_histogram(
+            &mut out,
+            "latency_us",
+            "Receive-to-sink latency of each forwarded trade, in microseconds",
+            &LATENCY_US_BUCKETS,
+            &self.latencies,
+            (count, micros),
+        );
+        let _ = writeln!(
+            out,
+            "# HELP perp_signal_hft_peak_queued_bytes Most bytes waiting for consumers at once"
+        );
+        let _ = writeln!(out, "# TYPE perp_signal_hft_peak_queued_bytes gauge");
+        let _ = writeln!(
+            out,
+            "perp_signal_hft_peak_queued_bytes {}",
+            self.peak_queued_bytes.load(Ordering::Relaxed)
+        );
+        let (timed, _) = self.encode_time_totals();
+        if timed > 0 {
+            render_histogram(
+                &mut out,
+                "encode_ns",
+                "Time to encode each trade, in nanoseconds",
+                &ENCODE_NS_BUCKETS,
+                &self.encode_times,
+                self.encode_time_totals(),
+            );
+            for (name, help, value) in [
+                ("encode_p50_ns", "p50 encode time", &self.encode_p50_ns),
+                ("encode_p99_ns", "p99 encode time", &self.encode_p99_ns),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "# HELP perp_signal_hft_{} {} over the last encode timing window",
+                    name, help
+                );
+                let _ = writeln!(out, "# TYPE perp_signal_hft_{} gauge", name);
+                let _ = writeln!(
+                    out,
+                    "perp_signal_hft_{} {}",
+                    name,
+                    value.load(Ordering::Relaxed)
+                );
+            }
+        }
+        let shard_trades = self.shard_trades.lock().unwrap();
+        if !shard_trades.is_empty() {
+            let _ = writeln!(
+                out,
+                "# HELP perp_signal_hft_shard_trades_total Trades received per websocket shard"
+            );
+            let _ = writeln!(out, "# TYPE perp_signal_hft_shard_trades_total counter");
+            for (shard, count) in shard_trades.iter() {
+                let _ = writeln!(
+                    out,
+                    "perp_signal_hft_shard_trades_total{{shard=\"{}\"}} {}",
+                    shard, count
+                );
+            }
+        }
+        let asset_trades = self.asset_trades.lock().unwrap();
+        if !asset_trades.is_empty() {
+            let _ = writeln!(
+                out,
+                "# HELP perp_signal_hft_asset_trades_forwarded_total Trades forwarded per symbol"
+            );
+            let _ = writeln!(
+                out,
+                "# TYPE perp_signal_hft_asset_trades_forwarded_total counter"
+            );
+            for (symbol, count) in asset_trades.iter() {
+                let _ = writeln!(
+                    out,
+                    "perp_signal_hft_asset_trades_forwarded_total{{symbol=\"{}\"}} {}",
+                    symbol, count
+                );
+            }
+        }
+        let funding_rates = self.funding_rates.lock().unwrap();
+        if !funding_rates.is_empty() {
+            let _ = writeln!(
+                out,
+                "# HELP perp_signal_hft_funding_rate Last funding rate per symbol, at startup"
+            );
+            let _ = writeln!(out, "# TYPE perp_signal_hft_funding_rate gauge");
+            for (symbol, rate) in funding_rates.iter() {
+                let _ = writeln!(
+                    out,
+                    "perp_signal_hft_funding_rate{{symbol=\"{}\"}} {}",
+                    symbol, rate
+                );
+            }
+        }
+        out
+    }
+}
+
+/// What a run got through, logged on shutdown, see `Metrics::summary`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub trades_forwarded: u64,
+    pub asset_trades: BTreeMap<String, u64>,
+    /// Encoded trade frame bytes
+    pub frame_bytes: u64,
+    pub ws_reconnects: u64,
+    pub peak_queued_bytes: u64,
+    pub tcp_lag_events: u64,
+    /// `None` until a trade was forwarded
+    pub latency: Option<LatencySummary>,
+}
+
+/// Receive-to-sink latency over a whole run, in microseconds. p50 and p99 are
+/// the `LATENCY_US_BUCKETS` bound they fall under, clamped to min..=max.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub min: u64,
+    pub p50: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "trades forwarded: {}", self.trades_forwarded)?;
+        for (symbol, count) in &self.asset_trades {
+            writeln!(f, "  {}: {}", symbol, count)?;
+        }
+        writeln!(f, "trade frame bytes: {}", self.frame_bytes)?;
+        writeln!(f, "websocket reconnects: {}", self.ws_reconnects)?;
+        writeln!(f, "peak queued bytes: {}", self.peak_queued_bytes)?;
+        writeln!(f, "TCP lag events: {}", self.tcp_lag_events)?;
+        match self.latency {
+            Some(l) => write!(
+                f,
+                "latency us: min {} / p50 <= {} / p99 <= {} / max {}",
+                l.min, l.p50, l.p99, l.max
+            ),
+            None => write!(f, "latency us: no trades"),
+        }
+    }
+}
+
+/// Write a Prometheus histogram from non-cumulative `buckets`, one per bound and
+/// a last one above every bound, with `totals` as (count, sum).
+fn render_histogram(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    bounds: &[u64],
+    buckets: &[AtomicU64],
+    (count, sum): (u64, u64),
+) {
+    let _ = writeln!(out, "# HELP perp_signal_hft_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE perp_signal_hft_{} histogram", name);
+    let mut cumulative = 0;
+    for (bound, bucket) in bounds.iter().zip(buckets) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "perp_signal_hft_{}_bucket{{le=\"{}\"}} {}",
+            name, bound, cumulative
+        );
+    }
+    let _ = writeln!(
+        out,
+        "perp_signal_hft_{}_bucket{{le=\"+Inf\"}} {}",
+        name, count
+    );
+    let _ = writeln!(out, "perp_signal_hft_{}_sum {}", name, sum);
+    let _ = writeln!(out, "perp_signal_hft_{}_count {}", name, count);
+}
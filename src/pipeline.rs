@@ -0,0 +1,2542 @@
+// std
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+// external
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use tokio::net::TcpListener;
+use tokio::sync::{Notify, broadcast, mpsc::UnboundedReceiver, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, Interval, Sleep};
+
+// internal
+use crate::binance::{BinanceClient, BinanceError, TradeMessage, reference_summary};
+use crate::clock::{Clock, SystemClock};
+use crate::format::{BinaryFormat, BinaryFormatError, STREAM_MAGIC};
+use crate::http;
+use crate::ipc::governor::{MemoryBudget, MemoryGovernor};
+use crate::ipc::shm_queue::{ShmQueue, WaitStrategy};
+use crate::ipc::tcp;
+use crate::metrics::Metrics;
+use crate::rawlog::RawLog;
+use crate::recent::RecentTrades;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PipelineError {
+    #[error("BinanceTrade error: {0}")]
+    BinanceError(#[from] BinanceError),
+    #[error("Format error: {0}")]
+    Format(#[from] BinaryFormatError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("TCP server failed: {0}")]
+    TcpServer(std::io::Error),
+    #[error("Time error: {0}")]
+    Time(#[from] std::time::SystemTimeError),
+    #[error("trade for {0}, which is not in the header: subscription and header have diverged")]
+    UnknownSymbol(String),
+    #[error(
+        "SHM queue capacity of {capacity} bytes can't take the {needed} byte handshake with room to spare, use a --capacity of at least {}",
+        2 * needed
+    )]
+    ShmCapacity { capacity: u32, needed: usize },
+    #[error("no trade received for {0:?}, the feed looks dead")]
+    Idle(Duration),
+}
+
+/// How a blocked SHM push polls for room: short sleeps, since the wait already
+/// runs on its own thread and a consumer frees space in bursts.
+const SHM_RETRY_WAIT: WaitStrategy = WaitStrategy::Block {
+    sleep: Duration::from_micros(50),
+};
+
+/// What `handle_trades` does with incoming trades while paused.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PausePolicy {
+    /// Keep draining the ingest channel and discard trades. Each asset that lost
+    /// trades gets a keyframe after resume.
+    #[default]
+    Drop,
+    /// Stop reading the ingest channel, so trades queue up in it (unbounded,
+    /// memory grows for as long as the pause lasts) and go out in order on resume.
+    Buffer,
+}
+
+/// Runtime switches shared between the pipeline and whoever controls it.
+#[derive(Debug, Default)]
+pub struct PipelineControl {
+    paused: AtomicBool,
+    resumed: Notify,
+    /// Assets whose trades are dropped before encoding
+    muted: RwLock<HashSet<String>>,
+    /// Asset list for the next epoch, see `reload_assets`
+    reload: Mutex<Option<Vec<String>>>,
+    reload_requested: Notify,
+    /// Assets of the header last published
+    header_assets: watch::Sender<Vec<String>>,
+    /// Assets dropped by a reload, whose trades still in flight are skipped
+    retired: RwLock<HashSet<String>>,
+}
+
+impl PipelineControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Release);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+        self.resumed.notify_waiters();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Stop forwarding `symbol`'s trades. Returns false if it was already muted.
+    pub fn mute(&self, symbol: &str) -> bool {
+        self.muted.write().unwrap().insert(symbol.to_string())
+    }
+
+    /// Forward `symbol` again; its next trade goes out as a keyframe. Returns
+    /// false if it wasn't muted.
+    pub fn unmute(&self, symbol: &str) -> bool {
+        self.muted.write().unwrap().remove(symbol)
+    }
+
+    pub fn is_muted(&self, symbol: &str) -> bool {
+        self.muted.read().unwrap().contains(symbol)
+    }
+
+    /// Currently muted assets, sorted.
+    pub fn muted(&self) -> Vec<String> {
+        let mut muted: Vec<String> = self.muted.read().unwrap().iter().cloned().collect();
+        muted.sort();
+        muted
+    }
+
+    /// Switch the pipeline to `assets`: it builds an encoder for them and
+    /// publishes its header as a new epoch. If that fails (eg: an asset Binance
+    /// doesn't know) the error is logged and the current encoder keeps running.
+    pub fn reload_assets(&self, assets: Vec<String>) {
+        *self.reload.lock().unwrap() = Some(assets);
+        // Stores a permit while the pipeline is busy, so the request isn't missed
+        self.reload_requested.notify_one();
+    }
+
+    /// Follows the assets of the header last published, eg: to subscribe to an
+    /// added asset only once its trades can be encoded.
+    pub fn header_assets(&self) -> watch::Receiver<Vec<String>> {
+        self.header_assets.subscribe()
+    }
+
+    /// Record a newly published header. Assets it no longer lists are retired,
+    /// so their trades still arriving from the websocket aren't encode errors.
+    fn header_published(&self, assets: &[String]) {
+        let mut retired = self.retired.write().unwrap();
+        retired.extend(
+            self.header_assets
+                .borrow()
+                .iter()
+                .filter(|symbol| !assets.contains(symbol))
+                .cloned(),
+        );
+        retired.retain(|symbol| !assets.contains(symbol));
+        self.header_assets.send_replace(assets.to_vec());
+    }
+
+    fn is_retired(&self, symbol: &str) -> bool {
+        self.retired.read().unwrap().contains(symbol)
+    }
+
+    async fn wait_resumed(&self) {
+        loop {
+            // Registered before the check, so a resume in between isn't missed
+            let notified = self.resumed.notified();
+            if !self.is_paused() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// End-to-end latency objective: the p99 of `now - received_at`, taken once the
+/// sink took the frame, should stay under `threshold` in every `window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySlo {
+    pub threshold: Duration,
+    pub window: Duration,
+}
+
+/// Default for `LatencySlo::window`.
+pub const DEFAULT_LATENCY_SLO_WINDOW: Duration = Duration::from_secs(10);
+
+/// Optional behaviour and shared state for `handle_trades`.
+#[derive(Clone)]
+pub struct PipelineOptions {
+    /// REST client used to fetch the header's reference prices
+    pub client: BinanceClient,
+    /// Forwarded trades are recorded here when set
+    pub recent: Option<Arc<RecentTrades>>,
+    pub metrics: Arc<Metrics>,
+    /// Trades older than this (`now - received_at`) are dropped before encoding
+    pub latency_budget: Option<Duration>,
+    /// Warn when the p99 receive-to-sink latency of a window exceeds this
+    pub latency_slo: Option<LatencySlo>,
+    /// Time each trade's encode, into the `encode_ns` histogram, and log its
+    /// p50/p99 once per this window. Off by default: two clock reads per trade
+    pub encode_timing: Option<Duration>,
+    /// Forward at most this many trades per second per asset, dropping the rest
+    pub max_rate_per_asset: Option<f64>,
+    /// Send a trade as a keyframe when its price moved more than this fraction
+    /// of the asset's last price, eg: after a halt
+    pub gap_keyframe: Option<f64>,
+    /// Send a `Record::Gap` with the number of trades dropped for an asset ahead
+    /// of the keyframe that resyncs it, so consumers know their volume is short
+    pub gap_records: bool,
+    /// Emit a heartbeat record after this long without a trade
+    pub heartbeat_interval: Option<Duration>,
+    /// End the pipeline with `PipelineError::Idle` after this long without a
+    /// trade from the feed, once the sink has taken every frame before it. Our
+    /// own heartbeats don't count, and neither does time spent paused
+    pub idle_timeout: Option<Duration>,
+    /// End the pipeline, as if the feed closed, once `Metrics::trades_forwarded`
+    /// reaches this, eg: for a bounded capture. Passthrough doesn't count trades
+    pub max_trades: Option<u64>,
+    pub control: Arc<PipelineControl>,
+    pub pause_policy: PausePolicy,
+    /// Debug sink for the trades taken in and the frames sent, see `rawlog::verify`
+    pub raw_log: Option<Arc<RawLog>>,
+    /// Time source for the header, heartbeats and the latency budget
+    pub clock: Arc<dyn Clock>,
+    /// Serve decoded trades as JSON lines / SSE here, see `http::serve_trades`
+    pub trade_stream_addr: Option<SocketAddr>,
+    /// End the pipeline with `UnknownSymbol` on a trade for an asset missing
+    /// from the header, instead of logging and skipping it
+    pub strict_symbols: bool,
+    /// Forward each trade's websocket JSON unchanged instead of encoding it,
+    /// see `forward_raw`
+    pub passthrough: bool,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        Self {
+            client: BinanceClient::default(),
+            recent: None,
+            metrics: Arc::default(),
+            latency_budget: None,
+            latency_slo: None,
+            encode_timing: None,
+            max_rate_per_asset: None,
+            gap_keyframe: None,
+            gap_records: false,
+            heartbeat_interval: None,
+            idle_timeout: None,
+            max_trades: None,
+            control: Arc::default(),
+            pause_policy: PausePolicy::default(),
+            raw_log: None,
+            clock: Arc::new(SystemClock),
+            trade_stream_addr: None,
+            strict_symbols: false,
+            passthrough: false,
+        }
+    }
+}
+
+pub async fn initialize_encoder(
+    assets: Vec<String>,
+    client: &BinanceClient,
+    clock: &dyn Clock,
+) -> Result<(BinaryFormat, Vec<u8>), PipelineError> {
+    tracing::info!(
+        "Initializing encoder for {} assets: {:?}",
+        assets.len(),
+        assets
+    );
+
+    let asset_len = assets.len();
+
+    tracing::debug!("Fetching price/quantity stats from Binance");
+    let pnqs = client.avg_stats_batch(assets.clone(), asset_len).await;
+
+    tracing::debug!("Received {} price/qty pairs from Binance", pnqs.len());
+    tracing::info!("Reference prices:\n{}", reference_summary(&assets, &pnqs));
+    let mut prices = Vec::with_capacity(pnqs.len());
+    let mut qtys = Vec::with_capacity(pnqs.len());
+    for pnq in pnqs {
+        prices.push(pnq.price);
+        qtys.push(pnq.qty);
+    }
+    let mut encoder = BinaryFormat::new().with_assets(assets)?;
+    let ts = encoder.timestamp_unit().from_duration(clock.now());
+    let mut header = Vec::new();
+    encoder.write_header(&mut header, ts, &prices, &qtys)?;
+    tracing::info!(
+        "Encoder initialized successfully with {} byte header",
+        header.len()
+    );
+    Ok((encoder, header))
+}
+
+/// Generic handler: applies `callback` to the header and every encoded trade.
+///
+/// With a latency budget set, stale trades are dropped and the next trade for
+/// that asset goes out as a keyframe. The encoder never sees a dropped trade so
+/// the delta chain stays intact; the keyframe marks the gap for consumers.
+///
+/// With a heartbeat interval set, a heartbeat goes out whenever that long passes
+/// without a trade, so consumers can tell a quiet market from a dead producer.
+///
+/// While `opts.control` is paused nothing but heartbeats reaches `callback`;
+/// `opts.pause_policy` decides whether trades are dropped or left queued.
+/// Trades of muted assets are dropped, with a keyframe once unmuted.
+///
+/// With `max_rate_per_asset` set, each asset draws from its own token bucket
+/// (one second of burst) and trades beyond it are dropped, again followed by a
+/// keyframe, so one asset's flash crash can't flood consumers.
+///
+/// `callback` resolves to whether the sink took the frame. A trade the sink
+/// rejected (eg: SHM queue full) already moved the encoder's delta state, so
+/// that asset's next trade goes out as a keyframe to resync the consumer.
+///
+/// With `gap_records`, each of those resync keyframes follows a `Record::Gap`
+/// counting the asset's trades dropped since its last forwarded one.
+///
+/// On `PipelineControl::reload_assets` a new encoder takes over once its
+/// START + header went through `callback`.
+///
+/// Returns once `rx` closes, or with `UnknownSymbol` under `strict_symbols`.
+pub async fn handle_trades<F, Fut>(
+    mut encoder: BinaryFormat,
+    header: Vec<u8>,
+    mut rx: UnboundedReceiver<TradeMessage>,
+    opts: PipelineOptions,
+    callback: F,
+) -> Result<(), PipelineError>
+where
+    F: Fn(Vec<u8>) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = bool> + Send,
+{
+    tracing::info!("Starting trade processing pipeline");
+    let mut header = header;
+    loop {
+        if callback(b"START".to_vec()).await && callback(header.clone()).await {
+            log_handshake(&opts, &header);
+        } else {
+            tracing::error!("sink rejected the handshake, consumers can't decode this stream");
+        }
+        opts.control.header_published(encoder.symbols());
+        tracing::info!("Header sent, waiting for trades");
+        match forward_trades(encoder, &mut rx, &opts, &callback).await? {
+            EpochEnd::Closed => return Ok(()),
+            EpochEnd::Reload(next, next_header) => (encoder, header) = (*next, next_header),
+        }
+    }
+}
+
+/// Passthrough: hand each trade's websocket JSON to `callback` verbatim, as one
+/// frame, until `rx` closes. Nothing is encoded and no reference prices are
+/// fetched. Transports still send their handshake, with an empty header, so a
+/// consumer can tell this stream from an encoded one.
+pub async fn forward_raw<F, Fut>(
+    mut rx: UnboundedReceiver<TradeMessage>,
+    idle_timeout: Option<Duration>,
+    callback: F,
+) -> Result<(), PipelineError>
+where
+    F: Fn(Vec<u8>) -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    tracing::info!("Forwarding raw websocket payloads, encoding is off");
+    loop {
+        let msg = match idle_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, rx.recv())
+                .await
+                .map_err(|_| PipelineError::Idle(timeout))?,
+            None => rx.recv().await,
+        };
+        let Some(msg) = msg else {
+            break;
+        };
+        match msg.raw {
+            Some(raw) => {
+                callback(raw.into_bytes()).await;
+            }
+            None => tracing::warn!("trade for {} without its raw payload", msg.asset),
+        }
+    }
+    Ok(())
+}
+
+/// Record a handshake that went out, see `PipelineOptions::raw_log`.
+fn log_handshake(opts: &PipelineOptions, header: &[u8]) {
+    if let Some(raw_log) = &opts.raw_log {
+        raw_log.record_frame(b"START");
+        raw_log.record_frame(header);
+    }
+}
+
+/// Why `forward_trades` returned.
+enum EpochEnd {
+    /// `rx` closed
+    Closed,
+    /// Encoder and header for the assets of `PipelineControl::reload_assets`
+    Reload(Box<BinaryFormat>, Vec<u8>),
+}
+
+/// `handle_trades` after the handshake: encode and forward until `rx` closes or
+/// an asset reload is ready.
+async fn forward_trades<F, Fut>(
+    mut encoder: BinaryFormat,
+    rx: &mut UnboundedReceiver<TradeMessage>,
+    opts: &PipelineOptions,
+    callback: &F,
+) -> Result<EpochEnd, PipelineError>
+where
+    F: Fn(Vec<u8>) -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    let callback = |frame: Vec<u8>| {
+        // Logged once delivered, so the log holds exactly what consumers got
+        let logged = opts.raw_log.clone().map(|raw_log| (raw_log, frame.clone()));
+        let sent = callback(frame);
+        async move {
+            let delivered = sent.await;
+            if delivered && let Some((raw_log, frame)) = logged {
+                raw_log.record_frame(&frame);
+            }
+            delivered
+        }
+    };
+
+    // Assets owing a keyframe, with the trades they dropped meanwhile
+    let mut needs_keyframe: HashMap<String, u64> = HashMap::new();
+    let mut buckets: HashMap<String, TokenBucket> = HashMap::new();
+    let mut slo = opts
+        .latency_slo
+        .map(|slo| SloWindow::new(slo, opts.clock.now()));
+    let mut encode_timer = opts
+        .encode_timing
+        .map(|window| EncodeTimer::new(window, opts.clock.now()));
+    let mut heartbeat = opts
+        .heartbeat_interval
+        .map(|period| tokio::time::interval_at(Instant::now() + period, period));
+    let mut idle = opts.idle_timeout.map(IdleTimer::new);
+    // Trade received just as a buffering pause started, sent once resumed
+    let mut held = None;
+    loop {
+        let hold = opts.pause_policy == PausePolicy::Buffer && opts.control.is_paused();
+        let msg = tokio::select! {
+            msg = rx.recv(), if !hold && held.is_none() => match msg {
+                Some(msg) => {
+                    if let Some(shard) = msg.shard {
+                        opts.metrics.inc_shard_trades(shard);
+                    }
+                    if let Some(idle) = idle.as_mut() {
+                        idle.rearm();
+                    }
+                    msg
+                }
+                None => return Ok(EpochEnd::Closed),
+            },
+            _ = opts.control.wait_resumed(), if hold || held.is_some() => {
+                // A pause is no sign of a dead feed, the wait starts over
+                if let Some(idle) = idle.as_mut() {
+                    idle.rearm();
+                }
+                match held.take() {
+                    Some(msg) => msg,
+                    None => continue,
+                }
+            }
+            timeout = idle_expired(&mut idle), if !hold && held.is_none() => {
+                tracing::error!("no trade for {:?}, ending the pipeline", timeout);
+                return Err(PipelineError::Idle(timeout));
+            }
+            _ = next_tick(&mut heartbeat) => {
+                let now = encoder.timestamp_unit().from_duration(opts.clock.now());
+                match encoder.encode_heartbeat(now) {
+                    Ok(bin) => {
+                        if callback(bin).await {
+                            Metrics::inc(&opts.metrics.heartbeats_emitted);
+                        }
+                    }
+                    Err(e) => tracing::error!("heartbeat encode error: {}", e),
+                }
+                continue;
+            }
+            _ = opts.control.reload_requested.notified(), if held.is_none() => {
+                let Some(assets) = opts.control.reload.lock().unwrap().take() else {
+                    continue;
+                };
+                // Trades queue in `rx` meanwhile, the current epoch keeps its state
+                match initialize_encoder(assets, &opts.client, opts.clock.as_ref()).await {
+                    Ok((encoder, header)) => return Ok(EpochEnd::Reload(Box::new(encoder), header)),
+                    Err(e) => {
+                        tracing::error!("asset reload failed, keeping the current assets: {}", e);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        if opts.control.is_paused() && opts.pause_policy == PausePolicy::Buffer {
+            held = Some(msg);
+            continue;
+        }
+
+        // Logged before any drop, so the verifier reports dropped trades too
+        if let Some(raw_log) = &opts.raw_log {
+            raw_log.record(&msg);
+        }
+
+        if opts.control.is_paused() {
+            Metrics::inc(&opts.metrics.trades_dropped_paused);
+            *needs_keyframe.entry(msg.asset).or_default() += 1;
+            continue;
+        }
+
+        if opts.control.is_retired(&msg.asset) {
+            tracing::debug!("skipping {} trade, removed by a reload", msg.asset);
+            continue;
+        }
+
+        if opts.control.is_muted(&msg.asset) {
+            Metrics::inc(&opts.metrics.trades_dropped_muted);
+            *needs_keyframe.entry(msg.asset).or_default() += 1;
+            continue;
+        }
+
+        if let Some(budget) = opts.latency_budget {
+            let now = opts.clock.now().as_micros();
+            let age = now.saturating_sub(msg.received_at);
+            if age > budget.as_micros() {
+                tracing::debug!("dropping stale {} trade ({} us old)", msg.asset, age);
+                Metrics::inc(&opts.metrics.trades_dropped_stale);
+                *needs_keyframe.entry(msg.asset).or_default() += 1;
+                continue;
+            }
+        }
+
+        if let Some(rate) = opts.max_rate_per_asset {
+            let now = opts.clock.now();
+            let bucket = match buckets.get_mut(&msg.asset) {
+                Some(bucket) => bucket,
+                None => buckets
+                    .entry(msg.asset.clone())
+                    .or_insert_with(|| TokenBucket::new(rate, now)),
+            };
+            if !bucket.take(now) {
+                Metrics::inc(&opts.metrics.trades_dropped_rate);
+                *needs_keyframe.entry(msg.asset).or_default() += 1;
+                continue;
+            }
+        }
+
+        let received_at = msg.received_at;
+        match msg.to_trade() {
+            Ok(trade) => {
+                // Cleared only once the keyframe is out, so a failed encode or
+                // publish leaves the next trade to resync
+                let dropped = needs_keyframe.get(&trade.symbol).copied();
+                let mut keyframe = dropped.is_some();
+                if let Some(gap) = opts.gap_keyframe
+                    && let Some(last) = encoder.last_price(&trade.symbol)
+                    && (trade.price - last).abs() > gap * last
+                {
+                    tracing::debug!(
+                        "{} gapped {} -> {}, keyframe",
+                        trade.symbol,
+                        last,
+                        trade.price
+                    );
+                    keyframe = true;
+                }
+                if let Some(dropped) = dropped
+                    && dropped > 0
+                    && opts.gap_records
+                {
+                    match encoder.encode_gap(&trade.symbol, dropped) {
+                        Ok(bin) => {
+                            if callback(bin).await {
+                                Metrics::inc(&opts.metrics.gaps_emitted);
+                                // Reported; the keyframe is still owed
+                                needs_keyframe.insert(trade.symbol.clone(), 0);
+                            }
+                        }
+                        Err(e) => tracing::error!("gap encode error: {}", e),
+                    }
+                }
+                let started = encode_timer.as_ref().map(|_| std::time::Instant::now());
+                let encoded = if keyframe {
+                    encoder.encode_keyframe(&trade)
+                } else {
+                    encoder.encode(&trade)
+                };
+                if let (Some(timer), Some(started)) = (encode_timer.as_mut(), started) {
+                    let nanos = started.elapsed().as_nanos() as u64;
+                    timer.record(nanos, opts.clock.now(), &opts.metrics);
+                }
+                match encoded {
+                    Ok(bin) => {
+                        opts.metrics.observe_frame_size(bin.len());
+                        if !callback(bin).await {
+                            tracing::debug!("sink dropped {} trade", trade.symbol);
+                            Metrics::inc(&opts.metrics.trades_dropped_sink);
+                            *needs_keyframe.entry(trade.symbol).or_default() += 1;
+                            continue;
+                        }
+                        if dropped.is_some() {
+                            needs_keyframe.remove(&trade.symbol);
+                        }
+                        if let Some(heartbeat) = heartbeat.as_mut() {
+                            heartbeat.reset();
+                        }
+                        Metrics::inc(&opts.metrics.trades_forwarded);
+                        opts.metrics.inc_asset_trades(&trade.symbol);
+                        let now = opts.clock.now();
+                        let latency = now.as_micros().saturating_sub(received_at) as u64;
+                        opts.metrics.observe_latency(latency);
+                        if let Some(slo) = slo.as_mut() {
+                            slo.record(latency, now, &opts.metrics);
+                        }
+                        if keyframe {
+                            Metrics::inc(&opts.metrics.keyframes_emitted);
+                        }
+                        if let Some(recent) = &opts.recent {
+                            recent.record(&trade);
+                        }
+                        if let Some(max) = opts.max_trades
+                            && opts.metrics.trades_forwarded.load(Ordering::Relaxed) >= max
+                        {
+                            tracing::info!("forwarded {} trades, ending the pipeline", max);
+                            return Ok(EpochEnd::Closed);
+                        }
+                    }
+                    Err(BinaryFormatError::InvalidSymbol(symbol)) if opts.strict_symbols => {
+                        tracing::error!("trade for {}, which is not in the header", symbol);
+                        return Err(PipelineError::UnknownSymbol(symbol));
+                    }
+                    Err(e) => tracing::error!("encode error: {}", e),
+                }
+            }
+            Err(e) => tracing::error!(
+                "failed to obtain trade, invalid trade params: {}",
+                e.to_string()
+            ),
+        }
+    }
+}
+
+/// Per-asset rate limit for `forward_trades`: refills at `rate` tokens per
+/// second up to one second's worth, one token per forwarded trade.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Duration,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Duration) -> Self {
+        Self {
+            rate,
+            tokens: rate.max(1.0),
+            last: now,
+        }
+    }
+
+    fn take(&mut self, now: Duration) -> bool {
+        let elapsed = now.saturating_sub(self.last).as_secs_f64();
+        self.last = self.last.max(now);
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Latency samples of the current `LatencySlo` window. A window closes with the
+/// first trade past its end, so an idle feed never reports a breach.
+struct SloWindow {
+    slo: LatencySlo,
+    started: Duration,
+    samples_us: Vec<u64>,
+}
+
+impl SloWindow {
+    fn new(slo: LatencySlo, now: Duration) -> Self {
+        Self {
+            slo,
+            started: now,
+            samples_us: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, latency_us: u64, now: Duration, metrics: &Metrics) {
+        self.samples_us.push(latency_us);
+        if now.saturating_sub(self.started) < self.slo.window {
+            return;
+        }
+        let rank = (self.samples_us.len() * 99).div_ceil(100) - 1;
+        let p99 = *self.samples_us.select_nth_unstable(rank).1;
+        metrics.latency_p99_us.store(p99, Ordering::Relaxed);
+        if p99 > self.slo.threshold.as_micros() as u64 {
+            tracing::warn!(
+                "latency SLO breached: p99 {} us over {} trades, SLO {} us",
+                p99,
+                self.samples_us.len(),
+                self.slo.threshold.as_micros()
+            );
+            Metrics::inc(&metrics.latency_slo_breaches);
+        }
+        self.samples_us.clear();
+        self.started = now;
+    }
+}
+
+/// Encode times of the current `PipelineOptions::encode_timing` window, which,
+/// like `SloWindow`, closes with the first trade past its end.
+struct EncodeTimer {
+    window: Duration,
+    started: Duration,
+    samples_ns: Vec<u64>,
+}
+
+impl EncodeTimer {
+    fn new(window: Duration, now: Duration) -> Self {
+        Self {
+            window,
+            started: now,
+            samples_ns: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, nanos: u64, now: Duration, metrics: &Metrics) {
+        metrics.observe_encode_time(nanos);
+        self.samples_ns.push(nanos);
+        if now.saturating_sub(self.started) < self.window {
+            return;
+        }
+        self.samples_ns.sort_unstable();
+        let percentile = |p: usize| self.samples_ns[(self.samples_ns.len() * p).div_ceil(100) - 1];
+        let (p50, p99) = (percentile(50), percentile(99));
+        metrics.encode_p50_ns.store(p50, Ordering::Relaxed);
+        metrics.encode_p99_ns.store(p99, Ordering::Relaxed);
+        tracing::info!(
+            "encode time over {} trades: p50 {} ns, p99 {} ns",
+            self.samples_ns.len(),
+            p50,
+            p99
+        );
+        self.samples_ns.clear();
+        self.started = now;
+    }
+}
+
+/// Deadline for the next trade, see `PipelineOptions::idle_timeout`.
+struct IdleTimer {
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl IdleTimer {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+        }
+    }
+
+    fn rearm(&mut self) {
+        self.sleep.as_mut().reset(Instant::now() + self.timeout);
+    }
+}
+
+/// Resolves with the timeout once it passed without a trade, or never when the
+/// idle timeout is off.
+async fn idle_expired(idle: &mut Option<IdleTimer>) -> Duration {
+    match idle {
+        Some(idle) => {
+            idle.sleep.as_mut().await;
+            idle.timeout
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves on the next tick, or never when heartbeats are off.
+async fn next_tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// SHM-based pipeline: writes header and trades into shared memory queue.
+pub async fn handle_trades_shm(
+    assets: Vec<String>,
+    name: String,
+    capacity: u32,
+    block_when_full: Option<Duration>,
+    rx: UnboundedReceiver<TradeMessage>,
+    opts: PipelineOptions,
+) -> Result<(), PipelineError> {
+    tracing::info!(
+        "Setting up SHM queue: name='{}', capacity={} bytes",
+        name,
+        capacity
+    );
+    if opts.passthrough {
+        let queue = Arc::new(ShmQueue::create(&name, capacity)?);
+        queue.push(STREAM_MAGIC)?;
+        let callback = shm_sink(queue, None, block_when_full, &opts.metrics);
+        callback(b"START".to_vec()).await;
+        callback(Vec::new()).await;
+        return forward_raw(rx, opts.idle_timeout, callback).await;
+    }
+    let (encoder, header) = initialize_encoder(assets, &opts.client, opts.clock.as_ref()).await?;
+    check_shm_capacity(capacity, &header)?;
+    let queue = Arc::new(ShmQueue::create(&name, capacity)?);
+    tracing::info!("SHM queue created successfully");
+    queue.push(STREAM_MAGIC)?;
+
+    // Only needed to feed the trade stream; SHM itself has a single consumer
+    let stream_tx = match opts.trade_stream_addr {
+        Some(addr) => {
+            let (tx, _) = broadcast::channel::<Vec<u8>>(100);
+            spawn_trade_stream(addr, Arc::new(RwLock::new(header.clone())), tx.clone()).await?;
+            Some(tx)
+        }
+        None => None,
+    };
+
+    let callback = shm_sink(queue, stream_tx, block_when_full, &opts.metrics);
+    handle_trades(encoder, header, rx, opts, callback).await
+}
+
+/// The magic, START and header all sit in the queue until the consumer attaches,
+/// so with their 4 byte length prefixes they must fit in half of `capacity`,
+/// leaving the rest for the trades that follow. Otherwise a large asset set can
+/// fill the queue with the handshake alone.
+fn check_shm_capacity(capacity: u32, header: &[u8]) -> Result<(), PipelineError> {
+    let needed = [STREAM_MAGIC, b"START", header]
+        .iter()
+        .map(|frame| 4 + frame.len())
+        .sum();
+    if needed > capacity as usize / 2 {
+        return Err(PipelineError::ShmCapacity { capacity, needed });
+    }
+    Ok(())
+}
+
+/// Callback pushing frames into `queue`, and copying them to `stream_tx` if set.
+/// The queue's fill after each push goes to `metrics.peak_queued_bytes`.
+/// A full queue drops the frame and reports it as not delivered, unless
+/// `block_when_full` is set: then the push is retried for up to that long on a
+/// blocking thread, pausing the pipeline but not the runtime.
+fn shm_sink(
+    queue: Arc<ShmQueue>,
+    stream_tx: Option<broadcast::Sender<Vec<u8>>>,
+    block_when_full: Option<Duration>,
+    metrics: &Metrics,
+) -> impl Fn(Vec<u8>) -> BoxFuture<'static, bool> + Send + Sync + 'static {
+    let peak = metrics.peak_queued_bytes.clone();
+    move |data: Vec<u8>| {
+        if let Some(tx) = &stream_tx {
+            let _ = tx.send(data.clone());
+        }
+        let queue = queue.clone();
+        let peak = peak.clone();
+        async move {
+            let pushed = match block_when_full {
+                Some(timeout) => queue.push_async(data, SHM_RETRY_WAIT, timeout).await,
+                None => queue.push(&data),
+            };
+            match pushed {
+                Ok(()) => {
+                    peak.fetch_max(queue.unread_bytes() as u64, Ordering::Relaxed);
+                    true
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "SHM push failed ({} dropped so far): {}",
+                        queue.dropped(),
+                        e
+                    );
+                    false
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Bind the JSON trade stream and serve it in the background.
+async fn spawn_trade_stream(
+    addr: SocketAddr,
+    header: tcp::SharedHeader,
+    tx: broadcast::Sender<Vec<u8>>,
+) -> Result<JoinHandle<()>, PipelineError> {
+    let listener = TcpListener::bind(addr).await?;
+    Ok(tokio::spawn(async move {
+        if let Err(e) = http::serve_trades(listener, header, tx).await {
+            tracing::error!("trade stream failed: {}", e);
+        }
+    }))
+}
+
+/// Run the TCP server and the pipeline feeding it as one. A server error
+/// cancels the pipeline. Once the pipeline ends (its trade feed closed, or an
+/// error) the server stops accepting, and each client is closed after the
+/// frames already queued for it, since `tx` is then the broadcast's last sender.
+/// It returns once those clients are gone, or after `DRAIN_TIMEOUT`, so a caller
+/// exiting on the result doesn't cut their last frames off.
+async fn serve_pipeline(
+    listener: TcpListener,
+    header: tcp::SharedHeader,
+    tx: broadcast::Sender<Vec<u8>>,
+    governor: Arc<MemoryGovernor>,
+    serve_opts: tcp::ServeOptions,
+    pipeline: impl Future<Output = Result<(), PipelineError>>,
+) -> Result<(), PipelineError> {
+    let clients = serve_opts.clients.clone();
+    let server = tcp::serve_listener(listener, header, tx, governor, serve_opts);
+    let res = tokio::select! {
+        res = server => return res.map_err(PipelineError::TcpServer),
+        res = pipeline => res,
+    };
+    tracing::info!("trade pipeline ended, closing TCP clients");
+    let drained = async {
+        while clients.load(Ordering::Relaxed) > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    if tokio::time::timeout(DRAIN_TIMEOUT, drained).await.is_err() {
+        tracing::warn!(
+            "{} TCP client(s) still draining after {:?}",
+            clients.load(Ordering::Relaxed),
+            DRAIN_TIMEOUT
+        );
+    }
+    res
+}
+
+/// How long `serve_pipeline` waits for clients to take their last frames.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TCP-based pipeline: broadcasts START, header, and trades to all connected clients.
+///
+/// With `snapshot_on_connect`, a new client also gets a keyframe of each asset's
+/// latest trade right after the header, see `tcp::Snapshot`. Credit flow
+/// control and framing come from `serve_opts`, whose snapshot is filled in here
+/// and whose client count is `opts.metrics.tcp_clients`.
+///
+/// If the pipeline task panics, the encoder is rebuilt from fresh reference data
+/// and a new START + header goes out before any of its trades, see `run_epochs`.
+/// The server and the pipeline end together, see `serve_pipeline`; failing to
+/// bind `bind_addr` ends it before any reference data is fetched.
+pub async fn handle_trades_tcp(
+    assets: Vec<String>,
+    bind_addr: SocketAddr,
+    budget: MemoryBudget,
+    snapshot_on_connect: bool,
+    mut serve_opts: tcp::ServeOptions,
+    rx: UnboundedReceiver<TradeMessage>,
+    opts: PipelineOptions,
+) -> Result<(), PipelineError> {
+    tracing::info!("Setting up TCP server on {}", bind_addr);
+    let listener = TcpListener::bind(bind_addr)
+        .await
+        .map_err(PipelineError::TcpServer)?;
+    tracing::info!("TCP server listening on {}", bind_addr);
+    let governor = Arc::new(
+        MemoryGovernor::new(budget)
+            .with_peak(opts.metrics.peak_queued_bytes.clone())
+            .with_oversized(opts.metrics.tcp_frames_oversized.clone()),
+    );
+    serve_opts.clients = opts.metrics.tcp_clients.clone();
+    serve_opts.lag_events = opts.metrics.tcp_lag_events.clone();
+    if opts.passthrough {
+        let (tx, _) = broadcast::channel::<Vec<u8>>(100);
+        let tx_clone = tx.clone();
+        let pipeline = forward_raw(rx, opts.idle_timeout, move |data| {
+            // Fails only while no client is connected
+            let _ = tx_clone.send(data);
+            async { true }
+        });
+        let header = Arc::new(RwLock::new(Vec::new()));
+        return serve_pipeline(listener, header, tx, governor, serve_opts, pipeline).await;
+    }
+    let (encoder, header) = initialize_encoder(assets, &opts.client, opts.clock.as_ref()).await?;
+    let snapshot = match snapshot_on_connect {
+        true => Some(Arc::new(Mutex::new(tcp::Snapshot::new(&header)?))),
+        false => None,
+    };
+
+    // `_` drops the initial receiver here and now, so only connected clients
+    // hold the buffer back; with none, a send fails and nothing is kept
+    let (tx, _) = broadcast::channel::<Vec<u8>>(100);
+    let shared_header = Arc::new(RwLock::new(header.clone()));
+    let trade_stream = match opts.trade_stream_addr {
+        Some(addr) => Some(spawn_trade_stream(addr, shared_header.clone(), tx.clone()).await?),
+        None => None,
+    };
+
+    let epochs = Epochs {
+        header: shared_header.clone(),
+        tx: tx.clone(),
+        snapshot: snapshot.clone(),
+    };
+    let reinit = {
+        let client = opts.client.clone();
+        let clock = opts.clock.clone();
+        let control = opts.control.clone();
+        move || {
+            // The panicked epoch's assets, which a reload may have changed
+            let assets = control.header_assets.borrow().clone();
+            let (client, clock) = (client.clone(), clock.clone());
+            async move { initialize_encoder(assets, &client, clock.as_ref()).await }
+        }
+    };
+    let (tx_clone, snapshot_clone) = (tx.clone(), snapshot.clone());
+    let pipeline = run_epochs((encoder, header), rx, opts, epochs, reinit, move |data| {
+        match &snapshot_clone {
+            Some(snapshot) => tcp::publish_frame(snapshot, data, &tx_clone),
+            // Fails only while no client is connected
+            None => {
+                let _ = tx_clone.send(data);
+            }
+        }
+        async { true }
+    });
+
+    tracing::info!("Starting TCP server");
+    serve_opts.snapshot = snapshot;
+    let res = serve_pipeline(listener, shared_header, tx, governor, serve_opts, pipeline).await;
+    // Its clients hold the broadcast open too
+    if let Some(trade_stream) = trade_stream {
+        trade_stream.abort();
+    }
+    res
+}
+
+/// Where a new encoder epoch's header is published: the header served to new
+/// clients, and the broadcast reaching connected ones.
+struct Epochs {
+    header: tcp::SharedHeader,
+    tx: broadcast::Sender<Vec<u8>>,
+    /// Reset to each new header, when snapshots are on
+    snapshot: Option<tcp::SharedSnapshot>,
+}
+
+/// Run the pipeline one encoder epoch after another until `rx` closes.
+///
+/// Each epoch publishes its START + header (`tcp::publish_header`) before any of
+/// its trades, so connected clients reset their decoder and new clients get the
+/// header matching the running encoder. An asset reload ends the epoch with the
+/// next encoder ready. A panicking pipeline ends it too; `reinit` builds the next
+/// encoder, retried every second while it fails. A pipeline error is not
+/// retried, since a new encoder would hit it again.
+async fn run_epochs<R, RFut, F, Fut>(
+    first: (BinaryFormat, Vec<u8>),
+    mut rx: UnboundedReceiver<TradeMessage>,
+    opts: PipelineOptions,
+    epochs: Epochs,
+    reinit: R,
+    callback: F,
+) -> Result<(), PipelineError>
+where
+    R: Fn() -> RFut,
+    RFut: std::future::Future<Output = Result<(BinaryFormat, Vec<u8>), PipelineError>>,
+    F: Fn(Vec<u8>) -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    let mut next = Some(first);
+    loop {
+        let (encoder, header) = match next.take() {
+            Some(epoch) => epoch,
+            None => match reinit().await {
+                Ok(epoch) => epoch,
+                Err(e) => {
+                    tracing::error!("encoder reinitialization failed, retrying: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+        };
+        tcp::publish_header(
+            &epochs.header,
+            header.clone(),
+            &epochs.tx,
+            epochs.snapshot.as_ref(),
+        );
+        log_handshake(&opts, &header);
+        opts.control.header_published(encoder.symbols());
+        tracing::info!("Header published, waiting for trades");
+
+        let run = forward_trades(encoder, &mut rx, &opts, &callback);
+        match AssertUnwindSafe(run).catch_unwind().await {
+            Ok(Ok(EpochEnd::Closed)) => return Ok(()),
+            Ok(Ok(EpochEnd::Reload(encoder, header))) => next = Some((*encoder, header)),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => tracing::error!("pipeline panicked, reinitializing the encoder"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{self, MockClock};
+    use crate::format::{Record, TimestampUnit};
+    use crate::metrics::FRAME_SIZE_BUCKETS;
+    use std::io::Cursor;
+    use std::sync::Mutex;
+
+    fn trade_message(asset: &str, timestamp: u64, price: &str, received_at: u128) -> TradeMessage {
+        TradeMessage {
+            timestamp,
+            asset: asset.to_string(),
+            price: price.to_string(),
+            quantity: "1.5".to_string(),
+            is_buyer_maker: false,
+            received_at,
+            shard: None,
+            raw: None,
+        }
+    }
+
+    /// In-memory transport for `handle_trades`: keeps every frame handed to the
+    /// sink, START and header included, so tests can run the pipeline without IO.
+    #[derive(Clone, Default)]
+    struct MemorySink {
+        frames: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl MemorySink {
+        fn callback(&self) -> impl Fn(Vec<u8>) -> std::future::Ready<bool> + Send + Sync + 'static {
+            let frames = self.frames.clone();
+            move |data| {
+                frames.lock().unwrap().push(data);
+                std::future::ready(true)
+            }
+        }
+
+        fn frames(&self) -> Vec<Vec<u8>> {
+            self.frames.lock().unwrap().clone()
+        }
+
+        async fn wait_for(&self, count: usize) {
+            for _ in 0..200 {
+                if self.frames.lock().unwrap().len() >= count {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            panic!("timed out waiting for {} frames", count);
+        }
+
+        /// Check the START + header handshake and decode every record after it.
+        fn records(&self) -> Vec<Record> {
+            let frames = self.frames();
+            assert_eq!(frames[0], b"START");
+            let mut decoder = BinaryFormat::new();
+            decoder.read_header(&mut Cursor::new(&frames[1])).unwrap();
+            frames[2..]
+                .iter()
+                .map(|f| decoder.read_record(&mut Cursor::new(f)).unwrap())
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_end_to_end_in_memory() {
+        let assets = vec![
+            "BTCUSDT".to_string(),
+            "ETHUSDT".to_string(),
+            "SOLUSDT".to_string(),
+        ];
+        let mut encoder = BinaryFormat::new().with_assets(assets.clone()).unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(
+                &mut header,
+                1_700_000_000_000,
+                &[45000.0, 2500.0, 120.0],
+                &[1.0, 10.0, 100.0],
+            )
+            .unwrap();
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let now = clock::unix_now().as_micros();
+        let message = |i: u64| {
+            let idx = (i % 3) as usize;
+            let price = [45000.0, 2500.0, 120.0][idx] + (i as f64 * 1.37) % 50.0 - 25.0;
+            let mut msg = trade_message(
+                &assets[idx],
+                1_700_000_000_000 + i * 7,
+                &format!("{:.2}", price),
+                now,
+            );
+            msg.quantity = format!("{:.3}", 0.001 + (i % 11) as f64 * 0.25);
+            msg.is_buyer_maker = i % 4 == 1;
+            msg
+        };
+        for i in 0..60 {
+            tx.send(message(i)).unwrap();
+        }
+        drop(tx);
+
+        let sink = MemorySink::default();
+        let opts = PipelineOptions::default();
+        let metrics = opts.metrics.clone();
+        handle_trades(encoder, header.clone(), rx, opts, sink.callback())
+            .await
+            .unwrap();
+
+        assert_eq!(sink.frames()[1], header);
+        let records = sink.records();
+        assert_eq!(records.len(), 60);
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&header)).unwrap();
+        // One step, plus f64 noise in the step itself
+        let tolerance = decoder.price_resolution() * (1.0 + 1e-6);
+        for (i, record) in records.into_iter().enumerate() {
+            let Record::Trade(trade) = record else {
+                panic!("expected trade, got {:?}", record);
+            };
+            let expected = message(i as u64).to_trade().unwrap();
+            assert_eq!(trade.symbol, expected.symbol);
+            assert_eq!(trade.timestamp, expected.timestamp);
+            assert_eq!(trade.is_buyer_maker, expected.is_buyer_maker);
+            assert!(
+                (trade.price - expected.price).abs() <= tolerance,
+                "{:?}",
+                trade
+            );
+            assert!(
+                (trade.quantity - expected.quantity).abs() <= tolerance,
+                "{:?}",
+                trade
+            );
+        }
+        assert_eq!(metrics.trades_forwarded.load(Ordering::Relaxed), 60);
+    }
+
+    #[tokio::test]
+    async fn test_stale_trade_dropped_with_keyframe() {
+        let assets = vec!["BTCUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets.clone()).unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.0])
+            .unwrap();
+
+        let now = clock::unix_now().as_micros();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tx.send(trade_message("BTCUSDT", 1_700_000_001_000, "45001", now))
+            .unwrap();
+        tx.send(trade_message(
+            "BTCUSDT",
+            1_700_000_002_000,
+            "45002",
+            now - 10_000_000,
+        ))
+        .unwrap();
+        tx.send(trade_message("BTCUSDT", 1_700_000_003_000, "45003", now))
+            .unwrap();
+        drop(tx);
+
+        let sink = MemorySink::default();
+        let opts = PipelineOptions {
+            latency_budget: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let metrics = opts.metrics.clone();
+        handle_trades(encoder, header, rx, opts, sink.callback())
+            .await
+            .unwrap();
+
+        // First trade, then a keyframe for the trade after the stale one
+        let records = sink.records();
+        assert_eq!(records.len(), 2);
+        match &records[0] {
+            Record::Trade(t) => assert_eq!(t.timestamp, 1_700_000_001_000),
+            other => panic!("expected trade, got {:?}", other),
+        }
+        match &records[1] {
+            Record::Keyframe(t) => {
+                assert_eq!(t.timestamp, 1_700_000_003_000);
+                assert_eq!(t.price, 45003.0);
+            }
+            other => panic!("expected keyframe, got {:?}", other),
+        }
+
+        assert_eq!(
+            metrics
+                .trades_dropped_stale
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+        assert_eq!(
+            metrics
+                .keyframes_emitted
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+        assert_eq!(
+            metrics
+                .trades_forwarded
+                .load(std::sync::atomic::Ordering::Relaxed),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_keyframe_leaves_the_resync_pending() {
+        let assets = vec!["BTCUSDT".to_string()];
+        let mut encoder = BinaryFormat::new()
+            .with_fixed_width_records(true)
+            .with_assets(assets)
+            .unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.0])
+            .unwrap();
+
+        let now = clock::unix_now().as_micros();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        for (timestamp, price, received_at) in [
+            (1_700_000_001_000, "45001", now - 10_000_000),
+            // Past the fixed-width range: the keyframe for it fails to encode
+            (1_700_000_002_000, "1e20", now),
+            (1_700_000_003_000, "45003", now),
+        ] {
+            tx.send(trade_message("BTCUSDT", timestamp, price, received_at))
+                .unwrap();
+        }
+        drop(tx);
+
+        let sink = MemorySink::default();
+        let opts = PipelineOptions {
+            latency_budget: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let metrics = opts.metrics.clone();
+        handle_trades(encoder, header, rx, opts, sink.callback())
+            .await
+            .unwrap();
+
+        let records = sink.records();
+        assert_eq!(records.len(), 1);
+        assert!(matches!(&records[0], Record::Keyframe(t) if t.price == 45003.0));
+        assert_eq!(metrics.keyframes_emitted.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_gap_record_precedes_resync_keyframe() {
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(
+                &mut header,
+                1_700_000_000_000,
+                &[45000.0, 2500.0],
+                &[1.0, 1.0],
+            )
+            .unwrap();
+
+        let now = clock::unix_now().as_micros();
+        let stale = now - 10_000_000;
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        for (asset, timestamp, price, received_at) in [
+            ("BTCUSDT", 1_700_000_001_000, "45001", now),
+            ("BTCUSDT", 1_700_000_002_000, "45002", stale),
+            ("ETHUSDT", 1_700_000_002_500, "2501", stale),
+            ("BTCUSDT", 1_700_000_003_000, "45003", stale),
+            ("ETHUSDT", 1_700_000_004_000, "2502", now),
+            ("BTCUSDT", 1_700_000_005_000, "45005", now),
+        ] {
+            tx.send(trade_message(asset, timestamp, price, received_at))
+                .unwrap();
+        }
+        drop(tx);
+
+        let sink = MemorySink::default();
+        let opts = PipelineOptions {
+            latency_budget: Some(Duration::from_secs(1)),
+            gap_records: true,
+            ..Default::default()
+        };
+        let metrics = opts.metrics.clone();
+        handle_trades(encoder, header, rx, opts, sink.callback())
+            .await
+            .unwrap();
+
+        let records = sink.records();
+        assert_eq!(records.len(), 5);
+        assert!(matches!(&records[0], Record::Trade(t) if t.symbol == "BTCUSDT"));
+        for (gap, keyframe, symbol, count) in [(1, 2, "ETHUSDT", 1), (3, 4, "BTCUSDT", 2)] {
+            match (&records[gap], &records[keyframe]) {
+                (
+                    Record::Gap {
+                        symbol: gapped,
+                        dropped,
+                    },
+                    Record::Keyframe(t),
+                ) => {
+                    assert_eq!((gapped.as_str(), *dropped), (symbol, count));
+                    assert_eq!(t.symbol, symbol);
+                }
+                other => panic!("expected a gap then a keyframe, got {:?}", other),
+            }
+        }
+        assert_eq!(metrics.gaps_emitted.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stale_drop_with_mock_clock() {
+        let assets = vec!["BTCUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.0])
+            .unwrap();
+
+        let start = Duration::from_millis(1_700_000_001_000);
+        let mock = Arc::new(MockClock::new(start));
+        let sink = MemorySink::default();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let opts = PipelineOptions {
+            latency_budget: Some(Duration::from_millis(5)),
+            clock: mock.clone(),
+            ..Default::default()
+        };
+        let metrics = opts.metrics.clone();
+        let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+        // 4ms old: within budget
+        let received = (start - Duration::from_millis(4)).as_micros();
+        tx.send(trade_message(
+            "BTCUSDT",
+            1_700_000_001_000,
+            "45001",
+            received,
+        ))
+        .unwrap();
+        sink.wait_for(3).await;
+
+        // Same trade age plus 2ms of clock: over budget
+        mock.advance(Duration::from_millis(2));
+        tx.send(trade_message(
+            "BTCUSDT",
+            1_700_000_001_001,
+            "45002",
+            received,
+        ))
+        .unwrap();
+        tx.send(trade_message(
+            "BTCUSDT",
+            1_700_000_001_002,
+            "45003",
+            mock.now().as_micros(),
+        ))
+        .unwrap();
+        drop(tx);
+        handle.await.unwrap().unwrap();
+
+        let records = sink.records();
+        assert_eq!(records.len(), 2);
+        match &records[1] {
+            Record::Keyframe(t) => assert_eq!(t.timestamp, 1_700_000_001_002),
+            other => panic!("expected keyframe, got {:?}", other),
+        }
+        assert_eq!(
+            metrics
+                .trades_dropped_stale
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_latency_slo_alert() {
+        let mut encoder = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.0])
+            .unwrap();
+
+        let start = Duration::from_millis(1_700_000_001_000);
+        let mock = Arc::new(MockClock::new(start));
+        let sink = MemorySink::default();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let opts = PipelineOptions {
+            latency_slo: Some(LatencySlo {
+                threshold: Duration::from_micros(500),
+                window: Duration::from_secs(1),
+            }),
+            clock: mock.clone(),
+            ..Default::default()
+        };
+        let metrics = opts.metrics.clone();
+        let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+        let send = |age_us: u64| {
+            let received = (mock.now() - Duration::from_micros(age_us)).as_micros();
+            tx.send(trade_message(
+                "BTCUSDT",
+                1_700_000_001_000,
+                "45001",
+                received,
+            ))
+            .unwrap();
+        };
+
+        // One slow trade in a hundred is within p99
+        for _ in 0..98 {
+            send(100);
+        }
+        send(2000);
+        sink.wait_for(2 + 99).await;
+        mock.advance(Duration::from_secs(1));
+        send(100);
+        sink.wait_for(2 + 100).await;
+        assert_eq!(metrics.latency_slo_breaches.load(Ordering::Relaxed), 0);
+        assert_eq!(metrics.latency_p99_us.load(Ordering::Relaxed), 100);
+
+        // A whole window over the SLO fires the alert once, when it closes
+        for _ in 0..99 {
+            send(2000);
+        }
+        sink.wait_for(2 + 199).await;
+        assert_eq!(metrics.latency_slo_breaches.load(Ordering::Relaxed), 0);
+        mock.advance(Duration::from_secs(1));
+        send(2000);
+        sink.wait_for(2 + 200).await;
+        assert_eq!(metrics.latency_slo_breaches.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.latency_p99_us.load(Ordering::Relaxed), 2000);
+        assert!(
+            metrics
+                .render()
+                .contains("perp_signal_hft_latency_slo_breaches_total 1")
+        );
+
+        drop(tx);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shard_trade_counts() {
+        let mut encoder = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()])
+            .unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(
+                &mut header,
+                1_700_000_000_000,
+                &[45000.0, 3000.0],
+                &[1.0, 1.0],
+            )
+            .unwrap();
+        let sink = MemorySink::default();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let opts = PipelineOptions::default();
+        let metrics = opts.metrics.clone();
+        let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+        let now = clock::unix_now().as_micros();
+        for (i, shard) in [0, 1, 0, 0, 1].into_iter().enumerate() {
+            let asset = ["BTCUSDT", "ETHUSDT"][shard as usize];
+            let mut msg = trade_message(asset, 1_700_000_000_000 + i as u64, "3001", now);
+            msg.shard = Some(shard);
+            tx.send(msg).unwrap();
+        }
+        // Shard 1 goes silent
+        for i in 0..3 {
+            let mut msg = trade_message("BTCUSDT", 1_700_000_000_010 + i, "45001", now);
+            msg.shard = Some(0);
+            tx.send(msg).unwrap();
+        }
+        drop(tx);
+        handle.await.unwrap().unwrap();
+
+        assert_eq!(metrics.shard_trades(0), 6);
+        assert_eq!(metrics.shard_trades(1), 2);
+        assert_eq!(metrics.shard_trades(2), 0);
+        let rendered = metrics.render();
+        assert!(rendered.contains("perp_signal_hft_shard_trades_total{shard=\"0\"} 6"));
+        assert!(rendered.contains("perp_signal_hft_shard_trades_total{shard=\"1\"} 2"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_symbol_lenient_and_strict() {
+        for strict_symbols in [false, true] {
+            let mut encoder = BinaryFormat::new()
+                .with_assets(vec!["BTCUSDT".to_string()])
+                .unwrap();
+            let mut header = Vec::new();
+            encoder
+                .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.0])
+                .unwrap();
+            let sink = MemorySink::default();
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            let opts = PipelineOptions {
+                strict_symbols,
+                ..Default::default()
+            };
+            let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+            let now = clock::unix_now().as_micros();
+            for (i, asset) in ["BTCUSDT", "DOGEUSDT", "BTCUSDT"].into_iter().enumerate() {
+                tx.send(trade_message(
+                    asset,
+                    1_700_000_000_000 + i as u64,
+                    "45001",
+                    now,
+                ))
+                .unwrap();
+            }
+            drop(tx);
+            let res = handle.await.unwrap();
+            let trades = sink.records().len();
+            if strict_symbols {
+                // Stopped at the unsubscribed trade
+                assert!(matches!(res, Err(PipelineError::UnknownSymbol(s)) if s == "DOGEUSDT"));
+                assert_eq!(trades, 1);
+            } else {
+                // Skipped it and carried on
+                res.unwrap();
+                assert_eq!(trades, 2);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_size_histogram() {
+        let mut encoder = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.5])
+            .unwrap();
+        let sink = MemorySink::default();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let opts = PipelineOptions::default();
+        let metrics = opts.metrics.clone();
+        let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+        // Unchanged price and quantity, then small and large price moves
+        let now = clock::unix_now().as_micros();
+        for (i, price) in ["45000", "45000.01", "47250.123456"]
+            .into_iter()
+            .enumerate()
+        {
+            tx.send(trade_message(
+                "BTCUSDT",
+                1_700_000_000_001 + i as u64,
+                price,
+                now,
+            ))
+            .unwrap();
+        }
+        drop(tx);
+        handle.await.unwrap().unwrap();
+
+        let sizes: Vec<usize> = sink.frames()[2..].iter().map(Vec::len).collect();
+        assert_eq!(sizes.len(), 3);
+        assert!(sizes[0] < sizes[2]);
+        assert_eq!(
+            metrics.frame_size_totals(),
+            (3, sizes.iter().sum::<usize>() as u64)
+        );
+        let rendered = metrics.render();
+        let at_most = |bound: usize| sizes.iter().filter(|&&size| size <= bound).count();
+        for bound in FRAME_SIZE_BUCKETS {
+            let line = format!(
+                "perp_signal_hft_trade_frame_bytes_bucket{{le=\"{}\"}} {}",
+                bound,
+                at_most(bound as usize)
+            );
+            assert!(rendered.contains(&line), "missing {}", line);
+        }
+        assert!(rendered.contains("perp_signal_hft_trade_frame_bytes_bucket{le=\"+Inf\"} 3"));
+    }
+
+    #[tokio::test]
+    async fn test_encode_timing_histogram() {
+        async fn run(encode_timing: Option<Duration>) -> Arc<Metrics> {
+            let mut encoder = BinaryFormat::new()
+                .with_assets(vec!["BTCUSDT".to_string()])
+                .unwrap();
+            let mut header = Vec::new();
+            encoder
+                .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.0])
+                .unwrap();
+            let mock = Arc::new(MockClock::new(Duration::from_millis(1_700_000_001_000)));
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            let opts = PipelineOptions {
+                encode_timing,
+                clock: mock.clone(),
+                ..Default::default()
+            };
+            let metrics = opts.metrics.clone();
+            let sink = MemorySink::default();
+            let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+            for i in 0..10 {
+                let received = mock.now().as_micros();
+                tx.send(trade_message(
+                    "BTCUSDT",
+                    1_700_000_001_000 + i,
+                    "45001",
+                    received,
+                ))
+                .unwrap();
+            }
+            sink.wait_for(2 + 10).await;
+            // The window closes with the next trade
+            mock.advance(Duration::from_secs(1));
+            tx.send(trade_message("BTCUSDT", 1_700_000_002_000, "45002", 0))
+                .unwrap();
+            drop(tx);
+            handle.await.unwrap().unwrap();
+            metrics
+        }
+
+        let metrics = run(None).await;
+        assert_eq!(metrics.encode_time_totals().0, 0);
+        assert!(!metrics.render().contains("perp_signal_hft_encode_ns"));
+
+        let metrics = run(Some(Duration::from_secs(1))).await;
+        assert_eq!(metrics.encode_time_totals().0, 11);
+        let p50 = metrics.encode_p50_ns.load(Ordering::Relaxed);
+        assert!(p50 <= metrics.encode_p99_ns.load(Ordering::Relaxed));
+        let rendered = metrics.render();
+        assert!(rendered.contains("perp_signal_hft_encode_ns_bucket{le=\"+Inf\"} 11"));
+        assert!(rendered.contains("perp_signal_hft_encode_ns_count 11"));
+    }
+
+    #[tokio::test]
+    async fn test_gap_keyframe() {
+        for gap_keyframe in [None, Some(0.1)] {
+            let mut encoder = BinaryFormat::new()
+                .with_assets(vec!["BTCUSDT".to_string()])
+                .unwrap();
+            let mut header = Vec::new();
+            encoder
+                .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.5])
+                .unwrap();
+            let sink = MemorySink::default();
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            let opts = PipelineOptions {
+                gap_keyframe,
+                ..Default::default()
+            };
+            let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+            // A 50% jump, then a small move from the new level
+            let now = clock::unix_now().as_micros();
+            for (i, price) in ["45000", "45010", "67515", "67516"].into_iter().enumerate() {
+                tx.send(trade_message(
+                    "BTCUSDT",
+                    1_700_000_000_001 + i as u64,
+                    price,
+                    now,
+                ))
+                .unwrap();
+            }
+            drop(tx);
+            handle.await.unwrap().unwrap();
+
+            let records = sink.records();
+            assert!(matches!(records[1], Record::Trade(_)));
+            assert!(matches!(records[3], Record::Trade(_)));
+            match (&records[2], gap_keyframe) {
+                (Record::Keyframe(t), Some(_)) => assert_eq!(t.price, 67515.0),
+                (Record::Trade(t), None) => assert_eq!(t.price, 67515.0),
+                (other, _) => panic!("unexpected {:?} with gap {:?}", other, gap_keyframe),
+            }
+            // Deltas after the keyframe are taken from the new price
+            assert_eq!(records[3].clone().into_trade().unwrap().price, 67516.0);
+            let frames = sink.frames();
+            assert!(frames[5].len() <= frames[3].len());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_per_asset() {
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(
+                &mut header,
+                1_700_000_000_000,
+                &[45000.0, 3000.0],
+                &[1.0, 1.0],
+            )
+            .unwrap();
+
+        let mock = Arc::new(MockClock::new(Duration::from_millis(1_700_000_001_000)));
+        let sink = MemorySink::default();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let opts = PipelineOptions {
+            max_rate_per_asset: Some(5.0),
+            clock: mock.clone(),
+            ..Default::default()
+        };
+        let metrics = opts.metrics.clone();
+        let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+        // A burst of 20 BTC trades within the same instant: only 5 get through,
+        // and ETH has its own budget
+        for i in 0..20 {
+            let price = format!("{}", 45000 + i);
+            tx.send(trade_message("BTCUSDT", 1_700_000_001_000 + i, &price, 0))
+                .unwrap();
+        }
+        tx.send(trade_message("ETHUSDT", 1_700_000_001_000, "3001", 0))
+            .unwrap();
+        sink.wait_for(2 + 5 + 1).await;
+
+        // A second later the bucket has refilled; the chain resumes with a keyframe
+        mock.advance(Duration::from_secs(1));
+        tx.send(trade_message("BTCUSDT", 1_700_000_002_000, "45100", 0))
+            .unwrap();
+        drop(tx);
+        handle.await.unwrap().unwrap();
+
+        let records = sink.records();
+        assert_eq!(records.len(), 5 + 1 + 1);
+        let forwarded: Vec<u64> = records[..5]
+            .iter()
+            .map(|r| match r {
+                Record::Trade(t) => t.timestamp,
+                other => panic!("expected trade, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            forwarded,
+            (0..5).map(|i| 1_700_000_001_000 + i).collect::<Vec<_>>()
+        );
+        assert!(matches!(&records[5], Record::Trade(t) if t.symbol == "ETHUSDT"));
+        match &records[6] {
+            Record::Keyframe(t) => assert_eq!((t.timestamp, t.price), (1_700_000_002_000, 45100.0)),
+            other => panic!("expected keyframe, got {:?}", other),
+        }
+        assert_eq!(
+            metrics
+                .trades_dropped_rate
+                .load(std::sync::atomic::Ordering::Relaxed),
+            15
+        );
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_during_idle() {
+        let assets = vec!["BTCUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.0])
+            .unwrap();
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let sink = MemorySink::default();
+        let opts = PipelineOptions {
+            heartbeat_interval: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let metrics = opts.metrics.clone();
+        let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+        // Quiet market: nothing but the sender kept alive
+        tokio::time::sleep(Duration::from_millis(110)).await;
+        drop(tx);
+        handle.await.unwrap().unwrap();
+
+        let records = sink.records();
+        let heartbeats = records
+            .iter()
+            .filter(|r| matches!(r, Record::Heartbeat(_)))
+            .count();
+        assert_eq!(heartbeats, records.len());
+        assert!(heartbeats >= 2, "only {} heartbeats", heartbeats);
+        assert_eq!(
+            metrics
+                .heartbeats_emitted
+                .load(std::sync::atomic::Ordering::Relaxed),
+            heartbeats as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_use_the_stream_timestamp_unit() {
+        let assets = vec!["BTCUSDT".to_string()];
+        let mut encoder = BinaryFormat::new()
+            .with_timestamp_unit(TimestampUnit::Micros)
+            .with_assets(assets)
+            .unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1_700_000_000_000_000, &[45000.0], &[1.0])
+            .unwrap();
+
+        let mock = Arc::new(MockClock::new(Duration::from_micros(1_700_000_000_250_500)));
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let sink = MemorySink::default();
+        let opts = PipelineOptions {
+            heartbeat_interval: Some(Duration::from_millis(20)),
+            clock: mock.clone(),
+            ..Default::default()
+        };
+        let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(tx);
+        handle.await.unwrap().unwrap();
+
+        let records = sink.records();
+        assert!(!records.is_empty());
+        for record in records {
+            assert!(matches!(record, Record::Heartbeat(1_700_000_000_250_500)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_ends_a_silent_feed() {
+        let assets = vec!["BTCUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.0])
+            .unwrap();
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let sink = MemorySink::default();
+        let idle_timeout = Duration::from_millis(150);
+        let opts = PipelineOptions {
+            idle_timeout: Some(idle_timeout),
+            // Heartbeats go out meanwhile without counting as trades
+            heartbeat_interval: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+        // Trades keep it going past the timeout, then the feed stops without closing
+        let started = Instant::now();
+        for i in 0..3 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            tx.send(trade_message("BTCUSDT", 1_700_000_000_001 + i, "45001", 0))
+                .unwrap();
+        }
+        let last_trade = Instant::now();
+        let res = handle.await.unwrap();
+        assert!(matches!(res, Err(PipelineError::Idle(timeout)) if timeout == idle_timeout));
+        assert!(started.elapsed() > idle_timeout * 2);
+        assert!(last_trade.elapsed() >= idle_timeout);
+
+        // Every trade reached the sink before it ended
+        let records = sink.records();
+        let trades = records
+            .iter()
+            .filter(|r| matches!(r, Record::Trade(_)))
+            .count();
+        assert_eq!(trades, 3);
+        assert!(records.len() > trades, "no heartbeats in {:?}", records);
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn test_max_trades_ends_with_a_summary() {
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(
+                &mut header,
+                1_700_000_000_000,
+                &[45000.0, 2500.0],
+                &[1.0, 1.0],
+            )
+            .unwrap();
+
+        let now = Duration::from_millis(1_700_000_001_000);
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let sink = MemorySink::default();
+        let opts = PipelineOptions {
+            max_trades: Some(5),
+            clock: Arc::new(MockClock::new(now)),
+            ..Default::default()
+        };
+        let metrics = opts.metrics.clone();
+        let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+        // The feed stays open: the pipeline ends on the count alone
+        for i in 0..8u64 {
+            let (asset, price) = if i % 3 == 0 {
+                ("ETHUSDT", "2500.5")
+            } else {
+                ("BTCUSDT", "45001")
+            };
+            let received_at = now.as_micros() - 100 * (i as u128 + 1);
+            tx.send(trade_message(
+                asset,
+                1_700_000_000_001 + i,
+                price,
+                received_at,
+            ))
+            .unwrap();
+        }
+        handle.await.unwrap().unwrap();
+
+        let summary = metrics.summary();
+        assert_eq!(summary.trades_forwarded, 5);
+        assert_eq!(summary.asset_trades["BTCUSDT"], 3);
+        assert_eq!(summary.asset_trades["ETHUSDT"], 2);
+        let frames = sink.frames();
+        assert_eq!(frames.len(), 2 + 5);
+        let record_bytes: usize = frames[2..].iter().map(Vec::len).sum();
+        assert_eq!(summary.frame_bytes, record_bytes as u64);
+        let latency = summary.latency.unwrap();
+        assert_eq!((latency.min, latency.max), (100, 500));
+        assert!(latency.min <= latency.p50 && latency.p50 <= latency.p99);
+        assert!(latency.p99 <= latency.max);
+        assert!(summary.to_string().contains("BTCUSDT"));
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume() {
+        for policy in [PausePolicy::Drop, PausePolicy::Buffer] {
+            let mut encoder = BinaryFormat::new()
+                .with_assets(vec!["BTCUSDT".to_string()])
+                .unwrap();
+            let mut header = Vec::new();
+            encoder
+                .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.0])
+                .unwrap();
+
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            let sink = MemorySink::default();
+            let opts = PipelineOptions {
+                pause_policy: policy,
+                ..Default::default()
+            };
+            let control = opts.control.clone();
+            let metrics = opts.metrics.clone();
+            let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+            let now = clock::unix_now().as_micros();
+            tx.send(trade_message("BTCUSDT", 1_700_000_001_000, "45001", now))
+                .unwrap();
+            sink.wait_for(3).await;
+
+            control.pause();
+            tx.send(trade_message("BTCUSDT", 1_700_000_002_000, "45002", now))
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            assert_eq!(sink.frames().len(), 3, "{:?}", policy);
+
+            control.resume();
+            tx.send(trade_message("BTCUSDT", 1_700_000_003_000, "45003", now))
+                .unwrap();
+            drop(tx);
+            handle.await.unwrap().unwrap();
+
+            let records = sink.records();
+            let dropped = metrics
+                .trades_dropped_paused
+                .load(std::sync::atomic::Ordering::Relaxed);
+            match policy {
+                PausePolicy::Drop => {
+                    assert_eq!(dropped, 1);
+                    assert_eq!(records.len(), 2);
+                    assert!(matches!(&records[1], Record::Keyframe(t) if t.price == 45003.0));
+                }
+                PausePolicy::Buffer => {
+                    assert_eq!(dropped, 0);
+                    assert_eq!(records.len(), 3);
+                    assert!(records.iter().all(|r| matches!(r, Record::Trade(_))));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mute_and_unmute_asset() {
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let mut encoder = BinaryFormat::new().with_assets(assets).unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(
+                &mut header,
+                1_700_000_000_000,
+                &[45000.0, 2500.0],
+                &[1.0, 1.0],
+            )
+            .unwrap();
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let sink = MemorySink::default();
+        let opts = PipelineOptions::default();
+        let control = opts.control.clone();
+        let metrics = opts.metrics.clone();
+        let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+        let now = clock::unix_now().as_micros();
+        assert!(control.mute("ETHUSDT"));
+        tx.send(trade_message("ETHUSDT", 1_700_000_001_000, "2501", now))
+            .unwrap();
+        tx.send(trade_message("BTCUSDT", 1_700_000_001_001, "45001", now))
+            .unwrap();
+        sink.wait_for(3).await;
+
+        assert!(control.unmute("ETHUSDT"));
+        tx.send(trade_message("ETHUSDT", 1_700_000_002_000, "2502", now))
+            .unwrap();
+        tx.send(trade_message("ETHUSDT", 1_700_000_003_000, "2503", now))
+            .unwrap();
+        drop(tx);
+        handle.await.unwrap().unwrap();
+
+        let records = sink.records();
+        assert_eq!(records.len(), 3);
+        assert!(matches!(&records[0], Record::Trade(t) if t.symbol == "BTCUSDT"));
+        assert!(matches!(&records[1], Record::Keyframe(t) if t.price == 2502.0));
+        assert!(matches!(&records[2], Record::Trade(t) if t.symbol == "ETHUSDT"));
+        assert_eq!(
+            metrics
+                .trades_dropped_muted
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_passthrough_forwards_raw_json() {
+        use crate::binance::{BinanceWebsocket, BinanceWebsocketConfig};
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        // Field order and spacing Binance wouldn't produce, so re-serializing shows
+        let payloads = [
+            r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1700000000100,"T":1700000000099,"s":"BTCUSDT","t":5001,"p":"45000.10","q":"0.250","X":"MARKET","m":true}}"#,
+            r#"{ "data": {"m":false, "q":"3.000","p":"2500.50","s":"ETHUSDT","T":1700000000198,"E":1700000000200,"e":"aggTrade","a":77,"f":100,"l":105}, "stream": "ethusdt@aggTrade" }"#,
+        ];
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+            for payload in payloads {
+                ws.send(Message::Text(payload.to_string())).await.unwrap();
+            }
+            ws.close(None).await.unwrap();
+        });
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let config = BinanceWebsocketConfig {
+            max_reconnects: 1,
+            url: Some(format!("ws://{}/stream", addr)),
+            passthrough: true,
+            ..Default::default()
+        };
+        // Ends, closing `tx`, once the reconnect after the close fails
+        tokio::spawn(async move {
+            let _ = BinanceWebsocket::start_with(tx, ["BTCUSDT", "ETHUSDT"], &config).await;
+        });
+
+        // No reference prices are fetched: the default client would need the network
+        let name = format!("perp_signal_hft_test_passthrough_{}", std::process::id());
+        let opts = PipelineOptions {
+            passthrough: true,
+            ..Default::default()
+        };
+        handle_trades_shm(vec![], name.clone(), 4096, None, rx, opts)
+            .await
+            .unwrap();
+
+        let queue = ShmQueue::create(&name, 4096).unwrap();
+        let frames: Vec<Vec<u8>> = std::iter::from_fn(|| queue.pop().unwrap()).collect();
+        assert_eq!(frames[0], STREAM_MAGIC);
+        assert_eq!(frames[1], b"START");
+        assert!(frames[2].is_empty());
+        assert_eq!(frames[3..], payloads.map(|p| p.as_bytes().to_vec()));
+
+        std::fs::remove_file(format!("/dev/shm/{}", name)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shm_drop_resyncs_with_keyframe() {
+        let name = format!("perp_signal_hft_test_pipeline_drop_{}", std::process::id());
+        // Room for the handshake and a handful of trades
+        let queue = Arc::new(ShmQueue::create(&name, 128).unwrap());
+        let mut encoder = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.0])
+            .unwrap();
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let opts = PipelineOptions::default();
+        let metrics = opts.metrics.clone();
+        let handle = tokio::spawn(handle_trades(
+            encoder,
+            header,
+            rx,
+            opts,
+            shm_sink(queue.clone(), None, None, &Metrics::new()),
+        ));
+
+        let now = clock::unix_now().as_micros();
+        let price = |i: u64| format!("{}", 45000.0 + i as f64 * 1.25);
+        for i in 0..20 {
+            tx.send(trade_message(
+                "BTCUSDT",
+                1_700_000_000_000 + i,
+                &price(i),
+                now,
+            ))
+            .unwrap();
+        }
+        let handled = || {
+            metrics.trades_forwarded.load(Ordering::Relaxed)
+                + metrics.trades_dropped_sink.load(Ordering::Relaxed)
+        };
+        for _ in 0..200 {
+            if handled() == 20 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(handled(), 20);
+        assert!(queue.dropped() > 0);
+        assert_eq!(
+            queue.dropped(),
+            metrics.trades_dropped_sink.load(Ordering::Relaxed)
+        );
+
+        // Consumer catches up, then one more trade arrives
+        let mut frames = Vec::new();
+        while let Some(frame) = queue.pop().unwrap() {
+            frames.push(frame);
+        }
+        tx.send(trade_message("BTCUSDT", 1_700_000_000_020, &price(20), now))
+            .unwrap();
+        drop(tx);
+        handle.await.unwrap().unwrap();
+        while let Some(frame) = queue.pop().unwrap() {
+            frames.push(frame);
+        }
+
+        assert_eq!(frames[0], b"START");
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&frames[1])).unwrap();
+        let mut keyframes = 0;
+        let mut last = 0;
+        for frame in &frames[2..] {
+            let (trade, keyframe) = match decoder.read_record(&mut Cursor::new(frame)).unwrap() {
+                Record::Trade(t) => (t, false),
+                Record::Keyframe(t) => (t, true),
+                other => panic!("unexpected {:?}", other),
+            };
+            keyframes += keyframe as usize;
+            last = trade.timestamp;
+            // Every trade the consumer sees decodes to what was sent
+            let i = trade.timestamp - 1_700_000_000_000;
+            let expected: f64 = price(i).parse().unwrap();
+            assert!((trade.price - expected).abs() <= decoder.price_resolution());
+        }
+        assert!(keyframes >= 1);
+        assert_eq!(last, 1_700_000_000_020);
+
+        std::fs::remove_file(format!("/dev/shm/{}", name)).unwrap();
+    }
+
+    #[test]
+    fn test_shm_capacity_must_fit_handshake() {
+        let assets = (0..100)
+            .map(|i| (format!("ASSET{}USDT", i), 100.0, 1.0, 100_000.0))
+            .collect();
+        let (_, header) = BinaryFormat::builder().assets(assets).build().unwrap();
+        let needed = STREAM_MAGIC.len() + 5 + header.len() + 12;
+
+        match check_shm_capacity(4096, &header) {
+            Err(e @ PipelineError::ShmCapacity { .. }) => {
+                assert!(e.to_string().contains(&format!("at least {}", 2 * needed)))
+            }
+            other => panic!("expected ShmCapacity, got {:?}", other),
+        }
+        assert!(check_shm_capacity(8192, &header).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_server_and_pipeline_end_together() {
+        use tokio::io::AsyncReadExt;
+
+        async fn read_frame(stream: &mut tokio::net::TcpStream) -> Vec<u8> {
+            let mut len = [0u8; 4];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut buf = vec![0u8; u32::from_le_bytes(len) as usize];
+            stream.read_exact(&mut buf).await.unwrap();
+            buf
+        }
+
+        // The trade feed closing stops the server and closes its clients
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, _) = broadcast::channel::<Vec<u8>>(16);
+        let (trades_tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let pipeline = {
+            let tx = tx.clone();
+            forward_raw(rx, None, move |data| {
+                let _ = tx.send(data);
+                async { true }
+            })
+        };
+        let served = tokio::spawn(serve_pipeline(
+            listener,
+            Arc::new(RwLock::new(b"HEADER".to_vec())),
+            tx,
+            Arc::new(MemoryGovernor::new(MemoryBudget::default())),
+            tcp::ServeOptions::default(),
+            pipeline,
+        ));
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        for expected in [STREAM_MAGIC, b"START", b"HEADER"] {
+            assert_eq!(read_frame(&mut client).await, expected);
+        }
+        let mut last = trade_message("BTCUSDT", 1_700_000_000_001, "45001", 0);
+        last.raw = Some("LAST".to_string());
+        trades_tx.send(last).unwrap();
+        drop(trades_tx);
+        served.await.unwrap().unwrap();
+
+        // Queued frames still go out before the close
+        assert_eq!(read_frame(&mut client).await, b"LAST");
+        let mut rest = Vec::new();
+        let closed = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest));
+        assert_eq!(closed.await.expect("client left open").unwrap(), 0);
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+
+        // A server that can't bind never starts the pipeline
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (trades_tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let opts = PipelineOptions {
+            passthrough: true,
+            ..Default::default()
+        };
+        let res = handle_trades_tcp(
+            vec![],
+            taken.local_addr().unwrap(),
+            MemoryBudget::default(),
+            false,
+            tcp::ServeOptions::default(),
+            rx,
+            opts,
+        )
+        .await;
+        assert!(matches!(res, Err(PipelineError::TcpServer(_))));
+        assert!(trades_tx.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_asset_reload_publishes_new_header() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Binance REST stand-in: every symbol's recent trades average 2500 x 3
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = vec![0u8; 1024];
+                let _ = socket.read(&mut request).await;
+                let body = r#"[{"price":"2500.0","qty":"3.0"}]"#;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let mut encoder = BinaryFormat::new()
+            .with_assets(vec!["BTCUSDT".to_string()])
+            .unwrap();
+        let mut header = Vec::new();
+        encoder
+            .write_header(&mut header, 1_700_000_000_000, &[45000.0], &[1.0])
+            .unwrap();
+        let opts = PipelineOptions {
+            client: BinanceClient::new()
+                .with_base_url(url::Url::parse(&format!("http://{}", addr)).unwrap()),
+            ..Default::default()
+        };
+        let control = opts.control.clone();
+        let mut header_assets = control.header_assets();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let sink = MemorySink::default();
+        let handle = tokio::spawn(handle_trades(encoder, header, rx, opts, sink.callback()));
+
+        let now = clock::unix_now().as_micros();
+        tx.send(trade_message("BTCUSDT", 1_700_000_000_001, "45001", now))
+            .unwrap();
+        sink.wait_for(3).await;
+
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        control.reload_assets(assets.clone());
+        header_assets
+            .wait_for(|published| *published == assets)
+            .await
+            .unwrap();
+        tx.send(trade_message("ETHUSDT", 1_700_000_000_002, "2501", now))
+            .unwrap();
+        drop(tx);
+        handle.await.unwrap().unwrap();
+
+        // START, header, BTC trade, then the reload's START, header and ETH trade
+        let frames = sink.frames();
+        assert_eq!(frames.len(), 6);
+        assert_eq!(frames[3], b"START");
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header(&mut Cursor::new(&frames[4])).unwrap();
+        assert_eq!(decoder.symbols(), assets);
+        let trade = decoder.read_message(&mut Cursor::new(&frames[5])).unwrap();
+        assert_eq!((trade.symbol.as_str(), trade.price), ("ETHUSDT", 2501.0));
+    }
+
+    #[tokio::test]
+    async fn test_encoder_restart_republishes_header() {
+        use tokio::io::AsyncReadExt;
+
+        async fn read_frame(stream: &mut tokio::net::TcpStream) -> Vec<u8> {
+            let mut len = [0u8; 4];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut buf = vec![0u8; u32::from_le_bytes(len) as usize];
+            stream.read_exact(&mut buf).await.unwrap();
+            buf
+        }
+        let epoch = |reference_price: f64| {
+            BinaryFormat::builder()
+                .reference_timestamp(1_700_000_000_000)
+                .assets(vec![(
+                    "BTCUSDT".to_string(),
+                    reference_price,
+                    1.0,
+                    100_000.0,
+                )])
+                .build()
+                .unwrap()
+        };
+
+        let (first_encoder, first_header) = epoch(45000.0);
+        let (tx, _) = broadcast::channel::<Vec<u8>>(16);
+        let shared = Arc::new(RwLock::new(first_header.clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(tcp::serve_listener(
+            listener,
+            shared.clone(),
+            tx.clone(),
+            Arc::new(MemoryGovernor::new(MemoryBudget::default())),
+            tcp::ServeOptions::default(),
+        ));
+
+        // Panics on the second trade, as if the pipeline hit an internal error
+        let sends = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let callback = {
+            let (tx, sends) = (tx.clone(), sends.clone());
+            move |data: Vec<u8>| {
+                if sends.fetch_add(1, Ordering::SeqCst) == 1 {
+                    panic!("injected pipeline failure");
+                }
+                let _ = tx.send(data);
+                async { true }
+            }
+        };
+        let epochs = Epochs {
+            header: shared.clone(),
+            tx: tx.clone(),
+            snapshot: None,
+        };
+        let (trades_tx, rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut early = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_frame(&mut early).await, STREAM_MAGIC);
+        assert_eq!(read_frame(&mut early).await, b"START");
+        assert_eq!(read_frame(&mut early).await, first_header);
+
+        let handle = tokio::spawn(run_epochs(
+            (first_encoder, first_header.clone()),
+            rx,
+            PipelineOptions::default(),
+            epochs,
+            move || async move { Ok(epoch(50000.0)) },
+            callback,
+        ));
+        let now = clock::unix_now().as_micros();
+        for (ts, price) in [(1, "45001"), (2, "45002"), (3, "50003")] {
+            trades_tx
+                .send(trade_message("BTCUSDT", 1_700_000_000_000 + ts, price, now))
+                .unwrap();
+        }
+        drop(trades_tx);
+        handle.await.unwrap().unwrap();
+
+        // The connected client sees both epochs, each behind its own START + header
+        let mut frames = Vec::new();
+        for _ in 0..6 {
+            frames.push(read_frame(&mut early).await);
+        }
+        let mut decoder = BinaryFormat::new();
+        let mut expect_header = false;
+        let mut trades = Vec::new();
+        for frame in &frames {
+            if frame == b"START" {
+                expect_header = true;
+            } else if std::mem::take(&mut expect_header) {
+                decoder = BinaryFormat::new();
+                decoder.read_header(&mut Cursor::new(frame)).unwrap();
+            } else {
+                trades.push(decoder.read_message(&mut Cursor::new(frame)).unwrap());
+            }
+        }
+        // Trade 2 died with the first epoch; trade 3 decodes against the new header
+        let prices: Vec<f64> = trades.iter().map(|t| t.price).collect();
+        assert_eq!(prices, vec![45001.0, 50003.0]);
+
+        // A client joining now gets the new epoch's header
+        let new_header = shared.read().unwrap().clone();
+        assert_ne!(new_header, frames[1]);
+        let mut late = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_frame(&mut late).await, STREAM_MAGIC);
+        assert_eq!(read_frame(&mut late).await, b"START");
+        assert_eq!(read_frame(&mut late).await, new_header);
+    }
+}
@@ -0,0 +1,499 @@
+// std
+use std::fs::File;
+use std::io::{self, BufRead, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// external
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+
+// internal
+use crate::binance::{TradeMessage, TradeMessageError, parse_decimal};
+use crate::format::{BinaryFormat, BinaryFormatError, STREAM_MAGIC, Trade, check_stream_magic};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RawLogError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Format error: {0}")]
+    Format(#[from] BinaryFormatError),
+    #[error("Invalid raw log line {line}: {source}")]
+    Json {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("Invalid trade in raw log: {0}")]
+    Trade(#[from] TradeMessageError),
+}
+
+/// One trade as it came off the websocket, before any fixed-point conversion.
+/// Price and quantity keep Binance's decimal strings so nothing is lost.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RawTrade {
+    pub timestamp: u64,
+    pub asset: String,
+    pub price: String,
+    pub quantity: String,
+    pub is_buyer_maker: bool,
+}
+
+impl From<&TradeMessage> for RawTrade {
+    fn from(msg: &TradeMessage) -> Self {
+        Self {
+            timestamp: msg.timestamp,
+            asset: msg.asset.clone(),
+            price: msg.price.clone(),
+            quantity: msg.quantity.clone(),
+            is_buyer_maker: msg.is_buyer_maker,
+        }
+    }
+}
+
+/// Debug sink running next to the lossy binary encode: every trade the pipeline
+/// takes in goes to a JSON-lines log, and every frame it emits goes, unframed,
+/// to a stream file. `verify` diffs the two afterwards.
+pub struct RawLog {
+    trades: Mutex<Box<dyn Write + Send>>,
+    stream: Mutex<Box<dyn Write + Send>>,
+}
+
+impl RawLog {
+    /// Starts the stream with `STREAM_MAGIC`, as a consumer would receive it.
+    pub fn new(trades: impl Write + Send + 'static, stream: impl Write + Send + 'static) -> Self {
+        let log = Self {
+            trades: Mutex::new(Box::new(trades)),
+            stream: Mutex::new(Box::new(stream)),
+        };
+        log.record_frame(STREAM_MAGIC);
+        log
+    }
+
+    /// Create (or truncate) `<prefix>.jsonl` and `<prefix>.bin`. Nothing is held
+    /// in a buffer past a line or frame, so both are complete even if the process
+    /// is killed.
+    pub fn create(prefix: impl AsRef<Path>) -> io::Result<Self> {
+        let (trades, stream) = Self::paths(prefix);
+        Ok(Self::new(
+            LineWriter::new(File::create(trades)?),
+            File::create(stream)?,
+        ))
+    }
+
+    /// The raw log and stream file paths for `prefix`.
+    pub fn paths(prefix: impl AsRef<Path>) -> (PathBuf, PathBuf) {
+        let prefix = prefix.as_ref().as_os_str();
+        let with = |ext: &str| {
+            let mut path = prefix.to_owned();
+            path.push(ext);
+            PathBuf::from(path)
+        };
+        (with(".jsonl"), with(".bin"))
+    }
+
+    pub fn record(&self, msg: &TradeMessage) {
+        let line = serde_json::to_string(&RawTrade::from(msg)).expect("RawTrade serializes");
+        let mut trades = self.trades.lock().unwrap();
+        if let Err(e) = writeln!(trades, "{}", line) {
+            tracing::error!("raw log write failed: {}", e);
+        }
+    }
+
+    /// Append a frame exactly as handed to the transport.
+    pub fn record_frame(&self, frame: &[u8]) {
+        if let Err(e) = self.stream.lock().unwrap().write_all(frame) {
+            tracing::error!("raw log stream write failed: {}", e);
+        }
+    }
+}
+
+/// Trades of a recorded stream (a `RawLog` `.bin`, or any capture of the magic,
+/// optional `START`, header and records), decoded in place from a memory-mapped
+/// file. Only the trades themselves are allocated, so recordings far larger
+/// than RAM can be scanned. A handshake between records resets the decoder, see
+/// `read_handshake`.
+///
+/// A truncated last record (the recorder was killed mid-write) is reported as
+/// an error, after which iteration stops.
+pub struct MmapRecordingReader {
+    mmap: Mmap,
+    /// Offset of the next record
+    pos: usize,
+    decoder: BinaryFormat,
+    failed: bool,
+}
+
+impl MmapRecordingReader {
+    /// Map `path` and read up to the end of its header.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RawLogError> {
+        let file = File::open(path)?;
+        // SAFETY: the map is read-only. A recording still being appended to is
+        // fine, its new tail is just not seen; one truncated under us would fault.
+        let mmap = unsafe { Mmap::map(&file)? };
+        let (magic, stream) = mmap.split_at(mmap.len().min(STREAM_MAGIC.len()));
+        check_stream_magic(magic)?;
+        let mut data = stream.strip_prefix(b"START").unwrap_or(stream);
+        let mut decoder = BinaryFormat::new();
+        decoder.read_header_slice(&mut data)?;
+        let pos = mmap.len() - data.len();
+        Ok(Self {
+            mmap,
+            pos,
+            decoder,
+            failed: false,
+        })
+    }
+
+    /// The decoder, set up from the recording's header.
+    pub fn decoder(&self) -> &BinaryFormat {
+        &self.decoder
+    }
+
+    /// Bytes not decoded yet.
+    pub fn remaining(&self) -> usize {
+        self.mmap.len() - self.pos
+    }
+}
+
+impl Iterator for MmapRecordingReader {
+    type Item = Result<Trade, RawLogError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let mut data = &self.mmap[self.pos..];
+        let trade = next_trade(&mut self.decoder, &mut data)?;
+        self.pos = self.mmap.len() - data.len();
+        self.failed = trade.is_err();
+        Some(trade.map_err(Into::into))
+    }
+}
+
+/// A handshake between records: an optional `START`, then a header by the rule
+/// of `BinaryFormat::from_header_prefix`. The pipeline sends (and logs) one
+/// every epoch, so a recording spanning an asset reload carries a second header
+/// mid-stream, after which records decode against it. `data` is advanced past
+/// the handshake if it starts with one.
+fn read_handshake(data: &mut &[u8]) -> Option<BinaryFormat> {
+    let mut rest = data.strip_prefix(b"START").unwrap_or(data);
+    let decoder = BinaryFormat::from_header_prefix(&mut rest)?;
+    *data = rest;
+    Some(decoder)
+}
+
+/// The next trade of a recording, advancing `data` past it. Control records
+/// are applied and skipped, and a handshake replaces `decoder`.
+fn next_trade(
+    decoder: &mut BinaryFormat,
+    data: &mut &[u8],
+) -> Option<Result<Trade, BinaryFormatError>> {
+    while !data.is_empty() {
+        if let Some(reset) = read_handshake(data) {
+            *decoder = reset;
+            continue;
+        }
+        match decoder.read_record_slice(data) {
+            Ok(record) => {
+                if let Some(trade) = record.into_trade() {
+                    return Some(Ok(trade));
+                }
+            }
+            Err(e) => return Some(Err(e)),
+        }
+    }
+    None
+}
+
+/// Parse a log written by `RawLog`.
+pub fn read_raw_log(reader: impl BufRead) -> Result<Vec<RawTrade>, RawLogError> {
+    let mut trades = Vec::new();
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let trade = serde_json::from_str(&line).map_err(|source| RawLogError::Json {
+            line: idx + 1,
+            source,
+        })?;
+        trades.push(trade);
+    }
+    Ok(trades)
+}
+
+/// Outcome of diffing a decoded binary stream against its raw log.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Trades found in both
+    pub matched: usize,
+    /// Raw trades with no decoded counterpart (dropped by the pipeline)
+    pub dropped: Vec<RawTrade>,
+    /// Decoded trades with no raw counterpart
+    pub extra: Vec<Trade>,
+    pub max_price_error: f64,
+    pub max_quantity_error: f64,
+    /// Step size of the stream's coarsest asset over every header, see
+    /// `BinaryFormat::price_resolution`
+    pub price_resolution: f64,
+    pub quantity_resolution: f64,
+}
+
+impl VerifyReport {
+    /// Nothing extra, and every matched trade decoded within resolution.
+    /// Dropped trades are reported but allowed, the pipeline drops on purpose.
+    pub fn within_resolution(&self) -> bool {
+        self.extra.is_empty()
+            && self.max_price_error <= self.price_resolution
+            && self.max_quantity_error <= self.quantity_resolution
+    }
+}
+
+/// Decode `stream` (the bytes a consumer received: magic, optional `START`,
+/// header, then records, with a handshake again wherever the decoder was reset,
+/// see `read_handshake`) and match each trade against `raw` in order.
+///
+/// A decoded trade matches the first raw trade after the previous match with the
+/// same asset, timestamp and side; raw trades skipped over count as dropped.
+pub fn verify(stream: &[u8], raw: &[RawTrade]) -> Result<VerifyReport, RawLogError> {
+    let (magic, stream) = stream.split_at(stream.len().min(STREAM_MAGIC.len()));
+    check_stream_magic(magic)?;
+    let mut data = stream.strip_prefix(b"START").unwrap_or(stream);
+    let mut decoder = BinaryFormat::new();
+    decoder.read_header_slice(&mut data)?;
+
+    let mut report = VerifyReport {
+        price_resolution: decoder.price_resolution(),
+        quantity_resolution: decoder.quantity_resolution(),
+        ..Default::default()
+    };
+    let mut next = 0;
+    while let Some(trade) = next_trade(&mut decoder, &mut data) {
+        let trade = trade?;
+        // A later header may have coarser scales
+        report.price_resolution = report.price_resolution.max(decoder.price_resolution());
+        report.quantity_resolution = report
+            .quantity_resolution
+            .max(decoder.quantity_resolution());
+        let found = raw[next..].iter().position(|r| {
+            r.asset == trade.symbol
+                && r.timestamp == trade.timestamp
+                && r.is_buyer_maker == trade.is_buyer_maker
+        });
+        let Some(offset) = found else {
+            report.extra.push(trade);
+            continue;
+        };
+        report.dropped.extend_from_slice(&raw[next..next + offset]);
+        let source = &raw[next + offset];
+        next += offset + 1;
+
+        let price = parse_decimal(&source.price)?;
+        let quantity = parse_decimal(&source.quantity)?;
+        report.matched += 1;
+        report.max_price_error = report.max_price_error.max((trade.price - price).abs());
+        report.max_quantity_error = report
+            .max_quantity_error
+            .max((trade.quantity - quantity).abs());
+    }
+    report.dropped.extend_from_slice(&raw[next..]);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct SharedBuf(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn message(asset: &str, timestamp: u64, price: &str, quantity: &str) -> TradeMessage {
+        TradeMessage {
+            timestamp,
+            asset: asset.to_string(),
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+            is_buyer_maker: timestamp.is_multiple_of(3),
+            received_at: 0,
+            shard: None,
+            raw: None,
+        }
+    }
+
+    #[test]
+    fn test_verify_against_raw_source() {
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1_700_000_000_000)
+            .assets(vec![
+                ("BTCUSDT".to_string(), 45000.0, 0.5, 100_000.0),
+                ("ETHUSDT".to_string(), 3000.0, 2.0, 100_000.0),
+            ])
+            .build()
+            .unwrap();
+
+        let trades = SharedBuf(Default::default());
+        let stream = SharedBuf(Default::default());
+        let log = RawLog::new(trades.clone(), stream.clone());
+        log.record_frame(b"START");
+        log.record_frame(&header);
+        let messages = [
+            message("BTCUSDT", 1_700_000_000_010, "45000.123456", "0.001234"),
+            message("ETHUSDT", 1_700_000_000_011, "3001.987654", "1.5"),
+            message("BTCUSDT", 1_700_000_000_012, "44999.999999", "0.25"),
+            message("ETHUSDT", 1_700_000_000_015, "2999.00001", "12.345678"),
+            message("BTCUSDT", 1_700_000_000_020, "45010.5", "3"),
+        ];
+        for (idx, msg) in messages.into_iter().enumerate() {
+            log.record(&msg);
+            // The pipeline dropped this one
+            if idx == 2 {
+                continue;
+            }
+            log.record_frame(&encoder.encode(&msg.to_trade().unwrap()).unwrap());
+        }
+        log.record_frame(&encoder.encode_heartbeat(1_700_000_000_030).unwrap());
+
+        let raw = read_raw_log(trades.0.lock().unwrap().as_slice()).unwrap();
+        let stream = stream.0.lock().unwrap().clone();
+        assert_eq!(raw.len(), 5);
+        let report = verify(&stream, &raw).unwrap();
+        assert_eq!(report.matched, 4);
+        assert_eq!(report.dropped.len(), 1);
+        assert_eq!(report.dropped[0].timestamp, 1_700_000_000_012);
+        assert!(report.extra.is_empty());
+        assert!(report.max_price_error > 0.0);
+        assert!(report.within_resolution(), "{:?}", report);
+
+        // A trade missing from the raw log shows up as extra
+        let report = verify(&stream, &raw[1..]).unwrap();
+        assert_eq!(report.extra.len(), 1);
+        assert!(!report.within_resolution());
+    }
+
+    #[test]
+    fn test_second_epoch_resets_the_decoder() {
+        let trades = SharedBuf(Default::default());
+        let stream = SharedBuf(Default::default());
+        let log = RawLog::new(trades.clone(), stream.clone());
+        let epochs = [
+            vec![("BTCUSDT".to_string(), 45000.0, 0.5, 100_000.0)],
+            // Reloaded: another asset first, and a coarser price scale
+            vec![
+                ("ETHUSDT".to_string(), 3000.0, 2.0, 1000.0),
+                ("BTCUSDT".to_string(), 45100.0, 0.5, 1000.0),
+            ],
+        ];
+        let messages = [
+            [
+                message("BTCUSDT", 1_700_000_000_010, "45000.5", "0.25"),
+                message("BTCUSDT", 1_700_000_000_011, "45001.25", "0.5"),
+            ],
+            [
+                message("ETHUSDT", 1_700_000_000_020, "3001.5", "1.5"),
+                message("BTCUSDT", 1_700_000_000_021, "45100.75", "0.125"),
+            ],
+        ];
+        for (assets, messages) in epochs.into_iter().zip(messages) {
+            let (mut encoder, header) = BinaryFormat::builder()
+                .reference_timestamp(1_700_000_000_000)
+                .assets(assets)
+                .build()
+                .unwrap();
+            log.record_frame(b"START");
+            log.record_frame(&header);
+            for msg in messages {
+                log.record(&msg);
+                log.record_frame(&encoder.encode(&msg.to_trade().unwrap()).unwrap());
+            }
+        }
+
+        let raw = read_raw_log(trades.0.lock().unwrap().as_slice()).unwrap();
+        let stream = stream.0.lock().unwrap().clone();
+        let report = verify(&stream, &raw).unwrap();
+        assert_eq!(report.matched, 4);
+        assert!(report.dropped.is_empty());
+        assert_eq!(report.price_resolution, 1e-3);
+        assert!(report.within_resolution(), "{:?}", report);
+
+        let path = std::env::temp_dir().join(format!(
+            "perp_signal_hft_test_two_epochs_{}.bin",
+            std::process::id()
+        ));
+        std::fs::write(&path, &stream).unwrap();
+        let mut reader = MmapRecordingReader::open(&path).unwrap();
+        let symbols: Vec<_> = reader.by_ref().map(|trade| trade.unwrap().symbol).collect();
+        assert_eq!(symbols, ["BTCUSDT", "BTCUSDT", "ETHUSDT", "BTCUSDT"]);
+        assert_eq!(reader.decoder().symbols(), ["ETHUSDT", "BTCUSDT"]);
+        assert_eq!(reader.remaining(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_mmap_recording_reader() {
+        let (mut encoder, header) = BinaryFormat::builder()
+            .reference_timestamp(1_700_000_000_000)
+            .assets(vec![
+                ("BTCUSDT".to_string(), 45000.0, 0.5, 100_000.0),
+                ("ETHUSDT".to_string(), 3000.0, 2.0, 100_000.0),
+            ])
+            .build()
+            .unwrap();
+        let prefix = std::env::temp_dir().join(format!(
+            "perp_signal_hft_test_mmap_recording_{}",
+            std::process::id()
+        ));
+        let log = RawLog::create(&prefix).unwrap();
+        log.record_frame(b"START");
+        log.record_frame(&header);
+        let mut sent = Vec::new();
+        for i in 0..1000u64 {
+            let trade = Trade {
+                symbol: ["BTCUSDT", "ETHUSDT"][i as usize % 2].to_string(),
+                timestamp: 1_700_000_000_000 + i,
+                price: [45000.0, 3000.0][i as usize % 2] + (i % 50) as f64 * 0.25,
+                quantity: 0.001 * (i + 1) as f64,
+                is_buyer_maker: i % 3 == 0,
+            };
+            log.record_frame(&encoder.encode(&trade).unwrap());
+            if i % 100 == 0 {
+                log.record_frame(&encoder.encode_heartbeat(trade.timestamp).unwrap());
+            }
+            sent.push(trade);
+        }
+        // Cut off partway through a record
+        log.record_frame(&encoder.encode(&sent[0]).unwrap()[..1]);
+        drop(log);
+
+        let (jsonl, bin) = RawLog::paths(&prefix);
+        let mut reader = MmapRecordingReader::open(&bin).unwrap();
+        // The header's scales made it into the decoder
+        assert_eq!(reader.decoder().price_resolution(), 1e-5);
+        let trades: Vec<_> = reader
+            .by_ref()
+            .take(sent.len())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(trades.len(), sent.len());
+        for (got, want) in trades.iter().zip(&sent) {
+            assert_eq!(got.symbol, want.symbol);
+            assert_eq!(got.timestamp, want.timestamp);
+            assert_eq!(got.is_buyer_maker, want.is_buyer_maker);
+            assert!((got.price - want.price).abs() <= 1e-5);
+            assert!((got.quantity - want.quantity).abs() <= 1e-5);
+        }
+        assert!(matches!(reader.next(), Some(Err(RawLogError::Format(_)))));
+        assert!(reader.next().is_none());
+
+        std::fs::remove_file(jsonl).unwrap();
+        std::fs::remove_file(bin).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+// std
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+// internal
+use crate::format::Trade;
+
+pub const DEFAULT_RECENT_DEPTH: usize = 16;
+
+/// Per-asset ring of the most recent trades forwarded by the pipeline.
+///
+/// Rings are allocated up front for the configured assets only, so memory is
+/// bounded by `assets.len() * depth` no matter what the feed sends.
+pub struct RecentTrades {
+    depth: usize,
+    rings: HashMap<String, Mutex<VecDeque<Trade>>>,
+}
+
+impl RecentTrades {
+    pub fn new(assets: &[String], depth: usize) -> Self {
+        let rings = assets
+            .iter()
+            .map(|a| (a.clone(), Mutex::new(VecDeque::with_capacity(depth))))
+            .collect();
+        Self { depth, rings }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Record a trade, evicting the oldest one once the ring is full.
+    /// Trades for symbols outside the configured asset set are ignored.
+    pub fn record(&self, trade: &Trade) {
+        if self.depth == 0 {
+            return;
+        }
+        let Some(ring) = self.rings.get(&trade.symbol) else {
+            return;
+        };
+        let mut ring = ring.lock().unwrap();
+        if ring.len() == self.depth {
+            ring.pop_front();
+        }
+        ring.push_back(trade.clone());
+    }
+
+    /// Recent trades for `symbol`, oldest first. `None` if the symbol isn't tracked.
+    pub fn latest(&self, symbol: &str) -> Option<Vec<Trade>> {
+        self.rings
+            .get(symbol)
+            .map(|ring| ring.lock().unwrap().iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(symbol: &str, timestamp: u64) -> Trade {
+        Trade {
+            symbol: symbol.to_string(),
+            timestamp,
+            price: 100.0 + timestamp as f64,
+            quantity: 1.0,
+            is_buyer_maker: false,
+        }
+    }
+
+    #[test]
+    fn test_ring_keeps_latest_in_order() {
+        let assets = vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()];
+        let recent = RecentTrades::new(&assets, 4);
+
+        for ts in 0..10 {
+            recent.record(&trade("BTCUSDT", ts));
+        }
+        recent.record(&trade("ETHUSDT", 42));
+        recent.record(&trade("SOLUSDT", 7));
+
+        let btc: Vec<u64> = recent
+            .latest("BTCUSDT")
+            .unwrap()
+            .iter()
+            .map(|t| t.timestamp)
+            .collect();
+        assert_eq!(btc, vec![6, 7, 8, 9]);
+
+        let eth = recent.latest("ETHUSDT").unwrap();
+        assert_eq!(eth.len(), 1);
+        assert_eq!(eth[0].timestamp, 42);
+
+        // Untracked symbols are never stored
+        assert!(recent.latest("SOLUSDT").is_none());
+    }
+}